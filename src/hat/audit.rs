@@ -0,0 +1,96 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Events of the durable audit log of a hash index (see `IndexConfig::audit`).
+//!
+//! Unlike the trace, the audit log is complete and written to the backend, in the same transaction
+//! as the change it describes, so that the two cannot diverge.
+
+use time;
+
+use hash_index::{Hash};
+
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AuditOp {
+  /// The hash was reserved. The detail is empty.
+  Reserve,
+  /// The entry was written to the backend. The detail is its persistent reference.
+  Commit,
+  /// The persistent reference of the entry was replaced. The detail is the new reference.
+  Relocate,
+  /// The entry was deleted from the backend and queued again (see `Msg::Uncommit`). The detail is
+  /// empty.
+  Uncommit,
+  /// The entry was deleted from the backend (see `Msg::DeleteIdRange`). The detail is its
+  /// persistent reference.
+  Delete,
+}
+
+impl AuditOp {
+  /// The code of the operation in the log.
+  pub fn code(&self) -> u8 {
+    match *self {
+      AuditOp::Reserve => 1,
+      AuditOp::Commit => 2,
+      AuditOp::Relocate => 3,
+      AuditOp::Uncommit => 4,
+      AuditOp::Delete => 5,
+    }
+  }
+
+  pub fn from_code(code: u8) -> Option<AuditOp> {
+    match code {
+      1 => Some(AuditOp::Reserve),
+      2 => Some(AuditOp::Commit),
+      3 => Some(AuditOp::Relocate),
+      4 => Some(AuditOp::Uncommit),
+      5 => Some(AuditOp::Delete),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuditEvent {
+  /// Increases with every event in the file (across namespaces).
+  pub seq: i64,
+  /// Milliseconds since the Unix epoch.
+  pub ts_ms: i64,
+  pub op: AuditOp,
+  pub hash: Hash,
+  pub detail: Vec<u8>,
+}
+
+
+/// The current wall clock time, in milliseconds since the Unix epoch.
+pub fn now_ms() -> i64 {
+  let now = time::get_time();
+  now.sec * 1000 + (now.nsec / 1000000) as i64
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn op_codes_round_trip() {
+    for &op in [AuditOp::Reserve, AuditOp::Commit, AuditOp::Relocate, AuditOp::Uncommit,
+                AuditOp::Delete].iter() {
+      assert_eq!(Some(op), AuditOp::from_code(op.code()));
+    }
+    assert_eq!(None, AuditOp::from_code(0));
+  }
+}
@@ -120,9 +120,9 @@ impl BlobStoreBackend for FileBackend {
 
 #[derive(Debug, Clone, Eq, PartialEq, RustcEncodable, RustcDecodable)]
 pub struct BlobID {
-  name: Vec<u8>,
-  begin: usize,
-  end: usize,
+  pub name: Vec<u8>,
+  pub begin: usize,
+  pub end: usize,
 }
 
 impl BlobID {
@@ -131,6 +131,11 @@ impl BlobID {
     return rustc_serialize::json::decode(str::from_utf8(bytes.as_slice()).unwrap()).unwrap();
   }
 
+  /// Like `from_bytes`, but returns `None` if `bytes` is not a valid `BlobID`.
+  pub fn try_from_bytes(bytes: &[u8]) -> Option<BlobID> {
+    str::from_utf8(bytes).ok().and_then(|s| rustc_serialize::json::decode(s).ok())
+  }
+
   pub fn as_bytes(&self) -> Vec<u8> {
     return rustc_serialize::json::encode(&self).unwrap().as_bytes().to_vec();
   }
@@ -0,0 +1,111 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A bloom filter over byte strings, to answer most lookups of unknown keys without a query.
+
+use std::cmp;
+
+
+/// Bits per expected key, and probes per key, for a false positive rate of about 1%.
+const BITS_PER_KEY: u64 = 10;
+const PROBES: u64 = 7;
+
+/// The smallest filter that is allocated, so that a small index can grow a while.
+const MIN_BITS: u64 = 1 << 16;
+
+
+pub struct BloomFilter {
+  bits: Vec<u64>,
+  num_bits: u64,
+  len: u64,
+}
+
+
+/// Two independent 64-bit FNV-1a hashes of `key`, for double hashing.
+fn fnv_pair(key: &[u8]) -> (u64, u64) {
+  let mut h1 = 0xcbf29ce484222325u64;
+  let mut h2 = 0x84222325cbf29ce4u64;
+  for &b in key.iter() {
+    h1 = (h1 ^ b as u64).wrapping_mul(0x100000001b3);
+    h2 = (h2 ^ b as u64).wrapping_mul(0x100000001b3);
+  }
+  // An odd step visits distinct bits for every probe:
+  (h1, h2 | 1)
+}
+
+
+impl BloomFilter {
+
+  /// A filter with a false positive rate of about 1% while it holds at most `capacity` keys. The
+  /// rate rises as more keys are inserted, but keys that were inserted are always found.
+  pub fn with_capacity(capacity: u64) -> BloomFilter {
+    let num_bits = cmp::max(MIN_BITS, capacity * BITS_PER_KEY);
+    let words = ((num_bits + 63) / 64) as usize;
+    BloomFilter{bits: vec![0; words], num_bits: words as u64 * 64, len: 0}
+  }
+
+  pub fn insert(&mut self, key: &[u8]) {
+    let (h1, h2) = fnv_pair(key);
+    for i in 0..PROBES {
+      let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+      self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+    }
+    self.len += 1;
+  }
+
+  /// Returns false only if `key` was never inserted.
+  pub fn may_contain(&self, key: &[u8]) -> bool {
+    let (h1, h2) = fnv_pair(key);
+    (0..PROBES).all(|i| {
+      let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+      self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+    })
+  }
+
+  /// The number of insertions (counting keys that were inserted more than once).
+  pub fn len(&self) -> u64 {
+    self.len
+  }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn inserted_keys_are_found() {
+    let mut bloom = BloomFilter::with_capacity(1000);
+    for i in 0..1000 {
+      bloom.insert(format!("key {}", i).as_bytes());
+    }
+    assert_eq!(1000, bloom.len());
+    for i in 0..1000 {
+      assert!(bloom.may_contain(format!("key {}", i).as_bytes()));
+    }
+  }
+
+  #[test]
+  fn few_false_positives() {
+    let mut bloom = BloomFilter::with_capacity(10000);
+    for i in 0..10000 {
+      bloom.insert(format!("key {}", i).as_bytes());
+    }
+    let false_positives = (0..10000).filter(|i| {
+      bloom.may_contain(format!("other {}", i).as_bytes())
+    }).count();
+    assert!(false_positives < 300, "{} false positives", false_positives);
+  }
+}
@@ -13,13 +13,26 @@
 // limitations under the License.
 
 use std::thunk::Thunk;
+use std::borrow::{Borrow};
 use std::collections::{BTreeMap};
 use std::collections::btree_map;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+/// Identifies a registered callback, so that it can be cancelled before it is called.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct CallbackToken(pub u64);
 
 
 pub struct CallbackContainer<K> {
-  callbacks: BTreeMap<K, Vec<Thunk<'static>>>,
-  ready: Vec<Thunk<'static>>,
+  callbacks: BTreeMap<K, Vec<(CallbackToken, Thunk<'static>)>>,
+  ready: Vec<(CallbackToken, u64, Thunk<'static>)>,
+  next_token: u64,
+  readied: u64,
+  // The number of callbacks registered under keys (an `add_all` callback counts once per key).
+  pending: usize,
 }
 
 
@@ -27,34 +40,197 @@ impl <K: Ord> CallbackContainer<K> {
 
   pub fn new() -> CallbackContainer<K> {
     CallbackContainer{callbacks: BTreeMap::new(),
-                      ready: vec!()}
+                      ready: vec!(),
+                      next_token: 0,
+                      readied: 0,
+                      pending: 0}
+  }
+
+  /// Like `new`, but with room for `n` callbacks that are allowed to flush before reallocating.
+  pub fn with_capacity(n: usize) -> CallbackContainer<K> {
+    let mut container = CallbackContainer::new();
+    container.ready.reserve(n);
+    container
+  }
+
+  fn new_token(&mut self) -> CallbackToken {
+    self.next_token += 1;
+    CallbackToken(self.next_token)
   }
 
-  pub fn add(&mut self, k: K, callback: Thunk<'static>) {
+  fn push(&mut self, k: K, token: CallbackToken, callback: Thunk<'static>) {
+    self.pending += 1;
     match self.callbacks.entry(k) {
       btree_map::Entry::Occupied(mut entry) => {
-        entry.get_mut().push(callback);
+        entry.get_mut().push((token, callback));
       },
       btree_map::Entry::Vacant(space) => {
-        space.insert(vec!(callback));
+        space.insert(vec!((token, callback)));
       }
     }
   }
 
-  pub fn allow_flush_of(&mut self, k: &K) {
-    self.ready.extend(self.callbacks.remove(k).unwrap_or(vec!()).into_iter());
+  pub fn add(&mut self, k: K, callback: Thunk<'static>) -> CallbackToken {
+    let token = self.new_token();
+    self.push(k, token, callback);
+    token
+  }
+
+  /// Call `callback` once all of `keys` are flushed (right away if `keys` is empty).
+  /// Cancelling the returned token drops the callback for all keys that are not yet flushed.
+  pub fn add_all(&mut self, keys: Vec<K>, callback: Thunk<'static>) -> CallbackToken {
+    if keys.len() == 0 {
+      return self.call_now(callback);
+    }
+    let token = self.new_token();
+    let remaining = Arc::new(AtomicUsize::new(keys.len()));
+    let callback = Arc::new(Mutex::new(Some(callback)));
+    for k in keys.into_iter() {
+      let remaining = remaining.clone();
+      let callback = callback.clone();
+      self.push(k, token, Box::new(move|| {
+        // The last key to be flushed calls the callback:
+        if remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+          match callback.lock().unwrap().take() {
+            Some(f) => f(),
+            None => (),
+          }
+        }
+      }));
+    }
+    token
+  }
+
+  /// Register a callback for each of the keys, all under one token, so that cancelling the token
+  /// drops those that are not called yet.
+  pub fn add_group(&mut self, callbacks: Vec<(K, Thunk<'static>)>) -> CallbackToken {
+    let token = self.new_token();
+    for (k, callback) in callbacks.into_iter() {
+      self.push(k, token, callback);
+    }
+    token
+  }
+
+  /// Call `callback` right away. The returned token is spent, so cancelling it does nothing.
+  pub fn call_now(&mut self, callback: Thunk<'static>) -> CallbackToken {
+    callback();
+    self.new_token()
+  }
+
+  /// Drop a callback that has not been called yet.
+  /// Returns false if it was already called or cancelled.
+  pub fn cancel(&mut self, token: CallbackToken) -> bool where K: Clone {
+    let before = self.ready.len();
+    self.ready.retain(|&(t, _, _)| t != token);
+    let mut cancelled = self.ready.len() < before;
+
+    // A callback added with `add_all` is registered under several keys:
+    let keys: Vec<K> = self.callbacks.iter()
+      .filter(|&(_, callbacks)| callbacks.iter().any(|&(t, _)| t == token))
+      .map(|(k, _)| k.clone())
+      .collect();
+    for k in keys.into_iter() {
+      let mut callbacks = self.callbacks.remove(&k).expect("key was found");
+      let before = callbacks.len();
+      callbacks.retain(|&(t, _)| t != token);
+      self.pending -= before - callbacks.len();
+      if callbacks.len() > 0 {
+        self.callbacks.insert(k, callbacks);
+      }
+      cancelled = true;
+    }
+    cancelled
+  }
+
+  /// Drop the oldest callback that is not yet allowed to flush, without calling it.
+  /// Returns false if there is none.
+  pub fn evict_oldest(&mut self) -> bool where K: Clone {
+    let oldest = self.callbacks.values()
+      .flat_map(|callbacks| callbacks.iter().map(|&(t, _)| t))
+      .min();
+    match oldest {
+      Some(token) => self.cancel(token),
+      None => false,
+    }
+  }
+
+  /// Drop all callbacks registered for `k` without calling them.
+  pub fn remove<Q: ?Sized + Ord>(&mut self, k: &Q) where K: Borrow<Q> {
+    self.pending -= self.callbacks.remove(k).map(|callbacks| callbacks.len()).unwrap_or(0);
+  }
+
+  pub fn allow_flush_of<Q: ?Sized + Ord>(&mut self, k: &Q) where K: Borrow<Q> {
+    let callbacks = self.callbacks.remove(k).unwrap_or(vec!());
+    self.pending -= callbacks.len();
+    for (token, f) in callbacks.into_iter() {
+      self.ready.push((token, self.readied, f));
+      self.readied += 1;
+    }
+  }
+
+  /// Marks the callbacks that are allowed to flush so far (see `drop_ready_since`).
+  pub fn ready_mark(&self) -> u64 {
+    self.readied
+  }
+
+  /// Drop the callbacks allowed to flush after `mark` was taken, without calling them.
+  pub fn drop_ready_since(&mut self, mark: u64) {
+    self.ready.retain(|&(_, seq, _)| seq < mark);
   }
 
   pub fn flush(&mut self) {
     while self.ready.len() > 0 {
-      let f = self.ready.pop().expect("len() > 0");
+      let (_, _, f) = self.ready.pop().expect("len() > 0");
       f();
     }
     assert_eq!(self.ready.len(), 0);
   }
 
+  /// Like `flush`, but call at most `max` of the ready callbacks.
+  /// Returns the number of ready callbacks that are left for a later call.
+  pub fn flush_some(&mut self, max: usize) -> usize {
+    for _ in 0..max {
+      match self.ready.pop() {
+        Some((_, _, f)) => f(),
+        None => break,
+      }
+    }
+    self.ready.len()
+  }
+
+  /// The number of callbacks that are allowed to flush but not yet called.
+  pub fn ready_len(&self) -> usize {
+    self.ready.len()
+  }
+
+  /// The keys with callbacks that are not yet allowed to flush.
+  #[cfg(test)]
+  pub fn keys(&self) -> Vec<&K> {
+    self.callbacks.keys().collect()
+  }
+
   pub fn len(&self) -> usize {
     self.callbacks.len()
   }
 
+  /// Release the spare capacity of the callback lists, e.g. after a burst of registrations.
+  /// Returns an estimate of the bytes released.
+  pub fn shrink(&mut self) -> usize {
+    let mut freed = (self.ready.capacity() - self.ready.len()) *
+      mem::size_of::<(CallbackToken, u64, Thunk<'static>)>();
+    self.ready.shrink_to_fit();
+    for callbacks in self.callbacks.values_mut() {
+      freed += (callbacks.capacity() - callbacks.len()) *
+        mem::size_of::<(CallbackToken, Thunk<'static>)>();
+      callbacks.shrink_to_fit();
+    }
+    freed
+  }
+
+  /// The number of callbacks that are not yet allowed to flush, counting a callback added with
+  /// `add_all` once for each of its keys.
+  pub fn pending(&self) -> usize {
+    self.pending
+  }
+
 }
@@ -12,6 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::i64;
+
+
+/// The largest value a counter can return. Ids are never reused, so this is the total capacity
+/// of ids over the lifetime of an index (counting gaps, and the offsets of merged indexes).
+pub const MAX_VALUE: i64 = i64::MAX;
+
+/// Returned by `CumulativeCounter::next` once `MAX_VALUE` has been used.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Exhausted;
+
 
 pub struct CumulativeCounter {
   previous: i64,
@@ -24,9 +35,44 @@ impl CumulativeCounter {
     CumulativeCounter{previous: previous}
   }
 
-  pub fn next(&mut self) -> i64 {
-    self.previous += 1;
-    return self.previous;
+  /// The value that the next call to `next` returns, or `None` if the counter is exhausted.
+  pub fn peek(&self) -> Option<i64> {
+    self.previous.checked_add(1)
+  }
+
+  /// Count up. Fails rather than wrapping around to a negative value, which would break the
+  /// ordering of everything counted so far.
+  pub fn next(&mut self) -> Result<i64, Exhausted> {
+    match self.peek() {
+      Some(value) => { self.previous = value; Ok(value) },
+      None => Err(Exhausted),
+    }
+  }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn counts_up() {
+    let mut counter = CumulativeCounter::new(0);
+    assert_eq!(Some(1), counter.peek());
+    assert_eq!(Ok(1), counter.next());
+    assert_eq!(Ok(2), counter.next());
+    assert_eq!(Some(3), counter.peek());
   }
 
+  #[test]
+  fn stops_at_max_value() {
+    let mut counter = CumulativeCounter::new(MAX_VALUE - 1);
+    assert_eq!(Some(MAX_VALUE), counter.peek());
+    assert_eq!(Ok(MAX_VALUE), counter.next());
+
+    assert_eq!(None, counter.peek());
+    assert_eq!(Err(Exhausted), counter.next());
+    assert_eq!(Err(Exhausted), counter.next());
+  }
 }
@@ -0,0 +1,112 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The digest behind `Hash::new`, chosen when building.
+//!
+//! By default this is SHA-512 (from libsodium). Building with the `blake2` feature switches to
+//! BLAKE2b with 32-byte digests instead. The widths differ on purpose: an index records the width
+//! of its hashes, so opening it with a build that uses the other digest fails with
+//! `HashIndexError::DigestWidthMismatch` rather than mixing digests in one index.
+//!
+//! Both builds are tested, i.e. `cargo test` and `cargo test --features blake2`. The tests below
+//! check the digest of the build they run in, as named by `ALGORITHM`.
+
+#[cfg(not(feature = "blake2"))]
+use sodiumoxide::crypto::hash::{sha512};
+
+#[cfg(feature = "blake2")]
+use blake2_rfc::blake2b::{Blake2b};
+
+
+#[cfg(not(feature = "blake2"))]
+pub const ALGORITHM: &'static str = "sha512";
+#[cfg(not(feature = "blake2"))]
+pub const DIGEST_BYTES: usize = sha512::HASHBYTES;
+
+#[cfg(feature = "blake2")]
+pub const ALGORITHM: &'static str = "blake2b-256";
+#[cfg(feature = "blake2")]
+pub const DIGEST_BYTES: usize = 32;
+
+
+/// Incremental computation of a digest, for input that is not available all at once.
+pub struct State {
+  #[cfg(not(feature = "blake2"))]
+  inner: sha512::State,
+  #[cfg(feature = "blake2")]
+  inner: Blake2b,
+}
+
+impl State {
+
+  #[cfg(not(feature = "blake2"))]
+  pub fn new() -> State {
+    State{inner: sha512::State::new()}
+  }
+
+  #[cfg(feature = "blake2")]
+  pub fn new() -> State {
+    State{inner: Blake2b::new(DIGEST_BYTES)}
+  }
+
+  pub fn update(&mut self, data: &[u8]) {
+    self.inner.update(data);
+  }
+
+  #[cfg(not(feature = "blake2"))]
+  pub fn finalize(self) -> Vec<u8> {
+    let sha512::Digest(digest_bytes) = self.inner.finalize();
+    digest_bytes[0 .. DIGEST_BYTES].to_vec()
+  }
+
+  #[cfg(feature = "blake2")]
+  pub fn finalize(self) -> Vec<u8> {
+    self.inner.finalize().as_bytes().to_vec()
+  }
+}
+
+/// The digest of `data`, `DIGEST_BYTES` bytes wide.
+pub fn digest(data: &[u8]) -> Vec<u8> {
+  let mut state = State::new();
+  state.update(data);
+  state.finalize()
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use rustc_serialize::hex::{ToHex};
+
+  #[test]
+  fn digest_of_empty_input() {
+    let expected = match ALGORITHM {
+      "sha512" => "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce\
+                   47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e",
+      "blake2b-256" => "0e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a8",
+      _ => panic!("No test vector for {}.", ALGORITHM),
+    };
+    assert_eq!(expected, &digest(b"").to_hex()[..]);
+    assert_eq!(DIGEST_BYTES, digest(b"").len());
+  }
+
+  #[test]
+  fn incremental_digest() {
+    let mut state = State::new();
+    state.update(b"foo");
+    state.update(b"bar");
+    assert_eq!(digest(b"foobar"), state.finalize());
+  }
+}
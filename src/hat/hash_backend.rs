@@ -0,0 +1,2805 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage backends for committed hash index entries.
+//!
+//! The hash index keeps reserved entries in memory and hands them to a `HashBackend` once they are
+//! committed (in id order), so message handling and queueing do not depend on the storage.
+//! `SqliteBackend` is the persistent default; `MemoryBackend` never touches sqlite and is meant for
+//! tests and for ephemeral deduplication within a single run.
+
+use std::cell::{RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::mem;
+use std::thread;
+use std::time::duration::{Duration};
+use rustc_serialize::hex::{ToHex};
+
+use audit::{self, AuditEvent, AuditOp};
+use hash_bytes::{HashBytes};
+use hash_index::{BlobRef, Hash, HashEntry, HashIndexError, IndexConfig, Interrupt, MergeReport,
+                 STREAM_BATCH_SIZE, TransactionMode};
+use hash_payload::{LEGACY_PAYLOAD_VERSION, upgrade_legacy};
+use ordered_collection::{OrderedCollection};
+
+use sqlite3::database::{Database};
+use sqlite3::cursor::{Cursor};
+use sqlite3::types::ResultCode;
+use sqlite3::types::ResultCode::{SQLITE_BUSY, SQLITE_DONE, SQLITE_FULL, SQLITE_OK, SQLITE_ROW};
+use sqlite3::BindArg::{Integer64, Blob, Null, Text};
+use sqlite3::{open};
+
+use sodiumoxide::crypto::auth;
+use sodiumoxide::crypto::hash::{sha512};
+use sodiumoxide::crypto::secretbox;
+
+
+/// `flags` bit of rows whose `payload` and `blob_ref` are encrypted.
+const FLAG_ENCRYPTED: i64 = 1;
+
+/// The columns that `SqliteBackend::read_entry` reads an entry from.
+const ENTRY_COLUMNS: &'static str =
+  "id, hash, height, payload, blob_ref, flags, payload_version, content_len";
+
+/// Plaintext of the key check value, which is used to detect a wrong key when opening the index.
+const KEY_CHECK: &'static [u8] = b"hat hash index key check";
+
+/// The version of the schema that `SqliteBackend::open` upgrades files to. It is stored in
+/// `hash_index_meta`, and is raised whenever a column or table is added.
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// `PRAGMA application_id` of hash index files ("HatI"), to tell them apart from other databases.
+const APPLICATION_ID: i64 = 0x48617449;
+
+/// The tables whose rows are keyed by the id of an entry, with their declarations. Ids are only
+/// unique within a namespace, so it is part of every key. The side tables are looked up by their
+/// primary key only, so they are created without a separate rowid (and rebuilt that way if they
+/// were created with one, see `drop_rowid`).
+const ID_TABLES: [(&'static str, &'static str); 3] = [
+  ("hash_index", "(id        INTEGER NOT NULL,
+                   hash      BLOB,
+                   height    INTEGER,
+                   payload   BLOB,
+                   blob_ref  BLOB,
+                   blob_name BLOB,
+                   blob_len  INTEGER,
+                   flags     INTEGER,
+                   namespace TEXT NOT NULL DEFAULT '',
+                   payload_version INTEGER NOT NULL DEFAULT 0,
+                   content_len INTEGER NOT NULL DEFAULT 0,
+                   ref_digest BLOB,
+                   PRIMARY KEY (namespace, id))"),
+  ("hash_payload_chunks", "(namespace TEXT NOT NULL DEFAULT '',
+                            id        INTEGER,
+                            seq       INTEGER,
+                            data      BLOB,
+                            PRIMARY KEY (namespace, id, seq)) WITHOUT ROWID"),
+  // Metadata of entries (see `set_meta`), kept apart so that it does not widen `hash_index`:
+  ("hash_meta", "(namespace TEXT NOT NULL DEFAULT '',
+                  id        INTEGER,
+                  key       TEXT,
+                  value     BLOB,
+                  flags     INTEGER,
+                  PRIMARY KEY (namespace, id, key)) WITHOUT ROWID"),
+];
+
+/// The table of settings of the whole file (e.g. the schema version), with its declaration.
+const INDEX_META_TABLE: (&'static str, &'static str) =
+  ("hash_index_meta", "(key   TEXT PRIMARY KEY,
+                        value INTEGER) WITHOUT ROWID");
+
+
+/// Reading an encrypted row that cannot be decrypted returns `HashIndexError::CorruptRow` instead
+/// of the entry, as does any enumeration that reaches such a row.
+pub trait HashBackend {
+  /// Locate a committed entry and its id.
+  /// Payloads that are stored out-of-line are returned as `None` (see `spilled_payload`).
+  /// Returns an error if the lookup failed, which must not be mistaken for an unknown hash.
+  fn locate(&mut self, hash: &Hash) -> Result<Option<(i64, HashEntry)>, HashIndexError>;
+
+  /// Find a committed entry by its id.
+  fn locate_id(&mut self, id: i64) -> Result<Option<HashEntry>, HashIndexError>;
+
+  /// Returns the first `limit` committed hashes from `low` up to (but excluding) `high` in bytewise
+  /// order, along with the number of all committed hashes in that range.
+  fn hashes_in_range(&mut self, low: &[u8], high: Option<&[u8]>, limit: usize) -> (Vec<Hash>, u64);
+
+  /// The hashes among `hashes` that are committed.
+  fn known(&mut self, hashes: &[Hash]) -> BTreeSet<Vec<u8>>;
+
+  /// Pass the hash of every committed entry to `sink`, without reading the rest of the entries.
+  fn for_each_hash(&mut self, sink: &mut FnMut(&[u8]));
+
+  /// Reassemble a payload that is stored out-of-line, if any. This includes payloads of reserved
+  /// entries that were handed over with `spill_payload`.
+  fn spilled_payload(&mut self, id: i64) -> Result<Option<Vec<u8>>, HashIndexError>;
+
+  /// Store the payload of the reserved entry `id` out-of-line right away, so that it need not be
+  /// kept in memory until the entry is inserted (without a payload). Returns the payload instead
+  /// if it is to be kept with the entry, e.g. because it is small.
+  fn spill_payload(&mut self, _id: i64, _level: i64, payload: Vec<u8>) -> Option<Vec<u8>> {
+    Some(payload)
+  }
+
+  /// Remove the payload stored by `spill_payload` for a reserved entry, which is not going to be
+  /// inserted with it.
+  fn drop_spilled(&mut self, _id: i64) {}
+
+  /// Insert committed entries. Entries are given in increasing id order, except for prioritized
+  /// entries, which may come before entries with smaller ids (see `Msg::ReservePrioritized`).
+  fn insert_batch(&mut self, entries: Vec<(i64, HashEntry)>);
+
+  /// Delete a committed entry (and its out-of-line payload, if any).
+  /// Returns false if the hash is not known.
+  fn delete(&mut self, hash: &Hash) -> bool;
+
+  /// Delete the committed entries with an id from `lo` up to (but not including) `hi`, like
+  /// `delete`. Returns the hash and persistent reference of each deleted entry, in id order.
+  /// Nothing is deleted if any of the entries cannot be decoded.
+  fn delete_id_range(&mut self, lo: i64, hi: i64) -> Result<Vec<(Hash, Vec<u8>)>, HashIndexError>;
+
+  /// Replace the persistent reference of a committed entry.
+  /// Returns false if the hash is not known.
+  fn relocate(&mut self, hash: &Hash, blob_ref: &BlobRef) -> bool;
+
+  /// Replace the persistent references of many committed entries.
+  /// Returns the number of updated entries and the number of hashes that are not known.
+  fn relocate_batch(&mut self, moves: Vec<(Hash, BlobRef)>) -> (usize, usize) {
+    let updated = moves.iter().filter(|&&(ref hash, ref blob_ref)| self.relocate(hash, blob_ref))
+                              .count();
+    (updated, moves.len() - updated)
+  }
+
+  /// Returns the moves (for `relocate_batch`) that replace the prefix `from` of the object names of
+  /// all committed persistent references with `to`, keeping their offsets and lengths. References
+  /// that are not structured are skipped.
+  fn ref_prefix_moves(&mut self, from: &[u8], to: &[u8])
+                      -> Result<Vec<(Hash, BlobRef)>, HashIndexError> {
+    let moves = RefCell::new(vec!());
+    let collect = |batch: Vec<HashEntry>| {
+      for entry in batch.into_iter() {
+        match entry.persistent_ref.as_ref().and_then(|r| BlobRef::from_bytes(&r[..])) {
+          Some(ref blob_ref) if blob_ref.name.starts_with(from) => {
+            let mut name = to.to_vec();
+            name.extend(blob_ref.name[from.len()..].iter().cloned());
+            moves.borrow_mut().push((entry.hash, BlobRef{name: name, ..blob_ref.clone()}));
+          },
+          _ => (),
+        }
+      }
+    };
+    let (rest, _) = try!(self.stream(false, 0, None, &|batch, _| collect(batch)));
+    collect(rest);
+    Ok(moves.into_inner())
+  }
+
+  /// The largest id in the backend, or `0` if it is empty.
+  fn max_id(&mut self) -> i64;
+
+  /// Make all inserted entries durable.
+  fn commit_txn(&mut self) -> Result<(), HashIndexError>;
+
+  /// Like `commit_txn`, but also wait until the data has reached stable storage.
+  fn barrier(&mut self) -> Result<(), HashIndexError>;
+
+  /// Like `commit_txn`, but also checkpoint and truncate the write-ahead log (if any).
+  /// Returns the pages in the log and the pages written back to the database.
+  fn checkpoint(&mut self) -> Result<(u64, u64), HashIndexError>;
+
+  /// Return at most `pages` free pages of the underlying storage to the file system, within the
+  /// open transaction. Returns the number of pages that were freed.
+  fn incremental_vacuum(&mut self, pages: u64) -> u64;
+
+  /// Open a savepoint named `name` within the open transaction. Committing ends all savepoints.
+  fn savepoint(&mut self, name: &str);
+
+  /// End the most recent savepoint named `name` (and those opened after it), keeping its changes.
+  fn release_savepoint(&mut self, name: &str);
+
+  /// Undo all changes since the most recent savepoint named `name` was opened. The savepoint
+  /// stays open, while those opened after it are ended.
+  fn rollback_to_savepoint(&mut self, name: &str);
+
+  /// Recreate and rebuild any lookup indexes.
+  fn rebuild_indexes(&mut self);
+
+  /// Enumerate committed entries (all of them, or only leaves) with ids larger than `after_id` in
+  /// id order, passing each full batch to `sink` along with the id of its last entry.
+  /// The enumeration stops early, after any entry, once `interrupt` is set.
+  /// Returns the remaining entries (less than a full batch) and the last id that was read.
+  fn stream(&mut self, leaves_only: bool, after_id: i64, interrupt: Option<&Interrupt>,
+            sink: &Fn(Vec<HashEntry>, i64)) -> Result<(Vec<HashEntry>, i64), HashIndexError>;
+
+  /// Enumerate committed entries whose persistent reference points into the blob object named
+  /// `object_name` in id order, passing each full batch to `sink`. Stops early like `stream`.
+  /// Returns the remaining entries (less than a full batch).
+  fn stream_in_object(&mut self, object_name: &[u8], interrupt: Option<&Interrupt>,
+                      sink: &Fn(Vec<HashEntry>)) -> Result<Vec<HashEntry>, HashIndexError>;
+
+  /// Returns up to `limit` committed entries in id order, skipping the first `offset` of them,
+  /// along with the total number of committed entries.
+  fn page(&mut self, offset: u64, limit: u64) -> Result<(Vec<HashEntry>, u64), HashIndexError>;
+
+  /// Returns the total referenced bytes, distinct referenced objects and number of leaves.
+  fn storage_summary(&mut self) -> Result<(u64, u64, u64), HashIndexError>;
+
+  /// Find a committed hash, other than `hash`, that uses `blob_ref` as its persistent reference.
+  fn ref_owner(&mut self, hash: &Hash, blob_ref: &[u8]) -> Result<Option<Hash>, HashIndexError>;
+
+  /// The number of bytes allocated by the underlying storage (for all namespaces).
+  fn file_size(&mut self) -> u64;
+
+  /// The namespaces that have committed entries in the underlying storage (in sorted order),
+  /// including those of other backends sharing it.
+  fn namespaces(&mut self) -> Vec<String>;
+
+  /// Describe the storage: the schema version, the columns of the entry table, and the optional
+  /// features that it has (`"encrypted"`, `"audit"`, `"metadata"`, `"spilled_payloads"` and
+  /// `"strict_refs"`).
+  fn schema_info(&mut self) -> (u32, Vec<String>, Vec<String>);
+
+  /// Append an event to the audit log, within the open transaction (see `IndexConfig::audit`).
+  fn append_audit(&mut self, op: AuditOp, hash: &Hash, detail: &[u8]);
+
+  /// Set the metadata `key` of the entry with `id` to `value`, replacing any previous value.
+  /// Metadata is deleted with its entry (see `delete`).
+  fn set_meta(&mut self, id: i64, key: &str, value: &[u8]);
+
+  /// Returns the metadata `key` of the entry with `id`, if set.
+  fn meta(&mut self, id: i64, key: &str) -> Result<Option<Vec<u8>>, HashIndexError>;
+
+  /// Returns up to `limit` events of the audit log with a sequence number after `after_seq`, in
+  /// sequence order. Events of an operation that this version does not know are skipped.
+  fn audit_since(&mut self, after_seq: i64, limit: usize)
+                 -> Result<Vec<AuditEvent>, HashIndexError>;
+}
+
+
+/// Authenticated encryption of column values with a key derived from the configured key material.
+/// Values are stored as the nonce followed by the sealed data.
+/// Values that must be found without decrypting every row are also stored as a keyed digest, which
+/// uses another part of the derived key.
+struct Cipher {
+  key: secretbox::Key,
+  digest_key: auth::Key,
+}
+
+impl Cipher {
+
+  fn new(key_material: &[u8]) -> Cipher {
+    let sha512::Digest(digest) = sha512::hash(key_material);
+    let mut key = [0u8; secretbox::KEYBYTES];
+    for (k, d) in key.iter_mut().zip(digest.iter()) {
+      *k = *d;
+    }
+    let mut digest_key = [0u8; auth::KEYBYTES];
+    for (k, d) in digest_key.iter_mut().zip(digest[secretbox::KEYBYTES..].iter()) {
+      *k = *d;
+    }
+    Cipher{key: secretbox::Key(key), digest_key: auth::Key(digest_key)}
+  }
+
+  /// A keyed digest of `plaintext`, which is the same for equal values (unlike `seal`).
+  fn digest(&self, plaintext: &[u8]) -> Vec<u8> {
+    let auth::Tag(tag) = auth::authenticate(plaintext, &self.digest_key);
+    tag.to_vec()
+  }
+
+  fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+    let nonce = secretbox::gen_nonce();
+    let secretbox::Nonce(nonce_bytes) = nonce;
+    let mut sealed = nonce_bytes.to_vec();
+    sealed.extend(secretbox::seal(plaintext, &nonce, &self.key).into_iter());
+    sealed
+  }
+
+  /// Returns `None` if the value was sealed with another key (or has been tampered with).
+  fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < secretbox::NONCEBYTES {
+      return None;
+    }
+    let mut nonce = [0u8; secretbox::NONCEBYTES];
+    for (n, s) in nonce.iter_mut().zip(sealed.iter()) {
+      *n = *s;
+    }
+    secretbox::open(&sealed[secretbox::NONCEBYTES..], &secretbox::Nonce(nonce), &self.key)
+  }
+}
+
+
+/// A change within the open transaction of `SqliteBackend`, for making it again if a failed
+/// commit has rolled back the transaction (see `recover_from_failed_commit`).
+#[derive(Clone)]
+enum Change {
+  Insert(Vec<(i64, HashEntry)>),
+  // The encoded payload stays here until the commit, as a failed commit may lose its chunks.
+  Spill(i64, u8, Vec<u8>),
+  DropSpilled(i64),
+  Delete(Hash),
+  DeleteIdRange(i64, i64),
+  Relocate(Vec<(Hash, BlobRef)>),
+  SetMeta(i64, String, Vec<u8>),
+  Audit(AuditEvent),
+}
+
+
+pub struct SqliteBackend {
+  dbh: Database,
+  path: String,
+
+  // All rows read and written by this backend belong to this namespace.
+  namespace: String,
+
+  // Set if new rows are encrypted (see `IndexConfig::encryption_key`).
+  cipher: Option<Cipher>,
+
+  busy_retries: u32,
+  busy_retry_delay: Duration,
+  transaction_mode: TransactionMode,
+  digest_width: usize,
+  max_inline_payload: usize,
+
+  // Changes made since the last commit, in order.
+  uncommitted: Vec<Change>,
+
+  // The payload versions of reserved entries whose payload was spilled before they are inserted
+  // (see `spill_payload`), to record with their rows.
+  spilled_versions: BTreeMap<i64, u8>,
+
+  // Open savepoints, with the number of changes in `uncommitted` when they were opened.
+  savepoints: Vec<(String, usize)>,
+
+  // Error codes to return from the next calls to `try_exec` (only ever set by tests).
+  injected_errors: RefCell<Vec<ResultCode>>,
+}
+
+impl SqliteBackend {
+
+  /// Open (or create) the index at `path`.
+  /// Returns `WrongKey` if the index was encrypted with another key, or without the configured one.
+  /// Returns `NotAHashIndex` if `path` is some other sqlite database.
+  pub fn open(path: String, config: &IndexConfig) -> Result<SqliteBackend, HashIndexError> {
+    let mut backend = match open(&path) {
+      Ok(dbh) => SqliteBackend{dbh: dbh,
+                               path: path.clone(),
+                               namespace: config.namespace.clone(),
+                               cipher: config.encryption_key.as_ref()
+                                             .map(|k| Cipher::new(&k.0[..])),
+                               busy_retries: config.busy_retries,
+                               busy_retry_delay: config.busy_retry_delay,
+                               transaction_mode: config.transaction_mode,
+                               digest_width: config.digest_width,
+                               max_inline_payload: config.max_inline_payload,
+                               uncommitted: vec!(),
+                               spilled_versions: BTreeMap::new(),
+                               savepoints: vec!(),
+                               injected_errors: RefCell::new(vec!())},
+      Err(err) => panic!("{:?}", err),
+    };
+    // This cannot be changed within a transaction, and sqlite ignores values it does not know:
+    match config.synchronous {
+      Some(mode) => {
+        backend.exec_or_die(&format!("PRAGMA synchronous={}", mode.pragma_value()));
+        let level = backend.select1_or_die("PRAGMA synchronous").expect("synchronous")
+                           .get_i64(0);
+        if level != mode.pragma_value() {
+          panic!("sqlite did not accept PRAGMA synchronous={} ({:?}), but uses {}",
+                 mode.pragma_value(), mode, level);
+        }
+      },
+      None => (),
+    }
+
+    try!(backend.check_application_id());
+
+    // Free pages can only be reclaimed incrementally if this is set before the first table is
+    // created; for older files it has no effect (short of a full VACUUM).
+    backend.exec_or_die("PRAGMA auto_vacuum=INCREMENTAL");
+    for &(table, declaration) in ID_TABLES.iter() {
+      backend.exec_or_die(&format!("CREATE TABLE IF NOT EXISTS {} {}", table, declaration));
+    }
+
+    // The blob columns are decoded from `blob_ref`, so that storage can be summarized in SQL:
+    let added_name = backend.add_column_if_missing("blob_name", "BLOB");
+    let added_len = backend.add_column_if_missing("blob_len", "INTEGER");
+    if added_name || added_len {
+      backend.backfill_blob_columns();
+    }
+    backend.add_column_if_missing("flags", "INTEGER");
+
+    // Hashes used to be unique across the whole file; now they are unique within a namespace:
+    if backend.add_column_if_missing("namespace", "TEXT NOT NULL DEFAULT ''") {
+      backend.exec_or_die("DROP INDEX IF EXISTS HashIndex_UniqueHash");
+    }
+
+    // Branch payloads written before they were versioned have no header (see `hash_payload`):
+    backend.add_column_if_missing("payload_version",
+                                  &format!("INTEGER NOT NULL DEFAULT {}", LEGACY_PAYLOAD_VERSION));
+    // Older rows do not know the length of their content:
+    backend.add_column_if_missing("content_len", "INTEGER NOT NULL DEFAULT 0");
+    // Keyed digests of encrypted references are filled in once the key is checked (see below):
+    backend.add_column_if_missing("ref_digest", "BLOB");
+
+    // Ids used to be unique across the whole file; now they are unique within a namespace:
+    backend.scope_ids_to_namespace();
+    for &(table, declaration) in ID_TABLES.iter() {
+      backend.drop_rowid(table, declaration);
+    }
+
+    // Payloads spilled for entries that were still reserved when the index was closed (see
+    // `spill_payload`). Their ids are handed out again:
+    let namespace = backend.quoted_namespace();
+    backend.exec_or_die(&format!(
+      "DELETE FROM hash_payload_chunks
+       WHERE namespace={} AND id NOT IN (SELECT id FROM hash_index WHERE namespace={})",
+      namespace, namespace));
+
+    backend.open_checked();
+
+    // For `ref_owner`, which finds encrypted references by their keyed digest:
+    if config.strict_refs && backend.cipher.is_none() {
+      backend.exec_or_die("CREATE INDEX IF NOT EXISTS
+                           HashIndex_BlobRef
+                           ON hash_index(blob_ref)");
+    }
+    if config.strict_refs && backend.cipher.is_some() {
+      backend.exec_or_die("CREATE INDEX IF NOT EXISTS
+                           HashIndex_RefDigest
+                           ON hash_index(namespace, ref_digest)");
+    }
+    // For finding the entries stored in an object (see `stream_in_object`):
+    backend.exec_or_die("CREATE INDEX IF NOT EXISTS
+                         HashIndex_BlobName
+                         ON hash_index(namespace, blob_name)");
+
+    backend.exec_or_die("CREATE TABLE IF NOT EXISTS hash_index_key (key_check BLOB)");
+    try!(backend.check_key());
+
+    // Rows from before the digest was stored, or from before the index was encrypted:
+    if backend.cipher.is_some() {
+      backend.exec_or_die("BEGIN");
+      backend.backfill_ref_digests();
+      backend.exec_or_die("COMMIT");
+    }
+
+    let (meta_table, meta_declaration) = INDEX_META_TABLE;
+    backend.exec_or_die(&format!("CREATE TABLE IF NOT EXISTS {} {}", meta_table, meta_declaration));
+    backend.drop_rowid(meta_table, meta_declaration);
+    try!(backend.check_digest_width(config.digest_width));
+    backend.exec_or_die(&format!(
+      "INSERT OR REPLACE INTO hash_index_meta (key, value)
+       SELECT 'schema_version', MAX({}, COALESCE(MAX(value), 0)) FROM hash_index_meta
+       WHERE key='schema_version'", SCHEMA_VERSION));
+
+    if config.audit {
+      backend.exec_or_die("CREATE TABLE IF NOT EXISTS
+                           audit_log (seq       INTEGER PRIMARY KEY,
+                                      ts        INTEGER,
+                                      op        INTEGER,
+                                      hash      BLOB,
+                                      detail    BLOB,
+                                      flags     INTEGER,
+                                      namespace TEXT NOT NULL DEFAULT '')");
+    }
+
+    match config.wal_autocheckpoint {
+      Some(pages) => backend.exec_or_die(&format!("PRAGMA wal_autocheckpoint={}", pages)),
+      None => (),
+    }
+
+    backend.begin();
+    Ok(backend)
+  }
+
+  /// Verify the configured key against the stored key check value, storing one on first use.
+  fn check_key(&mut self) -> Result<(), HashIndexError> {
+    let stored = self.select1_or_die("SELECT key_check FROM hash_index_key")
+      .map(|mut row| row.get_blob(0).unwrap_or(&[]).to_vec());
+    match (stored, self.cipher.as_ref().map(|c| c.seal(KEY_CHECK))) {
+      (None, None) => Ok(()),
+      (None, Some(sealed)) => {
+        self.exec_or_die(&format!("INSERT INTO hash_index_key (key_check) VALUES (x'{}')",
+                                  sealed.to_hex()));
+        Ok(())
+      },
+      (Some(_), None) => Err(HashIndexError::WrongKey),
+      (Some(sealed), Some(_)) => {
+        if self.key_matches(&sealed[..]) { Ok(()) } else { Err(HashIndexError::WrongKey) }
+      },
+    }
+  }
+
+  /// Whether a stored key check value was sealed with the configured key (if any).
+  fn key_matches(&self, sealed: &[u8]) -> bool {
+    match self.cipher.as_ref().and_then(|c| c.open(sealed)) {
+      Some(ref check) => &check[..] == KEY_CHECK,
+      None => false,
+    }
+  }
+
+  /// Verify `expected` against the stored digest width, storing it on first use.
+  /// Indexes from before the width was stored take it from their hashes, if they have any.
+  /// Refuse databases that were not created as a hash index, before any table is created in them.
+  /// Fresh files, and indexes from before the application id was set, are marked as ours.
+  fn check_application_id(&mut self) -> Result<(), HashIndexError> {
+    let id = self.select1_or_die("PRAGMA application_id").expect("application_id").get_int(0);
+    if id as i64 == APPLICATION_ID {
+      return Ok(());
+    }
+    let has_tables = self.select1_or_die("SELECT 1 FROM sqlite_master WHERE type='table'")
+                         .is_some();
+    let has_index = self.select1_or_die("SELECT 1 FROM sqlite_master
+                                         WHERE type='table' AND name='hash_index'").is_some();
+    if id != 0 || (has_tables && !has_index) {
+      return Err(HashIndexError::NotAHashIndex);
+    }
+    self.exec_or_die(&format!("PRAGMA application_id={}", APPLICATION_ID));
+    Ok(())
+  }
+
+  fn check_digest_width(&mut self, expected: usize) -> Result<(), HashIndexError> {
+    let stored = self.select1_or_die("SELECT value FROM hash_index_meta WHERE key='digest_width'")
+      .map(|mut row| row.get_int(0) as usize);
+    let found = match stored {
+      Some(width) => Some(width),
+      None => self.select1_or_die("SELECT length(hash) FROM hash_index LIMIT 1")
+                  .map(|mut row| row.get_int(0) as usize),
+    };
+    match found {
+      Some(width) if width != expected => {
+        Err(HashIndexError::DigestWidthMismatch{expected: expected, found: width})
+      },
+      _ => {
+        if stored.is_none() {
+          self.exec_or_die(&format!(
+            "INSERT INTO hash_index_meta (key, value) VALUES ('digest_width', {})", expected));
+        }
+        Ok(())
+      },
+    }
+  }
+
+  /// Present a branch payload from before payloads were versioned in the current encoding.
+  /// Payloads that are not a list of digests are returned as is.
+  fn honor_version(&self, level: i64, version: i64, payload: Vec<u8>) -> Vec<u8> {
+    if level == 0 || version != LEGACY_PAYLOAD_VERSION as i64 || payload.len() == 0 {
+      return payload;
+    }
+    upgrade_legacy(&payload[..], self.digest_width).unwrap_or(payload)
+  }
+
+  /// Read an entry from a row of `ENTRY_COLUMNS`.
+  fn read_entry(&self, cursor: &mut Cursor) -> Result<HashEntry, HashIndexError> {
+    let id = cursor.get_i64(0);
+    let level = cursor.get_i64(2);
+    let flags = cursor.get_i64(5);
+    let version = cursor.get_i64(6);
+    let payload = cursor.get_blob(3).unwrap_or(&[]).to_vec();
+    let persistent_ref = cursor.get_blob(4).unwrap_or(&[]).to_vec();
+    let payload = if payload.len() == 0 { None } else {
+      Some(self.honor_version(level, version, try!(self.decode(flags, payload, "entry", id))))
+    };
+    Ok(HashEntry{hash: Hash{bytes: HashBytes::new(cursor.get_blob(1).unwrap_or(&[]))},
+                 level: level,
+                 payload: payload,
+                 persistent_ref: Some(try!(self.decode(flags, persistent_ref, "entry", id))),
+                 content_len: cursor.get_i64(7) as u64})
+  }
+
+  /// Decrypt a column value if its row is flagged as encrypted. The `what` and `id` of the row
+  /// only describe it in the error.
+  fn decode(&self, flags: i64, bytes: Vec<u8>, what: &str, id: i64)
+            -> Result<Vec<u8>, HashIndexError> {
+    if flags & FLAG_ENCRYPTED == 0 {
+      return Ok(bytes);
+    }
+    // The key was verified when opening, so a failure here means the row is damaged:
+    self.cipher.as_ref().and_then(|cipher| cipher.open(&bytes[..])).ok_or_else(||
+      HashIndexError::CorruptRow(format!("{} {} in namespace {} could not be decrypted",
+                                         what, id, self.quoted_namespace())))
+  }
+
+  fn encode(&self, bytes: Vec<u8>) -> Vec<u8> {
+    match self.cipher {
+      Some(ref cipher) => cipher.seal(&bytes[..]),
+      None => bytes,
+    }
+  }
+
+  /// The namespace of this backend as an SQL literal.
+  fn quoted_namespace(&self) -> String {
+    quote(&self.namespace)
+  }
+
+  /// Copy the entries of the index at `other_path` that are not in this one (within the namespace
+  /// of this backend), giving them ids after `id_offset`. Entries with a known hash are compared by
+  /// their persistent reference, and counted as duplicates or conflicts.
+  /// The whole merge is a single transaction, so the open transaction is committed first.
+  /// Nothing is written to the other index (see `check_merge_source`).
+  pub fn merge_from(&mut self, other_path: &str, id_offset: i64)
+                    -> Result<MergeReport, HashIndexError>
+  {
+    // Attaching a missing file would create an empty index, and merge nothing without a word:
+    if fs::metadata(other_path).is_err() {
+      return Err(HashIndexError::NotAHashIndex);
+    }
+
+    // Databases cannot be attached inside a transaction:
+    try!(self.commit_with_retry());
+    self.uncommitted.clear();
+    self.exec_or_die(&format!("ATTACH DATABASE {} AS merge_src", quote(other_path)));
+    self.begin();
+    if let Err(e) = self.check_merge_source() {
+      self.exec_or_die("ROLLBACK");
+      self.exec_or_die("DETACH DATABASE merge_src");
+      self.begin();
+      return Err(e);
+    }
+
+    let namespace = self.quoted_namespace();
+    let mut overlap = vec!();
+    {
+      let mut cursor = self.prepare_or_die(&format!(
+        "SELECT m.blob_ref, m.flags, m.id, o.blob_ref, o.flags, o.id
+         FROM merge_src.hash_index o JOIN main.hash_index m
+           ON m.namespace = o.namespace AND m.hash = o.hash
+         WHERE o.namespace = {}", namespace));
+      while cursor.step() == SQLITE_ROW {
+        overlap.push((cursor.get_blob(0).unwrap_or(&[]).to_vec(), cursor.get_i64(1),
+                      cursor.get_i64(2), cursor.get_blob(3).unwrap_or(&[]).to_vec(),
+                      cursor.get_i64(4), cursor.get_i64(5)));
+      }
+    }
+    let mut report = MergeReport{added: 0, duplicates: 0, conflicts: 0};
+    let mut undecodable = None;
+    for (ours, our_flags, our_id, theirs, their_flags, their_id) in overlap.into_iter() {
+      match (self.decode(our_flags, ours, "entry", our_id),
+             self.decode(their_flags, theirs, "merged entry", their_id)) {
+        (Ok(ours), Ok(theirs)) => {
+          if ours == theirs { report.duplicates += 1 } else { report.conflicts += 1 }
+        },
+        (Err(e), _) | (_, Err(e)) => { undecodable = Some(e); break },
+      }
+    }
+    if let Some(e) = undecodable {
+      // Nothing has been copied yet:
+      self.exec_or_die("ROLLBACK");
+      self.exec_or_die("DETACH DATABASE merge_src");
+      self.begin();
+      return Err(e);
+    }
+
+    let new_rows = format!(
+      "FROM merge_src.hash_index o
+       WHERE o.namespace = {} AND NOT EXISTS (SELECT 1 FROM main.hash_index m
+                                              WHERE m.namespace = o.namespace AND m.hash = o.hash)",
+      namespace);
+    report.added = self.select1_or_die(&format!("SELECT COUNT(*) {}", new_rows))
+      .expect("count").get_i64(0) as u64;
+    self.exec_or_die(&format!(
+      "INSERT INTO main.hash_payload_chunks (namespace, id, seq, data)
+       SELECT c.namespace, c.id + {}, c.seq, c.data FROM merge_src.hash_payload_chunks c
+       WHERE c.namespace = {} AND c.id IN (SELECT o.id {})", id_offset, namespace, new_rows));
+    self.exec_or_die(&format!(
+      "INSERT INTO main.hash_index (id, hash, height, payload, blob_ref, blob_name, blob_len, flags,
+                                    namespace, payload_version, content_len, ref_digest)
+       SELECT o.id + {}, o.hash, o.height, o.payload, o.blob_ref, o.blob_name, o.blob_len, o.flags,
+              o.namespace, o.payload_version, o.content_len, o.ref_digest {}",
+      id_offset, new_rows));
+    // The other index may not be encrypted (the key check passes if it has none):
+    if self.cipher.is_some() {
+      self.backfill_ref_digests();
+    }
+
+    let committed = self.commit_with_retry();
+    if committed.is_err() {
+      self.exec_or_die("ROLLBACK");
+    }
+    self.exec_or_die("DETACH DATABASE merge_src");
+    self.begin();
+    committed.map(|()| report)
+  }
+
+  /// Check that the attached `merge_src` is an index in the current schema, with the key and digest
+  /// width of this one. Unlike `open`, this only reads it, so an index from an older version must
+  /// be opened (and so upgraded) once before it can be merged.
+  fn check_merge_source(&mut self) -> Result<(), HashIndexError> {
+    let id = self.select1_or_die("PRAGMA merge_src.application_id").expect("application_id")
+                 .get_int(0);
+    if id as i64 != APPLICATION_ID {
+      return Err(HashIndexError::NotAHashIndex);
+    }
+    for &(table, _) in ID_TABLES.iter() {
+      let theirs = self.table_info("merge_src", table);
+      if self.table_info("main", table).iter().any(|column| !theirs.contains(column)) {
+        return Err(HashIndexError::NotAHashIndex);
+      }
+    }
+    for table in ["hash_index_key", "hash_index_meta"].iter() {
+      if self.select1_or_die(&format!("SELECT 1 FROM merge_src.sqlite_master
+                                       WHERE type='table' AND name={}", quote(table))).is_none() {
+        return Err(HashIndexError::NotAHashIndex);
+      }
+    }
+
+    let stored = self.select1_or_die("SELECT key_check FROM merge_src.hash_index_key")
+      .map(|mut row| row.get_blob(0).unwrap_or(&[]).to_vec());
+    match stored {
+      Some(ref sealed) if !self.key_matches(&sealed[..]) => return Err(HashIndexError::WrongKey),
+      _ => (),
+    }
+
+    let width = self.select1_or_die("SELECT value FROM merge_src.hash_index_meta
+                                     WHERE key='digest_width'")
+      .map(|mut row| row.get_int(0) as usize);
+    match width {
+      Some(width) if width == self.digest_width => Ok(()),
+      Some(width) => Err(HashIndexError::DigestWidthMismatch{expected: self.digest_width,
+                                                             found: width}),
+      None => Err(HashIndexError::NotAHashIndex),
+    }
+  }
+
+  /// The name and whether it is part of the primary key, of each column of `table` in the
+  /// database `schema`.
+  fn table_info(&mut self, schema: &str, table: &str) -> Vec<(String, bool)> {
+    let mut columns = vec!();
+    let mut cursor = self.prepare_or_die(&format!("PRAGMA {}.table_info({})", schema, table));
+    while cursor.step() == SQLITE_ROW {
+      columns.push((cursor.get_text(1).unwrap_or("").to_string(), cursor.get_int(5) > 0));
+    }
+    columns
+  }
+
+  /// The decoded persistent references of all committed entries (or only leaves), for scans that
+  /// cannot be done in SQL when references are encrypted.
+  fn decoded_refs(&mut self, leaves_only: bool) -> Result<Vec<(Hash, Vec<u8>)>, HashIndexError> {
+    let mut refs = vec!();
+    let mut cursor = self.prepare_or_die(&format!(
+      "SELECT hash, blob_ref, flags, id FROM hash_index WHERE namespace = {} AND {}",
+      self.quoted_namespace(), if leaves_only { "height = 0" } else { "1" }));
+    while cursor.step() == SQLITE_ROW {
+      let hash = Hash{bytes: HashBytes::new(cursor.get_blob(0).unwrap_or(&[]))};
+      let blob_ref = cursor.get_blob(1).unwrap_or(&[]).to_vec();
+      let id = cursor.get_i64(3);
+      refs.push((hash, try!(self.decode(cursor.get_i64(2), blob_ref, "entry", id))));
+    }
+    Ok(refs)
+  }
+
+  fn exec_or_die(&mut self, sql: &str) {
+    match self.dbh.exec(sql) {
+      Ok(true) => (),
+      Ok(false) => panic!("exec: {}", self.dbh.get_errmsg()),
+      Err(msg) => panic!("exec: {:?}, {:?}\nIn sql: '{}'\n",
+                         msg, self.dbh.get_errmsg(), sql)
+    }
+  }
+
+  fn try_exec(&mut self, sql: &str) -> Result<(), ResultCode> {
+    match self.injected_errors.borrow_mut().pop() {
+      Some(code) => return Err(code),
+      None => (),
+    }
+    match self.dbh.exec(sql) {
+      Ok(true) => Ok(()),
+      Ok(false) => panic!("exec: {}", self.dbh.get_errmsg()),
+      Err(code) => Err(code),
+    }
+  }
+
+  #[cfg(test)]
+  pub fn inject_errors(&mut self, codes: Vec<ResultCode>) {
+    *self.injected_errors.borrow_mut() = codes;
+  }
+
+  fn prepare_or_die<'a>(&'a self, sql: &str) -> Cursor<'a> {
+    match self.dbh.prepare(sql, &None) {
+      Ok(s)  => s,
+      Err(x) => panic!("sqlite error: {} ({:?})",
+                       self.dbh.get_errmsg(), x),
+    }
+  }
+
+  /// Step to the first row of `sql`, telling a query without rows from a failed one (a busy
+  /// database is not retried).
+  fn select1<'a>(&'a self, sql: &str) -> Result<Option<Cursor<'a>>, HashIndexError> {
+    let injected = self.injected_errors.borrow_mut().pop();
+    let mut cursor = self.prepare_or_die(sql);
+    let code = match injected {
+      Some(code) => code,
+      None => cursor.step(),
+    };
+    match code {
+      SQLITE_ROW => Ok(Some(cursor)),
+      SQLITE_DONE => Ok(None),
+      SQLITE_BUSY => Err(HashIndexError::Busy),
+      code => Err(HashIndexError::QueryFailed(format!("{:?}: {}", code, self.dbh.get_errmsg()))),
+    }
+  }
+
+  fn select1_or_die<'a>(&'a self, sql: &str) -> Option<Cursor<'a>> {
+    let mut cursor = self.prepare_or_die(sql);
+    match cursor.step() {
+      SQLITE_ROW => Some(cursor),
+      SQLITE_DONE => None,
+      code => panic!("sqlite error: {} ({:?})\nIn sql: '{}'\n", self.dbh.get_errmsg(), code, sql),
+    }
+  }
+
+  fn has_column(&mut self, table: &str, column: &str) -> bool {
+    self.columns(table).iter().any(|c| &c[..] == column)
+  }
+
+  /// The columns of `table`, in order.
+  fn columns(&mut self, table: &str) -> Vec<String> {
+    let mut columns = vec!();
+    let mut cursor = self.prepare_or_die(&format!("PRAGMA table_info({})", table));
+    while cursor.step() == SQLITE_ROW {
+      columns.push(cursor.get_text(1).unwrap_or("").to_string());
+    }
+    columns
+  }
+
+  /// The columns of the primary key of `table`.
+  fn primary_key(&mut self, table: &str) -> Vec<String> {
+    let mut columns = vec!();
+    let mut cursor = self.prepare_or_die(&format!("PRAGMA table_info({})", table));
+    while cursor.step() == SQLITE_ROW {
+      if cursor.get_int(5) > 0 {
+        columns.push(cursor.get_text(1).unwrap_or("").to_string());
+      }
+    }
+    columns
+  }
+
+  /// Whether the schema has a table or index with this name.
+  fn has_schema_object(&mut self, name: &str) -> bool {
+    self.select1_or_die(&format!("SELECT 1 FROM sqlite_master WHERE name={}", quote(name)))
+        .is_some()
+  }
+
+  /// Add a column to `hash_index` if it was created by an older version.
+  /// Returns true if the column was added.
+  fn add_column_if_missing(&mut self, column: &str, decl: &str) -> bool {
+    if self.has_column("hash_index", column) {
+      return false;
+    }
+    self.exec_or_die(&format!("ALTER TABLE hash_index ADD COLUMN {} {}", column, decl));
+    true
+  }
+
+  /// Rebuild the tables in `ID_TABLES` that were created before ids were scoped to a namespace.
+  /// Ids were unique within the whole file then, so the rows of a side table are given the
+  /// namespace of the entry with their id.
+  fn scope_ids_to_namespace(&mut self) {
+    let mut outdated = vec!();
+    for &(table, declaration) in ID_TABLES.iter() {
+      if !self.primary_key(table).iter().any(|c| &c[..] == "namespace") {
+        outdated.push((table, declaration));
+      }
+    }
+    if outdated.len() == 0 {
+      return;
+    }
+
+    // The side tables are joined on the ids of `hash_index`, so it is rebuilt last:
+    self.exec_or_die("BEGIN");
+    for (table, declaration) in outdated.into_iter().rev() {
+      let columns = self.columns(table);
+      let copied: Vec<String> = columns.iter().map(|c| format!("t.{}", c)).collect();
+      self.exec_or_die(&format!("CREATE TABLE scoped_{} {}", table, declaration));
+      if columns.iter().any(|c| &c[..] == "namespace") {
+        self.exec_or_die(&format!("INSERT INTO scoped_{} ({}) SELECT {} FROM {} t",
+                                  table, columns.connect(", "), copied.connect(", "), table));
+      } else {
+        self.exec_or_die(&format!(
+          "INSERT INTO scoped_{} (namespace, {})
+           SELECT COALESCE(h.namespace, ''), {} FROM {} t LEFT JOIN hash_index h ON h.id = t.id",
+          table, columns.connect(", "), copied.connect(", "), table));
+      }
+      self.exec_or_die(&format!("DROP TABLE {}", table));
+      self.exec_or_die(&format!("ALTER TABLE scoped_{} RENAME TO {}", table, table));
+    }
+    self.exec_or_die("COMMIT");
+  }
+
+  /// Rebuild `table` in the form of `declaration` if that has no rowid, but the table does (as side
+  /// tables created by older versions do).
+  fn drop_rowid(&mut self, table: &str, declaration: &str) {
+    if !declaration.ends_with("WITHOUT ROWID") || !self.has_rowid(table) {
+      return;
+    }
+    self.exec_or_die("BEGIN");
+    self.rebuild_table(table, declaration);
+    self.exec_or_die("COMMIT");
+  }
+
+  fn has_rowid(&mut self, table: &str) -> bool {
+    self.select1_or_die(&format!("SELECT sql FROM sqlite_master WHERE type='table' AND name={}",
+                                 quote(table)))
+      .map(|mut row| !row.get_text(0).unwrap_or("").ends_with("WITHOUT ROWID"))
+      .unwrap_or(false)
+  }
+
+  /// Copy `table` into a new table with `declaration`, which has (at least) the same columns, and
+  /// put it in its place, within the open transaction.
+  fn rebuild_table(&mut self, table: &str, declaration: &str) {
+    let columns = self.columns(table).connect(", ");
+    self.exec_or_die(&format!("CREATE TABLE rebuilt_{} {}", table, declaration));
+    self.exec_or_die(&format!("INSERT INTO rebuilt_{} ({}) SELECT {} FROM {}",
+                              table, columns, columns, table));
+    self.exec_or_die(&format!("DROP TABLE {}", table));
+    self.exec_or_die(&format!("ALTER TABLE rebuilt_{} RENAME TO {}", table, table));
+  }
+
+  /// Rebuild a side table with a rowid, as older versions created it. This is the baseline of
+  /// `hash_index_bench::meta_lookups_with_rowid`, and undone by opening the index again.
+  #[cfg(test)]
+  pub fn restore_rowid(&mut self, table: &str) {
+    let declaration = ID_TABLES.iter().chain([INDEX_META_TABLE].iter())
+      .find(|&&(name, _)| name == table).expect("side table").1;
+    self.rebuild_table(table, &declaration.replace(" WITHOUT ROWID", ""));
+  }
+
+  fn backfill_blob_columns(&mut self) {
+    let mut refs = vec!();
+    {
+      let mut cursor = self.prepare_or_die("SELECT rowid, blob_ref FROM hash_index");
+      while cursor.step() == SQLITE_ROW {
+        let id = cursor.get_i64(0);
+        match BlobRef::from_bytes(cursor.get_blob(1).unwrap_or(&[])) {
+          Some(blob_ref) => refs.push((id, blob_ref)),
+          None => (),
+        }
+      }
+    }
+
+    self.exec_or_die("BEGIN");
+    {
+      let mut update_stm = self.dbh.prepare(
+        "UPDATE hash_index SET blob_name=?, blob_len=? WHERE rowid=?", &None).unwrap();
+      for (id, blob_ref) in refs.into_iter() {
+        assert_eq!(SQLITE_OK, update_stm.bind_param(1, &Blob(blob_ref.name)));
+        assert_eq!(SQLITE_OK, update_stm.bind_param(2, &Integer64(blob_ref.length as i64)));
+        assert_eq!(SQLITE_OK, update_stm.bind_param(3, &Integer64(id)));
+
+        assert_eq!(SQLITE_DONE, update_stm.step());
+
+        assert_eq!(SQLITE_OK, update_stm.clear_bindings());
+        assert_eq!(SQLITE_OK, update_stm.reset());
+      }
+    }
+    self.exec_or_die("COMMIT");
+  }
+
+  /// Store the keyed digest of the persistent reference of rows in this namespace that have none,
+  /// within the open transaction. Rows that cannot be decrypted are left without one.
+  fn backfill_ref_digests(&mut self) {
+    let mut digests = vec!();
+    {
+      let mut cursor = self.prepare_or_die(&format!(
+        "SELECT id, blob_ref, flags FROM hash_index
+         WHERE namespace={} AND ref_digest IS NULL AND blob_ref IS NOT NULL",
+        self.quoted_namespace()));
+      while cursor.step() == SQLITE_ROW {
+        let id = cursor.get_i64(0);
+        let blob_ref = cursor.get_blob(1).unwrap_or(&[]).to_vec();
+        match (self.decode(cursor.get_i64(2), blob_ref, "entry", id), self.cipher.as_ref()) {
+          (Ok(blob_ref), Some(cipher)) => digests.push((id, cipher.digest(&blob_ref[..]))),
+          _ => (),
+        }
+      }
+    }
+
+    let mut update_stm = self.dbh.prepare(
+      "UPDATE hash_index SET ref_digest=? WHERE namespace=? AND id=?", &None).unwrap();
+    for (id, digest) in digests.into_iter() {
+      assert_eq!(SQLITE_OK, update_stm.bind_param(1, &Blob(digest)));
+      assert_eq!(SQLITE_OK, update_stm.bind_param(2, &Text(self.namespace.clone())));
+      assert_eq!(SQLITE_OK, update_stm.bind_param(3, &Integer64(id)));
+
+      assert_eq!(SQLITE_DONE, update_stm.step());
+
+      assert_eq!(SQLITE_OK, update_stm.clear_bindings());
+      assert_eq!(SQLITE_OK, update_stm.reset());
+    }
+  }
+
+  /// Open a transaction in the configured mode. An immediate transaction waits for the write lock
+  /// like a busy commit does, and starts deferred if the lock is still taken after all retries.
+  fn begin(&mut self) {
+    if self.transaction_mode == TransactionMode::Immediate {
+      let mut delay = self.busy_retry_delay;
+      for retry in 0..self.busy_retries + 1 {
+        match self.try_exec("BEGIN IMMEDIATE") {
+          Ok(()) => return,
+          Err(SQLITE_BUSY) if retry < self.busy_retries => {
+            thread::sleep_ms(delay.num_milliseconds() as u32);
+            delay = delay + delay;
+          },
+          Err(SQLITE_BUSY) => (),
+          Err(code) => panic!("exec: {:?}, {:?}\nIn sql: 'BEGIN IMMEDIATE'\n",
+                              code, self.dbh.get_errmsg()),
+        }
+      }
+    }
+    self.exec_or_die("BEGIN");
+  }
+
+  fn commit_with_retry(&mut self) -> Result<(), HashIndexError> {
+    // Savepoints end with the transaction, and are forgotten even if the commit fails:
+    self.savepoints.clear();
+    let mut delay = self.busy_retry_delay;
+    let mut retries = 0;
+    loop {
+      match self.try_exec("COMMIT") {
+        Ok(()) => return Ok(()),
+        Err(SQLITE_BUSY) if retries < self.busy_retries => {
+          retries += 1;
+          thread::sleep_ms(delay.num_milliseconds() as u32);
+          delay = delay + delay;
+        },
+        Err(SQLITE_BUSY) => return Err(HashIndexError::Busy),
+        Err(SQLITE_FULL) => {
+          self.recover_from_failed_commit();
+          return Err(HashIndexError::DiskFull);
+        },
+        Err(code) => panic!("exec: {:?}, {:?}\nIn sql: 'COMMIT'\n",
+                            code, self.dbh.get_errmsg()),
+      }
+    }
+  }
+
+  /// Make sure that a transaction with all uncommitted changes is open after a failed `COMMIT`, so
+  /// that the commit can be retried later.
+  fn recover_from_failed_commit(&mut self) {
+    // Sqlite may have rolled back the whole transaction. If so, `BEGIN` succeeds and the changes
+    // are made again in order, which records them again (a deferred `BEGIN` is used, as an
+    // immediate one also fails when busy):
+    if self.try_exec("BEGIN").is_ok() {
+      for change in mem::replace(&mut self.uncommitted, vec!()).into_iter() {
+        match change {
+          Change::Insert(entries) => self.insert_batch(entries),
+          Change::Spill(id, version, sealed) => self.write_chunks(id, version, sealed),
+          Change::DropSpilled(id) => self.drop_spilled(id),
+          Change::Delete(hash) => { self.delete(&hash); },
+          Change::DeleteIdRange(lo, hi) => self.delete_rows(lo, hi),
+          Change::Relocate(moves) => { self.relocate_batch(moves); },
+          Change::SetMeta(id, key, value) => self.set_meta(id, &key[..], &value[..]),
+          Change::Audit(event) => {
+            self.uncommitted.push(Change::Audit(event.clone()));
+            self.write_audit(vec!(event));
+          },
+        }
+      }
+    }
+  }
+
+  /// The deletion of `delete_id_range`, without reading the deleted entries.
+  fn delete_rows(&mut self, lo: i64, hi: i64) {
+    self.uncommitted.push(Change::DeleteIdRange(lo, hi));
+    let namespace = self.quoted_namespace();
+    let range = format!("namespace={} AND id >= {} AND id < {}", namespace, lo, hi);
+    for table in ["hash_payload_chunks", "hash_meta"].iter() {
+      self.exec_or_die(&format!(
+        "DELETE FROM {} WHERE namespace={} AND id IN (SELECT id FROM hash_index WHERE {})",
+        table, namespace, range));
+    }
+    self.exec_or_die(&format!("DELETE FROM hash_index WHERE {}", range));
+  }
+
+  fn write_rows(&mut self, entries: Vec<(i64, HashEntry)>) {
+    let mut insert_stm = self.dbh.prepare(
+      "INSERT INTO hash_index (id, hash, height, payload, blob_ref, blob_name, blob_len, flags,
+                               namespace, payload_version, content_len, ref_digest)
+       VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+      &None).unwrap();
+
+    for (id, entry) in entries.into_iter() {
+      let HashEntry{hash, level, payload, persistent_ref, content_len} = entry;
+      let payload = payload.unwrap_or_else(|| vec!());
+      let persistent_ref = persistent_ref.expect("hash was comitted");
+
+      // Branch payloads are written with a version header, which is recorded with the row. A
+      // payload that was spilled while reserved left its version behind:
+      let version = match self.spilled_versions.remove(&id) {
+        Some(version) => version,
+        None => payload_version(level, &payload[..]),
+      };
+
+      // The decoded blob columns would reveal the references, so they are left empty when
+      // encrypting. An empty payload is left as is, so it still reads back as no payload.
+      let blob_ref_opt = if self.cipher.is_some() { None }
+                         else { BlobRef::from_bytes(&persistent_ref[..]) };
+      let ref_digest = self.cipher.as_ref().map(|c| c.digest(&persistent_ref[..]));
+      let payload = if payload.len() == 0 { payload } else { self.encode(payload) };
+      let persistent_ref = self.encode(persistent_ref);
+      let flags = if self.cipher.is_some() { FLAG_ENCRYPTED } else { 0 };
+
+      // Oversized payloads are spilled to the chunk table and stored as empty inline:
+      let payload = if payload.len() > self.max_inline_payload {
+        self.insert_chunks(id, &payload[..]);
+        vec!()
+      } else { payload };
+
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(1, &Integer64(id)));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(2, &Blob(hash.bytes.to_vec())));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(3, &Integer64(level)));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(4, &Blob(payload)));
+      match blob_ref_opt {
+        Some(blob_ref) => {
+          assert_eq!(SQLITE_OK, insert_stm.bind_param(6, &Blob(blob_ref.name)));
+          assert_eq!(SQLITE_OK, insert_stm.bind_param(7, &Integer64(blob_ref.length as i64)));
+        },
+        None => {
+          assert_eq!(SQLITE_OK, insert_stm.bind_param(6, &Null));
+          assert_eq!(SQLITE_OK, insert_stm.bind_param(7, &Null));
+        },
+      }
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(5, &Blob(persistent_ref)));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(8, &Integer64(flags)));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(9, &Text(self.namespace.clone())));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(10, &Integer64(version as i64)));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(11, &Integer64(content_len as i64)));
+      match ref_digest {
+        Some(digest) => assert_eq!(SQLITE_OK, insert_stm.bind_param(12, &Blob(digest))),
+        None => assert_eq!(SQLITE_OK, insert_stm.bind_param(12, &Null)),
+      }
+
+      assert_eq!(SQLITE_DONE, insert_stm.step());
+
+      assert_eq!(SQLITE_OK, insert_stm.clear_bindings());
+      assert_eq!(SQLITE_OK, insert_stm.reset());
+    }
+  }
+
+  /// Split an (encoded) payload into the chunk table under `id`.
+  fn insert_chunks(&self, id: i64, payload: &[u8]) {
+    let mut chunk_stm = self.dbh.prepare(
+      "INSERT INTO hash_payload_chunks (namespace, id, seq, data) VALUES (?, ?, ?, ?)",
+      &None).unwrap();
+    for (seq, chunk) in payload.chunks(self.max_inline_payload).enumerate() {
+      assert_eq!(SQLITE_OK, chunk_stm.bind_param(1, &Text(self.namespace.clone())));
+      assert_eq!(SQLITE_OK, chunk_stm.bind_param(2, &Integer64(id)));
+      assert_eq!(SQLITE_OK, chunk_stm.bind_param(3, &Integer64(seq as i64)));
+      assert_eq!(SQLITE_OK, chunk_stm.bind_param(4, &Blob(chunk.to_vec())));
+
+      assert_eq!(SQLITE_DONE, chunk_stm.step());
+
+      assert_eq!(SQLITE_OK, chunk_stm.clear_bindings());
+      assert_eq!(SQLITE_OK, chunk_stm.reset());
+    }
+  }
+
+  /// The chunks of `spill_payload`, which are written again if a failed commit lost them.
+  fn write_chunks(&mut self, id: i64, version: u8, sealed: Vec<u8>) {
+    self.insert_chunks(id, &sealed[..]);
+    self.spilled_versions.insert(id, version);
+    self.uncommitted.push(Change::Spill(id, version, sealed));
+  }
+
+  fn write_audit(&mut self, events: Vec<AuditEvent>) {
+    let mut insert_stm = self.dbh.prepare(
+      "INSERT INTO audit_log (seq, ts, op, hash, detail, flags, namespace)
+       VALUES (?, ?, ?, ?, ?, ?, ?)",
+      &None).unwrap();
+
+    for event in events.into_iter() {
+      // The detail is a persistent reference, so it is encrypted like those (see `write_rows`):
+      let flags = if self.cipher.is_some() { FLAG_ENCRYPTED } else { 0 };
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(1, &Integer64(event.seq)));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(2, &Integer64(event.ts_ms)));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(3, &Integer64(event.op.code() as i64)));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(4, &Blob(event.hash.bytes.to_vec())));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(5, &Blob(self.encode(event.detail))));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(6, &Integer64(flags)));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(7, &Text(self.namespace.clone())));
+
+      assert_eq!(SQLITE_DONE, insert_stm.step());
+
+      assert_eq!(SQLITE_OK, insert_stm.clear_bindings());
+      assert_eq!(SQLITE_OK, insert_stm.reset());
+    }
+  }
+
+  /// Create the unique hash index if it does not exist.
+  /// Returns true if the index was missing.
+  pub fn open_checked(&mut self) -> bool {
+    let missing = self.select1_or_die("SELECT 1 FROM sqlite_master
+                                WHERE type='index' AND name='HashIndex_UniqueHash'").is_none();
+    if missing {
+      self.exec_or_die("CREATE UNIQUE INDEX HashIndex_UniqueHash ON hash_index(namespace, hash)");
+    }
+    missing
+  }
+
+  /// Drop the unique hash index, so that inserts do not have to maintain it.
+  pub fn begin_bulk_load(&mut self) {
+    self.exec_or_die("DROP INDEX IF EXISTS HashIndex_UniqueHash");
+  }
+
+  /// Recreate the unique hash index dropped by `begin_bulk_load`.
+  /// Returns a hash that is in the namespace more than once, in which case the index is not
+  /// created.
+  pub fn end_bulk_load(&mut self) -> Result<(), Hash> {
+    let created = self.try_exec("CREATE UNIQUE INDEX IF NOT EXISTS
+                                 HashIndex_UniqueHash ON hash_index(namespace, hash)");
+    match created {
+      Ok(()) => Ok(()),
+      Err(code) => {
+        let duplicate = self.select1_or_die("SELECT hash FROM hash_index
+                                      GROUP BY namespace, hash HAVING COUNT(*) > 1 LIMIT 1")
+          .map(|mut cursor| Hash{bytes: HashBytes::new(cursor.get_blob(0).unwrap_or(&[]))});
+        match duplicate {
+          Some(hash) => Err(hash),
+          None => panic!("Could not create unique hash index: {:?}, {:?}",
+                         code, self.dbh.get_errmsg()),
+        }
+      },
+    }
+  }
+
+  #[cfg(test)]
+  pub fn drop_unique_index(&mut self) {
+    self.exec_or_die("DROP INDEX HashIndex_UniqueHash");
+  }
+}
+
+impl HashBackend for SqliteBackend {
+
+  fn locate(&mut self, hash: &Hash) -> Result<Option<(i64, HashEntry)>, HashIndexError> {
+    assert!(hash.bytes.len() > 0);
+
+    // Spilled payloads are stored as empty inline, so they are read as no payload (and are
+    // decoded by `spilled_payload`):
+    let sql = format!("SELECT {} FROM hash_index WHERE namespace={} AND hash=x'{}'",
+                      ENTRY_COLUMNS, self.quoted_namespace(), hash.bytes.to_hex());
+    let found = match try!(self.select1(&sql)) {
+      Some(mut cursor) => Some((cursor.get_i64(0), try!(self.read_entry(&mut cursor)))),
+      None => None,
+    };
+    Ok(found)
+  }
+
+  fn locate_id(&mut self, id: i64) -> Result<Option<HashEntry>, HashIndexError> {
+    let sql = format!("SELECT {} FROM hash_index WHERE namespace={} AND id={}",
+                      ENTRY_COLUMNS, self.quoted_namespace(), id);
+    let found = match try!(self.select1(&sql)) {
+      Some(mut cursor) => Some(try!(self.read_entry(&mut cursor))),
+      None => None,
+    };
+    Ok(found)
+  }
+
+  fn hashes_in_range(&mut self, low: &[u8], high: Option<&[u8]>, limit: usize) -> (Vec<Hash>, u64) {
+    let range = match high {
+      Some(high) => format!("hash >= x'{}' AND hash < x'{}'", low.to_hex(), high.to_hex()),
+      None => format!("hash >= x'{}'", low.to_hex()),
+    };
+    let namespace = self.quoted_namespace();
+    let total = self.select1_or_die(&format!(
+      "SELECT COUNT(*) FROM hash_index WHERE namespace = {} AND {}", namespace, range))
+      .expect("count").get_i64(0) as u64;
+
+    let mut hashes = vec!();
+    let mut cursor = self.prepare_or_die(&format!(
+      "SELECT hash FROM hash_index WHERE namespace = {} AND {} ORDER BY hash LIMIT {}",
+      namespace, range, limit));
+    while cursor.step() == SQLITE_ROW {
+      hashes.push(Hash{bytes: HashBytes::new(cursor.get_blob(0).unwrap_or(&[]))});
+    }
+    (hashes, total)
+  }
+
+  fn known(&mut self, hashes: &[Hash]) -> BTreeSet<Vec<u8>> {
+    let namespace = self.quoted_namespace();
+    let mut known = BTreeSet::new();
+    // Keep the statements well below the limits of sqlite on statement length:
+    for batch in hashes.chunks(STREAM_BATCH_SIZE) {
+      let literals: Vec<String> = batch.iter()
+                                       .map(|h| format!("x'{}'", h.bytes.to_hex()))
+                                       .collect();
+      let mut cursor = self.prepare_or_die(&format!(
+        "SELECT hash FROM hash_index WHERE namespace={} AND hash IN ({})",
+        namespace, literals.connect(", ")));
+      while cursor.step() == SQLITE_ROW {
+        known.insert(cursor.get_blob(0).unwrap_or(&[]).to_vec());
+      }
+    }
+    known
+  }
+
+  fn for_each_hash(&mut self, sink: &mut FnMut(&[u8])) {
+    let mut cursor = self.prepare_or_die(&format!(
+      "SELECT hash FROM hash_index WHERE namespace={}", self.quoted_namespace()));
+    while cursor.step() == SQLITE_ROW {
+      sink(cursor.get_blob(0).unwrap_or(&[]));
+    }
+  }
+
+  fn spilled_payload(&mut self, id: i64) -> Result<Option<Vec<u8>>, HashIndexError> {
+    let mut payload = vec!();
+    let mut found = false;
+    {
+      let mut cursor = self.prepare_or_die(&format!(
+        "SELECT data FROM hash_payload_chunks WHERE namespace={} AND id={} ORDER BY seq",
+        self.quoted_namespace(), id));
+      while cursor.step() == SQLITE_ROW {
+        found = true;
+        payload.extend(cursor.get_blob(0).unwrap_or(&[]).iter().map(|&x| x));
+      }
+    }
+    if !found {
+      return Ok(None);
+    }
+    let (flags, level, version) = self.select1_or_die(&format!(
+      "SELECT flags, height, payload_version FROM hash_index WHERE namespace={} AND id={}",
+      self.quoted_namespace(), id))
+      .map(|mut row| (row.get_i64(0), row.get_i64(1), row.get_i64(2)))
+      // Not inserted yet, so it was spilled by `spill_payload` in its current version:
+      .unwrap_or((if self.cipher.is_some() { FLAG_ENCRYPTED } else { 0 }, 0,
+                  LEGACY_PAYLOAD_VERSION as i64));
+    let payload = try!(self.decode(flags, payload, "spilled payload", id));
+    Ok(Some(self.honor_version(level, version, payload)))
+  }
+
+  fn spill_payload(&mut self, id: i64, level: i64, payload: Vec<u8>) -> Option<Vec<u8>> {
+    // Rolling back to a savepoint would lose the chunks, but not the reservation:
+    if payload.len() <= self.max_inline_payload || self.savepoints.len() > 0 {
+      return Some(payload);
+    }
+    let version = payload_version(level, &payload[..]);
+    let sealed = self.encode(payload);
+    self.write_chunks(id, version, sealed);
+    None
+  }
+
+  fn drop_spilled(&mut self, id: i64) {
+    self.exec_or_die(&format!("DELETE FROM hash_payload_chunks WHERE namespace={} AND id={}",
+                              self.quoted_namespace(), id));
+    self.spilled_versions.remove(&id);
+    self.uncommitted.push(Change::DropSpilled(id));
+  }
+
+  fn insert_batch(&mut self, entries: Vec<(i64, HashEntry)>) {
+    self.uncommitted.push(Change::Insert(entries.clone()));
+    self.write_rows(entries);
+  }
+
+  fn delete(&mut self, hash: &Hash) -> bool {
+    let id = match self.locate(hash) {
+      Ok(Some((id, _))) => id,
+      Ok(None) => return false,
+      Err(e) => panic!("Could not locate hash {} to delete: {:?}", hash.bytes.to_hex(), e),
+    };
+    let row = format!("namespace={} AND id={}", self.quoted_namespace(), id);
+    for table in ["hash_payload_chunks", "hash_meta", "hash_index"].iter() {
+      self.exec_or_die(&format!("DELETE FROM {} WHERE {}", table, row));
+    }
+    self.uncommitted.push(Change::Delete(hash.clone()));
+    true
+  }
+
+  fn delete_id_range(&mut self, lo: i64, hi: i64) -> Result<Vec<(Hash, Vec<u8>)>, HashIndexError> {
+    let range = format!("namespace={} AND id >= {} AND id < {}", self.quoted_namespace(), lo, hi);
+    let mut deleted = vec!();
+    {
+      let mut cursor = self.prepare_or_die(&format!(
+        "SELECT hash, blob_ref, flags, id FROM hash_index WHERE {} ORDER BY id", range));
+      while cursor.step() == SQLITE_ROW {
+        let hash = Hash{bytes: HashBytes::new(cursor.get_blob(0).unwrap_or(&[]))};
+        let persistent_ref = cursor.get_blob(1).unwrap_or(&[]).to_vec();
+        let id = cursor.get_i64(3);
+        deleted.push((hash, try!(self.decode(cursor.get_i64(2), persistent_ref, "entry",
+                                             id))));
+      }
+    }
+    self.delete_rows(lo, hi);
+    Ok(deleted)
+  }
+
+  fn relocate(&mut self, hash: &Hash, blob_ref: &BlobRef) -> bool {
+    self.relocate_batch(vec!((hash.clone(), blob_ref.clone()))).0 == 1
+  }
+
+  fn relocate_batch(&mut self, moves: Vec<(Hash, BlobRef)>) -> (usize, usize) {
+    self.uncommitted.push(Change::Relocate(moves.clone()));
+    let mut flags_stm = self.dbh.prepare(
+      "SELECT flags FROM hash_index WHERE namespace=? AND hash=?", &None).unwrap();
+    let mut update_stm = self.dbh.prepare(
+      "UPDATE hash_index SET blob_ref=?, blob_name=?, blob_len=?, ref_digest=?
+       WHERE namespace=? AND hash=?",
+      &None).unwrap();
+
+    let mut updated = 0;
+    let mut not_found = 0;
+    for (hash, blob_ref) in moves.into_iter() {
+      assert_eq!(SQLITE_OK, flags_stm.bind_param(1, &Text(self.namespace.clone())));
+      assert_eq!(SQLITE_OK, flags_stm.bind_param(2, &Blob(hash.bytes.to_vec())));
+      let flags_opt = match flags_stm.step() {
+        SQLITE_ROW => Some(flags_stm.get_i64(0)),
+        SQLITE_DONE => None,
+        code => panic!("relocate: {:?}", code),
+      };
+      assert_eq!(SQLITE_OK, flags_stm.clear_bindings());
+      assert_eq!(SQLITE_OK, flags_stm.reset());
+
+      let flags = match flags_opt {
+        Some(flags) => flags,
+        None => { not_found += 1; continue },
+      };
+
+      // Keep the row in the form it was written in (see `insert_batch`):
+      if flags & FLAG_ENCRYPTED == 0 {
+        assert_eq!(SQLITE_OK, update_stm.bind_param(1, &Blob(blob_ref.to_bytes())));
+        assert_eq!(SQLITE_OK, update_stm.bind_param(2, &Blob(blob_ref.name.clone())));
+        assert_eq!(SQLITE_OK, update_stm.bind_param(3, &Integer64(blob_ref.length as i64)));
+      } else {
+        let cipher = self.cipher.as_ref().expect("encrypted row requires a key");
+        assert_eq!(SQLITE_OK, update_stm.bind_param(1, &Blob(cipher.seal(&blob_ref.to_bytes()))));
+        assert_eq!(SQLITE_OK, update_stm.bind_param(2, &Null));
+        assert_eq!(SQLITE_OK, update_stm.bind_param(3, &Null));
+      }
+      match self.cipher.as_ref().map(|c| c.digest(&blob_ref.to_bytes())) {
+        Some(digest) => assert_eq!(SQLITE_OK, update_stm.bind_param(4, &Blob(digest))),
+        None => assert_eq!(SQLITE_OK, update_stm.bind_param(4, &Null)),
+      }
+      assert_eq!(SQLITE_OK, update_stm.bind_param(5, &Text(self.namespace.clone())));
+      assert_eq!(SQLITE_OK, update_stm.bind_param(6, &Blob(hash.bytes.to_vec())));
+
+      assert_eq!(SQLITE_DONE, update_stm.step());
+
+      assert_eq!(SQLITE_OK, update_stm.clear_bindings());
+      assert_eq!(SQLITE_OK, update_stm.reset());
+      updated += 1;
+    }
+    (updated, not_found)
+  }
+
+  fn max_id(&mut self) -> i64 {
+    self.select1_or_die(&format!("SELECT MAX(id) FROM hash_index WHERE namespace={}",
+                                 self.quoted_namespace())).expect("id").get_i64(0)
+  }
+
+  fn commit_txn(&mut self) -> Result<(), HashIndexError> {
+    try!(self.commit_with_retry());
+    self.uncommitted.clear();
+    self.begin();
+    Ok(())
+  }
+
+  fn barrier(&mut self) -> Result<(), HashIndexError> {
+    // Checkpoint between the commit and the next transaction, so that committed data has reached
+    // the main database file (and not just the write-ahead log) before any callback is run.
+    try!(self.commit_with_retry());
+    self.uncommitted.clear();
+    self.exec_or_die("PRAGMA wal_checkpoint(FULL)");
+    self.begin();
+    Ok(())
+  }
+
+  fn checkpoint(&mut self) -> Result<(u64, u64), HashIndexError> {
+    try!(self.commit_with_retry());
+    self.uncommitted.clear();
+    // Without a write-ahead log, sqlite reports -1 for both counts:
+    let count = |n: i64| if n > 0 { n as u64 } else { 0 };
+    let pages = self.select1_or_die("PRAGMA wal_checkpoint(TRUNCATE)")
+      .map(|mut row| (count(row.get_i64(1)), count(row.get_i64(2))))
+      .unwrap_or((0, 0));
+    self.begin();
+    Ok(pages)
+  }
+
+  fn savepoint(&mut self, name: &str) {
+    self.exec_or_die(&format!("SAVEPOINT {}", quote_ident(name)));
+    self.savepoints.push((name.to_string(), self.uncommitted.len()));
+  }
+
+  fn release_savepoint(&mut self, name: &str) {
+    let pos = self.savepoints.iter().rposition(|s| s.0 == name).expect("open savepoint");
+    self.exec_or_die(&format!("RELEASE SAVEPOINT {}", quote_ident(name)));
+    self.savepoints.truncate(pos);
+  }
+
+  fn rollback_to_savepoint(&mut self, name: &str) {
+    let pos = self.savepoints.iter().rposition(|s| s.0 == name).expect("open savepoint");
+    self.exec_or_die(&format!("ROLLBACK TO SAVEPOINT {}", quote_ident(name)));
+    let uncommitted_len = self.savepoints[pos].1;
+    let mut dropped = vec!();
+    while self.uncommitted.len() > uncommitted_len {
+      if let Some(Change::DropSpilled(id)) = self.uncommitted.pop() {
+        dropped.push(id);
+      }
+    }
+    self.savepoints.truncate(pos + 1);
+
+    // Reservations are not undone, so neither are the payloads that they dropped:
+    for id in dropped.into_iter().rev() {
+      self.drop_spilled(id);
+    }
+  }
+
+  fn rebuild_indexes(&mut self) {
+    // A freshly created index needs no rebuild, but it is cheap compared to the lookup scans
+    // that a damaged index would otherwise cause.
+    self.open_checked();
+    self.exec_or_die("REINDEX hash_index");
+  }
+
+  fn stream(&mut self, leaves_only: bool, after_id: i64, interrupt: Option<&Interrupt>,
+            sink: &Fn(Vec<HashEntry>, i64)) -> Result<(Vec<HashEntry>, i64), HashIndexError>
+  {
+    let mut cursor = self.prepare_or_die(&format!(
+      "SELECT {} FROM hash_index
+       WHERE namespace = {} AND id > {} AND {}
+       ORDER BY id",
+      ENTRY_COLUMNS, self.quoted_namespace(), after_id,
+      if leaves_only { "height = 0" } else { "1" }));
+
+    let mut last_id = after_id;
+    let mut batch = Vec::with_capacity(STREAM_BATCH_SIZE);
+    while !is_set(interrupt) && cursor.step() == SQLITE_ROW {
+      last_id = cursor.get_i64(0);
+      batch.push(try!(self.read_entry(&mut cursor)));
+      if batch.len() == STREAM_BATCH_SIZE {
+        sink(batch, last_id);
+        batch = Vec::with_capacity(STREAM_BATCH_SIZE);
+      }
+    }
+    Ok((batch, last_id))
+  }
+
+  fn stream_in_object(&mut self, object_name: &[u8], interrupt: Option<&Interrupt>,
+                      sink: &Fn(Vec<HashEntry>)) -> Result<Vec<HashEntry>, HashIndexError> {
+    // The blob columns are left empty when encrypting, so then every reference is decoded:
+    let name_filter = if self.cipher.is_some() { "1".to_string() }
+                      else { format!("blob_name = x'{}'", object_name.to_hex()) };
+    let mut cursor = self.prepare_or_die(&format!(
+      "SELECT {} FROM hash_index
+       WHERE namespace = {} AND {}
+       ORDER BY id",
+      ENTRY_COLUMNS, self.quoted_namespace(), name_filter));
+
+    let mut batch = Vec::with_capacity(STREAM_BATCH_SIZE);
+    while !is_set(interrupt) && cursor.step() == SQLITE_ROW {
+      let entry = try!(self.read_entry(&mut cursor));
+      if !in_object(&entry, object_name) {
+        continue;
+      }
+      batch.push(entry);
+      if batch.len() == STREAM_BATCH_SIZE {
+        sink(batch);
+        batch = Vec::with_capacity(STREAM_BATCH_SIZE);
+      }
+    }
+    Ok(batch)
+  }
+
+  fn page(&mut self, offset: u64, limit: u64) -> Result<(Vec<HashEntry>, u64), HashIndexError> {
+    let namespace = self.quoted_namespace();
+    let total = self.select1_or_die(&format!(
+      "SELECT COUNT(*) FROM hash_index WHERE namespace = {}", namespace))
+      .expect("count").get_i64(0) as u64;
+
+    let mut entries = vec!();
+    {
+      // A negative limit means no limit to sqlite:
+      let mut cursor = self.prepare_or_die(&format!(
+        "SELECT {} FROM hash_index
+         WHERE namespace = {}
+         ORDER BY id LIMIT {} OFFSET {}",
+        ENTRY_COLUMNS, namespace, limit as i64, offset as i64));
+      while cursor.step() == SQLITE_ROW {
+        entries.push(try!(self.read_entry(&mut cursor)));
+      }
+    }
+    Ok((entries, total))
+  }
+
+  fn storage_summary(&mut self) -> Result<(u64, u64, u64), HashIndexError> {
+    if self.cipher.is_some() {
+      return Ok(summarize_refs(try!(self.decoded_refs(true)).into_iter().map(|(_, r)| r)));
+    }
+    let namespace = self.quoted_namespace();
+    let mut row = self.select1_or_die(&format!(
+      "SELECT COALESCE(SUM(blob_len), 0), COUNT(DISTINCT blob_name), COUNT(*)
+       FROM hash_index WHERE namespace = {} AND height = 0", namespace)).expect("aggregate");
+    let total_bytes = row.get_i64(0) as u64;
+    let distinct_objects = row.get_i64(1) as u64;
+    let leaf_count = row.get_i64(2) as u64;
+    Ok((total_bytes, distinct_objects, leaf_count))
+  }
+
+  fn incremental_vacuum(&mut self, pages: u64) -> u64 {
+    // Zero pages would free all of them:
+    if pages == 0 {
+      return 0;
+    }
+    let free_pages = |backend: &mut SqliteBackend|
+      backend.select1_or_die("PRAGMA freelist_count").expect("freelist_count").get_i64(0) as u64;
+    let before = free_pages(self);
+    self.exec_or_die(&format!("PRAGMA incremental_vacuum({})", pages));
+    before - free_pages(self)
+  }
+
+  fn file_size(&mut self) -> u64 {
+    let page_count =
+      self.select1_or_die("PRAGMA page_count").expect("page_count").get_i64(0) as u64;
+    let page_size = self.select1_or_die("PRAGMA page_size").expect("page_size").get_i64(0) as u64;
+    let wal = self.select1_or_die("PRAGMA journal_mode").expect("journal_mode")
+                  .get_text(0).map(|mode| mode.to_lowercase() == "wal").unwrap_or(false);
+    let wal_size = if wal {
+      fs::metadata(&format!("{}-wal", self.path)).map(|m| m.len()).unwrap_or(0)
+    } else { 0 };
+    page_count * page_size + wal_size
+  }
+
+  fn namespaces(&mut self) -> Vec<String> {
+    let mut namespaces = vec!();
+    let mut cursor = self.prepare_or_die(
+      "SELECT DISTINCT namespace FROM hash_index ORDER BY namespace");
+    while cursor.step() == SQLITE_ROW {
+      namespaces.push(cursor.get_text(0).unwrap_or("").to_string());
+    }
+    namespaces
+  }
+
+  fn schema_info(&mut self) -> (u32, Vec<String>, Vec<String>) {
+    let version = self.select1_or_die(
+      "SELECT value FROM hash_index_meta WHERE key='schema_version'")
+      .map(|mut row| row.get_int(0) as u32).unwrap_or(0);
+    let mut features = vec!();
+    if self.select1_or_die("SELECT 1 FROM hash_index_key").is_some() {
+      features.push("encrypted");
+    }
+    for &(name, feature) in [("audit_log", "audit"), ("hash_meta", "metadata"),
+                             ("hash_payload_chunks", "spilled_payloads")].iter() {
+      if self.has_schema_object(name) {
+        features.push(feature);
+      }
+    }
+    if self.has_schema_object("HashIndex_BlobRef") ||
+       self.has_schema_object("HashIndex_RefDigest") {
+      features.push("strict_refs");
+    }
+    (version, self.columns("hash_index"), features.iter().map(|f| f.to_string()).collect())
+  }
+
+  fn append_audit(&mut self, op: AuditOp, hash: &Hash, detail: &[u8]) {
+    let seq = self.select1_or_die("SELECT COALESCE(MAX(seq), 0) + 1 FROM audit_log")
+                  .expect("seq").get_i64(0);
+    let event = AuditEvent{seq: seq, ts_ms: audit::now_ms(), op: op, hash: hash.clone(),
+                           detail: detail.to_vec()};
+    self.uncommitted.push(Change::Audit(event.clone()));
+    self.write_audit(vec!(event));
+  }
+
+  fn set_meta(&mut self, id: i64, key: &str, value: &[u8]) {
+    self.uncommitted.push(Change::SetMeta(id, key.to_string(), value.to_vec()));
+    // Values are encrypted like payloads, keys are not:
+    let flags = if self.cipher.is_some() { FLAG_ENCRYPTED } else { 0 };
+    let mut insert_stm = self.dbh.prepare(
+      "INSERT OR REPLACE INTO hash_meta (namespace, id, key, value, flags) VALUES (?, ?, ?, ?, ?)",
+      &None).unwrap();
+    assert_eq!(SQLITE_OK, insert_stm.bind_param(1, &Text(self.namespace.clone())));
+    assert_eq!(SQLITE_OK, insert_stm.bind_param(2, &Integer64(id)));
+    assert_eq!(SQLITE_OK, insert_stm.bind_param(3, &Text(key.to_string())));
+    assert_eq!(SQLITE_OK, insert_stm.bind_param(4, &Blob(self.encode(value.to_vec()))));
+    assert_eq!(SQLITE_OK, insert_stm.bind_param(5, &Integer64(flags)));
+    assert_eq!(SQLITE_DONE, insert_stm.step());
+  }
+
+  fn meta(&mut self, id: i64, key: &str) -> Result<Option<Vec<u8>>, HashIndexError> {
+    let found = self.select1_or_die(&format!(
+      "SELECT flags, value FROM hash_meta WHERE namespace={} AND id={} AND key={}",
+      self.quoted_namespace(), id, quote(key)))
+      .map(|mut row| (row.get_i64(0), row.get_blob(1).unwrap_or(&[]).to_vec()));
+    match found {
+      Some((flags, value)) => self.decode(flags, value, "metadata of entry", id).map(Some),
+      None => Ok(None),
+    }
+  }
+
+  fn audit_since(&mut self, after_seq: i64, limit: usize)
+                 -> Result<Vec<AuditEvent>, HashIndexError> {
+    let mut events = vec!();
+    let mut cursor = self.prepare_or_die(&format!(
+      "SELECT seq, ts, op, hash, detail, flags FROM audit_log
+       WHERE namespace = {} AND seq > {}
+       ORDER BY seq", self.quoted_namespace(), after_seq));
+    while events.len() < limit && cursor.step() == SQLITE_ROW {
+      // E.g. written by a newer version:
+      let op = match AuditOp::from_code(cursor.get_int(2) as u8) {
+        Some(op) => op,
+        None => continue,
+      };
+      let seq = cursor.get_i64(0);
+      let detail = cursor.get_blob(4).unwrap_or(&[]).to_vec();
+      events.push(AuditEvent{seq: seq,
+                             ts_ms: cursor.get_i64(1),
+                             op: op,
+                             hash: Hash{bytes: HashBytes::new(cursor.get_blob(3).unwrap_or(&[]))},
+                             detail: try!(self.decode(cursor.get_i64(5), detail,
+                                                      "audit event", seq))});
+    }
+    Ok(events)
+  }
+
+  fn ref_owner(&mut self, hash: &Hash, blob_ref: &[u8]) -> Result<Option<Hash>, HashIndexError> {
+    // Encrypted references differ even when equal, but their keyed digests do not:
+    let matching = match self.cipher {
+      Some(ref cipher) => format!("ref_digest=x'{}'", cipher.digest(blob_ref).to_hex()),
+      None => format!("blob_ref=x'{}'", blob_ref.to_hex()),
+    };
+    let namespace = self.quoted_namespace();
+    let owner_opt = self.select1_or_die(&format!(
+      "SELECT hash FROM hash_index WHERE namespace={} AND {} AND hash!=x'{}' LIMIT 1",
+      namespace, matching, hash.bytes.to_hex()));
+    Ok(owner_opt.map(|mut owner| Hash{bytes: HashBytes::new(owner.get_blob(0).unwrap_or(&[]))}))
+  }
+}
+
+
+/// Quote `text` as an SQL string literal.
+fn quote(text: &str) -> String {
+  format!("'{}'", text.replace("'", "''"))
+}
+
+/// Quote `text` as an SQL identifier.
+fn quote_ident(text: &str) -> String {
+  format!("\"{}\"", text.replace("\"", "\"\""))
+}
+
+/// The version to record with the row of an entry. Branch payloads start with their version.
+fn payload_version(level: i64, payload: &[u8]) -> u8 {
+  if level > 0 { payload.first().cloned().unwrap_or(LEGACY_PAYLOAD_VERSION) }
+  else { LEGACY_PAYLOAD_VERSION }
+}
+
+/// Whether an enumeration must stop early.
+fn is_set(interrupt: Option<&Interrupt>) -> bool {
+  interrupt.map(|i| i.is_set()).unwrap_or(false)
+}
+
+/// Whether the persistent reference of `entry` points into the blob object named `object_name`.
+fn in_object(entry: &HashEntry, object_name: &[u8]) -> bool {
+  entry.persistent_ref.as_ref()
+    .and_then(|r| BlobRef::from_bytes(&r[..]))
+    .map(|blob_ref| &blob_ref.name[..] == object_name)
+    .unwrap_or(false)
+}
+
+/// Summarize the persistent references of leaf entries, like `HashBackend::storage_summary`.
+fn summarize_refs<I: Iterator<Item=Vec<u8>>>(refs: I) -> (u64, u64, u64) {
+  let mut total_bytes = 0;
+  let mut objects = BTreeSet::new();
+  let mut leaf_count = 0;
+  for r in refs {
+    leaf_count += 1;
+    match BlobRef::from_bytes(&r[..]) {
+      Some(blob_ref) => {
+        total_bytes += blob_ref.length;
+        objects.insert(blob_ref.name);
+      },
+      None => (),
+    }
+  }
+  (total_bytes, objects.len() as u64, leaf_count)
+}
+
+
+/// A `HashBackend` that keeps all entries in memory. Nothing is persisted, so encryption does
+/// not apply.
+pub struct MemoryBackend {
+  namespace: String,
+  entries: BTreeMap<i64, HashEntry>,
+  ids: BTreeMap<HashBytes, i64>,
+  audit: Vec<AuditEvent>,
+  meta: BTreeMap<(i64, String), Vec<u8>>,
+  // Open savepoints, with a copy of the entries and metadata and the length of the audit log
+  // when they were opened.
+  savepoints: Vec<(String, BTreeMap<i64, HashEntry>, BTreeMap<(i64, String), Vec<u8>>, usize)>,
+}
+
+impl MemoryBackend {
+
+  pub fn new(namespace: String) -> MemoryBackend {
+    MemoryBackend{namespace: namespace, entries: BTreeMap::new(), ids: BTreeMap::new(),
+                  audit: vec!(), meta: BTreeMap::new(), savepoints: vec!()}
+  }
+}
+
+impl HashBackend for MemoryBackend {
+
+  fn locate(&mut self, hash: &Hash) -> Result<Option<(i64, HashEntry)>, HashIndexError> {
+    let id_opt = self.ids.get(&hash.bytes).map(|id| *id);
+    Ok(id_opt.and_then(|id| self.entries.get(&id).map(|entry| (id, entry.clone()))))
+  }
+
+  fn locate_id(&mut self, id: i64) -> Result<Option<HashEntry>, HashIndexError> {
+    Ok(self.entries.get(&id).cloned())
+  }
+
+  fn hashes_in_range(&mut self, low: &[u8], high: Option<&[u8]>, limit: usize) -> (Vec<Hash>, u64) {
+    let in_range: Vec<&HashBytes> = self.ids.keys()
+      .filter(|k| &k[..] >= low && high.map(|high| &k[..] < high).unwrap_or(true))
+      .collect();
+    let hashes = in_range.iter().take(limit).map(|k| Hash{bytes: (*k).clone()}).collect();
+    (hashes, in_range.len() as u64)
+  }
+
+  fn known(&mut self, hashes: &[Hash]) -> BTreeSet<Vec<u8>> {
+    hashes.iter().filter(|h| self.ids.contains_key(&h.bytes)).map(|h| h.bytes.to_vec()).collect()
+  }
+
+  fn for_each_hash(&mut self, sink: &mut FnMut(&[u8])) {
+    for hash_bytes in self.ids.keys() {
+      sink(&hash_bytes[..]);
+    }
+  }
+
+  fn spilled_payload(&mut self, _id: i64) -> Result<Option<Vec<u8>>, HashIndexError> {
+    Ok(None)
+  }
+
+  fn insert_batch(&mut self, entries: Vec<(i64, HashEntry)>) {
+    for (id, entry) in entries.into_iter() {
+      self.ids.insert_unique(entry.hash.bytes.clone(), id);
+      self.entries.insert_unique(id, entry);
+    }
+  }
+
+  fn delete(&mut self, hash: &Hash) -> bool {
+    match self.ids.remove(&hash.bytes) {
+      Some(id) => {
+        self.entries.remove(&id);
+        let keys: Vec<(i64, String)> =
+          self.meta.keys().filter(|k| k.0 == id).cloned().collect();
+        for k in keys.iter() {
+          self.meta.remove(k);
+        }
+        true
+      },
+      None => false,
+    }
+  }
+
+  fn delete_id_range(&mut self, lo: i64, hi: i64) -> Result<Vec<(Hash, Vec<u8>)>, HashIndexError> {
+    let deleted: Vec<(Hash, Vec<u8>)> = self.entries.iter()
+      .filter(|&(&id, _)| lo <= id && id < hi)
+      .map(|(_, e)| (e.hash.clone(), e.persistent_ref.clone().unwrap_or(vec!())))
+      .collect();
+    for &(ref hash, _) in deleted.iter() {
+      self.delete(hash);
+    }
+    Ok(deleted)
+  }
+
+  fn relocate(&mut self, hash: &Hash, blob_ref: &BlobRef) -> bool {
+    let id_opt = self.ids.get(&hash.bytes).map(|id| *id);
+    match id_opt.and_then(|id| self.entries.get_mut(&id)) {
+      Some(entry) => { entry.persistent_ref = Some(blob_ref.to_bytes()); true },
+      None => false,
+    }
+  }
+
+  fn max_id(&mut self) -> i64 {
+    self.entries.keys().next_back().map(|id| *id).unwrap_or(0)
+  }
+
+  fn commit_txn(&mut self) -> Result<(), HashIndexError> {
+    self.savepoints.clear();
+    Ok(())
+  }
+
+  fn barrier(&mut self) -> Result<(), HashIndexError> {
+    self.commit_txn()
+  }
+
+  fn checkpoint(&mut self) -> Result<(u64, u64), HashIndexError> {
+    try!(self.commit_txn());
+    Ok((0, 0))
+  }
+
+  fn savepoint(&mut self, name: &str) {
+    self.savepoints.push((name.to_string(), self.entries.clone(), self.meta.clone(),
+                          self.audit.len()));
+  }
+
+  fn release_savepoint(&mut self, name: &str) {
+    let pos = self.savepoints.iter().rposition(|s| s.0 == name).expect("open savepoint");
+    self.savepoints.truncate(pos);
+  }
+
+  fn rollback_to_savepoint(&mut self, name: &str) {
+    let pos = self.savepoints.iter().rposition(|s| s.0 == name).expect("open savepoint");
+    self.savepoints.truncate(pos + 1);
+    self.entries = self.savepoints[pos].1.clone();
+    self.meta = self.savepoints[pos].2.clone();
+    self.audit.truncate(self.savepoints[pos].3);
+    self.ids = self.entries.iter().map(|(&id, entry)| (entry.hash.bytes.clone(), id)).collect();
+  }
+
+  fn rebuild_indexes(&mut self) {
+  }
+
+  fn stream(&mut self, leaves_only: bool, after_id: i64, interrupt: Option<&Interrupt>,
+            sink: &Fn(Vec<HashEntry>, i64)) -> Result<(Vec<HashEntry>, i64), HashIndexError>
+  {
+    let mut last_id = after_id;
+    let mut batch = Vec::with_capacity(STREAM_BATCH_SIZE);
+    for (&id, entry) in self.entries.iter() {
+      if is_set(interrupt) {
+        break;
+      }
+      if id <= after_id || (leaves_only && entry.level != 0) {
+        continue;
+      }
+      last_id = id;
+      batch.push(entry.clone());
+      if batch.len() == STREAM_BATCH_SIZE {
+        sink(batch, last_id);
+        batch = Vec::with_capacity(STREAM_BATCH_SIZE);
+      }
+    }
+    Ok((batch, last_id))
+  }
+
+  fn stream_in_object(&mut self, object_name: &[u8], interrupt: Option<&Interrupt>,
+                      sink: &Fn(Vec<HashEntry>)) -> Result<Vec<HashEntry>, HashIndexError> {
+    let mut batch = Vec::with_capacity(STREAM_BATCH_SIZE);
+    for entry in self.entries.values().filter(|e| in_object(e, object_name)) {
+      if is_set(interrupt) {
+        break;
+      }
+      batch.push(entry.clone());
+      if batch.len() == STREAM_BATCH_SIZE {
+        sink(batch);
+        batch = Vec::with_capacity(STREAM_BATCH_SIZE);
+      }
+    }
+    Ok(batch)
+  }
+
+  fn page(&mut self, offset: u64, limit: u64) -> Result<(Vec<HashEntry>, u64), HashIndexError> {
+    let entries = self.entries.values()
+                      .skip(offset as usize)
+                      .take(limit as usize)
+                      .cloned()
+                      .collect();
+    Ok((entries, self.entries.len() as u64))
+  }
+
+  fn storage_summary(&mut self) -> Result<(u64, u64, u64), HashIndexError> {
+    Ok(summarize_refs(self.entries.values()
+                        .filter(|e| e.level == 0)
+                        .map(|e| e.persistent_ref.clone().unwrap_or_else(|| vec!()))))
+  }
+
+  fn ref_owner(&mut self, hash: &Hash, blob_ref: &[u8]) -> Result<Option<Hash>, HashIndexError> {
+    Ok(self.entries.values()
+         .find(|e| e.hash != *hash && e.persistent_ref.as_ref().map(|r| &r[..]) == Some(blob_ref))
+         .map(|e| e.hash.clone()))
+  }
+
+  fn incremental_vacuum(&mut self, _pages: u64) -> u64 {
+    0
+  }
+
+  fn file_size(&mut self) -> u64 {
+    0
+  }
+
+  fn namespaces(&mut self) -> Vec<String> {
+    if self.entries.len() == 0 { vec!() } else { vec!(self.namespace.clone()) }
+  }
+
+  fn schema_info(&mut self) -> (u32, Vec<String>, Vec<String>) {
+    // There are no tables, but the audit log and metadata are always kept:
+    (SCHEMA_VERSION, vec!(), vec!("audit".to_string(), "metadata".to_string()))
+  }
+
+  fn append_audit(&mut self, op: AuditOp, hash: &Hash, detail: &[u8]) {
+    let seq = self.audit.len() as i64 + 1;
+    self.audit.push(AuditEvent{seq: seq, ts_ms: audit::now_ms(), op: op, hash: hash.clone(),
+                               detail: detail.to_vec()});
+  }
+
+  fn audit_since(&mut self, after_seq: i64, limit: usize)
+                 -> Result<Vec<AuditEvent>, HashIndexError> {
+    Ok(self.audit.iter().filter(|e| e.seq > after_seq).take(limit).cloned().collect())
+  }
+
+  fn set_meta(&mut self, id: i64, key: &str, value: &[u8]) {
+    self.meta.insert((id, key.to_string()), value.to_vec());
+  }
+
+  fn meta(&mut self, id: i64, key: &str) -> Result<Option<Vec<u8>>, HashIndexError> {
+    Ok(self.meta.get(&(id, key.to_string())).cloned())
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::{APPLICATION_ID, FLAG_ENCRYPTED};
+
+  use std::env;
+  use std::fs;
+  use std::i32;
+
+  use rustc_serialize::hex::{ToHex};
+
+  use audit::{AuditEvent, AuditOp};
+  use hash_bytes::{HashBytes};
+  use hash_index::{BlobRef, EncryptionKey, Hash, HashEntry, HashIndexError, IndexConfig,
+                   RefKind, SyncMode, TransactionMode};
+  use hash_payload::{LEGACY_PAYLOAD_VERSION, PayloadVersion, encode_children};
+
+  use sqlite3::types::ResultCode::{SQLITE_BUSY, SQLITE_ERROR, SQLITE_FULL};
+  use sqlite3::{open};
+
+  fn entry(data: &[u8]) -> HashEntry {
+    HashEntry{hash: Hash::new(data), level: 0, payload: Some(data.to_vec()),
+              persistent_ref: Some(b"ref".to_vec()), content_len: data.len() as u64}
+  }
+
+  fn check_insert_and_delete<B: HashBackend>(mut backend: B) {
+    let foo = entry(b"foo");
+    let bar = entry(b"bar");
+    backend.insert_batch(vec!((1, foo.clone()), (2, bar.clone())));
+    assert_eq!(Ok(()), backend.commit_txn());
+    assert_eq!(2, backend.max_id());
+
+    assert!(backend.delete(&foo.hash));
+    assert!(!backend.delete(&foo.hash));
+    assert_eq!(Ok(None), backend.locate(&foo.hash));
+
+    match backend.locate(&bar.hash) {
+      Ok(Some((id, e))) => {
+        assert_eq!(2, id);
+        assert_eq!(bar.payload, e.payload);
+      },
+      _ => panic!("Entry was not found."),
+    }
+
+    let mut hashes = vec!();
+    backend.for_each_hash(&mut |hash_bytes| hashes.push(hash_bytes.to_vec()));
+    assert_eq!(vec!(bar.hash.bytes.to_vec()), hashes);
+  }
+
+  #[test]
+  fn failed_commit_makes_all_changes_again() {
+    let config = IndexConfig{audit: true, ..IndexConfig::new()};
+    let mut backend = SqliteBackend::open(":memory:".to_string(), &config).unwrap();
+    let (foo, bar, baz, qux) = (entry(b"foo"), entry(b"bar"), entry(b"baz"), entry(b"qux"));
+    backend.insert_batch(vec!((1, foo.clone()), (2, bar.clone()), (3, baz.clone())));
+    assert_eq!(Ok(()), backend.commit_txn());
+
+    let moved = BlobRef{name: b"moved".to_vec(), offset: 0, length: 3, kind: RefKind::Unknown};
+    backend.insert_batch(vec!((4, qux.clone())));
+    assert!(backend.delete(&foo.hash));
+    assert_eq!(1, backend.delete_id_range(3, 4).unwrap().len());
+    assert!(backend.relocate(&bar.hash, &moved));
+    backend.set_meta(2, "mime", b"text/plain");
+    backend.append_audit(AuditOp::Relocate, &bar.hash, &moved.to_bytes()[..]);
+
+    // Sqlite rolls back the whole transaction on some errors:
+    backend.exec_or_die("ROLLBACK");
+    backend.inject_errors(vec!(SQLITE_FULL));
+    assert_eq!(Err(HashIndexError::DiskFull), backend.commit_txn());
+    assert_eq!(Ok(()), backend.commit_txn());
+
+    assert_eq!(Ok(None), backend.locate(&foo.hash));
+    assert_eq!(Ok(None), backend.locate(&baz.hash));
+    assert!(backend.locate(&qux.hash).unwrap().is_some());
+    assert_eq!(Some(moved.to_bytes()),
+               backend.locate(&bar.hash).unwrap().and_then(|(_, e)| e.persistent_ref));
+    assert_eq!(Ok(Some(b"text/plain".to_vec())), backend.meta(2, "mime"));
+    assert_eq!(1, backend.audit_since(0, 10).unwrap().len());
+  }
+
+  #[test]
+  fn failed_lookups_are_not_absence() {
+    let mut backend = SqliteBackend::open(":memory:".to_string(), &IndexConfig::new()).unwrap();
+    let foo = entry(b"foo");
+    backend.insert_batch(vec!((1, foo.clone())));
+    assert_eq!(Ok(()), backend.commit_txn());
+
+    backend.inject_errors(vec!(SQLITE_ERROR, SQLITE_BUSY));
+    assert_eq!(Err(HashIndexError::Busy), backend.locate(&foo.hash));
+    match backend.locate(&foo.hash) {
+      Err(HashIndexError::QueryFailed(_)) => (),
+      other => panic!("Unexpected lookup result: {:?}", other),
+    }
+    assert_eq!(Some(1), backend.locate(&foo.hash).unwrap().map(|(id, _)| id));
+
+    backend.inject_errors(vec!(SQLITE_BUSY));
+    assert_eq!(Err(HashIndexError::Busy), backend.locate_id(1));
+    assert_eq!(Some(foo.hash), backend.locate_id(1).unwrap().map(|e| e.hash));
+  }
+
+  fn encrypted_config(key: &[u8]) -> IndexConfig {
+    IndexConfig{encryption_key: Some(EncryptionKey(key.to_vec())), ..IndexConfig::new()}
+  }
+
+  #[test]
+  fn encrypted_columns() {
+    let mut backend = SqliteBackend::open(":memory:".to_string(),
+                                          &encrypted_config(b"secret")).unwrap();
+    let blob_ref = BlobRef{name: b"name".to_vec(), offset: 0, length: 3, kind: RefKind::Unknown};
+    let foo = HashEntry{persistent_ref: Some(blob_ref.to_bytes()), ..entry(b"foo")};
+    backend.insert_batch(vec!((1, foo.clone())));
+
+    {
+      let mut row =
+        backend.select1_or_die("SELECT payload, blob_ref, flags FROM hash_index").unwrap();
+      assert!(row.get_blob(0) != Some(&b"foo"[..]));
+      assert!(row.get_blob(1) != Some(&blob_ref.to_bytes()[..]));
+      assert_eq!(FLAG_ENCRYPTED, row.get_i64(2));
+    }
+
+    let (_, e) = backend.locate(&foo.hash).unwrap().unwrap();
+    assert_eq!(foo.payload, e.payload);
+    assert_eq!(foo.persistent_ref, e.persistent_ref);
+    assert_eq!((3, 1, 1), backend.storage_summary().unwrap());
+  }
+
+  #[test]
+  fn damaged_encrypted_rows_are_errors() {
+    let mut backend = SqliteBackend::open(":memory:".to_string(),
+                                          &encrypted_config(b"secret")).unwrap();
+    let foo = entry(b"foo");
+    backend.insert_batch(vec!((1, foo.clone())));
+    backend.set_meta(1, "mime", b"text/plain");
+    backend.exec_or_die("UPDATE hash_index SET blob_ref = x'00'");
+    backend.exec_or_die("UPDATE hash_meta SET value = x'00'");
+
+    let is_corrupt = |result: Result<(), HashIndexError>| match result {
+      Err(HashIndexError::CorruptRow(_)) => true,
+      _ => false,
+    };
+    assert!(is_corrupt(backend.locate(&foo.hash).map(|_| ())));
+    assert!(is_corrupt(backend.locate_id(1).map(|_| ())));
+    assert!(is_corrupt(backend.page(0, 10).map(|_| ())));
+    assert!(is_corrupt(backend.stream(false, 0, None, &|_, _| ()).map(|_| ())));
+    assert!(is_corrupt(backend.storage_summary().map(|_| ())));
+    assert!(is_corrupt(backend.meta(1, "mime").map(|_| ())));
+
+    // Nothing is deleted when the deleted entries cannot be read:
+    assert!(is_corrupt(backend.delete_id_range(0, 10).map(|_| ())));
+    assert_eq!(1, backend.max_id());
+  }
+
+  #[test]
+  fn synchronous_is_configured() {
+    let mut backend = SqliteBackend::open(":memory:".to_string(), &IndexConfig::new()).unwrap();
+    let default = backend.select1_or_die("PRAGMA synchronous").unwrap().get_i64(0);
+    assert!(default > 0);
+
+    for &mode in [SyncMode::Off, SyncMode::Normal, SyncMode::Full].iter() {
+      let config = IndexConfig{synchronous: Some(mode), ..IndexConfig::new()};
+      let mut backend = SqliteBackend::open(":memory:".to_string(), &config).unwrap();
+      assert_eq!(mode.pragma_value(),
+                 backend.select1_or_die("PRAGMA synchronous").unwrap().get_i64(0));
+    }
+  }
+
+  #[test]
+  fn checkpoint_truncates_log() {
+    let path = env::temp_dir().join("hat_checkpoint_truncates_log.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+
+    let config = IndexConfig{wal_autocheckpoint: Some(100000), ..IndexConfig::new()};
+    let mut backend = SqliteBackend::open(path_str.clone(), &config).unwrap();
+    backend.exec_or_die("COMMIT; PRAGMA journal_mode=WAL; BEGIN");
+    assert_eq!(100000, backend.select1_or_die("PRAGMA wal_autocheckpoint").unwrap().get_int(0));
+
+    backend.insert_batch(vec!((1, entry(b"foo")), (2, entry(b"bar"))));
+    assert_eq!(Ok(()), backend.commit_txn());
+    let (log_pages, moved_pages) = backend.checkpoint().unwrap();
+    assert!(log_pages > 0);
+    assert_eq!(log_pages, moved_pages);
+    assert_eq!(0, fs::metadata(&format!("{}-wal", path_str)).unwrap().len());
+
+    drop(backend);
+    fs::remove_file(&path).unwrap();
+    let _ = fs::remove_file(&format!("{}-wal", path_str));
+    let _ = fs::remove_file(&format!("{}-shm", path_str));
+  }
+
+  #[test]
+  fn incremental_vacuum_frees_pages() {
+    let path = env::temp_dir().join("hat_incremental_vacuum_frees_pages.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+    let mut backend = SqliteBackend::open(path_str.clone(), &IndexConfig::new()).unwrap();
+    assert_eq!(2, backend.select1_or_die("PRAGMA auto_vacuum").unwrap().get_int(0));
+
+    let entries: Vec<HashEntry> = (0..100).map(|i| entry(&[i as u8; 2000][..])).collect();
+    backend.insert_batch(entries.iter().cloned().enumerate()
+                                .map(|(i, e)| (i as i64 + 1, e)).collect());
+    assert_eq!(Ok(()), backend.commit_txn());
+    for e in entries.iter() {
+      assert!(backend.delete(&e.hash));
+    }
+    assert_eq!(Ok(()), backend.commit_txn());
+
+    let size = backend.file_size();
+    assert_eq!(0, backend.incremental_vacuum(0));
+    assert_eq!(10, backend.incremental_vacuum(10));
+    assert_eq!(Ok(()), backend.commit_txn());
+    assert!(backend.file_size() < size);
+
+    drop(backend);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn immediate_transactions_take_the_write_lock() {
+    let path = env::temp_dir().join("hat_immediate_transactions_take_the_write_lock.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+    let config = |mode| IndexConfig{transaction_mode: mode, busy_retries: 0, ..IndexConfig::new()};
+
+    // Two deferred writers can deadlock: each reads (taking a shared lock), then the first to
+    // write blocks the other's write, and its commit is blocked by the other's shared lock until
+    // one of them gives up. An immediate transaction takes the write lock up front instead, so a
+    // second writer waits before it reads anything:
+    {
+      let _index = SqliteBackend::open(path_str.clone(), &config(TransactionMode::Deferred));
+      let mut other = SqliteBackend::open(path_str.clone(), &config(TransactionMode::Deferred))
+        .unwrap();
+      assert_eq!(Ok(()), other.try_exec("COMMIT; BEGIN IMMEDIATE"));
+      other.exec_or_die("COMMIT; BEGIN");
+    }
+    {
+      let _index = SqliteBackend::open(path_str.clone(), &config(TransactionMode::Immediate));
+      let mut other = SqliteBackend::open(path_str.clone(), &config(TransactionMode::Deferred))
+        .unwrap();
+      assert_eq!(Err(SQLITE_BUSY), other.try_exec("COMMIT; BEGIN IMMEDIATE"));
+    }
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn legacy_branch_payloads_are_versioned() {
+    let mut backend = SqliteBackend::open(":memory:".to_string(), &IndexConfig::new()).unwrap();
+    let children = vec!(Hash::new(b"foo"), Hash::new(b"bar"));
+    let mut legacy = children[0].bytes.to_vec();
+    legacy.extend(children[1].bytes.iter().cloned());
+
+    let branch = HashEntry{level: 1, payload: Some(legacy), ..entry(b"branch")};
+    backend.insert_batch(vec!((1, branch.clone())));
+    backend.exec_or_die(&format!("UPDATE hash_index SET payload_version={}",
+                                 LEGACY_PAYLOAD_VERSION));
+
+    let (_, e) = backend.locate(&branch.hash).unwrap().unwrap();
+    assert_eq!(Some(encode_children(PayloadVersion::V1, &children[..])), e.payload);
+  }
+
+  #[test]
+  fn wrong_key_is_rejected() {
+    let path = env::temp_dir().join("hat_wrong_key_is_rejected.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+
+    {
+      let mut backend = SqliteBackend::open(path_str.clone(), &encrypted_config(b"right")).unwrap();
+      backend.insert_batch(vec!((1, entry(b"foo"))));
+      assert_eq!(Ok(()), backend.commit_txn());
+    }
+    assert!(SqliteBackend::open(path_str.clone(), &encrypted_config(b"right")).is_ok());
+    assert_eq!(Some(HashIndexError::WrongKey),
+               SqliteBackend::open(path_str.clone(), &encrypted_config(b"wrong")).err());
+    assert_eq!(Some(HashIndexError::WrongKey),
+               SqliteBackend::open(path_str.clone(), &IndexConfig::new()).err());
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn other_databases_are_rejected() {
+    let path = env::temp_dir().join("hat_other_databases_are_rejected.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+
+    {
+      let mut backend = SqliteBackend::open(path_str.clone(), &IndexConfig::new()).unwrap();
+      assert_eq!(APPLICATION_ID,
+                 backend.select1_or_die("PRAGMA application_id").unwrap().get_i64(0));
+      backend.exec_or_die("PRAGMA application_id=42");
+    }
+    assert_eq!(Some(HashIndexError::NotAHashIndex),
+               SqliteBackend::open(path_str.clone(), &IndexConfig::new()).err());
+    fs::remove_file(&path).unwrap();
+
+    {
+      let mut dbh = open(&path_str[..]).unwrap();
+      assert!(dbh.exec("CREATE TABLE photos (id INTEGER PRIMARY KEY, name TEXT)").unwrap());
+    }
+    assert_eq!(Some(HashIndexError::NotAHashIndex),
+               SqliteBackend::open(path_str.clone(), &IndexConfig::new()).err());
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn merge_source_is_only_read() {
+    let path = env::temp_dir().join("hat_merge_source_is_only_read.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+    let mut backend = SqliteBackend::open(":memory:".to_string(), &IndexConfig::new()).unwrap();
+
+    // A missing index is not created:
+    assert_eq!(Err(HashIndexError::NotAHashIndex), backend.merge_from(&path_str[..], 1));
+    assert!(fs::metadata(&path).is_err());
+
+    // Nor is an index from before the current schema upgraded:
+    {
+      let mut dbh = open(&path_str[..]).unwrap();
+      assert!(dbh.exec("CREATE TABLE hash_index (id INTEGER PRIMARY KEY, hash BLOB,
+                                  height INTEGER, payload BLOB, blob_ref BLOB)").unwrap());
+    }
+    assert_eq!(Err(HashIndexError::NotAHashIndex), backend.merge_from(&path_str[..], 1));
+    {
+      let dbh = open(&path_str[..]).unwrap();
+      let mut cursor = dbh.prepare("PRAGMA application_id", &None).unwrap();
+      cursor.step();
+      assert_eq!(0, cursor.get_int(0));
+      let mut cursor = dbh.prepare("SELECT COUNT(*) FROM sqlite_master", &None).unwrap();
+      cursor.step();
+      assert_eq!(1, cursor.get_int(0));
+    }
+    fs::remove_file(&path).unwrap();
+
+    // An index in the current schema must use the same key:
+    drop(SqliteBackend::open(path_str.clone(), &encrypted_config(b"key")).unwrap());
+    assert_eq!(Err(HashIndexError::WrongKey), backend.merge_from(&path_str[..], 1));
+
+    // The backend is still usable after refusing a source:
+    backend.insert_batch(vec!((1, entry(b"foo"))));
+    assert_eq!(Ok(()), backend.commit_txn());
+    assert_eq!(1, backend.max_id());
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn payloads_are_spilled_before_insert() {
+    let path = env::temp_dir().join("hat_payloads_are_spilled_before_insert.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+    let config = IndexConfig{max_inline_payload: 2, ..encrypted_config(b"key")};
+
+    {
+      let mut backend = SqliteBackend::open(path_str.clone(), &config).unwrap();
+      assert_eq!(Some(b"ab".to_vec()), backend.spill_payload(1, 0, b"ab".to_vec()));
+      assert_eq!(None, backend.spill_payload(1, 0, b"foo".to_vec()));
+      assert_eq!(Ok(Some(b"foo".to_vec())), backend.spilled_payload(1));
+
+      // The entry is inserted without its payload, which stays where it was spilled:
+      backend.insert_batch(vec!((1, HashEntry{payload: None, ..entry(b"foo")})));
+      assert_eq!(Ok(Some(b"foo".to_vec())), backend.spilled_payload(1));
+
+      // A payload that is dropped is gone, and so is one that is never inserted:
+      assert_eq!(None, backend.spill_payload(2, 0, b"bar".to_vec()));
+      backend.drop_spilled(2);
+      assert_eq!(Ok(None), backend.spilled_payload(2));
+      assert_eq!(None, backend.spill_payload(3, 0, b"baz".to_vec()));
+      assert_eq!(Ok(()), backend.commit_txn());
+
+      // Nothing is spilled while a savepoint is open:
+      backend.savepoint("s");
+      assert_eq!(Some(b"qux".to_vec()), backend.spill_payload(4, 0, b"qux".to_vec()));
+      backend.release_savepoint("s");
+    }
+
+    let mut backend = SqliteBackend::open(path_str.clone(), &config).unwrap();
+    assert_eq!(Ok(Some(b"foo".to_vec())), backend.spilled_payload(1));
+    assert_eq!(Ok(None), backend.spilled_payload(3));
+    drop(backend);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn large_ids_survive_reopening() {
+    let path = env::temp_dir().join("hat_large_ids_survive_reopening.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+    let id = i32::MAX as i64 + 2;
+    let foo = entry(b"foo");
+
+    {
+      let mut backend = SqliteBackend::open(path_str.clone(), &IndexConfig::new()).unwrap();
+      backend.insert_batch(vec!((id, foo.clone())));
+      assert_eq!(Ok(()), backend.commit_txn());
+    }
+
+    let mut backend = SqliteBackend::open(path_str.clone(), &IndexConfig::new()).unwrap();
+    assert_eq!(id, backend.max_id());
+    assert_eq!(Ok(Some((id, foo.clone()))), backend.locate(&foo.hash));
+    assert_eq!(Ok(Some(foo)), backend.locate_id(id));
+    drop(backend);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn namespaces_are_separate() {
+    let path = env::temp_dir().join("hat_namespaces_are_separate.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+
+    // Payloads are spilled, so that the chunks of both namespaces share their ids as well:
+    let config_a = IndexConfig{namespace: "a".to_string(), max_inline_payload: 2,
+                               ..IndexConfig::new()};
+    let config_b = IndexConfig{namespace: "it's b".to_string(), ..config_a.clone()};
+    let foo = entry(b"foo");
+    let bar = entry(b"bar");
+    {
+      let mut a = SqliteBackend::open(path_str.clone(), &config_a).unwrap();
+      a.insert_batch(vec!((1, foo.clone()), (2, bar.clone())));
+      a.set_meta(1, "name", b"a");
+      assert_eq!(Ok(()), a.commit_txn());
+    }
+    {
+      // Ids are counted per namespace, so both namespaces use the same ones:
+      let mut b = SqliteBackend::open(path_str.clone(), &config_b).unwrap();
+      assert_eq!(0, b.max_id());
+      assert_eq!(Ok(None), b.locate(&foo.hash));
+      // The same hash may exist in both namespaces:
+      b.insert_batch(vec!((1, HashEntry{payload: Some(b"other".to_vec()), ..foo.clone()})));
+      b.set_meta(1, "name", b"b");
+      assert_eq!(Ok(()), b.commit_txn());
+      assert_eq!(Ok(Some(b"other".to_vec())), b.spilled_payload(1));
+      assert_eq!(Ok(Some(b"b".to_vec())), b.meta(1, "name"));
+      assert_eq!(1, b.max_id());
+      assert_eq!(vec!("a".to_string(), "it's b".to_string()), b.namespaces());
+
+      // Deleting in one namespace leaves the other as it is:
+      b.insert_batch(vec!((2, bar.clone())));
+      assert!(b.delete(&foo.hash));
+      assert_eq!(vec!(bar.hash.clone()),
+                 b.delete_id_range(0, 10).unwrap().into_iter().map(|(h, _)| h).collect::<Vec<_>>());
+      assert_eq!(Ok(()), b.commit_txn());
+    }
+    let mut a = SqliteBackend::open(path_str.clone(), &config_a).unwrap();
+    assert_eq!(Ok(Some(b"foo".to_vec())), a.spilled_payload(1));
+    assert_eq!(Ok(Some(b"bar".to_vec())), a.spilled_payload(2));
+    assert_eq!(Ok(Some(b"a".to_vec())), a.meta(1, "name"));
+    assert_eq!(2, a.max_id());
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn ids_are_scoped_to_namespace_when_opening() {
+    let path = env::temp_dir().join("hat_ids_are_scoped_to_namespace.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+
+    // Before, all namespaces shared the ids of the file:
+    {
+      let mut dbh = open(&path_str[..]).unwrap();
+      assert!(dbh.exec("CREATE TABLE hash_index (id INTEGER PRIMARY KEY, hash BLOB,
+                                  height INTEGER, payload BLOB, blob_ref BLOB, blob_name BLOB,
+                                  blob_len INTEGER, flags INTEGER,
+                                  namespace TEXT NOT NULL DEFAULT '',
+                                  payload_version INTEGER NOT NULL DEFAULT 0,
+                                  content_len INTEGER NOT NULL DEFAULT 0)").unwrap());
+      assert!(dbh.exec("CREATE TABLE hash_meta (id INTEGER, key TEXT, value BLOB, flags INTEGER,
+                                                PRIMARY KEY (id, key)) WITHOUT ROWID").unwrap());
+      assert!(dbh.exec("INSERT INTO hash_index (id, hash, height, payload, blob_ref, flags,
+                                                namespace)
+                        VALUES (1, x'01', 0, x'', x'', 0, 'a'),
+                               (2, x'02', 0, x'', x'', 0, 'b')").unwrap());
+      assert!(dbh.exec("INSERT INTO hash_meta (id, key, value, flags)
+                        VALUES (1, 'name', x'61', 0), (2, 'name', x'62', 0)").unwrap());
+    }
+    let config_a = IndexConfig{namespace: "a".to_string(), digest_width: 1,
+                               ..IndexConfig::new()};
+    let config_b = IndexConfig{namespace: "b".to_string(), ..config_a.clone()};
+    {
+      let mut b = SqliteBackend::open(path_str.clone(), &config_b).unwrap();
+      assert_eq!(vec!("id".to_string(), "namespace".to_string()),
+                 { let mut key = b.primary_key("hash_index"); key.sort(); key });
+      assert_eq!(Ok(Some(b"b".to_vec())), b.meta(2, "name"));
+      assert_eq!(Ok(None), b.meta(1, "name"));
+    }
+    let mut a = SqliteBackend::open(path_str.clone(), &config_a).unwrap();
+    assert_eq!(Ok(Some(b"a".to_vec())), a.meta(1, "name"));
+    assert_eq!(Ok(None), a.meta(2, "name"));
+    assert_eq!(Some(1), a.locate(&Hash{bytes: HashBytes::new(&[1])}).unwrap().map(|(id, _)| id));
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn insert_and_delete() {
+    check_insert_and_delete(MemoryBackend::new(String::new()));
+    check_insert_and_delete(
+      SqliteBackend::open(":memory:".to_string(), &IndexConfig::new()).unwrap());
+  }
+
+  fn check_stream_in_object<B: HashBackend>(mut backend: B) {
+    let in_object = |data: &[u8], name: &[u8]| {
+      let blob_ref = BlobRef{name: name.to_vec(), offset: 0, length: data.len() as u64,
+                             kind: RefKind::Unknown};
+      HashEntry{persistent_ref: Some(blob_ref.to_bytes()), ..entry(data)}
+    };
+    let foo = in_object(b"foo", b"a");
+    let bar = in_object(b"bar", b"b");
+    let baz = in_object(b"baz", b"a");
+    backend.insert_batch(vec!((1, foo.clone()), (2, bar.clone()), (3, baz.clone()),
+                              (4, entry(b"unstructured"))));
+    assert_eq!(Ok(()), backend.commit_txn());
+
+    let hashes = |entries: Vec<HashEntry>| entries.into_iter().map(|e| e.hash).collect::<Vec<_>>();
+    assert_eq!(vec!(foo.hash, baz.hash),
+               hashes(backend.stream_in_object(b"a", None, &|_| ()).unwrap()));
+    assert_eq!(vec!(bar.hash), hashes(backend.stream_in_object(b"b", None, &|_| ()).unwrap()));
+    assert_eq!(0, backend.stream_in_object(b"c", None, &|_| ()).unwrap().len());
+  }
+
+  #[test]
+  fn stream_in_object() {
+    check_stream_in_object(MemoryBackend::new(String::new()));
+    check_stream_in_object(
+      SqliteBackend::open(":memory:".to_string(), &IndexConfig::new()).unwrap());
+    check_stream_in_object(
+      SqliteBackend::open(":memory:".to_string(), &encrypted_config(b"secret")).unwrap());
+  }
+
+  fn check_audit_log<B: HashBackend>(mut backend: B) {
+    let foo = entry(b"foo");
+    backend.insert_batch(vec!((1, foo.clone())));
+    backend.append_audit(AuditOp::Commit, &foo.hash, b"ref");
+    assert_eq!(Ok(()), backend.commit_txn());
+
+    // Audit events are rolled back along with the changes they describe:
+    backend.savepoint("relocate");
+    backend.append_audit(AuditOp::Relocate, &foo.hash, b"moved");
+    backend.rollback_to_savepoint("relocate");
+    backend.release_savepoint("relocate");
+    backend.append_audit(AuditOp::Uncommit, &foo.hash, b"");
+
+    let events = backend.audit_since(0, 10).unwrap();
+    assert_eq!(vec!((1, AuditOp::Commit, b"ref".to_vec()), (2, AuditOp::Uncommit, vec!())),
+               events.iter().map(|e| (e.seq, e.op, e.detail.clone())).collect::<Vec<_>>());
+    assert!(events.iter().all(|e| e.hash == foo.hash && e.ts_ms > 0));
+    assert_eq!(vec!(2),
+               backend.audit_since(1, 10).unwrap().iter().map(|e| e.seq).collect::<Vec<_>>());
+    assert_eq!(1, backend.audit_since(0, 1).unwrap().len());
+  }
+
+  #[test]
+  fn audit_log() {
+    let audited = |config: IndexConfig| IndexConfig{audit: true, ..config};
+    check_audit_log(MemoryBackend::new(String::new()));
+    check_audit_log(
+      SqliteBackend::open(":memory:".to_string(), &audited(IndexConfig::new())).unwrap());
+    check_audit_log(
+      SqliteBackend::open(":memory:".to_string(), &audited(encrypted_config(b"secret"))).unwrap());
+  }
+
+  #[test]
+  fn unknown_audit_ops_are_skipped() {
+    let config = IndexConfig{audit: true, ..IndexConfig::new()};
+    let mut backend = SqliteBackend::open(":memory:".to_string(), &config).unwrap();
+    let foo = entry(b"foo");
+    backend.append_audit(AuditOp::Commit, &foo.hash, b"ref");
+    backend.exec_or_die("INSERT INTO audit_log (seq, ts, op, hash, detail, flags, namespace)
+                         VALUES (2, 1, 255, x'', x'', 0, '')");
+    backend.append_audit(AuditOp::Uncommit, &foo.hash, b"");
+
+    let seqs = |events: Vec<AuditEvent>| events.iter().map(|e| e.seq).collect::<Vec<_>>();
+    assert_eq!(vec!(1, 3), seqs(backend.audit_since(0, 10).unwrap()));
+    // The limit only counts the events that are returned:
+    assert_eq!(vec!(1, 3), seqs(backend.audit_since(0, 2).unwrap()));
+  }
+
+  fn check_meta<B: HashBackend>(mut backend: B) {
+    let (foo, bar) = (entry(b"foo"), entry(b"bar"));
+    backend.insert_batch(vec!((1, foo.clone()), (2, bar.clone())));
+    backend.set_meta(1, "mime", b"text/plain");
+    backend.set_meta(1, "mime", b"text/html");
+    backend.set_meta(1, "it's", b"quoted");
+    backend.set_meta(2, "mime", b"image/png");
+    assert_eq!(Ok(()), backend.commit_txn());
+
+    assert_eq!(Ok(Some(b"text/html".to_vec())), backend.meta(1, "mime"));
+    assert_eq!(Ok(Some(b"quoted".to_vec())), backend.meta(1, "it's"));
+    assert_eq!(Ok(None), backend.meta(1, "tags"));
+
+    // Metadata is rolled back with savepoints, whether it was set or deleted with its entry:
+    backend.savepoint("meta");
+    backend.set_meta(1, "mime", b"text/css");
+    assert!(backend.delete(&bar.hash));
+    backend.rollback_to_savepoint("meta");
+    backend.release_savepoint("meta");
+    assert_eq!(Ok(Some(b"text/html".to_vec())), backend.meta(1, "mime"));
+    assert_eq!(Ok(Some(b"image/png".to_vec())), backend.meta(2, "mime"));
+
+    // Metadata is deleted with its entry:
+    assert!(backend.delete(&foo.hash));
+    assert_eq!(Ok(None), backend.meta(1, "mime"));
+    assert_eq!(Ok(None), backend.meta(1, "it's"));
+    assert_eq!(Ok(Some(b"image/png".to_vec())), backend.meta(2, "mime"));
+  }
+
+  #[test]
+  fn meta() {
+    check_meta(MemoryBackend::new(String::new()));
+    check_meta(SqliteBackend::open(":memory:".to_string(), &IndexConfig::new()).unwrap());
+    check_meta(SqliteBackend::open(":memory:".to_string(), &encrypted_config(b"secret")).unwrap());
+  }
+
+  fn check_ref_owner<B: HashBackend>(mut backend: B) {
+    let (foo, bar) = (entry(b"foo"), entry(b"bar"));
+    let moved = BlobRef{name: b"moved".to_vec(), offset: 0, length: 3, kind: RefKind::Unknown};
+    let other = HashEntry{persistent_ref: Some(b"other".to_vec()), ..bar.clone()};
+    backend.insert_batch(vec!((1, foo.clone()), (2, other)));
+    assert_eq!(Ok(()), backend.commit_txn());
+
+    assert_eq!(Ok(Some(foo.hash.clone())), backend.ref_owner(&bar.hash, b"ref"));
+    assert_eq!(Ok(None), backend.ref_owner(&foo.hash, b"ref"));
+    assert_eq!(Ok(None), backend.ref_owner(&bar.hash, b"unused"));
+
+    // A relocated entry is found by its new reference only:
+    assert!(backend.relocate(&foo.hash, &moved));
+    assert_eq!(Ok(None), backend.ref_owner(&bar.hash, b"ref"));
+    assert_eq!(Ok(Some(foo.hash)), backend.ref_owner(&bar.hash, &moved.to_bytes()[..]));
+  }
+
+  #[test]
+  fn ref_owner() {
+    let strict = |config: IndexConfig| IndexConfig{strict_refs: true, ..config};
+    check_ref_owner(MemoryBackend::new(String::new()));
+    check_ref_owner(
+      SqliteBackend::open(":memory:".to_string(), &strict(IndexConfig::new())).unwrap());
+    check_ref_owner(
+      SqliteBackend::open(":memory:".to_string(), &strict(encrypted_config(b"secret"))).unwrap());
+  }
+
+  #[test]
+  fn encrypted_refs_are_found_by_digest() {
+    let path = env::temp_dir().join("hat_encrypted_refs_are_found_by_digest.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+    let (foo, bar) = (entry(b"foo"), entry(b"bar"));
+
+    // Rows written before the index was encrypted get their digest when it is opened with a key:
+    {
+      let mut backend = SqliteBackend::open(path_str.clone(), &IndexConfig::new()).unwrap();
+      backend.insert_batch(vec!((1, foo.clone())));
+      assert_eq!(Ok(()), backend.commit_txn());
+    }
+    let config = IndexConfig{strict_refs: true, ..encrypted_config(b"secret")};
+    let mut backend = SqliteBackend::open(path_str.clone(), &config).unwrap();
+    backend.insert_batch(vec!((2, bar.clone())));
+    assert_eq!(Ok(()), backend.commit_txn());
+    assert_eq!(Ok(Some(foo.hash.clone())), backend.ref_owner(&bar.hash, b"ref"));
+    assert_eq!(Ok(Some(bar.hash.clone())), backend.ref_owner(&foo.hash, b"ref"));
+
+    // The digest is the same for equal references, but does not reveal them:
+    {
+      let mut row = backend.select1_or_die(
+        "SELECT COUNT(DISTINCT ref_digest), MIN(ref_digest) FROM hash_index").unwrap();
+      assert_eq!(1, row.get_i64(0));
+      assert!(row.get_blob(1) != Some(&b"ref"[..]));
+    }
+    // The lookup is answered by the index, without decrypting any row:
+    {
+      let digest = backend.cipher.as_ref().unwrap().digest(b"ref");
+      let mut plan = backend.select1_or_die(&format!(
+        "EXPLAIN QUERY PLAN SELECT hash FROM hash_index
+         WHERE namespace='' AND ref_digest=x'{}' AND hash!=x'00' LIMIT 1",
+        digest.to_hex())).unwrap();
+      assert!(plan.get_text(3).unwrap_or("").contains("HashIndex_RefDigest"));
+    }
+
+    drop(backend);
+    fs::remove_file(&path).unwrap();
+  }
+
+  fn check_delete_id_range<B: HashBackend>(mut backend: B) {
+    let entries: Vec<HashEntry> = (0..5).map(|i| entry(format!("e{}", i).as_bytes())).collect();
+    backend.insert_batch(entries.iter().cloned().enumerate().map(|(i, e)| (i as i64 + 1, e))
+                                .collect());
+    backend.set_meta(2, "mime", b"text/plain");
+    assert_eq!(Ok(()), backend.commit_txn());
+
+    let deleted = backend.delete_id_range(2, 4).unwrap();
+    assert_eq!(vec!((entries[1].hash.clone(), entries[1].persistent_ref.clone().unwrap()),
+                    (entries[2].hash.clone(), entries[2].persistent_ref.clone().unwrap())),
+               deleted);
+    assert_eq!(Ok(None), backend.meta(2, "mime"));
+    for (i, e) in entries.iter().enumerate() {
+      assert_eq!(i == 1 || i == 2, backend.locate(&e.hash).unwrap().is_none());
+    }
+    assert_eq!(0, backend.delete_id_range(2, 4).unwrap().len());
+  }
+
+  #[test]
+  fn delete_id_range() {
+    check_delete_id_range(MemoryBackend::new(String::new()));
+    check_delete_id_range(
+      SqliteBackend::open(":memory:".to_string(), &IndexConfig::new()).unwrap());
+    check_delete_id_range(
+      SqliteBackend::open(":memory:".to_string(), &encrypted_config(b"secret")).unwrap());
+  }
+
+  #[test]
+  fn schema_info() {
+    let mut backend = SqliteBackend::open(":memory:".to_string(), &IndexConfig::new()).unwrap();
+    let (version, columns, features) = backend.schema_info();
+    assert_eq!(SCHEMA_VERSION, version);
+    assert!(columns.iter().any(|c| &c[..] == "namespace"));
+    assert!(columns.iter().any(|c| &c[..] == "content_len"));
+    assert_eq!(vec!("metadata".to_string(), "spilled_payloads".to_string()), features);
+
+    let config = IndexConfig{audit: true, strict_refs: true, ..encrypted_config(b"secret")};
+    let mut backend = SqliteBackend::open(":memory:".to_string(), &config).unwrap();
+    let (_, _, features) = backend.schema_info();
+    assert_eq!(vec!("encrypted", "audit", "metadata", "spilled_payloads", "strict_refs"),
+               features.iter().map(|f| &f[..]).collect::<Vec<&str>>());
+  }
+
+  #[test]
+  fn side_tables_lose_their_rowid() {
+    let path = env::temp_dir().join("hat_side_tables_lose_their_rowid.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+    let tables = ["hash_payload_chunks", "hash_index_meta", "hash_meta"];
+
+    {
+      let mut backend = SqliteBackend::open(path_str.clone(), &IndexConfig::new()).unwrap();
+      backend.insert_batch(vec!((1, entry(b"foo"))));
+      backend.set_meta(1, "mime", b"text/plain");
+      for table in tables.iter() {
+        backend.restore_rowid(table);
+        assert!(backend.has_rowid(table));
+      }
+      assert_eq!(Ok(()), backend.commit_txn());
+    }
+
+    // Opening rebuilds them, with their rows:
+    let mut backend = SqliteBackend::open(path_str.clone(), &IndexConfig::new()).unwrap();
+    for table in tables.iter() {
+      assert!(!backend.has_rowid(table), "{}", table);
+    }
+    assert_eq!(Ok(Some(b"text/plain".to_vec())), backend.meta(1, "mime"));
+    assert_eq!(Some(1), backend.locate(&entry(b"foo").hash).unwrap().map(|(id, _)| id));
+
+    drop(backend);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn side_tables_have_no_rowid() {
+    let backend = SqliteBackend::open(":memory:".to_string(), &IndexConfig::new()).unwrap();
+    for table in ["hash_payload_chunks", "hash_index_meta", "hash_meta"].iter() {
+      let sql = backend.select1_or_die(&format!(
+        "SELECT sql FROM sqlite_master WHERE type='table' AND name='{}'", table))
+        .map(|mut row| row.get_text(0).unwrap_or("").to_string()).unwrap_or(String::new());
+      assert!(sql.ends_with("WITHOUT ROWID"), "{}: {}", table, sql);
+    }
+    let sql = backend.select1_or_die("SELECT sql FROM sqlite_master WHERE name='hash_index'")
+      .map(|mut row| row.get_text(0).unwrap_or("").to_string()).unwrap_or(String::new());
+    assert!(!sql.contains("WITHOUT ROWID"));
+  }
+}
@@ -0,0 +1,173 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The bytes of a `Hash`, stored inline rather than on the heap.
+//!
+//! Digests have a small, fixed width, so allocating each of them separately is mostly overhead
+//! for the allocator. Byte strings up to `INLINE_BYTES` long (which includes the digests of all
+//! builds) are kept inline. Longer ones are not valid digests, but they can still arrive in
+//! messages and must be representable to be rejected, so they are kept on the heap.
+//!
+//! `HashBytes` dereferences to `[u8]`, and compares, orders and hashes like it.
+
+use std::borrow::{Borrow};
+use std::cmp::{Ordering};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Deref};
+
+
+/// The widest byte string that is stored inline.
+pub const INLINE_BYTES: usize = 64;
+
+
+enum Repr {
+  Inline(u8, [u8; INLINE_BYTES]),
+  Heap(Vec<u8>),
+}
+
+pub struct HashBytes {
+  repr: Repr,
+}
+
+
+impl HashBytes {
+
+  pub fn new(bytes: &[u8]) -> HashBytes {
+    if bytes.len() > INLINE_BYTES {
+      return HashBytes{repr: Repr::Heap(bytes.to_vec())};
+    }
+    let mut inline = [0u8; INLINE_BYTES];
+    for (i, b) in inline.iter_mut().zip(bytes.iter()) {
+      *i = *b;
+    }
+    HashBytes{repr: Repr::Inline(bytes.len() as u8, inline)}
+  }
+
+  /// Like `new`, but keeps the allocation of `bytes` if they are too long to be stored inline.
+  pub fn from_vec(bytes: Vec<u8>) -> HashBytes {
+    if bytes.len() > INLINE_BYTES {
+      HashBytes{repr: Repr::Heap(bytes)}
+    } else {
+      HashBytes::new(&bytes[..])
+    }
+  }
+
+  /// Whether the bytes are stored inline (rather than on the heap).
+  pub fn is_inline(&self) -> bool {
+    match self.repr {
+      Repr::Inline(..) => true,
+      Repr::Heap(_) => false,
+    }
+  }
+
+}
+
+impl Deref for HashBytes {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    match self.repr {
+      Repr::Inline(len, ref inline) => &inline[..len as usize],
+      Repr::Heap(ref bytes) => &bytes[..],
+    }
+  }
+}
+
+impl Borrow<[u8]> for HashBytes {
+  fn borrow(&self) -> &[u8] {
+    &self[..]
+  }
+}
+
+impl Clone for HashBytes {
+  fn clone(&self) -> HashBytes {
+    match self.repr {
+      Repr::Inline(len, inline) => HashBytes{repr: Repr::Inline(len, inline)},
+      Repr::Heap(ref bytes) => HashBytes{repr: Repr::Heap(bytes.clone())},
+    }
+  }
+}
+
+impl PartialEq for HashBytes {
+  fn eq(&self, other: &HashBytes) -> bool {
+    self[..] == other[..]
+  }
+}
+
+impl Eq for HashBytes {}
+
+impl PartialOrd for HashBytes {
+  fn partial_cmp(&self, other: &HashBytes) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for HashBytes {
+  fn cmp(&self, other: &HashBytes) -> Ordering {
+    self[..].cmp(&other[..])
+  }
+}
+
+impl Hash for HashBytes {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self[..].hash(state)
+  }
+}
+
+impl fmt::Debug for HashBytes {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    fmt::Debug::fmt(&self[..], f)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn short_bytes_are_inline() {
+    let bytes = HashBytes::new(b"foo");
+    assert!(bytes.is_inline());
+    assert_eq!(b"foo", &bytes[..]);
+    assert_eq!(HashBytes::from_vec(b"foo".to_vec()), bytes);
+
+    let empty = HashBytes::new(b"");
+    assert!(empty.is_inline());
+    assert_eq!(0, empty.len());
+
+    let widest = vec![7u8; INLINE_BYTES];
+    assert!(HashBytes::new(&widest[..]).is_inline());
+  }
+
+  #[test]
+  fn long_bytes_are_kept() {
+    let long = vec![7u8; INLINE_BYTES + 1];
+    let bytes = HashBytes::from_vec(long.clone());
+    assert!(!bytes.is_inline());
+    assert_eq!(long, bytes.to_vec());
+    assert_eq!(HashBytes::new(&long[..]), bytes);
+  }
+
+  #[test]
+  fn compares_like_slices() {
+    let foo = HashBytes::new(b"foo");
+    let long = HashBytes::new(&vec![b'f'; INLINE_BYTES + 1][..]);
+    assert!(HashBytes::new(b"bar") < foo);
+    assert!(HashBytes::new(b"fo") < foo);
+    assert!(foo < long);
+    assert_eq!(format!("{:?}", b"foo".to_vec()), format!("{:?}", foo));
+  }
+}
@@ -14,46 +14,153 @@
 
 //! Local state for known hashes and their external location (blob reference).
 
+use std::cell::{RefCell};
+use std::cmp;
+use std::collections::{btree_map, BTreeMap, BTreeSet};
+use std::fmt;
+use std::i64;
+use std::io::{self, Read};
+use std::mem;
+use std::rc::{Rc};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{self, AtomicBool};
+use std::thread;
 use std::thunk::Thunk;
 use std::time::duration::{Duration};
 use rustc_serialize::hex::{ToHex};
+use time::{SteadyTime};
 
-use callback_container::{CallbackContainer};
-use cumulative_counter::{CumulativeCounter};
+use audit::{AuditEvent, AuditOp};
+use blob_store::{BlobID};
+use bloom::{BloomFilter};
+use digest::{self, DIGEST_BYTES};
+use hash_bytes::{HashBytes};
+use callback_container::{CallbackContainer, CallbackToken};
+use cumulative_counter::{self, CumulativeCounter};
+use histogram::{Histogram};
 use unique_priority_queue::{UniquePriorityQueue};
 use process::{Process, MsgHandler};
-
-use sqlite3::database::{Database};
-use sqlite3::cursor::{Cursor};
-use sqlite3::types::ResultCode::{SQLITE_DONE, SQLITE_OK, SQLITE_ROW};
-use sqlite3::BindArg::{Integer64, Blob};
-use sqlite3::{open};
+use hash_backend::{HashBackend, MemoryBackend, SqliteBackend};
+use hash_payload::{decode_children};
+use trace::{Trace, TraceEvent, TraceKind, TraceLimits};
 
 use periodic_timer::{PeriodicTimer};
 
-use sodiumoxide::crypto::hash::{sha512};
-
 
 pub type HashIndexProcess = Process<Msg, Reply>;
 
+/// Maximum number of entries in each batch of a streaming enumeration.
+pub const STREAM_BATCH_SIZE: usize = 1024;
+
+/// The queue rank of prioritized entries, which puts them ahead of all other entries (see
+/// `QueuePriority`).
+const PRIORITIZED_RANK: i64 = i64::MIN;
+
+/// The queue rank of uncommitted entries, which puts them behind all other entries (see
+/// `Msg::Uncommit`).
+const UNCOMMITTED_RANK: i64 = i64::MAX;
+
+/// How often an idle index checks whether a debounced flush is due, in milliseconds.
+const IDLE_TICK_MS: u32 = 10;
+
 
 /// A wrapper around Hash digests.
+///
+/// The bytes are stored inline (see `HashBytes`), so creating and cloning a `Hash` does not
+/// allocate. They dereference to `[u8]`, and `to_vec` copies them out where a `Vec` is needed.
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub struct Hash{
-  pub bytes: Vec<u8>,
+  pub bytes: HashBytes,
 }
 
 impl Hash {
   /// Computes `hash(text)` and stores this digest as the `bytes` field in a new `Hash` structure.
+  /// The digest depends on the build (see the `digest` module).
   pub fn new(text: &[u8]) -> Hash {
-    let sha512::Digest(digest_bytes) = sha512::hash(text);
-    Hash{bytes: digest_bytes[0 .. sha512::HASHBYTES].iter().map(|&x| x).collect()}
+    Hash{bytes: HashBytes::from_vec(digest::digest(text))}
+  }
+
+  /// Computes the digest of everything read from `reader`, like `Hash::new` does for a slice,
+  /// without buffering all of it in memory.
+  pub fn from_reader<R: Read>(reader: &mut R) -> io::Result<Hash> {
+    let mut state = digest::State::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+      match reader.read(&mut buf) {
+        Ok(0) => break,
+        Ok(n) => state.update(&buf[..n]),
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(Hash{bytes: HashBytes::from_vec(state.finalize())})
+  }
+
+  /// Computes `hash(tag || text)`, so that equal `text`s with different `tag`s (e.g. a leaf and a
+  /// branch, using the level as tag) get different digests.
+  ///
+  /// The tagged and untagged digests of the same text differ, so content that was indexed with
+  /// `new` is not deduplicated against the same content hashed with `tagged`, and vice versa. An
+  /// existing index should keep using one of the two consistently.
+  pub fn tagged(text: &[u8], tag: u8) -> Hash {
+    let mut tagged_text = Vec::with_capacity(text.len() + 1);
+    tagged_text.push(tag);
+    tagged_text.extend(text.iter().map(|&x| x));
+    Hash::new(&tagged_text[..])
+  }
+
+  /// Checks that this is a plausible digest of width `digest_width`.
+  ///
+  /// The all-zero digest is rejected as well: a real digest is practically never all-zero, while
+  /// a zeroed buffer is a typical result of a bug (or a sentinel value), and accepting it would
+  /// silently deduplicate unrelated data against it.
+  pub fn validate(&self, digest_width: usize) -> Result<(), HashError> {
+    if self.bytes.len() != digest_width {
+      return Err(HashError::WrongWidth{expected: digest_width, found: self.bytes.len()});
+    }
+    if self.bytes.iter().all(|&b| b == 0) {
+      return Err(HashError::AllZero);
+    }
+    Ok(())
   }
 }
 
+/// Reasons for rejecting a `Hash`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HashError {
+  WrongWidth{expected: usize, found: usize},
+  AllZero,
+  /// A hash prefix that is not made of hex digits (see `Msg::ResolvePrefix`).
+  NotHex,
+}
+
+/// Reasons for not walking a hash tree to its leaves (see `Msg::LeavesOf`). Each names the `Hash`
+/// of the offending node.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TreeError {
+  /// The node is neither committed nor reserved.
+  UnknownHash(Hash),
+
+  /// The node is a leaf that is reserved, but not yet committed.
+  NotCommitted(Hash),
+
+  /// The node is a branch whose payload does not decode to a list of children.
+  InvalidPayload(Hash),
+
+  /// The node is a leaf whose persistent reference is not a structured `BlobRef`.
+  InvalidRef(Hash),
+
+  /// The persistent reference of the node is of the wrong `RefKind`: a leaf that points at a branch
+  /// node, or a branch that points at leaf data. References of unknown kind are not checked.
+  WrongRefKind(Hash),
+
+  /// The tree below this root has more nodes than `IndexConfig::max_tree_nodes`.
+  TooLarge(Hash),
+}
+
 
 /// An entry that can be inserted into the hash index.
-#[derive(Clone)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct HashEntry{
 
   /// The hash of this entry (unique among all entries in the index).
@@ -70,365 +177,6122 @@ pub struct HashEntry{
   /// A reference to a location in the external persistent storage (a blob reference) that contains
   /// the data for this entry (e.g. an object-name and a byte range).
   pub persistent_ref: Option<Vec<u8>>,
+
+  /// The length in bytes of the content of this entry, or `0` if it is not known (as for entries
+  /// committed by older versions).
+  pub content_len: u64,
 }
 
-pub enum Msg {
-  /// Check whether this `Hash` already exists in the system.
-  /// Returns `HashKnown` or `HashNotKnown`.
-  HashExists(Hash),
+/// What a `BlobRef` points at, so that a walk of a hash tree cannot mistake user data for a
+/// branch node or the other way around (see `Msg::LeavesOf`). The key store writes the references
+/// of its hash trees with the kind that matches the level of each node; other writers must set it
+/// themselves.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RefKind {
+  /// Written without a kind, e.g. by the blob store itself or before kinds were recorded.
+  Unknown,
+  /// The data of a leaf.
+  Leaf,
+  /// The serialized node of a branch.
+  Branch,
+}
 
-  /// Locate the local payload of the `Hash`. This is currently not used.
-  /// Returns `Payload` or `HashNotKnown`.
-  FetchPayload(Hash),
+impl RefKind {
+  /// The code of the kind in an encoded `BlobRef`.
+  pub fn code(&self) -> u8 {
+    match *self {
+      RefKind::Unknown => 0,
+      RefKind::Leaf => 1,
+      RefKind::Branch => 2,
+    }
+  }
 
-  /// Locate the persistent reference (external blob reference) for this `Hash`.
-  /// Returns `PersistentRef` or `HashNotKnown`.
-  FetchPersistentRef(Hash),
+  pub fn from_code(code: u8) -> Option<RefKind> {
+    match code {
+      0 => Some(RefKind::Unknown),
+      1 => Some(RefKind::Leaf),
+      2 => Some(RefKind::Branch),
+      _ => None,
+    }
+  }
+}
 
-  /// Reserve a `Hash` in the index, while sending its content to external storage.
-  /// This is used to ensure that each `Hash` is stored only once.
-  /// Returns `ReserveOK` or `HashKnown`.
-  Reserve(HashEntry),
+/// A structured persistent reference: a byte range inside an object in external storage.
+///
+/// References are copied between machines (see `merge_from`), so `to_bytes` writes a fixed,
+/// architecture independent layout: the byte `BLOB_REF_V2` and the code of the kind (or only the
+/// byte `BLOB_REF_V1` if the kind is unknown), the length of the name as a big-endian `u32`, the
+/// name, and then the offset and length as big-endian `u64`s.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlobRef {
+  pub name: Vec<u8>,
+  pub offset: u64,
+  pub length: u64,
+  pub kind: RefKind,
+}
 
-  /// Update the info for a reserved `Hash`. The `Hash` remains reserved. This is used to update
-  /// the persistent reference (external blob reference) as soon as it is available (to allow new
-  /// references to the `Hash` to be created before it is committed).
-  /// Returns ReserveOK.
-  UpdateReserved(HashEntry),
+/// The first byte of an encoded `BlobRef` without a kind. The references written by the blob
+/// store are JSON and start with `{` instead.
+pub const BLOB_REF_V1: u8 = 1;
 
-  /// A `Hash` is committed when it has been `finalized` in the external storage. `Commit` includes
-  /// the persistent reference that the content is available at.
-  /// Returns CommitOK.
-  Commit(Hash, Vec<u8>),
+/// The first byte of an encoded `BlobRef` with a kind.
+pub const BLOB_REF_V2: u8 = 2;
 
-  /// Install a "on-commit" handler to be called after `Hash` is committed.
-  /// Returns `CallbackRegistered` or `HashNotKnown`.
-  CallAfterHashIsComitted(Hash, Thunk<'static>),
+fn be_u64(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64)
+}
 
-  /// Flush the hash index to clear internal buffers and commit the underlying database.
-  Flush,
+impl BlobRef {
+
+  /// Decode a persistent reference, as written by `to_bytes` or by the blob store.
+  /// Returns `None` if `bytes` is not a structured reference.
+  pub fn from_bytes(bytes: &[u8]) -> Option<BlobRef> {
+    let (kind, bytes) = match bytes.first() {
+      Some(&BLOB_REF_V1) => (RefKind::Unknown, &bytes[1..]),
+      Some(&BLOB_REF_V2) if bytes.len() > 1 => match RefKind::from_code(bytes[1]) {
+        Some(kind) => (kind, &bytes[2..]),
+        None => return None,
+      },
+      _ => {
+        let id_opt = BlobID::try_from_bytes(bytes);
+        return id_opt.map(|id| BlobRef::from_blob_id(&id, RefKind::Unknown));
+      },
+    };
+    if bytes.len() < 4 {
+      return None;
+    }
+    let name_len = be_u64(&bytes[..4]) as usize;
+    if bytes.len() != 4 + name_len + 16 {
+      return None;
+    }
+    let (name, rest) = bytes[4..].split_at(name_len);
+    Some(BlobRef{name: name.to_vec(), offset: be_u64(&rest[..8]), length: be_u64(&rest[8..]),
+                 kind: kind})
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(6 + self.name.len() + 16);
+    match self.kind {
+      RefKind::Unknown => bytes.push(BLOB_REF_V1),
+      kind => { bytes.push(BLOB_REF_V2); bytes.push(kind.code()); },
+    }
+    let name_len = self.name.len() as u32;
+    for i in 0..4 {
+      bytes.push((name_len >> (24 - 8 * i)) as u8);
+    }
+    bytes.extend(self.name.iter().cloned());
+    for &x in [self.offset, self.length].iter() {
+      for i in 0..8 {
+        bytes.push((x >> (56 - 8 * i)) as u8);
+      }
+    }
+    bytes
+  }
+
+  /// The reference to the byte range of a blob store identifier, pointing at a `kind` of data.
+  pub fn from_blob_id(id: &BlobID, kind: RefKind) -> BlobRef {
+    BlobRef{name: id.name.clone(),
+            offset: id.begin as u64,
+            length: (id.end - id.begin) as u64,
+            kind: kind}
+  }
+
+  /// The blob store identifier of the same byte range.
+  pub fn blob_id(&self) -> BlobID {
+    BlobID{name: self.name.clone(),
+           begin: self.offset as usize,
+           end: (self.offset + self.length) as usize}
+  }
 }
 
-pub enum Reply {
-  HashKnown,
-  HashNotKnown,
-  Entry(HashEntry),
+/// Identifies a request for tracing, e.g. to correlate a `Reserve` with its `Commit` (see
+/// `Msg::Tagged`). The index does not interpret it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RequestId(pub u64);
 
-  Payload(Option<Vec<u8>>),
-  PersistentRef(Vec<u8>),
+/// The position of a streaming enumeration, for continuing it with `Msg::AllHashesFrom`.
+///
+/// Committed entries are never renumbered and new entries always get larger ids, so a token stays
+/// valid across flushes and restarts of the index. The token is the last id that was read, though,
+/// and entries are not always inserted in id order: prioritized entries (see
+/// `Msg::ReservePrioritized`) and entries queued by `QueueOrder::BranchesFirst` may be inserted
+/// after entries with larger ids. An entry that is committed while an enumeration is under way
+/// can thus land behind its token and be skipped. Only an enumeration of an index that does not
+/// change until it completes is sure to see every entry.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ResumeToken {
+  last_id: i64,
+}
 
-  ReserveOK,
-  CommitOK,
-  CallbackRegistered,
+impl ResumeToken {
 
-  Retry,
+  /// A token that starts the enumeration from the beginning.
+  pub fn start() -> ResumeToken {
+    ResumeToken{last_id: 0}
+  }
+
+  /// Encode the token, e.g. for storing a checkpoint.
+  pub fn as_bytes(&self) -> Vec<u8> {
+    (0..8).map(|i| (self.last_id >> (8 * (7 - i))) as u8).collect()
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Option<ResumeToken> {
+    if bytes.len() != 8 {
+      return None;
+    }
+    Some(ResumeToken{last_id: bytes.iter().fold(0, |id, &b| (id << 8) | b as i64)})
+  }
 }
 
+/// The outcome of `HashIndex::merge_from`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MergeReport {
+  /// Entries that were copied from the other index.
+  pub added: u64,
 
-#[derive(Clone)]
-struct QueueEntry {
-  id: i64,
-  level: i64,
-  payload: Option<Vec<u8>>,
-  persistent_ref: Option<Vec<u8>>,
+  /// Entries that were already known with the same persistent reference.
+  pub duplicates: u64,
+
+  /// Entries that were already known with a different persistent reference. The known reference
+  /// is kept.
+  pub conflicts: u64,
 }
 
-pub struct HashIndex {
-  dbh: Database,
+/// The outcome of the warm-up when opening a `HashIndex` (see `IndexConfig::warm_up`).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WarmUpReport {
+  /// Committed entries whose hashes were loaded.
+  pub entries: u64,
 
-  id_counter: CumulativeCounter,
+  /// How long the warm-up took.
+  pub elapsed: Duration,
+}
 
-  queue: UniquePriorityQueue<i64, Vec<u8>, QueueEntry>,
+/// What `Msg::Rescan` found and corrected in the state that the index derives from the backend.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RescanReport {
+  /// Committed entries that were scanned.
+  pub entries: u64,
 
-  callbacks: CallbackContainer<Vec<u8>>,
+  /// How far the id counter was raised to get past the largest id in use, committed or queued
+  /// (`0` if it already was).
+  pub ids_skipped: i64,
 
-  flush_timer: PeriodicTimer,
+  /// Committed hashes that were missing from the bloom filter, which is rebuilt (always `0` if the
+  /// index was not warmed up, and has no bloom filter).
+  pub missing_from_bloom: u64,
+}
+
+/// Errors that are reported back to the caller instead of taking down the index.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HashIndexError {
+  /// The database stayed locked by another connection (`SQLITE_BUSY`) after all retries.
+  Busy,
+
+  /// The disk filled up while committing (`SQLITE_FULL`). The transaction is kept open, so the
+  /// flush can be retried after freeing space.
+  DiskFull,
+
+  /// The index is encrypted with another key than the configured one (or no key was configured).
+  WrongKey,
+
+  /// The index holds hashes of `found` bytes, but is opened for hashes of `expected` bytes (see
+  /// `IndexConfig::digest_width`). Lookups would never match, so the index is not opened.
+  DigestWidthMismatch{expected: usize, found: usize},
+
+  /// No savepoint with this name is open (see `Msg::Savepoint`).
+  UnknownSavepoint(String),
+
+  /// The unique index on hashes could not be recreated after a bulk load, as this hash was
+  /// inserted more than once (see `HashIndex::end_bulk_load`).
+  DuplicateHash(Hash),
+
+  /// A query failed in sqlite, with the error code and message. Unlike a query without results,
+  /// this says nothing about whether the entries exist.
+  QueryFailed(String),
+
+  /// The file is some other sqlite database (its `PRAGMA application_id` is not the one set when
+  /// creating an index), so it is not opened, and nothing is written to it. The source of
+  /// `HashIndex::merge_from` is also refused if it is missing or in an older schema.
+  NotAHashIndex,
+
+  /// A row could not be decoded: it is encrypted, but does not decrypt with the key that opened
+  /// the index, so it was damaged or tampered with. The message says which row.
+  CorruptRow(String),
 
+  /// All ids up to `cumulative_counter::MAX_VALUE` have been used, so no entries can be added.
+  IdsExhausted,
 }
 
-impl HashIndex {
+/// What to do when registering a callback would exceed `IndexConfig::max_callbacks`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CallbackLimitPolicy {
+  /// Refuse the new callback with `Reply::CallbackLimitReached`.
+  Reject,
 
-  pub fn new(path: String) -> HashIndex {
-    let mut hi = match open(&path) {
-      Ok(dbh) => {
-        HashIndex{dbh: dbh,
-                  id_counter: CumulativeCounter::new(0),
-                  queue: UniquePriorityQueue::new(),
-                  callbacks: CallbackContainer::new(),
-                  flush_timer: PeriodicTimer::new(Duration::seconds(10)),
-        }
-      },
-      Err(err) => panic!("{:?}", err),
-    };
-    hi.exec_or_die("CREATE TABLE IF NOT EXISTS
-                  hash_index (id        INTEGER PRIMARY KEY,
-                              hash      BLOB,
-                              height    INTEGER,
-                              payload   BLOB,
-                              blob_ref  BLOB)");
+  /// Drop the oldest pending callbacks (without calling them) to make room for the new one.
+  EvictOldest,
+}
 
-    hi.exec_or_die("CREATE UNIQUE INDEX IF NOT EXISTS
-                  HashIndex_UniqueHash
-                  ON hash_index(hash)");
+/// How the index starts its transactions (see `IndexConfig::transaction_mode`).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransactionMode {
+  /// `BEGIN`: take locks only when first reading or writing. Other connections can write to the
+  /// file in between, but two connections that both read before writing can block each other
+  /// until one of them gives up (`HashIndexError::Busy`).
+  Deferred,
 
-    hi.exec_or_die("BEGIN");
+  /// `BEGIN IMMEDIATE`: take the write lock when starting the transaction, so it cannot end up
+  /// waiting for another writer halfway through. The index keeps a transaction open at all times,
+  /// so this holds the write lock for as long as the index is open, except between commits.
+  Immediate,
+}
 
-    hi.refresh_id_counter();
-    hi
-  }
+/// How hard sqlite works to make commits durable (see `IndexConfig::synchronous`). Levels that
+/// sync less commit faster, but may lose recent commits on power loss (not on a crash of the
+/// process, as the operating system still writes the data).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SyncMode {
+  /// `PRAGMA synchronous=OFF`: never wait for the disk. Power loss may lose recent commits, and
+  /// may also corrupt the index file. Only use this for an index that can be rebuilt.
+  Off,
 
-  #[cfg(test)]
-  pub fn new_for_testing() -> HashIndex {
-    HashIndex::new(":memory:".to_string())
-  }
+  /// `PRAGMA synchronous=NORMAL`: wait for the disk less often. In WAL mode, power loss may lose
+  /// the most recent commits, but never corrupts the index file, so the lost work is redone when
+  /// the backup runs again. Without WAL, there is a small chance of corruption.
+  Normal,
+
+  /// `PRAGMA synchronous=FULL`: wait for the disk on every commit, so that committed data
+  /// survives power loss.
+  Full,
+}
 
-  fn exec_or_die(&mut self, sql: &str) {
-    match self.dbh.exec(sql) {
-      Ok(true) => (),
-      Ok(false) => panic!("exec: {}", self.dbh.get_errmsg()),
-      Err(msg) => panic!("exec: {:?}, {:?}\nIn sql: '{}'\n",
-                         msg, self.dbh.get_errmsg(), sql)
+impl SyncMode {
+  /// The value of `PRAGMA synchronous` for this level.
+  pub fn pragma_value(&self) -> i64 {
+    match *self {
+      SyncMode::Off => 0,
+      SyncMode::Normal => 1,
+      SyncMode::Full => 2,
     }
   }
+}
+
+/// The order in which committed entries are inserted from the queue (see
+/// `IndexConfig::queue_order`).
+///
+/// Whatever the order, an entry is only inserted once all entries ahead of it in that order are
+/// committed, so that the inserted entries always form a complete prefix of the queue. In reserve
+/// order, that prefix includes the children of every inserted branch, as a tree is reserved
+/// bottom-up. In other orders it may not: a branch can be inserted while children that were
+/// reserved before it are still queued, so after a crash the index may know a branch without
+/// knowing all of its children.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum QueueOrder {
+  /// In reserve order (by id).
+  Fifo,
+
+  /// Higher levels first, and in reserve order within a level, so that branches are inserted as
+  /// soon as they are committed instead of waiting for all leaves reserved before them.
+  BranchesFirst,
+}
 
-  fn prepare_or_die<'a>(&'a self, sql: &str) -> Cursor<'a> {
-    match self.dbh.prepare(sql, &None) {
-      Ok(s)  => s,
-      Err(x) => panic!("sqlite error: {} ({:?})",
-                       self.dbh.get_errmsg(), x),
+impl QueueOrder {
+  /// The queue rank of an entry at `level`. Lower ranks are inserted first.
+  fn rank(&self, level: i64) -> i64 {
+    match *self {
+      QueueOrder::Fifo => 0,
+      QueueOrder::BranchesFirst => -level,
     }
   }
+}
+
+/// Key material for encrypting payloads and persistent references at rest.
+#[derive(Clone)]
+pub struct EncryptionKey(pub Vec<u8>);
 
-  fn select1<'a>(&'a mut self, sql: &str) -> Option<Cursor<'a>> {
-    let mut cursor = self.prepare_or_die(sql);
-    if cursor.step() == SQLITE_ROW { Some(cursor) } else { None }
+impl fmt::Debug for EncryptionKey {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    // Never print the key material:
+    write!(f, "EncryptionKey(..)")
   }
+}
 
-  fn index_locate(&mut self, hash: &Hash) -> Option<QueueEntry> {
-    assert!(hash.bytes.len() > 0);
+/// Stops the enumeration that an index is running, from any other thread (see
+/// `IndexConfig::interrupt`). Messages are handled one at a time, so an enumeration cannot be
+/// stopped by a message. Clones share the same flag.
+#[derive(Clone)]
+pub struct Interrupt {
+  flag: Arc<AtomicBool>,
+}
 
-    let result_opt = self.select1(&format!(
-      "SELECT id, height, payload, blob_ref FROM hash_index WHERE hash=x'{}'",
-      hash.bytes.to_hex()
-    ));
-    result_opt.map(|result| {
-      let mut result = result;
-      let id = result.get_int(0) as i64;
-      let level = result.get_int(1) as i64;
-      let payload: Vec<u8> = result.get_blob(2).unwrap_or(&[]).iter().map(|&x| x).collect();
-      let persistent_ref: Vec<u8> = result.get_blob(3).unwrap_or(&[]).iter().map(|&x| x).collect();
-      QueueEntry{id: id, level: level,
-                 payload: if payload.len() == 0 { None }
-                          else {Some(payload) },
-                 persistent_ref: Some(persistent_ref)
-      } })
+impl Interrupt {
+  pub fn new() -> Interrupt {
+    Interrupt{flag: Arc::new(AtomicBool::new(false))}
   }
 
-  fn locate(&mut self, hash: &Hash) -> Option<QueueEntry> {
-    let result_opt = self.queue.find_value_of_key(&hash.bytes);
-    result_opt.map(|x| x).or_else(|| self.index_locate(hash))
+  /// Stop the running enumeration after the current entry. An interrupt while no enumeration is
+  /// running is discarded when the next one starts.
+  pub fn interrupt(&self) {
+    self.flag.store(true, atomic::Ordering::SeqCst);
   }
 
-  fn refresh_id_counter(&mut self) {
-    let id = self.select1("SELECT MAX(id) FROM hash_index").expect("id").get_int(0);
-    self.id_counter = CumulativeCounter::new(id as i64);
+  pub fn is_set(&self) -> bool {
+    self.flag.load(atomic::Ordering::SeqCst)
   }
 
-  fn next_id(&mut self) -> i64 {
-    self.id_counter.next()
+  /// Clear the flag. Returns whether it was set.
+  pub fn take(&self) -> bool {
+    self.flag.swap(false, atomic::Ordering::SeqCst)
   }
+}
 
-  fn reserve(&mut self, hash_entry: HashEntry) -> i64 {
-    self.maybe_flush();
-
-    let HashEntry{hash, level, payload, persistent_ref} = hash_entry;
-    assert!(hash.bytes.len() > 0);
+impl fmt::Debug for Interrupt {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Interrupt({})", self.is_set())
+  }
+}
 
-    let my_id = self.next_id();
+/// Receives every entry written to the backend, in the order of insertion (see
+/// `IndexConfig::replication`). Clones share the same sink.
+#[derive(Clone)]
+pub struct ReplicationSink {
+  sink: Arc<Box<Fn(&HashEntry) + Send + Sync>>,
+}
 
-    assert!(self.queue.reserve_priority(my_id, hash.bytes.clone()).is_ok());
-    self.queue.put_value(hash.bytes,
-                         QueueEntry{id: my_id,
-                                    level: level,
-                                    payload: payload,
-                                    persistent_ref: persistent_ref
-                         });
-    my_id
+impl ReplicationSink {
+  pub fn new(sink: Box<Fn(&HashEntry) + Send + Sync>) -> ReplicationSink {
+    ReplicationSink{sink: Arc::new(sink)}
   }
 
-  fn update_reserved(&mut self, hash_entry: HashEntry) {
-    let HashEntry{hash, level, payload, persistent_ref} = hash_entry;
-    assert!(hash.bytes.len() > 0);
-    let old_entry = self.locate(&hash).expect("hash was reserved");
-
-    // If we didn't already commit and pop() the hash, update it:
-    let id_opt = self.queue.find_key(&hash.bytes).map(|id| id.clone());
-    if id_opt.is_some() {
-      assert_eq!(id_opt, Some(old_entry.id));
-      self.queue.update_value(&hash.bytes,
-                              |qe| QueueEntry{level: level,
-                                              payload: payload.clone(),
-                                              persistent_ref: persistent_ref.clone(),
-                                              ..qe.clone()});
-    }
-  }
-
-  fn register_hash_callback(&mut self, hash: &Hash, callback: Thunk<'static>) -> bool {
-    assert!(hash.bytes.len() > 0);
-
-    if self.queue.find_value_of_key(&hash.bytes).is_some() {
-      self.callbacks.add(hash.bytes.clone(), callback);
-    } else if self.locate(hash).is_some() {
-      // Hash was already committed
-      callback();
-    } else {
-      // We cannot register this callback, since the hash doesn't exist anywhere
-      return false
-    }
+  fn send(&self, entry: &HashEntry) {
+    (**self.sink)(entry)
+  }
+}
 
-    return true;
+impl fmt::Debug for ReplicationSink {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "ReplicationSink(..)")
   }
+}
 
-  fn insert_completed_in_order(&mut self) {
-    let mut insert_stm = self.dbh.prepare(
-      "INSERT INTO hash_index (id, hash, height, payload, blob_ref) VALUES (?, ?, ?, ?, ?)",
-      &None).unwrap();
+/// The effective settings of a `HashIndex`. Use `HashIndexBuilder` to change the defaults.
+#[derive(Clone, Debug)]
+pub struct IndexConfig {
+  /// How many times a commit is retried while the database is locked by another connection.
+  pub busy_retries: u32,
 
-    loop {
-      match self.queue.pop_min_if_complete() {
-        None => break,
-        Some((id, hash_bytes, queue_entry)) => {
-          assert_eq!(id, queue_entry.id);
+  /// How long to wait before the first retry of a busy commit. The delay doubles for each retry.
+  pub busy_retry_delay: Duration,
 
-          let child_refs_opt = queue_entry.payload;
-          let payload = child_refs_opt.unwrap_or_else(|| vec!());
-          let level = queue_entry.level;
-          let persistent_ref = queue_entry.persistent_ref.expect("hash was comitted");
+  /// The width in bytes of the hashes in this index. This is recorded when the index is created,
+  /// and opening it with another width fails with `HashIndexError::DigestWidthMismatch`.
+  pub digest_width: usize,
 
-          assert_eq!(SQLITE_OK, insert_stm.bind_param(1, &Integer64(id)));
-          assert_eq!(SQLITE_OK, insert_stm.bind_param(2, &Blob(hash_bytes.clone())));
-          assert_eq!(SQLITE_OK, insert_stm.bind_param(3, &Integer64(level)));
-          assert_eq!(SQLITE_OK, insert_stm.bind_param(4, &Blob(payload)));
-          assert_eq!(SQLITE_OK, insert_stm.bind_param(5, &Blob(persistent_ref)));
+  /// Payloads larger than this many bytes are not stored inline, but split into chunks of this
+  /// size in the `hash_payload_chunks` table. They are spilled as soon as their entry is reserved,
+  /// so that the queue does not hold them while the entry waits for its commit (except while a
+  /// savepoint is open, as rolling back to it would lose them).
+  pub max_inline_payload: usize,
 
-          assert_eq!(SQLITE_DONE, insert_stm.step());
+  /// Reject entries with a payload larger than this many bytes (see `Reply::PayloadTooLarge`), so
+  /// that a single malformed entry cannot exhaust memory when it is loaded.
+  pub max_payload: usize,
 
-          assert_eq!(SQLITE_OK, insert_stm.clear_bindings());
-          assert_eq!(SQLITE_OK, insert_stm.reset());
+  /// The most nodes that `Msg::TreeStats` and `Msg::LeavesOf` visit before giving up, so that a
+  /// corrupt or shared structure cannot keep the index busy indefinitely.
+  pub max_tree_nodes: u64,
 
-          self.callbacks.allow_flush_of(&hash_bytes);
-        },
-      }
-    }
-  }
+  /// The most hashes that `Msg::ResolvePrefix` returns for an ambiguous prefix. A short prefix
+  /// can match a large part of the index, so only this many matches are read.
+  pub max_prefix_matches: usize,
 
-  fn commit(&mut self, hash: &Hash, blob_ref: &Vec<u8>) {
-    // Update persistent reference for ready hash
-    let queue_entry = self.locate(hash).expect("hash was committed");
-    self.queue.update_value(&hash.bytes,
-                            |old_qe| QueueEntry{persistent_ref: Some(blob_ref.clone()),
-                                                ..old_qe.clone()});
-    self.queue.set_ready(queue_entry.id);
+  /// Refuse to commit a hash with a persistent reference that is already used by another hash,
+  /// whether that hash was inserted or is committed but still queued.
+  /// This is off by default, since several hashes may legitimately share a blob reference.
+  /// The check is an index lookup, also with an `encryption_key`: encrypted references are then
+  /// looked up by a keyed digest, which is stored next to them.
+  pub strict_refs: bool,
 
-    self.insert_completed_in_order();
+  /// When reserving a hash that is already known, check that the new entry has the same level and
+  /// payload length as the known one, to catch digest collisions (see `Reply::CollisionSuspected`).
+  /// This is off by default, since it costs a payload lookup for every known hash.
+  pub verify_collisions: bool,
 
-    self.maybe_flush();
-  }
+  /// When reserving a hash that is already known at another level, e.g. as a leaf and as a branch
+  /// of the same bytes, refuse the reserve (see `Reply::LevelMismatch`) instead of deduplicating
+  /// it to the known level. Such a reserve points to a bug in building the hash tree.
+  pub strict_levels: bool,
 
-  fn maybe_flush(&mut self) {
-    if self.flush_timer.did_fire() {
-      self.flush();
-    }
-  }
+  /// Refuse entries that do not fit a hash tree (see `Reply::InvalidEntry`): leaves must not carry
+  /// a payload, branches must carry their children as payload, and every entry is committed with a
+  /// non-empty persistent reference (to the leaf data or to the stored branch node). This is off
+  /// by default, since other users of the index may store both or neither.
+  pub strict_tree: bool,
 
-  fn flush(&mut self) {
-    // Callbacks assume their data is safe, so commit before calling them
-    self.exec_or_die("COMMIT; BEGIN");
+  /// The order in which committed entries are inserted from the queue (see `QueueOrder` for how
+  /// this affects what is inserted after a crash). Prioritized entries always come first.
+  pub queue_order: QueueOrder,
 
-    // Run ready callbacks
-    self.callbacks.flush();
+  /// Reserved entries that are not committed or updated within this time are abandoned, so that a
+  /// crashed uploader cannot block the insertion of later entries forever. `None` disables this.
+  pub reserve_ttl: Option<Duration>,
+
+  /// Encrypt the payload and persistent reference of new entries at rest. Hashes are stored in
+  /// plaintext, as they are one-way digests already, and so are content lengths. Entries that were
+  /// committed without a key stay readable.
+  pub encryption_key: Option<EncryptionKey>,
+
+  /// Several indexes can share a file, each in its own namespace. A hash may exist in several
+  /// namespaces, and all lookups and enumerations are limited to this namespace. Ids are counted
+  /// per namespace, so the handles of different namespaces do not allocate them from each other.
+  pub namespace: String,
+
+  /// Debounce flushing: commit only once no new commits have arrived for this long, instead of
+  /// periodically. An index that is idle by then flushes on its own (see `MsgHandler::resume`).
+  /// `None` flushes periodically, which also commits when nothing was written.
+  pub flush_quiet_period: Option<Duration>,
+
+  /// With a debounced flush, commit anyway once writes have been pending for this long, so that a
+  /// steady stream of commits cannot postpone flushing forever.
+  pub flush_max_delay: Duration,
+
+  /// Let sqlite checkpoint the write-ahead log into the database file once it reaches this many
+  /// pages (see `PRAGMA wal_autocheckpoint`). `None` keeps the sqlite default. This only applies
+  /// to databases in WAL mode.
+  pub wal_autocheckpoint: Option<u32>,
+
+  /// How hard sqlite works to make commits durable (see `SyncMode` for the tradeoffs). `None`
+  /// keeps the sqlite default, which is `SyncMode::Full` unless sqlite was built otherwise.
+  pub synchronous: Option<SyncMode>,
+
+  /// The most callbacks that may wait for queued hashes at once, so that reserved hashes that are
+  /// never committed cannot grow memory without bounds. A callback waiting for several hashes
+  /// counts once for each of them. `None` allows any number.
+  pub max_callbacks: Option<usize>,
+
+  /// What to do when `max_callbacks` is reached.
+  pub callback_limit_policy: CallbackLimitPolicy,
+
+  /// The number of entries expected to be in flight at once, e.g. the size of an upload burst.
+  /// This is a performance hint only: the buffers that collect the callbacks of flushed entries
+  /// are sized for it up front instead of growing during the first burst. The queue is ordered by
+  /// b-trees, which have no capacity to reserve, and `Msg::TrimMemory` releases the reserved
+  /// capacity again. `None` starts with empty buffers.
+  pub expected_inflight: Option<usize>,
+
+  /// Insert at most this many ready entries into the backend per `Commit`, leaving the rest for
+  /// later commits and flushes, so that a large backlog does not hold up other messages for long.
+  /// `None` inserts all ready entries right away.
+  pub max_inserts_per_commit: Option<usize>,
+
+  /// Call at most this many ready callbacks per flush, so that a flush that completes many hashes
+  /// does not hold up other messages while all of their callbacks run. The rest are called in
+  /// batches of the same size in between later messages, or whenever no message is waiting (see
+  /// `MsgHandler::resume`). `None` calls all ready callbacks as part of the flush.
+  pub max_callbacks_per_batch: Option<usize>,
+
+  /// Panic when a message finds the index in an unexpected state (e.g. a `Commit` of a hash that
+  /// is already committed), as is the default in debug builds. Otherwise, the message is answered
+  /// with `Reply::InternalError` and the index keeps running, which suits a long-running server.
+  pub fail_fast: bool,
+
+  /// How transactions are started. Immediate transactions avoid deadlocks between writers sharing
+  /// the index file, at the cost of locking out other writers. If the write lock stays taken for
+  /// all busy retries, the transaction starts deferred instead.
+  pub transaction_mode: TransactionMode,
+
+  /// Accept `Msg::Uncommit`, which makes committed entries unavailable until they are committed
+  /// again. This is off by default, as it is only meant for repacking tools.
+  pub allow_uncommit: bool,
+
+  /// Append every reserve, commit, relocation and uncommit to the `audit_log` table of the
+  /// backend, in the same transaction as the change itself (see `Msg::AuditSince`). Unlike the
+  /// trace, the log is durable and complete, but it costs a write per change, so it is off by
+  /// default.
+  pub audit: bool,
+
+  /// Trace the state transitions of hashes, for debugging (see `Msg::TraceFor`). This costs a
+  /// little time and memory for every message, so `None` disables it.
+  pub trace: Option<TraceLimits>,
+
+  /// Load the hashes of all committed entries into a bloom filter when opening, so that most
+  /// lookups of unknown hashes are answered without a query. This reads the whole hash column,
+  /// which takes a while for huge indexes, so it is off by default.
+  pub warm_up: bool,
+
+  /// Lets other threads stop a long enumeration (`AllHashes`, `AllLeaves`, `AllHashesFrom`,
+  /// `HashesInObject` or `FindOrphans`), which then replies `Interrupted`. The open transaction is
+  /// not affected.
+  pub interrupt: Option<Interrupt>,
+
+  /// Mirrors each committed entry to a sink, e.g. to ship a log to a replica. Entries are handed
+  /// over in insertion order (the id order of the reservations, unless some were prioritized),
+  /// right after they are written to the open transaction, which is committed by the next flush.
+  /// The sink runs on the index thread, so a slow sink holds up all inserts; it should buffer
+  /// (e.g. into a channel) rather than do I/O itself.
+  pub replication: Option<ReplicationSink>,
+}
+
+impl IndexConfig {
+  pub fn new() -> IndexConfig {
+    IndexConfig{busy_retries: 5,
+                busy_retry_delay: Duration::milliseconds(10),
+                digest_width: DIGEST_BYTES,
+                max_inline_payload: 1024 * 1024,
+                max_payload: 256 * 1024 * 1024,
+                max_prefix_matches: 10,
+                max_tree_nodes: 10 * 1000 * 1000,
+                strict_refs: false,
+                verify_collisions: false,
+                strict_levels: false,
+                strict_tree: false,
+                queue_order: QueueOrder::Fifo,
+                reserve_ttl: None,
+                encryption_key: None,
+                namespace: String::new(),
+                flush_quiet_period: None,
+                flush_max_delay: Duration::seconds(10),
+                wal_autocheckpoint: None,
+                synchronous: None,
+                max_callbacks: None,
+                callback_limit_policy: CallbackLimitPolicy::Reject,
+                expected_inflight: None,
+                max_inserts_per_commit: None,
+                max_callbacks_per_batch: None,
+                fail_fast: cfg!(debug_assertions),
+                transaction_mode: TransactionMode::Deferred,
+                allow_uncommit: false,
+                audit: false,
+                trace: None,
+                warm_up: false,
+                interrupt: None,
+                replication: None}
   }
 }
 
-// #[unsafe_desctructor]
-// impl  Drop for HashIndex {
-//   fn drop(&mut self) {
-//     self.flush();
+pub enum Msg {
+  /// Check whether this `Hash` already exists in the system.
+  /// Returns `HashKnown`, `HashNotKnown`, or `Error` if the lookup failed. Messages that look up
+  /// a `Hash` reply `Error` for failed lookups as well, rather than treating the `Hash` as unknown.
+  HashExists(Hash),
 
-//     assert_eq!(self.callbacks.len(), 0);
+  /// Find the hashes that are neither committed nor reserved, e.g. to plan which data to upload.
+  /// Returns `Unknown` with those hashes in the order given (or `InvalidHash` for the first hash
+  /// that is not valid).
+  FilterUnknown(Vec<Hash>),
 
-//     assert_eq!(self.queue.len(), 0);
-//     self.exec_or_die("COMMIT");
-//   }
-// }
+  /// Locate the local payload of the `Hash`. This is currently not used.
+  /// Payloads that were spilled to the chunk table are transparently reassembled.
+  /// Returns `Payload` or `HashNotKnown`.
+  FetchPayload(Hash),
 
+  /// Look up the length of the content of this `Hash` (see `HashEntry::content_len`), without
+  /// decoding its persistent reference.
+  /// Returns `Length` (`0` if the length is not known) or `HashNotKnown`.
+  FetchLength(Hash),
 
-impl MsgHandler<Msg, Reply> for HashIndex {
-  fn handle(&mut self, msg: Msg, reply: Box<Fn(Reply)>) {
-    match msg {
+  /// Look up the id of this `Hash`, e.g. for keying other tables by the much shorter id. Ids are
+  /// assigned when reserving, so queued entries have one as well.
+  /// Returns `Id` or `HashNotKnown`.
+  FetchId(Hash),
 
-      Msg::HashExists(hash) => {
-        assert!(hash.bytes.len() > 0);
-        return reply(match self.locate(&hash) {
-          Some(_) => Reply::HashKnown,
-          None => Reply::HashNotKnown,
-        });
-      },
+  /// Look up a committed or queued entry by its id (see `FetchId`).
+  /// Returns `Entry` or `HashNotKnown`.
+  FetchById(i64),
 
-      Msg::FetchPayload(hash) => {
-        assert!(hash.bytes.len() > 0);
-        return reply(match self.locate(&hash) {
-          Some(ref queue_entry) => Reply::Payload(queue_entry.payload.clone()),
-          None => Reply::HashNotKnown,
-        });
-      },
+  /// Find the committed hashes whose hex digits start with the given prefix (in either case), e.g.
+  /// to accept abbreviated hashes on the command line. Queued entries are not included.
+  /// Returns `Resolved` for a single match, `ResolvedAmbiguous`, `HashNotKnown` if nothing
+  /// matches, or `InvalidHash` if the prefix is not hex.
+  ResolvePrefix(String),
 
-      Msg::FetchPersistentRef(hash) => {
-        assert!(hash.bytes.len() > 0);
-        return reply(match self.locate(&hash) {
-          Some(ref queue_entry) if queue_entry.persistent_ref.is_none() => Reply::Retry,
-          Some(queue_entry) =>
-            Reply::PersistentRef(queue_entry.persistent_ref.expect("persistent_ref")),
-          None => Reply::HashNotKnown,
-        });
-      },
+  /// Locate the persistent reference (external blob reference) for this `Hash`.
+  /// Returns `PersistentRef` or `HashNotKnown`.
+  FetchPersistentRef(Hash),
 
-      Msg::Reserve(hash_entry) => {
-        assert!(hash_entry.hash.bytes.len() > 0);
-        // To avoid unused IO, we store entries in-memory until committed to persistent storage.
-        // This allows us to continue after a crash without needing to scan through and delete
-        // uncommitted entries.
-        return reply(match self.locate(&hash_entry.hash) {
-          Some(_) => Reply::HashKnown,
-          None => { self.reserve(hash_entry); Reply::ReserveOK },
-        });
-      },
+  /// Like `FetchPersistentRef`, but instead of replying `Retry` while the `Hash` is reserved, wait
+  /// for its persistent reference to be set (by `UpdateReserved` or `Commit`), for at most the
+  /// given duration. The deadline is checked whenever the
+  /// index handles a message, so an otherwise idle index may reply late. A waiting `Hash` that is
+  /// abandoned is no longer known.
+  /// Returns `PersistentRef`, `HashNotKnown` or `Timeout`.
+  AwaitPersistentRef(Hash, Duration),
 
-      Msg::UpdateReserved(hash_entry) => {
-        assert!(hash_entry.hash.bytes.len() > 0);
-        self.update_reserved(hash_entry);
-        return reply(Reply::ReserveOK);
-      }
+  /// Reserve a `Hash` in the index, while sending its content to external storage.
+  /// This is used to ensure that each `Hash` is stored only once.
+  /// Returns `ReserveOK`, `HashKnown` if the `Hash` has a committed entry, `AlreadyReserved` if
+  /// it is still queued, or `CollisionSuspected` if collisions are verified and the known entry
+  /// does not match. With `IndexConfig::strict_levels`, a known entry at another level is
+  /// reported as `LevelMismatch` instead. Entries with a payload over `IndexConfig::max_payload`
+  /// are refused with `PayloadTooLarge`, and nothing is reserved if the lookup fails or all ids
+  /// are used (`Error`). With `IndexConfig::strict_tree`, entries that do not fit a hash tree are
+  /// refused with `InvalidEntry`.
+  Reserve(HashEntry),
 
-      Msg::Commit(hash, persistent_ref) => {
-        assert!(hash.bytes.len() > 0);
-        self.commit(&hash, &persistent_ref);
-        return reply(Reply::CommitOK);
-      },
+  /// Like `Reserve`, but the entry is queued ahead of all entries reserved with `Reserve`, so that
+  /// it is inserted (and its callbacks run) as soon as it is committed, instead of waiting for a
+  /// backlog of earlier entries. Prioritized entries are inserted in the order they were reserved.
+  /// The entry still gets the next id, so it is inserted before entries with smaller ids; an
+  /// enumeration resumed from a `ResumeToken` may skip entries that are inserted behind it.
+  /// Returns `ReserveOK`, `HashKnown`, `AlreadyReserved` or `CollisionSuspected` (see `Reserve`).
+  ReservePrioritized(HashEntry),
 
-      Msg::CallAfterHashIsComitted(hash, callback) => {
-        assert!(hash.bytes.len() > 0);
-        if self.register_hash_callback(&hash, callback) {
-          return reply(Reply::CallbackRegistered);
-        } else {
-          return reply(Reply::HashNotKnown);
-        }
-      },
+  /// Like `Reserve`, but reply with the id of the reserved entry (see `FetchById`), which saves
+  /// a `FetchId` after the reserve.
+  /// Returns `Reserved`, `HashKnown`, `AlreadyReserved` or `CollisionSuspected` (see `Reserve`).
+  ReserveReturningId(HashEntry),
 
-      Msg::Flush => {
-        self.flush();
-        return reply(Reply::CommitOK);
-      }
-    }
+  /// Like `ReserveReturningId`, but if the `Hash` is already committed or reserved, reply with its
+  /// persistent reference as `FetchPersistentRef` would, which saves a round trip when the known
+  /// entry is referenced right away.
+  /// Returns `Reserved`, `PersistentRef`, `Retry` (if the known entry has no persistent reference
+  /// yet) or `CollisionSuspected` (see `Reserve`).
+  ReserveOrFetch(HashEntry),
+
+  /// Update the info for a reserved `Hash`. The `Hash` remains reserved. This is used to update
+  /// the persistent reference (external blob reference) as soon as it is available (to allow new
+  /// references to the `Hash` to be created before it is committed).
+  /// Returns ReserveOK, `PayloadTooLarge` (see `Reserve`), or `InternalError` if the `Hash` is not
+  /// known.
+  UpdateReserved(HashEntry),
+
+  /// A `Hash` is committed when it has been `finalized` in the external storage. `Commit` includes
+  /// the persistent reference that the content is available at.
+  /// Returns `HashNotKnown` if the reservation has expired.
+  /// Returns CommitOK, or `RefConflict` if strict refs are enabled and another hash was already
+  /// committed with the same persistent reference.
+  /// Committing a `Hash` again (e.g. on a retry, or from two racing writers) replies `CommitOK` if
+  /// the persistent reference is the same, and `RefConflict` with the `Hash` itself otherwise. The
+  /// first reference is kept either way. With `IndexConfig::strict_tree`, an empty reference is
+  /// refused with `InvalidEntry`.
+  Commit(Hash, Vec<u8>),
+
+  /// Replace the persistent reference of a committed `Hash`, e.g. after its blob was moved when
+  /// repacking external storage. Unlike `UpdateReserved`, this only applies to committed entries.
+  /// Returns `CommitOK` or `HashNotKnown` (also if the `Hash` is still queued).
+  Relocate(Hash, BlobRef),
+
+  /// Like `Relocate`, but for many hashes at once, as when repacking external storage. All updates
+  /// are committed together by a single flush.
+  /// Returns `Relocated` with the number of updated and unknown (or still queued) hashes, or
+  /// `Error` if the flush failed.
+  BatchRelocate(Vec<(Hash, BlobRef)>),
+
+  /// Replace the prefix `from` of the object names in the persistent references of all committed
+  /// entries with `to`, e.g. when moving external storage to another bucket. Offsets and lengths
+  /// are kept. Entries whose reference is not a structured `BlobRef`, or whose object name does
+  /// not start with `from`, are left alone, as are queued entries. All updates are committed
+  /// together by a single flush.
+  /// Returns `Relocated` with the number of updated entries (`not_found` is always `0`), or
+  /// `Error` if the flush failed.
+  RewriteRefPrefix{from: Vec<u8>, to: Vec<u8>},
+
+  /// Set a metadata key of a committed `Hash` (e.g. a mime type) to a small value, replacing any
+  /// previous value. Metadata is stored apart from the entry, and is deleted along with it (e.g.
+  /// by `Uncommit`).
+  /// Returns `CommitOK` or `HashNotKnown`.
+  SetMeta(Hash, String, Vec<u8>),
+
+  /// Look up a metadata key of a committed `Hash` (see `SetMeta`).
+  /// Returns `MetaValue` or `HashNotKnown`.
+  GetMeta(Hash, String),
+
+  /// Report on the open write transaction, so that a writer that has not flushed for a long time
+  /// can be spotted. This does not change any state.
+  /// Returns `Health`.
+  Health,
+
+  /// Report how long committed entries were queued, from their reservation until their commit.
+  /// High waits point to an uploader that reserves far ahead of committing.
+  /// Returns `Stats`.
+  Stats,
+
+  /// Report the effective settings of the index, e.g. to diagnose a misconfigured index without
+  /// restarting it. This does not change any state.
+  /// Returns `Config`.
+  Config,
+
+  /// Report the size of the index file, including the write-ahead log (if any). This counts the
+  /// pages allocated by sqlite, not the size of the entries, and includes all namespaces in the
+  /// file. An index that is kept in memory reports `0`.
+  /// Returns `FileSize`.
+  FileSize,
+
+  /// List the namespaces with committed entries in the file of this index (see
+  /// `IndexConfig::namespace`). Unlike other messages, this is not limited to one namespace.
+  /// Returns `Namespaces`.
+  ListNamespaces,
+
+  /// Describe the storage of the index, so that generic tools can adapt to what an opened file
+  /// supports (see `HashBackend::schema_info`). This does not change any state.
+  /// Returns `SchemaInfo`.
+  SchemaInfo,
+
+  /// Install a "on-commit" handler to be called after `Hash` is committed.
+  /// Returns `CallbackRegistered` with a token for cancelling the callback, `HashNotKnown`, or
+  /// `CallbackLimitReached` (see `IndexConfig::max_callbacks`).
+  CallAfterHashIsComitted(Hash, Thunk<'static>),
+
+  /// Install a handler to be called once, after all of the hashes are committed, e.g. when all
+  /// chunks of a file are stored. Hashes that are already committed count as committed right
+  /// away, so the handler is called before replying if they all are. Nothing is installed if any
+  /// of the hashes is not known.
+  /// Returns `CallbackRegistered` (the token cancels the handler), `HashNotKnown` or
+  /// `CallbackLimitReached`.
+  CallAfterAllCommitted(Vec<Hash>, Thunk<'static>),
+
+  /// Install a handler to be called with each of the hashes once it is committed, e.g. to track
+  /// the chunks of a large file as they are stored. Hashes that are already committed are passed
+  /// to the handler before replying. Nothing is installed if any of the hashes is not known.
+  /// Returns `CallbacksRegistered` (the token cancels the handler for the hashes that are not
+  /// committed yet), `HashNotKnown` or `CallbackLimitReached`.
+  CallAfterEachCommitted(Vec<Hash>, Box<Fn(Hash) + Send>),
+
+  /// Drop a callback registered with `CallAfterHashIsComitted` before it is called, e.g. when the
+  /// caller is no longer interested in the `Hash`. Cancelling a callback that was already called
+  /// (or cancelled) does nothing.
+  /// Returns `CallbackCancelled` with whether the callback was dropped.
+  CancelCallback(CallbackToken),
+
+  /// Count the callbacks that are waiting for queued hashes (as limited by
+  /// `IndexConfig::max_callbacks`), e.g. to spot uploaders that never commit.
+  /// Returns `CallbackCount`.
+  CallbackCount,
+
+  /// Fetch the recent state transitions of a `Hash`, oldest first, if tracing is enabled (see
+  /// `IndexConfig::trace`). Hashes that were traced long ago may be forgotten.
+  /// Returns `Trace`, which is empty if the `Hash` was not traced.
+  TraceFor(Hash),
+
+  /// Fetch the events of the audit log with a sequence number after the given one, oldest first
+  /// and at most `STREAM_BATCH_SIZE` at a time, if the log is enabled (see `IndexConfig::audit`).
+  /// Pass the last sequence number that was fetched to continue, or `0` to start.
+  /// Returns `AuditEvents` or `Disabled`.
+  AuditSince(i64),
+
+  /// Flush the hash index to clear internal buffers and commit the underlying database.
+  /// Returns `CommitOK`, `FlushedNothing` if nothing was written since the last commit, or
+  /// `Error`.
+  Flush,
+
+  /// Flush the hash index and force the committed data onto stable storage before replying.
+  /// Unlike `Flush`, this also checkpoints the write-ahead log (if any) into the main database
+  /// file, so it costs at least one extra `fsync` and blocks until the checkpoint has completed.
+  /// Use it sparingly, e.g. before acknowledging a finished backup to a remote party.
+  /// Returns `CommitOK` or `Error`.
+  Barrier,
+
+  /// Start draining the index before shutting it down: from now on, reserves and updates of
+  /// reserved entries (`Reserve`, `ReservePrioritized`, `ReserveReturningId`, `ReserveOrFetch`,
+  /// `UpdateReserved` and `Uncommit`) are refused with `ShuttingDown`, while queued entries can
+  /// still be committed and flushed.
+  /// Returns `CommitOK`.
+  BeginShutdown,
+
+  /// Finish shutting down once the queue has drained (see `BeginShutdown`, which this implies):
+  /// flush, and check that no entries are still queued.
+  /// Returns `CommitOK`, `Error` if the flush failed, or `InternalError` if entries are queued.
+  Shutdown,
+
+  /// Seal an index whose ingest has finished: from now on, lookups go straight to the backend
+  /// without consulting the queue, and reserves and updates (as refused by `BeginShutdown`) are
+  /// refused with `Sealed`. Only an index with an empty queue can be sealed; sealing also flushes.
+  /// Returns `CommitOK`, `QueueNotEmpty` with the number of queued entries, or `Error`.
+  Seal,
+
+  /// Commit the open transaction and checkpoint the write-ahead log (if any) into the database
+  /// file, truncating the log. This keeps the log from growing during long backups, but blocks
+  /// until the checkpoint has completed.
+  /// Returns `Checkpointed` with the pages in the log and the pages moved, or `Error`.
+  Checkpoint,
+
+  /// Reclaim at most this many free pages of the index file and commit, which shrinks the file
+  /// without the long stall (and the free disk space for a copy) of a full `VACUUM`. This needs
+  /// a file created with incremental auto-vacuum, which is the case for files created since it
+  /// was enabled; other files do not shrink.
+  /// Returns `Vacuumed` with the number of freed pages, or `Error`.
+  IncrementalVacuum(u64),
+
+  /// Release the spare capacity that the queue, the callbacks and other buffers keep after a
+  /// burst, so that an index that idles between bursts gives memory back. The queue itself is
+  /// only trimmed once it is empty.
+  /// Returns `Trimmed`.
+  TrimMemory,
+
+  /// Report whether a flush is due, by the flush timer or the debounce settings (see
+  /// `IndexConfig::flush_quiet_period`), without flushing or restarting the timer. This lets an
+  /// external scheduler decide when to send `Flush`, e.g. to flush several indexes together.
+  /// Returns `FlushDue`.
+  FlushDue,
+
+  /// Re-derive the state that the index keeps about the backend, e.g. after an unclean restart or
+  /// when it is suspected to be wrong: the id counter is raised past the largest id in use, and
+  /// the bloom filter (if warmed up, see `IndexConfig::warm_up`) is rebuilt, in a single read of
+  /// the committed hashes. Nothing is written, and the queue is left as it is.
+  /// Returns `Rescanned`.
+  Rescan,
+
+  /// Open a named savepoint within the open transaction, e.g. before inserting the chunks of one
+  /// file, so that they can be undone as a group. Savepoints nest, and names may be reused (the
+  /// most recent one is meant). Periodic flushes are held back while savepoints are open, as a
+  /// commit ends all of them; an explicit `Flush`, `Barrier` or `Checkpoint` still commits.
+  /// Returns `CommitOK`.
+  Savepoint(String),
+
+  /// End a savepoint (and those opened after it), keeping its changes in the open transaction.
+  /// Returns `CommitOK` or `Error` if no such savepoint is open.
+  ReleaseSavepoint(String),
+
+  /// Undo the changes since a savepoint was opened, keeping the changes before it. Entries that
+  /// were committed after the savepoint become unknown again, and their callbacks are dropped
+  /// without being called. Those that were still queued behind a lower id are reserved again,
+  /// with the persistent reference they had before, and keep their callbacks. The savepoint stays
+  /// open.
+  /// Returns `CommitOK` or `Error` if no such savepoint is open.
+  RollbackToSavepoint(String),
+
+  /// Recreate the unique hash index if it is missing and rebuild all indexes of the hash table.
+  /// This is meant for recovering a partially damaged index file in place.
+  /// Returns `CommitOK`.
+  RebuildIndexes,
+
+  /// Abandon reserved entries that have been neither committed nor updated within the configured
+  /// reserve TTL, along with their callbacks. This also happens periodically when flushing.
+  /// Returns `ExpiredReserves` with the number of abandoned entries.
+  ExpireStaleReserves,
+
+  /// Abandon a reserved `Hash` that will never be committed, dropping its callbacks. Its id is
+  /// never used, and later entries are inserted without waiting for it.
+  /// Returns `CommitOK` or `HashNotKnown` (if the `Hash` is not reserved or already committed).
+  Abandon(Hash),
+
+  /// Move a committed entry back into the queue with its persistent reference cleared, e.g. while
+  /// the blob it refers to is rebuilt: `FetchPersistentRef` replies `Retry` until the `Hash` is
+  /// committed again, which inserts it under its old id.
+  ///
+  /// This is an advanced and dangerous operation, and is only accepted with
+  /// `IndexConfig::allow_uncommit`. Branches that refer to the entry stay committed. The entry is
+  /// queued behind all others, so it does not hold up their insertion, but like any reserve it
+  /// holds up the entries queued behind it (other uncommitted entries), and it is abandoned (and
+  /// its `Hash` forgotten) if it is not committed within the reserve TTL.
+  /// Returns `CommitOK`, `HashNotKnown`, `AlreadyReserved` (if the `Hash` is queued rather than
+  /// inserted), `Disabled` or `Error`.
+  Uncommit(Hash),
+
+  /// Delete the committed entries with an id from `lo` up to (but not including) `hi`, e.g. to
+  /// prune a whole backup generation, whose ids are contiguous. Nothing is deleted if any id in
+  /// the range is still queued. Branches that refer to deleted entries are not checked. The
+  /// deletion is committed by a flush.
+  /// Returns `DeletedRange`, `QueueNotEmpty` with the number of queued ids in the range, or
+  /// `Error` if the flush failed.
+  DeleteIdRange{lo: i64, hi: i64},
+
+  /// Enumerate all committed entries in id order, without materializing them all at once: full
+  /// batches of `STREAM_BATCH_SIZE` entries are handed to the sink as they are read.
+  /// Entries that are still queued are not included.
+  /// Returns `HashBatch` with the final, possibly empty, batch, or `Interrupted`.
+  AllHashes(Box<Fn(Vec<HashEntry>) + Send>),
+
+  /// Like `AllHashes`, but only enumerates leaf entries (level `0`).
+  /// Returns `HashBatch` with the final, possibly empty, batch, or `Interrupted`.
+  AllLeaves(Box<Fn(Vec<HashEntry>) + Send>),
+
+  /// Like `AllHashes`, but continues after the position of a `ResumeToken` (use
+  /// `ResumeToken::start()` to enumerate from the beginning). Each batch is handed to the sink with
+  /// the token to resume from after it, so an interrupted enumeration can pick up where it stopped.
+  /// Returns `ResumableBatch` with the final, possibly empty, batch, or `Interrupted`.
+  AllHashesFrom(ResumeToken, Box<Fn(Vec<HashEntry>, ResumeToken) + Send>),
+
+  /// Like `AllHashesFrom`, but only reads the next batch after the position of the `ResumeToken`,
+  /// so that a client can pull entries at its own pace (see `hash_iter::HashIter`).
+  /// Returns `ResumableBatch`, whose batch is empty once the enumeration is done.
+  HashesAfter(ResumeToken),
+
+  /// Like `AllHashes`, but only enumerates the entries whose persistent reference points into the
+  /// blob object with the given name, e.g. to find what must be relocated before the object is
+  /// moved or deleted.
+  /// Returns `HashBatch` with the final, possibly empty, batch, or `Interrupted`.
+  HashesInObject(Vec<u8>, Box<Fn(Vec<HashEntry>) + Send>),
+
+  /// Fetch a page of at most `limit` committed entries in id order, after skipping the first
+  /// `offset` of them. Unlike the enumerations, this allows jumping to any page, e.g. for display.
+  /// Pages are stable across calls as long as no entries are deleted. Queued entries are not
+  /// included.
+  /// Returns `Page` with the entries and the total number of committed entries.
+  Page{offset: u64, limit: u64},
+
+  /// Estimate the number of committed entries without counting them, e.g. for the progress bar of
+  /// an enumeration. This is the largest committed id, which is read from the primary key and is
+  /// fast on any size of index. It is only an upper bound: ids of deleted and abandoned entries are
+  /// counted as well.
+  /// Returns `Estimate`.
+  EstimateCount,
+
+  /// Find committed entries whose blob is missing from external storage, e.g. after an unclean
+  /// shutdown. The closure tells whether a blob exists; it is asked once for every committed
+  /// entry (leaves and branches), streaming through the index in id order. Entries with a
+  /// persistent reference that is not a structured `BlobRef` cannot be checked and are skipped.
+  /// Returns `Orphans` with the hashes of the entries whose blob is missing, or `Interrupted`.
+  FindOrphans(Box<Fn(&BlobRef) -> bool + Send>),
+
+  /// Walk the hash tree below a root `Hash` depth-first, handing the `BlobRef` of each leaf to the
+  /// sink in order, so that the original data can be restored by concatenating their content.
+  /// A root that is a leaf itself is handed over as is. The walk stops at the first node that
+  /// cannot be followed, after the leaves before it have been handed over. This includes nodes
+  /// whose reference is of the wrong `RefKind`, so that a corrupt payload cannot lead the walk
+  /// into data that is not part of the tree, and trees with more nodes than
+  /// `IndexConfig::max_tree_nodes`, so that a payload that lists one of its ancestors cannot lead
+  /// it in circles.
+  /// Returns `LeafCount` with the number of leaves, or `BrokenTree`.
+  LeavesOf(Hash, Box<Fn(BlobRef) + Send>),
+
+  /// Report the shape of the hash tree below a root `Hash`, for tuning how trees are built. Leaves
+  /// need not be committed. Subtrees that occur several times are counted each time.
+  /// Returns `TreeStats` or `BrokenTree`.
+  TreeStats(Hash),
+
+  /// Summarize the external storage referenced by committed leaf entries: the total number of
+  /// referenced bytes and the number of distinct blob objects. Queued entries are not included.
+  /// Returns `StorageSummary`.
+  StorageSummary,
+
+  /// Handle the wrapped message as usual, and echo `RequestId` back with its reply. This lets a
+  /// caller with several requests in flight match up replies for tracing. Untagged messages are
+  /// handled without any overhead.
+  /// Returns `Tagged` with the reply to the wrapped message.
+  Tagged(RequestId, Box<Msg>),
+}
+
+pub enum Reply {
+  HashKnown,
+  HashNotKnown,
+  Entry(HashEntry),
+  Unknown(Vec<Hash>),
+
+  Payload(Option<Vec<u8>>),
+  PersistentRef(Vec<u8>),
+  Length(u64),
+  Id(i64),
+
+  ReserveOK,
+  CommitOK,
+  /// A `Msg::Flush` found nothing to commit.
+  FlushedNothing,
+  /// The hash was reserved under this id (see `Msg::ReserveReturningId` and
+  /// `Msg::ReserveOrFetch`).
+  Reserved(i64),
+  /// The hash is reserved, but its entry is not inserted yet (see `Msg::Reserve`).
+  AlreadyReserved,
+  CallbackRegistered(CallbackToken),
+  /// The handler of a `Msg::CallAfterEachCommitted` waits for `registered` hashes, and was called
+  /// for `fired` hashes that were already committed.
+  CallbacksRegistered{token: CallbackToken, registered: usize, fired: usize},
+  CallbackCancelled(bool),
+  CallbackCount(usize),
+
+  /// The callback was not registered, as `IndexConfig::max_callbacks` callbacks are waiting.
+  CallbackLimitReached,
+
+  Retry,
+
+  /// The `Hash` was not committed in time (see `Msg::AwaitPersistentRef`).
+  Timeout,
+
+  RefConflict(Hash),
+
+  /// The `Hash` is known, but with a different level or payload length than the reserved entry.
+  CollisionSuspected(Hash),
+
+  /// The only committed `Hash` with the prefix (see `Msg::ResolvePrefix`).
+  Resolved(Hash),
+
+  /// The first matches of an ambiguous prefix in hash order, `total` of them in all. If there are
+  /// more than `IndexConfig::max_prefix_matches`, only that many are given and `too_many` is set.
+  ResolvedAmbiguous{hashes: Vec<Hash>, total: u64, too_many: bool},
+
+  /// The `Hash` is known at the `existing` level, but was reserved at the `requested` level (see
+  /// `IndexConfig::strict_levels`).
+  LevelMismatch{existing: i64, requested: i64},
+
+  /// The index is shutting down and accepts no new reserves (see `Msg::BeginShutdown`).
+  ShuttingDown,
+
+  /// The message is not enabled in the configuration (see `IndexConfig::allow_uncommit` and
+  /// `IndexConfig::audit`).
+  Disabled,
+
+  /// The index is sealed and accepts no new reserves (see `Msg::Seal`).
+  Sealed,
+
+  /// The index was not sealed (or the range not deleted), as this many entries are still queued.
+  QueueNotEmpty(usize),
+
+  /// The number of entries deleted by `DeleteIdRange`, and their non-empty persistent references,
+  /// in id order, so that the blobs they refer to can be cleaned up.
+  DeletedRange{deleted: usize, freed_refs: Vec<Vec<u8>>},
+
+  ExpiredReserves(usize),
+
+  Relocated{updated: usize, not_found: usize},
+
+  HashBatch(Vec<HashEntry>),
+  ResumableBatch(Vec<HashEntry>, ResumeToken),
+  Page(Vec<HashEntry>, u64),
+  Estimate(u64),
+  Orphans(Vec<Hash>),
+
+  /// The enumeration was stopped early (see `IndexConfig::interrupt`). The batches that were
+  /// handed to the sink are complete, but the entries after them were not read; an `AllHashesFrom`
+  /// enumeration can be resumed from the last token handed to the sink.
+  Interrupted,
+  LeafCount(u64),
+
+  /// The shape of a hash tree: `depth` counts the nodes on the longest path from the root to a
+  /// leaf (so a lone leaf has depth `1`), and `avg_fanout` is the mean number of children of the
+  /// branches (`0` if there are none).
+  TreeStats{depth: u32, leaves: u64, branches: u64, avg_fanout: f64},
+  BrokenTree(TreeError),
+
+  StorageSummary{total_bytes: u64, distinct_objects: u64, leaf_count: u64},
+
+  /// `uncommitted_writes` counts the rows written since the last commit, `seconds_since_commit`
+  /// is the age of the open transaction and `queue_depth` the number of queued entries.
+  Health{uncommitted_writes: u64, seconds_since_commit: i64, queue_depth: usize},
+
+  /// `committed` counts the entries committed since the index was opened. The queue waits of
+  /// these entries are given in milliseconds, with percentiles rounded up to a power of two.
+  Stats{committed: u64, wait_p50_ms: i64, wait_p95_ms: i64, wait_max_ms: i64},
+
+  /// The value of a metadata key, or `None` if it is not set.
+  MetaValue(Option<Vec<u8>>),
+
+  /// A copy of the settings the index runs with, except for the encryption key: `encryption_key`
+  /// is always `None`, and `encrypted` tells whether one is set.
+  Config{config: IndexConfig, encrypted: bool},
+
+  Namespaces(Vec<String>),
+
+  /// The schema version of the storage, the columns of its entry table, and its optional
+  /// features. An index kept in memory has no columns.
+  SchemaInfo{version: u32, columns: Vec<String>, features: Vec<String>},
+
+  /// `log_pages` is the size of the write-ahead log before the checkpoint, and `moved_pages` the
+  /// number of pages that were written back to the database file (both `0` without a log).
+  Checkpointed{log_pages: u64, moved_pages: u64},
+
+  Vacuumed(u64),
+
+  /// An estimate of the bytes released by `TrimMemory`.
+  Trimmed(u64),
+
+  FlushDue(bool),
+
+  Rescanned(RescanReport),
+
+  Trace(Vec<TraceEvent>),
+
+  AuditEvents(Vec<AuditEvent>),
+
+  FileSize(u64),
+
+  /// The `Hash` given in the message was rejected by `Hash::validate`.
+  InvalidHash(HashError),
+
+  /// The entry given in the message has a payload of this many bytes, which is more than
+  /// `IndexConfig::max_payload`. The entry is ignored.
+  PayloadTooLarge(u64),
+
+  /// The entry or reference given in the message does not fit a hash tree, and is ignored (see
+  /// `IndexConfig::strict_tree`). Says what is wrong with it.
+  InvalidEntry(String),
+
+  Error(HashIndexError),
+
+  /// The message found the index in an unexpected state, and was ignored (see
+  /// `IndexConfig::fail_fast`).
+  InternalError(String),
+
+  /// The reply to a `Msg::Tagged`.
+  Tagged(RequestId, Box<Reply>),
+}
+
+
+/// The bytes of a queued hash, shared by the queue and the callbacks waiting for it, so that each
+/// queued hash is stored once however many structures refer to it.
+type HashKey = Rc<HashBytes>;
+
+/// The position of an entry in the queue, which is drained in ascending order: by rank, and by id
+/// within a rank. Prioritized entries have `PRIORITIZED_RANK` and uncommitted ones
+/// `UNCOMMITTED_RANK`, while the rank of the others is given by the `QueueOrder`.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct QueuePriority {
+  rank: i64,
+  id: i64,
+}
+
+#[derive(Clone)]
+struct QueueEntry {
+  id: i64,
+  level: i64,
+  // `None` if there is none, or if it is stored out-of-line (see `HashBackend::spilled_payload`).
+  payload: Option<Vec<u8>>,
+  // Set if the payload was spilled while the entry is queued, so it is dropped with the entry.
+  spilled: bool,
+  persistent_ref: Option<Vec<u8>>,
+  content_len: u64,
+
+  // When this entry was reserved or last updated (`None` for committed entries).
+  reserved_at: Option<SteadyTime>,
+
+  // When this entry was reserved, which is kept when it is updated.
+  queued_at: Option<SteadyTime>,
+}
+
+/// Reunite a queued (or located) entry with its hash, which the queue keeps as the key.
+fn queue_entry_to_hash_entry(hash: Hash, qe: QueueEntry) -> HashEntry {
+  HashEntry{hash: hash,
+            level: qe.level,
+            payload: qe.payload,
+            persistent_ref: qe.persistent_ref,
+            content_len: qe.content_len}
+}
+
+/// Split an entry into its hash and the `QueueEntry` to keep under it.
+fn hash_entry_to_queue_entry(id: i64, entry: HashEntry, reserved_at: Option<SteadyTime>)
+                             -> (Hash, QueueEntry) {
+  let HashEntry{hash, level, payload, persistent_ref, content_len} = entry;
+  (hash, QueueEntry{id: id,
+                    level: level,
+                    payload: payload,
+                    spilled: false,
+                    persistent_ref: persistent_ref,
+                    content_len: content_len,
+                    reserved_at: reserved_at,
+                    queued_at: reserved_at})
+}
+
+pub struct HashIndex<B = SqliteBackend> {
+  backend: B,
+
+  config: IndexConfig,
+
+  id_counter: CumulativeCounter,
+
+  queue: UniquePriorityQueue<QueuePriority, HashKey, QueueEntry>,
+
+  // The ranks that entries have been queued at (since opening), to find queued entries by id.
+  queue_ranks: BTreeSet<i64>,
+
+  // The persistent references of committed entries that are still queued, with their hashes, so
+  // that a commit need not scan the queue for them. Only kept with `IndexConfig::strict_refs`.
+  queued_refs: BTreeMap<Vec<u8>, HashKey>,
+
+  callbacks: CallbackContainer<HashKey>,
+
+  // Replies to `Msg::AwaitPersistentRef` for queued hashes, with their deadlines.
+  ref_waiters: BTreeMap<HashKey, Vec<(SteadyTime, Box<Fn(Reply)>)>>,
+
+  flush_timer: PeriodicTimer,
+
+  clock: Box<Fn() -> SteadyTime>,
+
+  // Rows written to the backend since the last commit, and when that commit happened.
+  uncommitted_writes: u64,
+  last_commit: SteadyTime,
+
+  // For a debounced flush: the last commit message, and the first write since the last flush.
+  last_activity: Option<SteadyTime>,
+  pending_since: Option<SteadyTime>,
+
+  // Set when a debounced flush failed while idle, so that it is retried by the next message
+  // rather than on every idle tick.
+  idle_flush_failed: bool,
+
+  // Open savepoints, with the uncommitted writes, the ready callbacks and the length of
+  // `savepoint_commits` when they were opened.
+  savepoints: Vec<(String, u64, u64, usize)>,
+
+  // The hashes committed while savepoints are open, with their persistent reference before.
+  savepoint_commits: Vec<(HashKey, Option<Vec<u8>>)>,
+
+  // How long committed entries were queued, in milliseconds.
+  queue_waits: Histogram,
+
+  // The trace (if enabled), and the traced hashes whose callbacks are ready, with the ready mark
+  // of their callbacks.
+  trace: Option<Trace>,
+  opened_at: SteadyTime,
+  fired_pending: Vec<(u64, HashKey)>,
+
+  // All committed hashes, if warmed up (so a miss means that a hash is not committed), and how
+  // the warm-up went.
+  bloom: Option<BloomFilter>,
+  warm_up_report: Option<WarmUpReport>,
+
+  // Whether new reserves are refused, as the index is shutting down (see `Msg::BeginShutdown`).
+  draining: bool,
+
+  // Whether lookups skip the queue and reserves are refused (see `Msg::Seal`).
+  sealed: bool,
+}
+
+
+/// Configures and opens a `HashIndex`.
+///
+/// ```rust,ignore
+/// let hi = HashIndexBuilder::new(path).busy_retries(10).build();
+/// ```
+pub struct HashIndexBuilder {
+  path: String,
+  config: IndexConfig,
+}
+
+impl HashIndexBuilder {
+
+  pub fn new(path: String) -> HashIndexBuilder {
+    HashIndexBuilder{path: path, config: IndexConfig::new()}
+  }
+
+  /// Retry a busy commit at most `retries` times before reporting `HashIndexError::Busy`.
+  pub fn busy_retries(mut self, retries: u32) -> HashIndexBuilder {
+    self.config.busy_retries = retries;
+    self
+  }
+
+  /// Wait `delay` before the first retry of a busy commit (the delay doubles for each retry).
+  pub fn busy_retry_delay(mut self, delay: Duration) -> HashIndexBuilder {
+    self.config.busy_retry_delay = delay;
+    self
+  }
+
+  /// Spill payloads larger than `size` bytes to the chunk table, in chunks of `size` bytes.
+  pub fn max_inline_payload(mut self, size: usize) -> HashIndexBuilder {
+    assert!(size > 0);
+    self.config.max_inline_payload = size;
+    self
+  }
+
+  /// Reject entries with payloads larger than `size` bytes.
+  pub fn max_payload(mut self, size: usize) -> HashIndexBuilder {
+    self.config.max_payload = size;
+    self
+  }
+
+  /// Visit at most `max` nodes of a tree (see `IndexConfig::max_tree_nodes`).
+  pub fn max_tree_nodes(mut self, max: u64) -> HashIndexBuilder {
+    self.config.max_tree_nodes = max;
+    self
+  }
+
+  /// Return at most `max` hashes for an ambiguous prefix (see `IndexConfig::max_prefix_matches`).
+  pub fn max_prefix_matches(mut self, max: usize) -> HashIndexBuilder {
+    self.config.max_prefix_matches = max;
+    self
+  }
+
+  /// Accept only hashes of `width` bytes (see `Hash::validate`).
+  pub fn digest_width(mut self, width: usize) -> HashIndexBuilder {
+    self.config.digest_width = width;
+    self
+  }
+
+  /// Reject commits that reuse the persistent reference of another hash (see `Reply::RefConflict`).
+  pub fn strict_refs(mut self, strict: bool) -> HashIndexBuilder {
+    self.config.strict_refs = strict;
+    self
+  }
+
+  /// Start transactions with `BEGIN IMMEDIATE` or `BEGIN` (see `TransactionMode`).
+  pub fn transaction_mode(mut self, mode: TransactionMode) -> HashIndexBuilder {
+    self.config.transaction_mode = mode;
+    self
+  }
+
+  /// Panic on unexpected internal state instead of replying with `Reply::InternalError`.
+  pub fn fail_fast(mut self, fail_fast: bool) -> HashIndexBuilder {
+    self.config.fail_fast = fail_fast;
+    self
+  }
+
+  /// Compare reserves of known hashes with the known entry (see `Reply::CollisionSuspected`).
+  pub fn verify_collisions(mut self, verify: bool) -> HashIndexBuilder {
+    self.config.verify_collisions = verify;
+    self
+  }
+
+  /// Refuse reserves of known hashes at another level (see `Reply::LevelMismatch`).
+  pub fn strict_levels(mut self, strict: bool) -> HashIndexBuilder {
+    self.config.strict_levels = strict;
+    self
+  }
+
+  /// Refuse entries that do not fit a hash tree (see `IndexConfig::strict_tree`).
+  pub fn strict_tree(mut self, strict: bool) -> HashIndexBuilder {
+    self.config.strict_tree = strict;
+    self
+  }
+
+  /// Insert committed entries in this order (see `QueueOrder`).
+  pub fn queue_order(mut self, order: QueueOrder) -> HashIndexBuilder {
+    self.config.queue_order = order;
+    self
+  }
+
+  /// Abandon reserved entries that have been neither committed nor updated for `ttl`.
+  pub fn reserve_ttl(mut self, ttl: Duration) -> HashIndexBuilder {
+    self.config.reserve_ttl = Some(ttl);
+    self
+  }
+
+  /// Scope the index to `namespace` within its file (the default namespace is `""`).
+  pub fn namespace(mut self, namespace: String) -> HashIndexBuilder {
+    self.config.namespace = namespace;
+    self
+  }
+
+  /// Flush once no commits have arrived for `quiet`, rather than periodically.
+  pub fn flush_quiet_period(mut self, quiet: Duration) -> HashIndexBuilder {
+    self.config.flush_quiet_period = Some(quiet);
+    self
+  }
+
+  /// Flush a debounced index at the latest `max_delay` after the first pending write.
+  pub fn flush_max_delay(mut self, max_delay: Duration) -> HashIndexBuilder {
+    self.config.flush_max_delay = max_delay;
+    self
+  }
+
+  /// Insert at most `max` ready entries per `Commit`.
+  pub fn max_inserts_per_commit(mut self, max: usize) -> HashIndexBuilder {
+    self.config.max_inserts_per_commit = Some(max);
+    self
+  }
+
+  /// Call at most `max` ready callbacks at a time (see `IndexConfig::max_callbacks_per_batch`).
+  /// A batch must make progress, so `max` cannot be zero.
+  pub fn max_callbacks_per_batch(mut self, max: usize) -> HashIndexBuilder {
+    assert!(max > 0);
+    self.config.max_callbacks_per_batch = Some(max);
+    self
+  }
+
+  /// Accept `Msg::Uncommit` (see `IndexConfig::allow_uncommit`).
+  pub fn allow_uncommit(mut self, allow: bool) -> HashIndexBuilder {
+    self.config.allow_uncommit = allow;
+    self
+  }
+
+  /// Keep a durable audit log of all changes (see `IndexConfig::audit`).
+  pub fn audit(mut self, audit: bool) -> HashIndexBuilder {
+    self.config.audit = audit;
+    self
+  }
+
+  /// Trace the most recent `events_per_hash` state transitions of up to `max_hashes` hashes.
+  pub fn trace(mut self, events_per_hash: usize, max_hashes: usize) -> HashIndexBuilder {
+    self.config.trace = Some(TraceLimits{events_per_hash: events_per_hash,
+                                         max_hashes: max_hashes});
+    self
+  }
+
+  /// Let `interrupt` stop enumerations from other threads (see `IndexConfig::interrupt`).
+  pub fn interrupt(mut self, interrupt: Interrupt) -> HashIndexBuilder {
+    self.config.interrupt = Some(interrupt);
+    self
+  }
+
+  /// Hand every committed entry to `sink` (see `IndexConfig::replication`).
+  pub fn replicate_to(mut self, sink: Box<Fn(&HashEntry) + Send + Sync>) -> HashIndexBuilder {
+    self.config.replication = Some(ReplicationSink::new(sink));
+    self
+  }
+
+  /// Load the committed hashes into a bloom filter when opening (see `IndexConfig::warm_up`).
+  pub fn warm_up(mut self, warm_up: bool) -> HashIndexBuilder {
+    self.config.warm_up = warm_up;
+    self
+  }
+
+  /// Limit the number of waiting callbacks, applying `policy` when the limit is reached.
+  pub fn max_callbacks(mut self, max: usize, policy: CallbackLimitPolicy) -> HashIndexBuilder {
+    self.config.max_callbacks = Some(max);
+    self.config.callback_limit_policy = policy;
+    self
+  }
+
+  /// Size buffers for `n` entries in flight (see `IndexConfig::expected_inflight`).
+  pub fn expected_inflight(mut self, n: usize) -> HashIndexBuilder {
+    self.config.expected_inflight = Some(n);
+    self
+  }
+
+  /// Checkpoint the write-ahead log automatically once it reaches `pages` pages.
+  pub fn wal_autocheckpoint(mut self, pages: u32) -> HashIndexBuilder {
+    self.config.wal_autocheckpoint = Some(pages);
+    self
+  }
+
+  /// Set how hard sqlite works to make commits durable (see `SyncMode`).
+  pub fn synchronous(mut self, mode: SyncMode) -> HashIndexBuilder {
+    self.config.synchronous = Some(mode);
+    self
+  }
+
+  /// Encrypt payloads and persistent references at rest with a key derived from `key`.
+  pub fn encryption_key(mut self, key: Vec<u8>) -> HashIndexBuilder {
+    self.config.encryption_key = Some(EncryptionKey(key));
+    self
+  }
+
+  pub fn build(self) -> HashIndex<SqliteBackend> {
+    match self.try_build() {
+      Ok(hi) => hi,
+      Err(e) => panic!("Could not open hash index: {:?}", e),
+    }
+  }
+
+  /// Like `build`, but reports a `WrongKey`, `DigestWidthMismatch` or `NotAHashIndex` instead of
+  /// panicking.
+  pub fn try_build(self) -> Result<HashIndex<SqliteBackend>, HashIndexError> {
+    let backend = try!(SqliteBackend::open(self.path, &self.config));
+    Ok(HashIndex::open(backend, self.config))
+  }
+
+  /// Build an index that is kept in memory only and never touches sqlite. The path is ignored.
+  /// Nothing survives the index, so this is meant for tests and for deduplicating within a run.
+  pub fn build_in_memory(self) -> HashIndex<MemoryBackend> {
+    let backend = MemoryBackend::new(self.config.namespace.clone());
+    HashIndex::open(backend, self.config)
+  }
+}
+
+
+impl HashIndex<SqliteBackend> {
+
+  pub fn new(path: String) -> HashIndex<SqliteBackend> {
+    HashIndexBuilder::new(path).build()
+  }
+
+  /// Merge the committed entries of the index at `other_path` into this one, in a single
+  /// transaction. Both indexes must use the same encryption key (if any), and only entries in the
+  /// namespace of this index are merged. Copied entries get new ids after those in use here.
+  ///
+  /// The other index is only read. Returns `NotAHashIndex` if it does not exist or has not been
+  /// opened by this version (which upgrades it), and `WrongKey` or `DigestWidthMismatch` if it
+  /// does not match this one. An entry is stored once per hash, with a single persistent
+  /// reference and no count of its users, so a shared hash keeps the entry here; there are no
+  /// reference counts to add up.
+  pub fn merge_from(&mut self, other_path: &str) -> Result<MergeReport, HashIndexError> {
+    try!(self.flush());
+
+    let id_offset = try!(self.next_id());
+    let report = try!(self.backend.merge_from(other_path, id_offset));
+    // Entries in flight may have ids above all merged ones, so never count back down:
+    self.id_counter = CumulativeCounter::new(cmp::max(id_offset, self.backend.max_id()));
+    if self.bloom.is_some() {
+      self.warm_up();
+    }
+    Ok(report)
+  }
+
+  /// Drop the unique index on hashes until `end_bulk_load`, which speeds up a large import.
+  /// Lookups of hashes scan the whole table in the meantime, so they are slow. If the index is
+  /// reopened before `end_bulk_load`, it recreates the unique index while opening.
+  pub fn begin_bulk_load(&mut self) -> Result<(), HashIndexError> {
+    try!(self.flush());
+    self.backend.begin_bulk_load();
+    Ok(())
+  }
+
+  /// Recreate the unique index dropped by `begin_bulk_load`.
+  /// Returns `DuplicateHash` if the bulk load inserted a hash twice. The index stays without the
+  /// unique index then, so the duplicate can be removed before calling this again.
+  pub fn end_bulk_load(&mut self) -> Result<(), HashIndexError> {
+    try!(self.flush());
+    try!(self.backend.end_bulk_load().map_err(HashIndexError::DuplicateHash));
+    self.flush()
+  }
+}
+
+impl HashIndex<MemoryBackend> {
+
+  #[cfg(test)]
+  pub fn new_for_testing() -> HashIndex<MemoryBackend> {
+    HashIndexBuilder::new(String::new()).build_in_memory()
+  }
+}
+
+impl <B: HashBackend> HashIndex<B> {
+
+  fn open(backend: B, config: IndexConfig) -> HashIndex<B> {
+    let inflight = config.expected_inflight.unwrap_or(0);
+    let mut hi = HashIndex{backend: backend,
+                           config: config,
+                           id_counter: CumulativeCounter::new(0),
+                           queue: UniquePriorityQueue::new(),
+                           queue_ranks: BTreeSet::new(),
+                           queued_refs: BTreeMap::new(),
+                           callbacks: CallbackContainer::with_capacity(inflight),
+                           ref_waiters: BTreeMap::new(),
+                           flush_timer: PeriodicTimer::new(Duration::seconds(10)),
+                           clock: Box::new(|| SteadyTime::now()),
+                           uncommitted_writes: 0,
+                           last_commit: SteadyTime::now(),
+                           last_activity: None,
+                           pending_since: None,
+                           idle_flush_failed: false,
+                           savepoints: vec!(),
+                           savepoint_commits: vec!(),
+                           queue_waits: Histogram::new(),
+                           trace: None,
+                           opened_at: SteadyTime::now(),
+                           fired_pending: Vec::with_capacity(inflight),
+                           bloom: None,
+                           warm_up_report: None,
+                           draining: false,
+                           sealed: false,
+    };
+    hi.trace = hi.config.trace.map(Trace::new);
+    hi.refresh_id_counter();
+    if hi.config.warm_up {
+      hi.warm_up();
+    }
+    hi
+  }
+
+  /// How the warm-up went, if the index was warmed up when opening (see `IndexConfig::warm_up`).
+  pub fn warm_up_report(&self) -> Option<WarmUpReport> {
+    self.warm_up_report.clone()
+  }
+
+  /// Load all committed hashes into a new bloom filter.
+  fn warm_up(&mut self) {
+    let started = SteadyTime::now();
+    // An empty page reads no entries, so it cannot fail to decode one:
+    let committed = self.backend.page(0, 0).map(|(_, total)| total).unwrap_or(0);
+    let mut bloom = BloomFilter::with_capacity(2 * committed);
+    self.backend.for_each_hash(&mut |hash_bytes| bloom.insert(hash_bytes));
+    self.warm_up_report = Some(WarmUpReport{entries: bloom.len(),
+                                            elapsed: SteadyTime::now() - started});
+    self.bloom = Some(bloom);
+  }
+
+  fn rescan(&mut self) -> RescanReport {
+    // The counter is ahead of the backend while entries are queued, so it is only ever raised:
+    let counted = self.id_counter.peek().map(|next| next - 1)
+      .unwrap_or(cumulative_counter::MAX_VALUE);
+    let queued = self.queue.dump().into_iter().map(|(p, _, _)| p.id).max().unwrap_or(0);
+    let max_id = cmp::max(self.backend.max_id(), queued);
+    if max_id > counted {
+      self.id_counter = CumulativeCounter::new(max_id);
+    }
+
+    let mut entries = 0;
+    let mut missing = 0;
+    let mut rebuilt = None;
+    {
+      let old = self.bloom.as_ref();
+      if old.is_some() {
+        let committed = self.backend.page(0, 0).map(|(_, total)| total).unwrap_or(0);
+        rebuilt = Some(BloomFilter::with_capacity(2 * committed));
+      }
+      self.backend.for_each_hash(&mut |hash_bytes| {
+        entries += 1;
+        match (old, rebuilt.as_mut()) {
+          (Some(old), Some(bloom)) => {
+            if !old.may_contain(hash_bytes) {
+              missing += 1;
+            }
+            bloom.insert(hash_bytes);
+          },
+          _ => (),
+        }
+      });
+    }
+    if rebuilt.is_some() {
+      self.bloom = rebuilt;
+    }
+    RescanReport{entries: entries,
+                 ids_skipped: if max_id > counted { max_id - counted } else { 0 },
+                 missing_from_bloom: missing}
+  }
+
+  #[cfg(test)]
+  fn set_clock(&mut self, clock: Box<Fn() -> SteadyTime>) {
+    self.clock = clock;
+    self.opened_at = self.now();
+  }
+
+  /// The queued entries as `(id, hash, ready)` in the order of insertion.
+  #[cfg(test)]
+  pub fn debug_dump_queue(&self) -> Vec<(i64, Vec<u8>, bool)> {
+    self.queue.dump().into_iter().map(|(p, key, ready)| (p.id, key.to_vec(), ready)).collect()
+  }
+
+  /// Verify the internal consistency of the queue and callbacks against the backend.
+  /// Returns a description of each violated invariant. This ignores `IndexConfig::interrupt`, so
+  /// the check is always complete.
+  #[cfg(test)]
+  pub fn check_invariants(&mut self) -> Result<(), Vec<String>> {
+    let mut errors = vec!();
+    let queue = self.debug_dump_queue();
+
+    let mut ids = BTreeSet::new();
+    for &(id, ref hash_bytes, _) in queue.iter() {
+      let hash = Hash{bytes: HashBytes::new(&hash_bytes[..])};
+      if !ids.insert(id) {
+        errors.push(format!("id {} is queued twice", id));
+      }
+      if self.queue.find_key(&hash.bytes).map(|p| p.id) != Some(id) {
+        errors.push(format!("hash {} does not map back to id {}", hash_bytes.to_hex(), id));
+      }
+      match self.backend.locate(&hash) {
+        Ok(None) => (),
+        Ok(Some(_)) =>
+          errors.push(format!("hash {} is both committed and queued", hash_bytes.to_hex())),
+        Err(e) =>
+          errors.push(format!("hash {} could not be located: {:?}", hash_bytes.to_hex(), e)),
+      }
+    }
+
+    // Ready entries are inserted as soon as all entries before them are (unless the number of
+    // inserts per commit is limited):
+    match queue.first() {
+      Some(&(id, _, true)) if self.config.max_inserts_per_commit.is_none() =>
+        errors.push(format!("ready id {} is at the front of the queue", id)),
+      _ => (),
+    }
+
+    for (blob_ref, key) in self.queued_refs.iter() {
+      let queued_ref = self.queue.find_value_of_key(&**key).and_then(|qe| qe.persistent_ref);
+      if self.queue.is_ready(&**key) != Some(true) || queued_ref.as_ref() != Some(blob_ref) {
+        errors.push(format!("reference {} of hash {} is not committed in the queue",
+                            blob_ref.to_hex(), key.to_hex()));
+      }
+    }
+
+    for hash_bytes in self.callbacks.keys().into_iter() {
+      if self.queue.find_key(hash_bytes).is_none() {
+        errors.push(format!("callback for hash {} that is not queued", hash_bytes.to_hex()));
+      }
+    }
+
+    // A warmed up index must not overlook committed hashes:
+    if let Some(ref bloom) = self.bloom {
+      self.backend.for_each_hash(&mut |hash_bytes| {
+        if !bloom.may_contain(hash_bytes) {
+          errors.push(format!("committed hash {} is not in the bloom filter", hash_bytes.to_hex()));
+        }
+      });
+    }
+
+    if errors.len() == 0 { Ok(()) } else { Err(errors) }
+  }
+
+  fn now(&self) -> SteadyTime {
+    (self.clock)()
+  }
+
+  fn trace_event(&mut self, hash_bytes: &[u8], kind: TraceKind) {
+    if self.trace.is_none() {
+      return;
+    }
+    let at_ms = (self.now() - self.opened_at).num_milliseconds();
+    self.trace.as_mut().expect("tracing").record(hash_bytes, kind, at_ms);
+  }
+
+  /// Append an event to the audit log of the backend, if it is enabled.
+  fn audit(&mut self, op: AuditOp, hash: &Hash, detail: &[u8]) {
+    if self.config.audit {
+      self.backend.append_audit(op, hash, detail);
+    }
+  }
+
+  /// Like `HashBackend::relocate_batch`, but with the audit log enabled, relocate the entries one
+  /// by one to audit each of them.
+  fn relocate_batch(&mut self, moves: Vec<(Hash, BlobRef)>) -> (usize, usize) {
+    if !self.config.audit {
+      return self.backend.relocate_batch(moves);
+    }
+    let mut updated = 0;
+    for &(ref hash, ref blob_ref) in moves.iter() {
+      if self.backend.relocate(hash, blob_ref) {
+        self.audit(AuditOp::Relocate, hash, &blob_ref.to_bytes()[..]);
+        updated += 1;
+      }
+    }
+    (updated, moves.len() - updated)
+  }
+
+  fn index_locate(&mut self, hash: &Hash) -> Result<Option<QueueEntry>, HashIndexError> {
+    match self.bloom {
+      Some(ref bloom) if !bloom.may_contain(&hash.bytes[..]) => return Ok(None),
+      _ => (),
+    }
+    let found = try!(self.backend.locate(hash));
+    Ok(found.map(|(id, entry)| hash_entry_to_queue_entry(id, entry, None).1))
+  }
+
+  fn locate(&mut self, hash: &Hash) -> Result<Option<QueueEntry>, HashIndexError> {
+    if self.sealed {
+      return self.index_locate(hash);
+    }
+    match self.queue.find_value_of_key(&hash.bytes) {
+      Some(queue_entry) => Ok(Some(queue_entry)),
+      None => self.index_locate(hash),
+    }
+  }
+
+  /// The reply to `Msg::FetchPersistentRef`.
+  fn persistent_ref_reply(&mut self, hash: &Hash) -> Reply {
+    match self.locate(hash) {
+      Ok(Some(ref queue_entry)) if queue_entry.persistent_ref.is_none() => Reply::Retry,
+      Ok(Some(queue_entry)) =>
+        Reply::PersistentRef(queue_entry.persistent_ref.expect("persistent_ref")),
+      Ok(None) => Reply::HashNotKnown,
+      Err(e) => Reply::Error(e),
+    }
+  }
+
+  /// Reply to a `Msg::AwaitPersistentRef` now if the persistent reference is known (or the `Hash`
+  /// is not), or else once it is committed.
+  fn await_persistent_ref(&mut self, hash: &Hash, timeout: Duration, reply: Box<Fn(Reply)>) {
+    match self.persistent_ref_reply(hash) {
+      Reply::Retry if timeout > Duration::zero() => {
+        let key = self.queue.find_stored_key(&hash.bytes).expect("hash is queued");
+        let deadline = self.now() + timeout;
+        match self.ref_waiters.entry(key) {
+          btree_map::Entry::Occupied(mut entry) => {
+            entry.get_mut().push((deadline, reply));
+          },
+          btree_map::Entry::Vacant(space) => {
+            space.insert(vec!((deadline, reply)));
+          },
+        }
+      },
+      Reply::Retry => reply(Reply::Timeout),
+      r => reply(r),
+    }
+  }
+
+  /// Reply to the waiters on `hash_bytes`, once its persistent reference is set or it is abandoned.
+  fn wake_ref_waiters<F: Fn() -> Reply>(&mut self, hash_bytes: &HashBytes, make_reply: F) {
+    if let Some(waiters) = self.ref_waiters.remove(hash_bytes) {
+      for (_deadline, reply) in waiters.into_iter() {
+        reply(make_reply());
+      }
+    }
+  }
+
+  /// Reply `Timeout` to the waiters whose deadline has passed.
+  fn expire_ref_waiters(&mut self) {
+    if self.ref_waiters.is_empty() {
+      return;
+    }
+    let now = self.now();
+    let mut expired = vec!();
+    for (_key, waiters) in self.ref_waiters.iter_mut() {
+      let (late, waiting): (Vec<_>, Vec<_>) = mem::replace(waiters, vec!()).into_iter()
+        .partition(|&(ref deadline, _)| *deadline <= now);
+      *waiters = waiting;
+      expired.extend(late.into_iter());
+    }
+    let emptied: Vec<HashKey> =
+      self.ref_waiters.iter().filter(|&(_, w)| w.is_empty()).map(|(k, _)| k.clone()).collect();
+    for key in emptied.iter() {
+      self.ref_waiters.remove(key);
+    }
+    for (_deadline, reply) in expired.into_iter() {
+      reply(Reply::Timeout);
+    }
+  }
+
+  /// Report a message that found the index in an unexpected state, or panic when failing fast.
+  fn internal_error(&self, what: String) -> Reply {
+    if self.config.fail_fast {
+      panic!("{}", what);
+    }
+    println!("Warning: Hash index: {}", what);
+    Reply::InternalError(what)
+  }
+
+  /// Check everything that `msg` carries (its hashes, and the payload of its entry) before it is
+  /// handled, so that malformed input is refused with a reply instead of reaching an assertion.
+  /// Returns the reply to refuse the message with.
+  fn validate_msg(&self, msg: &Msg) -> Result<(), Reply> {
+    let hashes: Vec<&Hash> = match *msg {
+      Msg::FilterUnknown(ref hashes) |
+      Msg::CallAfterAllCommitted(ref hashes, _) |
+      Msg::CallAfterEachCommitted(ref hashes, _) => hashes.iter().collect(),
+      Msg::BatchRelocate(ref moves) => moves.iter().map(|&(ref hash, _)| hash).collect(),
+      _ => msg_hash(msg).into_iter().collect(),
+    };
+    for hash in hashes.into_iter() {
+      try!(hash.validate(self.config.digest_width).map_err(Reply::InvalidHash));
+    }
+    match msg_entry(msg).and_then(|entry| entry.payload.as_ref()) {
+      Some(payload) if payload.len() > self.config.max_payload =>
+        return Err(Reply::PayloadTooLarge(payload.len() as u64)),
+      _ => (),
+    }
+    if self.config.strict_tree {
+      try!(check_tree_shape(msg).map_err(Reply::InvalidEntry));
+    }
+    Ok(())
+  }
+
+  /// Find a queued entry by its id, trying each rank that entries were queued at.
+  fn queued_by_id(&self, id: i64) -> Option<HashEntry> {
+    for &rank in self.queue_ranks.iter() {
+      match self.queue.find_priority(&QueuePriority{rank: rank, id: id}) {
+        Some((hash_bytes, Some(ref qe))) if qe.id == id =>
+          return Some(queue_entry_to_hash_entry(Hash{bytes: (*hash_bytes).clone()}, qe.clone())),
+        _ => (),
+      }
+    }
+    None
+  }
+
+  /// Compare the metadata of a known entry with a new entry for the same hash.
+  fn same_content(&mut self, known: &QueueEntry, entry: &HashEntry)
+                  -> Result<bool, HashIndexError> {
+    let known_len = match known.payload {
+      Some(ref p) => p.len(),
+      None => try!(self.backend.spilled_payload(known.id)).map(|p| p.len()).unwrap_or(0),
+    };
+    let new_len = entry.payload.as_ref().map(|p| p.len()).unwrap_or(0);
+    Ok(known.level == entry.level && known_len == new_len)
+  }
+
+  fn refresh_id_counter(&mut self) {
+    let id = self.backend.max_id();
+    self.id_counter = CumulativeCounter::new(id);
+  }
+
+  /// The next unused id, or `IdsExhausted` once all ids are used.
+  fn next_id(&mut self) -> Result<i64, HashIndexError> {
+    // Reusing or wrapping ids would corrupt the order of the index, so stop instead:
+    self.id_counter.next().map_err(|_| HashIndexError::IdsExhausted)
+  }
+
+  #[cfg(test)]
+  fn reserve(&mut self, hash_entry: HashEntry) -> i64 {
+    match self.reserve_at(hash_entry, false) {
+      Ok(id) => id,
+      Err(_) => panic!("The hash index has used all ids."),
+    }
+  }
+
+  /// Returns the id of the reserved entry, or the reply to a hash that is already known.
+  fn reserve_unless_known(&mut self, hash_entry: HashEntry, prioritized: bool)
+                          -> Result<i64, Reply> {
+    let known = match self.locate(&hash_entry.hash) {
+      Ok(known) => known,
+      // Reserving a hash that may be known would store it twice:
+      Err(e) => return Err(Reply::Error(e)),
+    };
+    let collides = match known {
+      Some(ref known) if self.config.verify_collisions => {
+        match self.same_content(known, &hash_entry) {
+          Ok(same) => !same,
+          Err(e) => return Err(Reply::Error(e)),
+        }
+      },
+      _ => false,
+    };
+    match known {
+      Some(ref known) if self.config.strict_levels && known.level != hash_entry.level =>
+        Err(Reply::LevelMismatch{existing: known.level, requested: hash_entry.level}),
+      Some(_) if collides => Err(Reply::CollisionSuspected(hash_entry.hash)),
+      // Reserved but not yet inserted, so not safe to reference yet:
+      Some(_) if self.queue.find_key(&hash_entry.hash.bytes).is_some() =>
+        Err(Reply::AlreadyReserved),
+      Some(_) => Err(Reply::HashKnown),
+      None => self.reserve_at(hash_entry, prioritized),
+    }
+  }
+
+  /// Queue an entry under the next id. Prioritized entries are queued ahead of all others.
+  /// Returns the id, or the reply to refuse the entry with if no ids are left.
+  fn reserve_at(&mut self, hash_entry: HashEntry, prioritized: bool) -> Result<i64, Reply> {
+    self.maybe_flush();
+
+    debug_assert!(hash_entry.hash.bytes.len() > 0);
+    let my_id = try!(self.next_id().map_err(Reply::Error));
+    self.audit(AuditOp::Reserve, &hash_entry.hash, &[]);
+
+    let now = self.now();
+    let (hash, queue_entry) = hash_entry_to_queue_entry(my_id, hash_entry, Some(now));
+    let queue_entry = self.spill_payload(queue_entry);
+
+    let rank = if prioritized { PRIORITIZED_RANK }
+               else { self.config.queue_order.rank(queue_entry.level) };
+    self.queue_ranks.insert(rank);
+    let priority = QueuePriority{rank: rank, id: my_id};
+    let key = Rc::new(hash.bytes);
+    assert!(self.queue.reserve_priority(priority, key.clone()).is_ok());
+    self.trace_event(&key, TraceKind::Reserved);
+    self.queue.put_value(key, queue_entry);
+    Ok(my_id)
+  }
+
+  /// Move a committed entry back into the queue under its own id, with its persistent reference
+  /// cleared (see `Msg::Uncommit`). Nothing changes if its spilled payload cannot be read.
+  fn uncommit(&mut self, id: i64, mut entry: HashEntry) -> Result<(), HashIndexError> {
+    // The spilled payload is deleted along with the row, and spilled again for the queue:
+    if entry.payload.is_none() {
+      entry.payload = try!(self.backend.spilled_payload(id));
+    }
+    entry.persistent_ref = None;
+    assert!(self.backend.delete(&entry.hash));
+    self.audit(AuditOp::Uncommit, &entry.hash, &[]);
+    self.uncommitted_writes += 1;
+
+    let now = self.now();
+    let (hash, queue_entry) = hash_entry_to_queue_entry(id, entry, Some(now));
+    let queue_entry = self.spill_payload(queue_entry);
+    self.queue_ranks.insert(UNCOMMITTED_RANK);
+    let key = Rc::new(hash.bytes);
+    let priority = QueuePriority{rank: UNCOMMITTED_RANK, id: id};
+    assert!(self.queue.reserve_priority(priority, key.clone()).is_ok());
+    self.trace_event(&key, TraceKind::Reserved);
+    self.queue.put_value(key, queue_entry);
+    Ok(())
+  }
+
+  fn update_reserved(&mut self, hash_entry: HashEntry) {
+    let hash = hash_entry.hash.clone();
+    debug_assert!(hash.bytes.len() > 0);
+
+    // If we didn't already commit and pop() the hash, update it (this also refreshes its TTL):
+    if let Some(old) = self.queue.find_value_of_key(&hash.bytes) {
+      if old.spilled {
+        self.backend.drop_spilled(old.id);
+      }
+      let now = self.now();
+      let (_, updated) = hash_entry_to_queue_entry(old.id, hash_entry.clone(), Some(now));
+      let updated = self.spill_payload(QueueEntry{queued_at: old.queued_at, ..updated});
+      self.queue.update_value(&hash.bytes, |_| updated.clone());
+      self.trace_event(&hash.bytes, TraceKind::Updated);
+      // `FetchPersistentRef` replies with the new reference from now on, so waiters get it too:
+      if let Some(ref blob_ref) = hash_entry.persistent_ref {
+        self.wake_ref_waiters(&hash.bytes, || Reply::PersistentRef(blob_ref.clone()));
+      }
+    }
+  }
+
+  /// Hand the payload of a queued entry to the backend if it is too large to keep in the queue
+  /// (see `IndexConfig::max_inline_payload`).
+  fn spill_payload(&mut self, mut queue_entry: QueueEntry) -> QueueEntry {
+    if let Some(payload) = queue_entry.payload.take() {
+      queue_entry.payload = self.backend.spill_payload(queue_entry.id, queue_entry.level, payload);
+      queue_entry.spilled = queue_entry.payload.is_none();
+    }
+    queue_entry
+  }
+
+  fn register_hash_callback(&mut self, hash: &Hash, callback: Thunk<'static>) -> Reply {
+    debug_assert!(hash.bytes.len() > 0);
+
+    if let Some(key) = self.queue.find_stored_key(&hash.bytes) {
+      if !self.make_room_for_callbacks(1) {
+        return Reply::CallbackLimitReached;
+      }
+      Reply::CallbackRegistered(self.callbacks.add(key, callback))
+    } else {
+      match self.locate(hash) {
+        // Hash was already committed
+        Ok(Some(_)) => Reply::CallbackRegistered(self.callbacks.call_now(callback)),
+        // We cannot register this callback, since the hash doesn't exist anywhere
+        Ok(None) => Reply::HashNotKnown,
+        Err(e) => Reply::Error(e),
+      }
+    }
+  }
+
+  /// Apply the callback limit before registering `count` more waiting callbacks.
+  /// Returns false if they must be rejected. Evicting makes room even for more than
+  /// `max_callbacks` callbacks at once, by dropping all others.
+  fn make_room_for_callbacks(&mut self, count: usize) -> bool {
+    let max = match self.config.max_callbacks {
+      Some(max) => max,
+      None => return true,
+    };
+    match self.config.callback_limit_policy {
+      CallbackLimitPolicy::Reject => self.callbacks.pending() + count <= max,
+      CallbackLimitPolicy::EvictOldest => {
+        while self.callbacks.pending() + count > max && self.callbacks.evict_oldest() {}
+        true
+      },
+    }
+  }
+
+  /// Insert committed entries in priority order, for as long as the entry with the lowest priority
+  /// is ready. This is id order, except that prioritized entries come first (see
+  /// `Msg::ReservePrioritized`) and that other `QueueOrder`s may put later ids first, so the
+  /// backend may receive ids out of order.
+  /// Priorities are not required to be contiguous: gaps left by abandoned entries are simply
+  /// skipped, as the queue only ever waits for the lowest priority that is still present.
+  fn insert_completed_in_order(&mut self) {
+    self.drain_ready(None);
+  }
+
+  /// Like `insert_completed_in_order`, but insert at most `max` entries.
+  fn drain_ready(&mut self, max: Option<usize>) {
+    let mut completed = vec!();
+    let mut spilled = BTreeSet::new();
+    while max.map(|max| completed.len() < max).unwrap_or(true) {
+      match self.queue.pop_min_if_complete() {
+        None => break,
+        Some((_priority, hash_bytes, queue_entry)) => {
+          let id = queue_entry.id;
+          let mark = self.callbacks.ready_mark();
+          self.callbacks.allow_flush_of(&hash_bytes);
+          if self.trace.is_some() {
+            self.trace_event(&hash_bytes, TraceKind::Inserted);
+            if self.callbacks.ready_mark() > mark {
+              self.fired_pending.push((mark, hash_bytes.clone()));
+            }
+          }
+          if queue_entry.spilled {
+            spilled.insert(id);
+          }
+          if let Some(ref blob_ref) = queue_entry.persistent_ref {
+            self.queued_refs.remove(blob_ref);
+          }
+          let hash = Hash{bytes: (*hash_bytes).clone()};
+          completed.push((id, queue_entry_to_hash_entry(hash, queue_entry)));
+        },
+      }
+    }
+    if completed.len() > 0 {
+      self.uncommitted_writes += completed.len() as u64;
+      for &(_, ref entry) in completed.iter() {
+        self.audit(AuditOp::Commit, &entry.hash,
+                   entry.persistent_ref.as_ref().map(|r| &r[..]).unwrap_or(&[]));
+      }
+      if let Some(ref mut bloom) = self.bloom {
+        for &(_, ref entry) in completed.iter() {
+          bloom.insert(&entry.hash.bytes[..]);
+        }
+      }
+      let replicated = if self.config.replication.is_some() { completed.clone() } else { vec!() };
+      self.backend.insert_batch(completed);
+      for (id, mut entry) in replicated.into_iter() {
+        // Spilled payloads are read back one at a time, rather than all kept until here:
+        if spilled.contains(&id) {
+          match self.backend.spilled_payload(id) {
+            Ok(payload) => entry.payload = payload,
+            Err(e) => {
+              self.internal_error(format!("payload {} that was just spilled cannot be read: {:?}",
+                                          id, e));
+            },
+          }
+        }
+        if let Some(ref replication) = self.config.replication {
+          replication.send(&entry);
+        }
+      }
+    }
+  }
+
+  fn commit(&mut self, hash: &Hash, blob_ref: &Vec<u8>) {
+    // Update persistent reference for ready hash
+    let priority = *self.queue.find_key(&hash.bytes).expect("hash was reserved");
+    let queued_at = self.queue.find_value_of_key(&hash.bytes).and_then(|qe| qe.queued_at);
+    match queued_at {
+      Some(t) => {
+        let wait = (self.now() - t).num_milliseconds();
+        self.queue_waits.record(if wait > 0 { wait as u64 } else { 0 });
+      },
+      None => (),
+    }
+    if self.savepoints.len() > 0 {
+      let key = self.queue.find_stored_key(&hash.bytes).expect("hash was reserved");
+      let old_ref = self.queue.find_value_of_key(&hash.bytes).and_then(|qe| qe.persistent_ref);
+      self.savepoint_commits.push((key, old_ref));
+    }
+    self.queue.update_value(&hash.bytes,
+                            |old_qe| QueueEntry{persistent_ref: Some(blob_ref.clone()),
+                                                ..old_qe.clone()});
+    self.queue.set_ready(priority);
+    if self.config.strict_refs {
+      let key = self.queue.find_stored_key(&hash.bytes).expect("hash was reserved");
+      self.queued_refs.insert(blob_ref.clone(), key);
+    }
+    self.trace_event(&hash.bytes, TraceKind::Committed);
+    self.wake_ref_waiters(&hash.bytes, || Reply::PersistentRef(blob_ref.clone()));
+
+    let max = self.config.max_inserts_per_commit;
+    self.drain_ready(max);
+
+    self.maybe_flush();
+    self.note_activity();
+  }
+
+  /// Reset the debounce timer of the flush.
+  fn note_activity(&mut self) {
+    if self.config.flush_quiet_period.is_none() {
+      return;
+    }
+    let now = self.now();
+    self.last_activity = Some(now);
+    if self.pending_since.is_none() && self.uncommitted_writes > 0 {
+      self.pending_since = Some(now);
+    }
+  }
+
+  /// Whether written rows wait for a debounced flush (see `IndexConfig::flush_quiet_period`), which
+  /// an idle index checks for on its own.
+  fn flush_is_debounced(&self) -> bool {
+    self.config.flush_quiet_period.is_some() && self.uncommitted_writes > 0 &&
+      self.savepoints.len() == 0 && !self.idle_flush_failed
+  }
+
+  /// Whether the next message that commits would flush (see `Msg::FlushDue`). This does not
+  /// restart the flush timer.
+  fn flush_is_due(&self) -> bool {
+    let quiet = match self.config.flush_quiet_period {
+      Some(quiet) => quiet,
+      None => return self.flush_timer.is_due(),
+    };
+    // Nothing to commit, so do not bother:
+    if self.uncommitted_writes == 0 {
+      return false;
+    }
+    let now = self.now();
+    self.last_activity.map(|t| now - t >= quiet).unwrap_or(false) ||
+      self.pending_since.map(|t| now - t >= self.config.flush_max_delay).unwrap_or(false)
+  }
+
+  fn expire_stale_reserves(&mut self) -> usize {
+    let ttl = match self.config.reserve_ttl {
+      Some(ttl) => ttl,
+      None => return 0,
+    };
+    let now = self.now();
+    let stale = self.queue.find_pending(
+      |qe| qe.reserved_at.map(|t| now - t >= ttl).unwrap_or(false));
+
+    for hash_bytes in stale.iter() {
+      self.abandon(hash_bytes);
+    }
+    stale.len()
+  }
+
+  /// Drop a reserved entry that is not yet committed, leaving a gap in the ids.
+  fn abandon(&mut self, hash_bytes: &HashBytes) -> bool {
+    match self.queue.remove_pending(hash_bytes) {
+      None => return false,
+      Some((_, Some(ref qe))) if qe.spilled => self.backend.drop_spilled(qe.id),
+      Some(_) => (),
+    }
+    self.callbacks.remove(hash_bytes);
+    self.wake_ref_waiters(hash_bytes, || Reply::HashNotKnown);
+    self.trace_event(hash_bytes, TraceKind::Abandoned);
+
+    // The abandoned entry may have been blocking entries that are ready for insertion:
+    self.insert_completed_in_order();
+    true
+  }
+
+  fn maybe_flush(&mut self) {
+    // Committing would end the open savepoints:
+    if self.savepoints.len() > 0 {
+      return;
+    }
+    if self.flush_is_due() {
+      if self.config.flush_quiet_period.is_none() {
+        self.flush_timer.did_fire();
+      }
+      self.expire_stale_reserves();
+
+      // A busy database or a full disk is not fatal here: everything stays in the open
+      // transaction and is committed by the next flush.
+      let _ = self.flush();
+    }
+  }
+
+  fn mark_committed(&mut self) {
+    self.uncommitted_writes = 0;
+    self.last_commit = self.now();
+    self.pending_since = None;
+  }
+
+  fn savepoint_position(&self, name: &str) -> Result<usize, HashIndexError> {
+    self.savepoints.iter().rposition(|&(ref n, _, _, _)| n == name)
+      .ok_or_else(|| HashIndexError::UnknownSavepoint(name.to_string()))
+  }
+
+  fn release_savepoint(&mut self, name: &str) -> Result<(), HashIndexError> {
+    let pos = try!(self.savepoint_position(name));
+    self.backend.release_savepoint(name);
+    self.savepoints.truncate(pos);
+    if self.savepoints.len() == 0 {
+      self.savepoint_commits.clear();
+    }
+    Ok(())
+  }
+
+  fn rollback_to_savepoint(&mut self, name: &str) -> Result<(), HashIndexError> {
+    let pos = try!(self.savepoint_position(name));
+    self.backend.rollback_to_savepoint(name);
+    self.savepoints.truncate(pos + 1);
+
+    let (_, writes, ready_mark, commits) = self.savepoints[pos].clone();
+    self.uncommitted_writes = writes;
+    self.callbacks.drop_ready_since(ready_mark);
+    self.fired_pending.retain(|&(mark, _)| mark < ready_mark);
+
+    // Entries that were inserted since are gone from the queue, the others wait for a commit:
+    while self.savepoint_commits.len() > commits {
+      let (key, old_ref) = self.savepoint_commits.pop().expect("commit since the savepoint");
+      if self.queue.is_ready(&*key) == Some(true) {
+        let priority = *self.queue.find_key(&*key).expect("queued");
+        let committed_ref = self.queue.find_value_of_key(&*key).and_then(|qe| qe.persistent_ref);
+        if let Some(blob_ref) = committed_ref {
+          self.queued_refs.remove(&blob_ref);
+        }
+        self.queue.update_value(&*key, |qe| QueueEntry{persistent_ref: old_ref.clone(),
+                                                       ..qe.clone()});
+        self.queue.set_pending(priority);
+      }
+    }
+    Ok(())
+  }
+
+  /// Forget the open savepoints, which the backend has ended.
+  fn end_savepoints(&mut self) {
+    self.savepoints.clear();
+    self.savepoint_commits.clear();
+  }
+
+  fn flush(&mut self) -> Result<(), HashIndexError> {
+    // Insert entries left behind by a limited `Commit`:
+    self.insert_completed_in_order();
+
+    // The backend ends all savepoints when committing, even if the commit fails:
+    self.end_savepoints();
+
+    // Callbacks assume their data is safe, so commit before calling them
+    try!(self.backend.commit_txn());
+    self.mark_committed();
+
+    // Run ready callbacks
+    self.run_ready_callbacks();
+    Ok(())
+  }
+
+  fn run_ready_callbacks(&mut self) {
+    let left = match self.config.max_callbacks_per_batch {
+      Some(max) => self.callbacks.flush_some(max),
+      None => { self.callbacks.flush(); 0 },
+    };
+    if left > 0 {
+      // Trace the callbacks once the last batch has run:
+      return;
+    }
+    for (_, hash_bytes) in mem::replace(&mut self.fired_pending, vec!()).into_iter() {
+      self.trace_event(&hash_bytes, TraceKind::CallbackFired);
+    }
+  }
+
+  fn barrier(&mut self) -> Result<(), HashIndexError> {
+    self.insert_completed_in_order();
+    self.end_savepoints();
+    try!(self.backend.barrier());
+    self.mark_committed();
+
+    self.run_ready_callbacks();
+    Ok(())
+  }
+
+  fn checkpoint(&mut self) -> Result<(u64, u64), HashIndexError> {
+    self.insert_completed_in_order();
+    self.end_savepoints();
+    let pages = try!(self.backend.checkpoint());
+    self.mark_committed();
+
+    self.run_ready_callbacks();
+    Ok(pages)
+  }
+
+  /// Returns the number of leaves, or `BrokenTree` or `Error` if the walk failed.
+  fn leaves_of(&mut self, root: Hash, sink: Box<Fn(BlobRef) + Send>) -> Result<u64, Reply> {
+    let mut leaf_count = 0;
+    let mut visited = 0;
+    // The nodes still to visit, with the next one last:
+    let mut pending = vec!(root.clone());
+    while let Some(hash) = pending.pop() {
+      visited += 1;
+      if visited > self.config.max_tree_nodes {
+        return Err(Reply::BrokenTree(TreeError::TooLarge(root)));
+      }
+      let node = match self.locate(&hash) {
+        Ok(Some(node)) => node,
+        Ok(None) => return Err(Reply::BrokenTree(TreeError::UnknownHash(hash))),
+        Err(e) => return Err(Reply::Error(e)),
+      };
+      if node.level > 0 {
+        // The reference of a branch need not be structured, but if it is, it must not be leaf data:
+        match node.persistent_ref.as_ref().and_then(|r| BlobRef::from_bytes(&r[..])) {
+          Some(BlobRef{kind: RefKind::Leaf, ..}) =>
+            return Err(Reply::BrokenTree(TreeError::WrongRefKind(hash))),
+          _ => (),
+        }
+        let children = try!(self.children_of(hash, node.id, node.payload));
+        pending.extend(children.into_iter().rev());
+      } else {
+        let persistent_ref = match node.persistent_ref {
+          Some(r) => r,
+          None => return Err(Reply::BrokenTree(TreeError::NotCommitted(hash))),
+        };
+        match BlobRef::from_bytes(&persistent_ref[..]) {
+          Some(BlobRef{kind: RefKind::Branch, ..}) =>
+            return Err(Reply::BrokenTree(TreeError::WrongRefKind(hash))),
+          Some(blob_ref) => sink(blob_ref),
+          None => return Err(Reply::BrokenTree(TreeError::InvalidRef(hash))),
+        }
+        leaf_count += 1;
+      }
+    }
+    Ok(leaf_count)
+  }
+
+  /// Release spare capacity (see `Msg::TrimMemory`). Returns an estimate of the released bytes.
+  fn trim_memory(&mut self) -> usize {
+    let mut freed = self.callbacks.shrink();
+    self.queue.shrink();
+    freed += (self.fired_pending.capacity() - self.fired_pending.len()) *
+      mem::size_of::<(u64, HashKey)>();
+    self.fired_pending.shrink_to_fit();
+    for waiters in self.ref_waiters.values_mut() {
+      freed += (waiters.capacity() - waiters.len()) *
+        mem::size_of::<(SteadyTime, Box<Fn(Reply)>)>();
+      waiters.shrink_to_fit();
+    }
+    freed
+  }
+
+  /// The children of the branch `hash`, decoded from its inline `payload` or from the spilled
+  /// payload of entry `id`.
+  fn children_of(&mut self, hash: Hash, id: i64, payload: Option<Vec<u8>>)
+                 -> Result<Vec<Hash>, Reply> {
+    let payload = match payload {
+      Some(payload) => Some(payload),
+      None => try!(self.backend.spilled_payload(id).map_err(Reply::Error)),
+    };
+    match payload.as_ref().map(|p| decode_children(&p[..])) {
+      Some(Ok(children)) => Ok(children),
+      _ => Err(Reply::BrokenTree(TreeError::InvalidPayload(hash))),
+    }
+  }
+
+  fn tree_stats(&mut self, root: Hash) -> Result<Reply, Reply> {
+    let (mut depth, mut leaves, mut branches, mut children_total) = (0, 0, 0, 0);
+    let mut visited = 0;
+    // The nodes still to visit, with their depth:
+    let mut pending = vec!((root.clone(), 1));
+    while let Some((hash, node_depth)) = pending.pop() {
+      visited += 1;
+      if visited > self.config.max_tree_nodes {
+        return Err(Reply::BrokenTree(TreeError::TooLarge(root)));
+      }
+      if node_depth > depth {
+        depth = node_depth;
+      }
+      let node = match self.locate(&hash) {
+        Ok(Some(node)) => node,
+        Ok(None) => return Err(Reply::BrokenTree(TreeError::UnknownHash(hash))),
+        Err(e) => return Err(Reply::Error(e)),
+      };
+      if node.level > 0 {
+        let children = try!(self.children_of(hash, node.id, node.payload));
+        branches += 1;
+        children_total += children.len() as u64;
+        pending.extend(children.into_iter().map(|child| (child, node_depth + 1)));
+      } else {
+        leaves += 1;
+      }
+    }
+    let avg_fanout = if branches == 0 { 0.0 } else { children_total as f64 / branches as f64 };
+    Ok(Reply::TreeStats{depth: depth, leaves: leaves, branches: branches, avg_fanout: avg_fanout})
+  }
+
+  /// Clear an interrupt that was set before an enumeration starts, as it was not meant for it.
+  fn start_enumeration(&self) {
+    match self.config.interrupt {
+      Some(ref interrupt) => { interrupt.take(); },
+      None => (),
+    }
+  }
+
+  /// The reply to an enumeration that finished with `reply`, or `Interrupted` if it was stopped
+  /// early. This clears the interrupt, so an interrupt that arrives as the enumeration finishes
+  /// may still discard its reply.
+  fn unless_interrupted(&self, reply: Reply) -> Reply {
+    match self.config.interrupt {
+      Some(ref interrupt) if interrupt.take() => Reply::Interrupted,
+      _ => reply,
+    }
+  }
+
+  fn find_orphans(&mut self, exists: Box<Fn(&BlobRef) -> bool + Send>)
+                  -> Result<Vec<Hash>, HashIndexError> {
+    let orphans = RefCell::new(vec!());
+    let check = |batch: Vec<HashEntry>| {
+      for entry in batch.into_iter() {
+        let blob_ref = entry.persistent_ref.as_ref().and_then(|r| BlobRef::from_bytes(&r[..]));
+        match blob_ref {
+          Some(ref blob_ref) if !exists(blob_ref) => orphans.borrow_mut().push(entry.hash),
+          _ => (),
+        }
+      }
+    };
+    self.start_enumeration();
+    let (rest, _) = try!(self.backend.stream(false, 0, self.config.interrupt.as_ref(),
+                                             &|batch, _| check(batch)));
+    check(rest);
+    Ok(orphans.into_inner())
+  }
+}
+
+// #[unsafe_desctructor]
+// impl  Drop for HashIndex {
+//   fn drop(&mut self) {
+//     self.flush();
+
+//     assert_eq!(self.callbacks.len(), 0);
+
+//     assert_eq!(self.queue.len(), 0);
+//     self.exec_or_die("COMMIT");
+//   }
+// }
+
+
+/// The range of hashes that start with the hex digits `prefix`: the lowest such hash, and the
+/// lowest hash after them (or `None` if there is none). Returns `None` if `prefix` is not hex.
+fn prefix_range(prefix: &str) -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+  let mut nibbles = vec!();
+  for c in prefix.chars() {
+    match c.to_digit(16) {
+      Some(n) => nibbles.push(n as u8),
+      None => return None,
+    }
+  }
+  // An odd number of digits covers the byte of the last digit from its lowest to highest value:
+  let low: Vec<u8> = nibbles.chunks(2)
+    .map(|pair| (pair[0] << 4) | pair.get(1).cloned().unwrap_or(0))
+    .collect();
+  let mut high = low.clone();
+  let step = if nibbles.len() % 2 == 1 { 0x10 } else { 1 };
+  // Increment the prefix, carrying into earlier bytes:
+  let mut carry = step as u16;
+  for b in high.iter_mut().rev() {
+    let sum = *b as u16 + carry;
+    *b = sum as u8;
+    carry = sum >> 8;
+    if carry == 0 {
+      break;
+    }
+  }
+  Some((low, if carry == 0 { Some(high) } else { None }))
+}
+
+/// The `Hash` that a message refers to, if any.
+fn msg_hash<'a>(msg: &'a Msg) -> Option<&'a Hash> {
+  match *msg {
+    Msg::HashExists(ref hash) |
+    Msg::FetchPayload(ref hash) |
+    Msg::FetchPersistentRef(ref hash) |
+    Msg::AwaitPersistentRef(ref hash, _) |
+    Msg::FetchLength(ref hash) |
+    Msg::FetchId(ref hash) |
+    Msg::TraceFor(ref hash) |
+    Msg::LeavesOf(ref hash, _) |
+    Msg::TreeStats(ref hash) |
+    Msg::SetMeta(ref hash, _, _) |
+    Msg::GetMeta(ref hash, _) |
+    Msg::Commit(ref hash, _) |
+    Msg::CallAfterHashIsComitted(ref hash, _) |
+    Msg::Abandon(ref hash) |
+    Msg::Uncommit(ref hash) |
+    Msg::Relocate(ref hash, _) => Some(hash),
+    Msg::Reserve(ref hash_entry) |
+    Msg::ReservePrioritized(ref hash_entry) |
+    Msg::ReserveReturningId(ref hash_entry) |
+    Msg::ReserveOrFetch(ref hash_entry) |
+    Msg::UpdateReserved(ref hash_entry) => Some(&hash_entry.hash),
+    _ => None,
+  }
+}
+
+/// The `HashEntry` that a message carries, if any.
+fn msg_entry<'a>(msg: &'a Msg) -> Option<&'a HashEntry> {
+  match *msg {
+    Msg::Reserve(ref hash_entry) |
+    Msg::ReservePrioritized(ref hash_entry) |
+    Msg::ReserveReturningId(ref hash_entry) |
+    Msg::ReserveOrFetch(ref hash_entry) |
+    Msg::UpdateReserved(ref hash_entry) => Some(hash_entry),
+    _ => None,
+  }
+}
+
+/// Check that the entry or reference that a message carries fits a hash tree (see
+/// `IndexConfig::strict_tree`). Returns what is wrong with it.
+fn check_tree_shape(msg: &Msg) -> Result<(), String> {
+  match *msg {
+    Msg::Commit(ref hash, ref persistent_ref) if persistent_ref.is_empty() =>
+      return Err(format!("hash {} is committed without a persistent reference",
+                         hash.bytes.to_hex())),
+    _ => (),
+  }
+  match msg_entry(msg) {
+    Some(e) if e.level == 0 && e.payload.is_some() =>
+      Err(format!("leaf {} has a payload", e.hash.bytes.to_hex())),
+    Some(e) if e.level > 0 && e.payload.is_none() =>
+      Err(format!("branch {} at level {} has no payload", e.hash.bytes.to_hex(), e.level)),
+    Some(e) if e.persistent_ref.as_ref().map_or(false, |r| r.is_empty()) =>
+      Err(format!("hash {} has an empty persistent reference", e.hash.bytes.to_hex())),
+    _ => Ok(()),
+  }
+}
+
+impl <B: HashBackend> MsgHandler<Msg, Reply> for HashIndex<B> {
+  fn handle(&mut self, msg: Msg, reply: Box<Fn(Reply)>) {
+    match self.validate_msg(&msg) {
+      Err(r) => return reply(r),
+      Ok(()) => (),
+    }
+    self.idle_flush_failed = false;
+    self.expire_ref_waiters();
+    if self.callbacks.ready_len() > 0 {
+      self.run_ready_callbacks();
+    }
+    if self.draining || self.sealed {
+      match msg {
+        Msg::Reserve(_) | Msg::ReservePrioritized(_) | Msg::ReserveReturningId(_) |
+        Msg::ReserveOrFetch(_) | Msg::UpdateReserved(_) | Msg::Uncommit(_) =>
+          return reply(if self.sealed { Reply::Sealed } else { Reply::ShuttingDown }),
+        _ => (),
+      }
+    }
+
+    match msg {
+
+      Msg::Tagged(id, msg) => {
+        return self.handle(*msg, Box::new(move|r| reply(Reply::Tagged(id, Box::new(r)))));
+      },
+
+      Msg::HashExists(hash) => {
+        return reply(match self.locate(&hash) {
+          Ok(Some(_)) => Reply::HashKnown,
+          Ok(None) => Reply::HashNotKnown,
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::FilterUnknown(hashes) => {
+        // Most hashes are usually committed, so ask the backend for all of them at once:
+        let known = self.backend.known(&hashes[..]);
+        let sealed = self.sealed;
+        let unknown = hashes.into_iter()
+          .filter(|h| !known.contains(&h.bytes[..]) &&
+                      (sealed || self.queue.find_value_of_key(&h.bytes).is_none()))
+          .collect();
+        return reply(Reply::Unknown(unknown));
+      },
+
+      Msg::FetchLength(hash) => {
+        return reply(match self.locate(&hash) {
+          Ok(Some(queue_entry)) => Reply::Length(queue_entry.content_len),
+          Ok(None) => Reply::HashNotKnown,
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::FetchPayload(hash) => {
+        return reply(match self.locate(&hash) {
+          Ok(Some(ref queue_entry)) if queue_entry.payload.is_none() => {
+            match self.backend.spilled_payload(queue_entry.id) {
+              Ok(payload) => Reply::Payload(payload),
+              Err(e) => Reply::Error(e),
+            }
+          },
+          Ok(Some(ref queue_entry)) => Reply::Payload(queue_entry.payload.clone()),
+          Ok(None) => Reply::HashNotKnown,
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::FetchId(hash) => {
+        return reply(match self.locate(&hash) {
+          Ok(Some(queue_entry)) => Reply::Id(queue_entry.id),
+          Ok(None) => Reply::HashNotKnown,
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::FetchById(id) => {
+        let entry_res = match self.queued_by_id(id) {
+          Some(entry) => Ok(Some(entry)),
+          None => self.backend.locate_id(id),
+        };
+        return reply(match entry_res {
+          Ok(Some(mut entry)) => {
+            if entry.payload.is_none() {
+              match self.backend.spilled_payload(id) {
+                Ok(payload) => entry.payload = payload,
+                Err(e) => return reply(Reply::Error(e)),
+              }
+            }
+            Reply::Entry(entry)
+          },
+          Ok(None) => Reply::HashNotKnown,
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::FetchPersistentRef(hash) => {
+        return reply(self.persistent_ref_reply(&hash));
+      },
+
+      Msg::ResolvePrefix(prefix) => {
+        let (low, high) = match prefix_range(&prefix[..]) {
+          Some(range) => range,
+          None => return reply(Reply::InvalidHash(HashError::NotHex)),
+        };
+        let max = self.config.max_prefix_matches;
+        let high = high.as_ref().map(|h| &h[..]);
+        // Reading one more than the cap tells a unique match from an ambiguous one, also for a
+        // cap of zero:
+        let (mut hashes, total) = self.backend.hashes_in_range(&low[..], high, max + 1);
+        if total == 1 {
+          return reply(Reply::Resolved(hashes.pop().expect("one match")));
+        } else if total == 0 {
+          return reply(Reply::HashNotKnown);
+        }
+        hashes.truncate(max);
+        return reply(Reply::ResolvedAmbiguous{hashes: hashes, total: total,
+                                              too_many: total > max as u64});
+      },
+
+      Msg::AwaitPersistentRef(hash, timeout) => {
+        return self.await_persistent_ref(&hash, timeout, reply);
+      },
+
+      Msg::Reserve(hash_entry) => {
+        // To avoid unused IO, we store entries in-memory until committed to persistent storage.
+        // This allows us to continue after a crash without needing to scan through and delete
+        // uncommitted entries.
+        return reply(self.reserve_unless_known(hash_entry, false)
+                         .map(|_| Reply::ReserveOK).unwrap_or_else(|r| r));
+      },
+
+      Msg::ReservePrioritized(hash_entry) => {
+        return reply(self.reserve_unless_known(hash_entry, true)
+                         .map(|_| Reply::ReserveOK).unwrap_or_else(|r| r));
+      },
+
+      Msg::ReserveReturningId(hash_entry) => {
+        return reply(self.reserve_unless_known(hash_entry, false)
+                         .map(Reply::Reserved).unwrap_or_else(|r| r));
+      },
+
+      Msg::ReserveOrFetch(hash_entry) => {
+        let hash = hash_entry.hash.clone();
+        return reply(match self.reserve_unless_known(hash_entry, false) {
+          Ok(id) => Reply::Reserved(id),
+          Err(Reply::HashKnown) | Err(Reply::AlreadyReserved) => self.persistent_ref_reply(&hash),
+          Err(r) => r,
+        });
+      },
+
+      Msg::UpdateReserved(hash_entry) => {
+        match self.locate(&hash_entry.hash) {
+          Ok(Some(_)) => (),
+          Ok(None) => return reply(self.internal_error(
+            format!("update of hash {} that was not reserved", hash_entry.hash.bytes.to_hex()))),
+          Err(e) => return reply(Reply::Error(e)),
+        }
+        self.update_reserved(hash_entry);
+        return reply(Reply::ReserveOK);
+      }
+
+      Msg::Commit(hash, persistent_ref) => {
+        if self.config.strict_refs {
+          // Committed entries that are still queued have not reached the backend:
+          match self.queued_refs.get(&persistent_ref) {
+            Some(key) if **key != hash.bytes =>
+              return reply(Reply::RefConflict(Hash{bytes: (**key).clone()})),
+            _ => (),
+          }
+          match self.backend.ref_owner(&hash, &persistent_ref[..]) {
+            Ok(Some(owner)) => return reply(Reply::RefConflict(owner)),
+            Ok(None) => (),
+            Err(e) => return reply(Reply::Error(e)),
+          }
+        }
+        // A reserved entry may carry a persistent reference already (see `UpdateReserved`), so
+        // only a ready priority or an inserted entry means that the hash was committed before:
+        let committed = match self.queue.is_ready(&hash.bytes) {
+          Some(false) => {
+            self.commit(&hash, &persistent_ref);
+            return reply(Reply::CommitOK);
+          },
+          Some(true) => Ok(self.queue.find_value_of_key(&hash.bytes)),
+          None => self.index_locate(&hash),
+        };
+        return reply(match committed {
+          Ok(Some(ref entry)) if entry.persistent_ref.as_ref() == Some(&persistent_ref) =>
+            Reply::CommitOK,
+          Ok(Some(_)) => Reply::RefConflict(hash),
+          // The reservation expired before the commit arrived.
+          Ok(None) => Reply::HashNotKnown,
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::Relocate(hash, blob_ref) => {
+        if self.queue.find_value_of_key(&hash.bytes).is_none() &&
+           self.backend.relocate(&hash, &blob_ref) {
+          self.audit(AuditOp::Relocate, &hash, &blob_ref.to_bytes()[..]);
+          self.uncommitted_writes += 1;
+          return reply(Reply::CommitOK);
+        } else {
+          return reply(Reply::HashNotKnown);
+        }
+      },
+
+      Msg::SetMeta(hash, key, value) => {
+        if self.queue.find_value_of_key(&hash.bytes).is_some() {
+          return reply(Reply::HashNotKnown);
+        }
+        return reply(match self.backend.locate(&hash) {
+          Ok(Some((id, _))) => {
+            self.backend.set_meta(id, &key[..], &value[..]);
+            self.uncommitted_writes += 1;
+            Reply::CommitOK
+          },
+          Ok(None) => Reply::HashNotKnown,
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::GetMeta(hash, key) => {
+        return reply(match self.backend.locate(&hash) {
+          Ok(Some((id, _))) => match self.backend.meta(id, &key[..]) {
+            Ok(value) => Reply::MetaValue(value),
+            Err(e) => Reply::Error(e),
+          },
+          Ok(None) => Reply::HashNotKnown,
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::RewriteRefPrefix{from, to} => {
+        let moves = match self.backend.ref_prefix_moves(&from[..], &to[..]) {
+          Ok(moves) => moves,
+          Err(e) => return reply(Reply::Error(e)),
+        };
+        let (updated, _) = self.relocate_batch(moves);
+        self.uncommitted_writes += updated as u64;
+        return reply(match self.flush() {
+          Ok(()) => Reply::Relocated{updated: updated, not_found: 0},
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::BatchRelocate(moves) => {
+        let total = moves.len();
+        let committed: Vec<(Hash, BlobRef)> = moves.into_iter()
+          .filter(|&(ref hash, _)| self.queue.find_value_of_key(&hash.bytes).is_none())
+          .collect();
+        // Queued hashes were left out, so count them with the unknown ones:
+        let (updated, _) = self.relocate_batch(committed);
+        self.uncommitted_writes += updated as u64;
+        return reply(match self.flush() {
+          Ok(()) => Reply::Relocated{updated: updated, not_found: total - updated},
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::FileSize => {
+        return reply(Reply::FileSize(self.backend.file_size()));
+      },
+
+      Msg::EstimateCount => {
+        return reply(Reply::Estimate(self.backend.max_id() as u64));
+      },
+
+      Msg::Page{offset, limit} => {
+        return reply(match self.backend.page(offset, limit) {
+          Ok((entries, total)) => Reply::Page(entries, total),
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::ListNamespaces => {
+        return reply(Reply::Namespaces(self.backend.namespaces()));
+      },
+
+      Msg::FlushDue => {
+        return reply(Reply::FlushDue(self.flush_is_due()));
+      },
+
+      Msg::TrimMemory => {
+        return reply(Reply::Trimmed(self.trim_memory() as u64));
+      },
+
+      Msg::Rescan => {
+        return reply(Reply::Rescanned(self.rescan()));
+      },
+
+      Msg::SchemaInfo => {
+        let (version, columns, features) = self.backend.schema_info();
+        return reply(Reply::SchemaInfo{version: version, columns: columns, features: features});
+      },
+
+      Msg::Health => {
+        return reply(Reply::Health{uncommitted_writes: self.uncommitted_writes,
+                                   seconds_since_commit: (self.now() - self.last_commit)
+                                                           .num_seconds(),
+                                   queue_depth: self.queue.len()});
+      },
+
+      Msg::Stats => {
+        return reply(Reply::Stats{committed: self.queue_waits.count(),
+                                  wait_p50_ms: self.queue_waits.percentile(50) as i64,
+                                  wait_p95_ms: self.queue_waits.percentile(95) as i64,
+                                  wait_max_ms: self.queue_waits.max() as i64});
+      },
+
+      Msg::Config => {
+        // The key material must not leave the index:
+        let config = IndexConfig{encryption_key: None, ..self.config.clone()};
+        return reply(Reply::Config{config: config,
+                                   encrypted: self.config.encryption_key.is_some()});
+      },
+
+      Msg::CallAfterHashIsComitted(hash, callback) => {
+        return reply(self.register_hash_callback(&hash, callback));
+      },
+
+      Msg::CallAfterAllCommitted(hashes, callback) => {
+        let mut queued = vec!();
+        for hash in hashes.into_iter() {
+          if let Some(key) = self.queue.find_stored_key(&hash.bytes) {
+            queued.push(key);
+          } else {
+            match self.locate(&hash) {
+              Ok(Some(_)) => (),
+              Ok(None) => return reply(Reply::HashNotKnown),
+              Err(e) => return reply(Reply::Error(e)),
+            }
+          }
+        }
+        if !self.make_room_for_callbacks(queued.len()) {
+          return reply(Reply::CallbackLimitReached);
+        }
+        return reply(Reply::CallbackRegistered(self.callbacks.add_all(queued, callback)));
+      },
+
+      Msg::CallAfterEachCommitted(hashes, callback) => {
+        let mut queued = vec!();
+        let mut committed = vec!();
+        for hash in hashes.into_iter() {
+          if let Some(key) = self.queue.find_stored_key(&hash.bytes) {
+            queued.push((key, hash));
+          } else {
+            match self.locate(&hash) {
+              Ok(Some(_)) => committed.push(hash),
+              Ok(None) => return reply(Reply::HashNotKnown),
+              Err(e) => return reply(Reply::Error(e)),
+            }
+          }
+        }
+        if !self.make_room_for_callbacks(queued.len()) {
+          return reply(Reply::CallbackLimitReached);
+        }
+        let (registered, fired) = (queued.len(), committed.len());
+        let callback = Arc::new(Mutex::new(callback));
+        let callbacks = queued.into_iter().map(|(key, hash)| {
+          let callback = callback.clone();
+          let thunk: Thunk<'static> = Box::new(move|| (*callback.lock().unwrap())(hash));
+          (key, thunk)
+        }).collect();
+        let token = self.callbacks.add_group(callbacks);
+        for hash in committed.into_iter() {
+          (*callback.lock().unwrap())(hash);
+        }
+        return reply(Reply::CallbacksRegistered{token: token, registered: registered,
+                                                fired: fired});
+      },
+
+      Msg::CancelCallback(token) => {
+        return reply(Reply::CallbackCancelled(self.callbacks.cancel(token)));
+      },
+
+      Msg::CallbackCount => {
+        return reply(Reply::CallbackCount(self.callbacks.pending()));
+      },
+
+      Msg::TraceFor(hash) => {
+        return reply(Reply::Trace(self.trace.as_ref().map(|t| t.events_for(&hash.bytes))
+                                                     .unwrap_or(vec!())));
+      },
+
+      Msg::AuditSince(seq) => {
+        if !self.config.audit {
+          return reply(Reply::Disabled);
+        }
+        return reply(match self.backend.audit_since(seq, STREAM_BATCH_SIZE) {
+          Ok(events) => Reply::AuditEvents(events),
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::Flush => {
+        // Include the entries that the flush inserts first:
+        self.insert_completed_in_order();
+        let wrote = self.uncommitted_writes > 0;
+        return reply(match self.flush() {
+          Ok(()) if wrote => Reply::CommitOK,
+          Ok(()) => Reply::FlushedNothing,
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::Barrier => {
+        return reply(match self.barrier() {
+          Ok(()) => Reply::CommitOK,
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::BeginShutdown => {
+        self.draining = true;
+        return reply(Reply::CommitOK);
+      },
+
+      Msg::Shutdown => {
+        self.draining = true;
+        // Committed entries may wait for insertion (see `IndexConfig::max_inserts_per_commit`):
+        self.insert_completed_in_order();
+        if self.queue.len() > 0 {
+          return reply(self.internal_error(
+            format!("shutdown with {} entries still queued", self.queue.len())));
+        }
+        return reply(match self.flush() {
+          Ok(()) => Reply::CommitOK,
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::Seal => {
+        if self.queue.len() > 0 {
+          return reply(Reply::QueueNotEmpty(self.queue.len()));
+        }
+        self.sealed = true;
+        return reply(match self.flush() {
+          Ok(()) => Reply::CommitOK,
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::Checkpoint => {
+        return reply(match self.checkpoint() {
+          Ok((log_pages, moved_pages)) => Reply::Checkpointed{log_pages: log_pages,
+                                                              moved_pages: moved_pages},
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::IncrementalVacuum(pages) => {
+        let freed = self.backend.incremental_vacuum(pages);
+        return reply(match self.flush() {
+          Ok(()) => Reply::Vacuumed(freed),
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::Savepoint(name) => {
+        self.backend.savepoint(&name);
+        self.savepoints.push((name, self.uncommitted_writes, self.callbacks.ready_mark(),
+                              self.savepoint_commits.len()));
+        return reply(Reply::CommitOK);
+      },
+
+      Msg::ReleaseSavepoint(name) => {
+        return reply(match self.release_savepoint(&name) {
+          Ok(()) => Reply::CommitOK,
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::RollbackToSavepoint(name) => {
+        return reply(match self.rollback_to_savepoint(&name) {
+          Ok(()) => Reply::CommitOK,
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::RebuildIndexes => {
+        self.backend.rebuild_indexes();
+        return reply(Reply::CommitOK);
+      },
+
+      Msg::ExpireStaleReserves => {
+        return reply(Reply::ExpiredReserves(self.expire_stale_reserves()));
+      },
+
+      Msg::Abandon(hash) => {
+        if self.abandon(&hash.bytes) {
+          return reply(Reply::CommitOK);
+        } else {
+          return reply(Reply::HashNotKnown);
+        }
+      },
+
+      Msg::Uncommit(hash) => {
+        if !self.config.allow_uncommit {
+          return reply(Reply::Disabled);
+        }
+        if self.queue.find_key(&hash.bytes).is_some() {
+          return reply(Reply::AlreadyReserved);
+        }
+        return reply(match self.backend.locate(&hash) {
+          Ok(Some((id, entry))) => match self.uncommit(id, entry) {
+            Ok(()) => Reply::CommitOK,
+            Err(e) => Reply::Error(e),
+          },
+          Ok(None) => Reply::HashNotKnown,
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::DeleteIdRange{lo, hi} => {
+        let queued = self.queue.count_priorities(|p| lo <= p.id && p.id < hi);
+        if queued > 0 {
+          return reply(Reply::QueueNotEmpty(queued));
+        }
+        let deleted = match self.backend.delete_id_range(lo, hi) {
+          Ok(deleted) => deleted,
+          Err(e) => return reply(Reply::Error(e)),
+        };
+        for &(ref hash, ref persistent_ref) in deleted.iter() {
+          self.audit(AuditOp::Delete, hash, &persistent_ref[..]);
+        }
+        self.uncommitted_writes += deleted.len() as u64;
+        let count = deleted.len();
+        let freed_refs = deleted.into_iter().map(|(_, r)| r).filter(|r| r.len() > 0).collect();
+        return reply(match self.flush() {
+          Ok(()) => Reply::DeletedRange{deleted: count, freed_refs: freed_refs},
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::LeavesOf(root, sink) => {
+        return reply(self.leaves_of(root, sink).map(Reply::LeafCount).unwrap_or_else(|r| r));
+      },
+
+      Msg::TreeStats(root) => {
+        return reply(self.tree_stats(root).unwrap_or_else(|r| r));
+      },
+
+      Msg::FindOrphans(exists) => {
+        return reply(match self.find_orphans(exists) {
+          Ok(orphans) => self.unless_interrupted(Reply::Orphans(orphans)),
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::AllHashes(sink) => {
+        self.start_enumeration();
+        let streamed = self.backend.stream(false, 0, self.config.interrupt.as_ref(),
+                                           &|batch, _| sink(batch));
+        return reply(match streamed {
+          Ok((batch, _)) => self.unless_interrupted(Reply::HashBatch(batch)),
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::AllLeaves(sink) => {
+        self.start_enumeration();
+        let streamed = self.backend.stream(true, 0, self.config.interrupt.as_ref(),
+                                           &|batch, _| sink(batch));
+        return reply(match streamed {
+          Ok((batch, _)) => self.unless_interrupted(Reply::HashBatch(batch)),
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::AllHashesFrom(token, sink) => {
+        self.start_enumeration();
+        let streamed = self.backend.stream(
+          false, token.last_id, self.config.interrupt.as_ref(),
+          &|batch, id| sink(batch, ResumeToken{last_id: id}));
+        return reply(match streamed {
+          Ok((batch, last_id)) => {
+            let token = ResumeToken{last_id: last_id};
+            self.unless_interrupted(Reply::ResumableBatch(batch, token))
+          },
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::HashesAfter(token) => {
+        // Stop the stream after its first full batch:
+        let stop = Interrupt::new();
+        let first = RefCell::new(None);
+        let streamed = self.backend.stream(false, token.last_id, Some(&stop), &|batch, id| {
+          *first.borrow_mut() = Some((batch, id));
+          stop.interrupt();
+        });
+        return reply(match streamed {
+          Ok((rest, last_id)) => {
+            let (batch, last_id) = first.into_inner().unwrap_or((rest, last_id));
+            Reply::ResumableBatch(batch, ResumeToken{last_id: last_id})
+          },
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::HashesInObject(object_name, sink) => {
+        self.start_enumeration();
+        let streamed = self.backend.stream_in_object(&object_name[..],
+                                                     self.config.interrupt.as_ref(),
+                                                     &|batch| sink(batch));
+        return reply(match streamed {
+          Ok(batch) => self.unless_interrupted(Reply::HashBatch(batch)),
+          Err(e) => Reply::Error(e),
+        });
+      },
+
+      Msg::StorageSummary => {
+        return reply(match self.backend.storage_summary() {
+          Ok((total_bytes, distinct_objects, leaf_count)) =>
+            Reply::StorageSummary{total_bytes: total_bytes,
+                                  distinct_objects: distinct_objects,
+                                  leaf_count: leaf_count},
+          Err(e) => Reply::Error(e),
+        });
+      },
+    }
+  }
+
+  fn has_deferred_work(&self) -> bool {
+    self.callbacks.ready_len() > 0 || self.flush_is_debounced()
+  }
+
+  fn resume(&mut self) {
+    if self.callbacks.ready_len() > 0 {
+      self.run_ready_callbacks();
+      return;
+    }
+    // Only a debounced flush is left, which no message may come to trigger:
+    if !self.flush_is_due() {
+      thread::sleep_ms(IDLE_TICK_MS);
+      return;
+    }
+    self.maybe_flush();
+    if self.uncommitted_writes > 0 {
+      self.idle_flush_failed = true;
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::cell::{Cell};
+  use std::env;
+  use std::fs;
+  use std::rc::{Rc};
+  use std::sync::{mpsc, Arc, Mutex};
+  use std::time::duration::{Duration};
+  use time::{SteadyTime};
+  use std::thunk::Thunk;
+  use sqlite3::types::ResultCode::{SQLITE_BUSY, SQLITE_ERROR, SQLITE_FULL};
+
+  use process::{MsgHandler};
+  use bloom::{BloomFilter};
+  use periodic_timer::{PeriodicTimer};
+  use cumulative_counter::{CumulativeCounter, MAX_VALUE};
+  use hash_backend::{HashBackend, MemoryBackend, SCHEMA_VERSION};
+  use hash_payload::{PayloadVersion, encode_children, encode_sequenced};
+  use digest::{DIGEST_BYTES};
+  use hash_bytes::{HashBytes};
+  use audit::{AuditOp};
+  use trace::{TraceEvent, TraceKind};
+  use rustc_serialize::hex::{ToHex};
+  use super::{prefix_range};
+  use blob_store::{BlobID};
+
+  fn leaf(data: &[u8]) -> HashEntry {
+    HashEntry{hash: Hash::new(data), level: 0, payload: None, persistent_ref: None,
+              content_len: data.len() as u64}
+  }
+
+  #[test]
+  fn queue_entry_round_trip() {
+    let entry = HashEntry{level: 2, payload: Some(b"payload".to_vec()),
+                          persistent_ref: Some(b"ref".to_vec()), ..leaf(b"foo")};
+    let now = SteadyTime::now();
+
+    let (hash, qe) = super::hash_entry_to_queue_entry(7, entry.clone(), Some(now));
+    assert_eq!(entry.hash, hash);
+    assert_eq!(7, qe.id);
+    assert_eq!(Some(now), qe.reserved_at);
+    assert_eq!(entry, super::queue_entry_to_hash_entry(hash, qe));
+  }
+
+  fn send<B: HashBackend>(hi: &mut HashIndex<B>, msg: Msg) -> Reply {
+    let (sender, receiver) = mpsc::channel();
+    hi.handle(msg, Box::new(move|r| { sender.send(r).unwrap(); }));
+    receiver.recv().unwrap()
+  }
+
+  #[test]
+  fn busy_commit_is_retried() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string())
+      .busy_retries(3)
+      .busy_retry_delay(Duration::milliseconds(1))
+      .build();
+
+    let entry = leaf(b"foo");
+    hi.reserve(entry.clone());
+    hi.commit(&entry.hash, &b"ref".to_vec());
+
+    hi.backend.inject_errors(vec!(SQLITE_BUSY, SQLITE_BUSY));
+    assert_eq!(Ok(()), hi.flush());
+    assert!(hi.index_locate(&entry.hash).unwrap().is_some());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn busy_commit_gives_up_after_retries() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string())
+      .busy_retries(1)
+      .busy_retry_delay(Duration::milliseconds(1))
+      .build();
+
+    let entry = leaf(b"foo");
+    hi.reserve(entry.clone());
+    hi.commit(&entry.hash, &b"ref".to_vec());
+
+    hi.backend.inject_errors(vec!(SQLITE_BUSY, SQLITE_BUSY));
+    assert_eq!(Err(HashIndexError::Busy), hi.flush());
+
+    // The transaction is still open, so a later flush commits the entry:
+    assert_eq!(Ok(()), hi.flush());
+    assert!(hi.index_locate(&entry.hash).unwrap().is_some());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn disk_full_commit_can_be_retried() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+
+    let entry = leaf(b"foo");
+    hi.reserve(entry.clone());
+    let (sender, receiver) = mpsc::channel();
+    hi.register_hash_callback(&entry.hash, Thunk::new(move|| sender.send(()).unwrap()));
+
+    // The flush after the commit fails, which is not fatal:
+    hi.flush_timer = PeriodicTimer::new(Duration::zero());
+    hi.backend.inject_errors(vec!(SQLITE_FULL));
+    hi.commit(&entry.hash, &b"ref".to_vec());
+    assert!(receiver.try_recv().is_err());
+
+    hi.backend.inject_errors(vec!(SQLITE_FULL));
+    assert_eq!(Err(HashIndexError::DiskFull), hi.flush());
+    assert!(receiver.try_recv().is_err());
+    assert!(hi.index_locate(&entry.hash).unwrap().is_some());
+
+    assert_eq!(Ok(()), hi.flush());
+    assert!(receiver.try_recv().is_ok());
+    assert!(hi.index_locate(&entry.hash).unwrap().is_some());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn failed_lookup_is_not_absence() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+
+    let entry = leaf(b"foo");
+    hi.reserve(entry.clone());
+    hi.commit(&entry.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+
+    hi.backend.inject_errors(vec!(SQLITE_ERROR));
+    match send(&mut hi, Msg::HashExists(entry.hash.clone())) {
+      Reply::Error(HashIndexError::QueryFailed(_)) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // The committed hash must not be reserved again:
+    hi.backend.inject_errors(vec!(SQLITE_BUSY));
+    match send(&mut hi, Msg::Reserve(entry.clone())) {
+      Reply::Error(HashIndexError::Busy) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(0, hi.debug_dump_queue().len());
+
+    match send(&mut hi, Msg::Reserve(entry.clone())) {
+      Reply::HashKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn missing_unique_index_is_recreated() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    assert!(!hi.backend.open_checked());
+
+    hi.backend.drop_unique_index();
+    hi.backend.rebuild_indexes();
+    assert!(!hi.backend.open_checked());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn bulk_load() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    assert_eq!(Ok(()), hi.begin_bulk_load());
+
+    for i in 0..10 {
+      let entry = leaf(format!("{}", i).as_bytes());
+      hi.reserve(entry.clone());
+      hi.commit(&entry.hash, &b"ref".to_vec());
+    }
+    assert_eq!(Ok(()), hi.end_bulk_load());
+    assert!(!hi.backend.open_checked());
+    assert!(hi.locate(&leaf(b"3").hash).unwrap().is_some());
+
+    // A duplicate is reported, and the unique index is left out:
+    let foo = leaf(b"foo");
+    assert_eq!(Ok(()), hi.begin_bulk_load());
+    hi.backend.insert_batch(vec!((100, foo.clone()), (101, foo.clone())));
+    assert_eq!(Err(HashIndexError::DuplicateHash(foo.hash.clone())), hi.end_bulk_load());
+
+    assert!(hi.backend.delete(&foo.hash));
+    assert_eq!(Ok(()), hi.end_bulk_load());
+    assert!(!hi.backend.open_checked());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn large_payload_is_chunked() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).max_inline_payload(3).build();
+
+    let payload = b"0123456789".to_vec();
+    let entry = HashEntry{payload: Some(payload.clone()), ..leaf(b"foo")};
+    hi.reserve(entry.clone());
+    hi.commit(&entry.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+
+    match send(&mut hi, Msg::FetchPayload(entry.hash.clone())) {
+      Reply::Payload(p) => assert_eq!(Some(payload), p),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn large_payload_is_spilled_when_reserved() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).max_inline_payload(3).build();
+    let fetch = |hi: &mut HashIndex, hash: &Hash| {
+      match send(hi, Msg::FetchPayload(hash.clone())) {
+        Reply::Payload(p) => p,
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    };
+
+    // The queue does not keep the payload, which is read back from where it was spilled:
+    let foo = HashEntry{payload: Some(b"0123456789".to_vec()), ..leaf(b"foo")};
+    hi.reserve(foo.clone());
+    assert!(hi.queue.find_value_of_key(&foo.hash.bytes).unwrap().payload.is_none());
+    assert_eq!(foo.payload, fetch(&mut hi, &foo.hash));
+
+    let updated = HashEntry{payload: Some(b"abcdef".to_vec()), ..foo.clone()};
+    match send(&mut hi, Msg::UpdateReserved(updated.clone())) {
+      Reply::ReserveOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(updated.payload, fetch(&mut hi, &foo.hash));
+
+    // Small payloads stay in the queue:
+    let small = HashEntry{payload: Some(b"abc".to_vec()), ..leaf(b"small")};
+    hi.reserve(small.clone());
+    assert_eq!(small.payload, hi.queue.find_value_of_key(&small.hash.bytes).unwrap().payload);
+
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    hi.commit(&small.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+    assert_eq!(updated.payload, fetch(&mut hi, &foo.hash));
+    assert_eq!(small.payload, fetch(&mut hi, &small.hash));
+
+    // An abandoned entry leaves no payload behind:
+    let bar = HashEntry{payload: Some(b"0123456789".to_vec()), ..leaf(b"bar")};
+    let bar_id = hi.reserve(bar.clone());
+    assert!(hi.abandon(&bar.hash.bytes));
+    assert_eq!(Ok(None), hi.backend.spilled_payload(bar_id));
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn strict_refs_detects_shared_ref() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).strict_refs(true).build();
+
+    let foo = leaf(b"foo");
+    let bar = leaf(b"bar");
+    hi.reserve(foo.clone());
+    hi.reserve(bar.clone());
+
+    match send(&mut hi, Msg::Commit(foo.hash.clone(), b"ref".to_vec())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::Commit(bar.hash.clone(), b"ref".to_vec())) {
+      Reply::RefConflict(owner) => assert_eq!(foo.hash, owner),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn strict_refs_detects_ref_of_queued_commit() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).strict_refs(true).build();
+
+    // The pending entry keeps the committed ones after it in the queue:
+    let (pending, foo, bar) = (leaf(b"pending"), leaf(b"foo"), leaf(b"bar"));
+    hi.reserve(pending.clone());
+    hi.reserve(foo.clone());
+    hi.reserve(bar.clone());
+
+    match send(&mut hi, Msg::Commit(foo.hash.clone(), b"ref".to_vec())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::Commit(bar.hash.clone(), b"ref".to_vec())) {
+      Reply::RefConflict(owner) => assert_eq!(foo.hash, owner),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    // Committing the same reference again is not a conflict with itself:
+    match send(&mut hi, Msg::Commit(foo.hash.clone(), b"ref".to_vec())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn strict_refs_forget_rolled_back_commits() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).strict_refs(true).build();
+
+    let (pending, foo, bar) = (leaf(b"pending"), leaf(b"foo"), leaf(b"bar"));
+    hi.reserve(pending.clone());
+    hi.reserve(foo.clone());
+    hi.reserve(bar.clone());
+
+    match send(&mut hi, Msg::Savepoint("file".to_string())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::Commit(foo.hash.clone(), b"ref".to_vec())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::RollbackToSavepoint("file".to_string())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::ReleaseSavepoint("file".to_string())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // The reference is free again, until it reaches the backend with `bar`:
+    match send(&mut hi, Msg::Commit(bar.hash.clone(), b"ref".to_vec())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    hi.commit(&pending.hash, &b"pending".to_vec());
+    assert!(hi.index_locate(&bar.hash).unwrap().is_some());
+    match send(&mut hi, Msg::Commit(foo.hash.clone(), b"ref".to_vec())) {
+      Reply::RefConflict(owner) => assert_eq!(bar.hash, owner),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn queued_hash_bytes_are_shared() {
+    let mut hi = HashIndex::new_for_testing();
+    let foo = leaf(b"foo");
+    hi.reserve(foo.clone());
+    for _ in 0..3 {
+      send(&mut hi, Msg::CallAfterHashIsComitted(foo.hash.clone(), Box::new(move|| {})));
+    }
+    send(&mut hi, Msg::CallAfterAllCommitted(vec!(foo.hash.clone()), Box::new(move|| {})));
+
+    // The callbacks refer to the bytes held by the queue, rather than to copies:
+    let key = hi.queue.find_stored_key(&foo.hash.bytes).unwrap();
+    {
+      let callback_keys = hi.callbacks.keys();
+      assert_eq!(1, callback_keys.len());
+      assert_eq!(&*key as *const HashBytes, &**callback_keys[0] as *const HashBytes);
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn large_payloads_are_rejected() {
+    let mut hi = HashIndexBuilder::new(String::new()).max_payload(4).build_in_memory();
+    let foo = HashEntry{level: 1, payload: Some(b"1234".to_vec()), ..leaf(b"foo")};
+    let large = HashEntry{payload: Some(b"12345".to_vec()), ..foo.clone()};
+
+    match send(&mut hi, Msg::Reserve(large.clone())) {
+      Reply::PayloadTooLarge(5) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::Reserve(foo.clone())) {
+      Reply::ReserveOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::UpdateReserved(large.clone())) {
+      Reply::PayloadTooLarge(5) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::FetchPayload(foo.hash.clone())) {
+      Reply::Payload(payload) => assert_eq!(foo.payload, payload),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn reserve_of_queued_hash() {
+    let mut hi = HashIndex::new_for_testing();
+    let foo = leaf(b"foo");
+    let bar = leaf(b"bar");
+    hi.reserve(foo.clone());
+    hi.reserve(bar.clone());
+
+    match send(&mut hi, Msg::Reserve(bar.clone())) {
+      Reply::AlreadyReserved => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    // Committed, but waiting for `foo` to be inserted first:
+    hi.commit(&bar.hash, &b"ref".to_vec());
+    match send(&mut hi, Msg::ReservePrioritized(bar.clone())) {
+      Reply::AlreadyReserved => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    match send(&mut hi, Msg::Reserve(bar.clone())) {
+      Reply::HashKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn shutdown_inserts_committed_entries() {
+    // Hold back the periodic flush, which would insert everything:
+    let mut hi = HashIndexBuilder::new(String::new())
+      .fail_fast(true)
+      .max_inserts_per_commit(1)
+      .flush_quiet_period(Duration::seconds(5))
+      .build_in_memory();
+    let start = SteadyTime::now();
+    hi.set_clock(Box::new(move|| start));
+    let foo = leaf(b"foo");
+    let bar = leaf(b"bar");
+    hi.reserve(foo.clone());
+    hi.reserve(bar.clone());
+    hi.commit(&bar.hash, &b"ref".to_vec());
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    assert_eq!(1, hi.queue.len());
+
+    match send(&mut hi, Msg::Shutdown) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert!(hi.index_locate(&bar.hash).unwrap().is_some());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn shutdown_drains_queue() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).fail_fast(false).build();
+    let foo = leaf(b"foo");
+    hi.reserve(foo.clone());
+
+    match send(&mut hi, Msg::BeginShutdown) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::Reserve(leaf(b"bar"))) {
+      Reply::ShuttingDown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::UpdateReserved(foo.clone())) {
+      Reply::ShuttingDown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::Shutdown) {
+      Reply::InternalError(_) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // The pending commit still goes through:
+    match send(&mut hi, Msg::Commit(foo.hash.clone(), b"ref".to_vec())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::Shutdown) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert!(hi.index_locate(&foo.hash).unwrap().is_some());
+    match send(&mut hi, Msg::Reserve(leaf(b"bar"))) {
+      Reply::ShuttingDown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn audit_log_records_changes() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    match send(&mut hi, Msg::AuditSince(0)) {
+      Reply::Disabled => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).audit(true).build();
+    let foo = leaf(b"foo");
+    hi.reserve(foo.clone());
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    let moved = BlobRef{name: b"packed".to_vec(), offset: 0, length: 3, kind: RefKind::Unknown};
+    send(&mut hi, Msg::Relocate(foo.hash.clone(), moved.clone()));
+    assert_eq!(Ok(()), hi.flush());
+
+    let events = match send(&mut hi, Msg::AuditSince(0)) {
+      Reply::AuditEvents(events) => events,
+      _ => panic!("Unexpected reply from hash index."),
+    };
+    assert_eq!(vec!((AuditOp::Reserve, vec!()),
+                    (AuditOp::Commit, b"ref".to_vec()),
+                    (AuditOp::Relocate, moved.to_bytes())),
+               events.iter().map(|e| (e.op, e.detail.clone())).collect::<Vec<_>>());
+    assert!(events.iter().all(|e| e.hash == foo.hash));
+
+    match send(&mut hi, Msg::AuditSince(events[2].seq)) {
+      Reply::AuditEvents(events) => assert_eq!(0, events.len()),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // Rewriting a prefix audits each relocation as well:
+    send(&mut hi, Msg::RewriteRefPrefix{from: b"pack".to_vec(), to: b"repack".to_vec()});
+    let repacked = BlobRef{name: b"repacked".to_vec(), ..moved.clone()};
+    match send(&mut hi, Msg::AuditSince(events[2].seq)) {
+      Reply::AuditEvents(events) => {
+        assert_eq!(vec!((AuditOp::Relocate, repacked.to_bytes())),
+                   events.iter().map(|e| (e.op, e.detail.clone())).collect::<Vec<_>>());
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn seal_requires_empty_queue() {
+    let mut hi = HashIndex::new_for_testing();
+    let foo = leaf(b"foo");
+    hi.reserve(foo.clone());
+
+    match send(&mut hi, Msg::Seal) {
+      Reply::QueueNotEmpty(1) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    // The index is not sealed, so the entry can still be reserved and committed:
+    match send(&mut hi, Msg::Reserve(leaf(b"bar"))) {
+      Reply::ReserveOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    hi.commit(&leaf(b"bar").hash, &b"ref".to_vec());
+
+    match send(&mut hi, Msg::Seal) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::Reserve(leaf(b"baz"))) {
+      Reply::Sealed => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::FetchPersistentRef(foo.hash.clone())) {
+      Reply::PersistentRef(r) => assert_eq!(b"ref".to_vec(), r),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::FilterUnknown(vec!(foo.hash.clone(), leaf(b"baz").hash))) {
+      Reply::Unknown(unknown) => assert_eq!(vec!(leaf(b"baz").hash), unknown),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn uncommit_requeues_entry() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).max_inline_payload(2).build();
+    let foo = HashEntry{payload: Some(b"spilled".to_vec()), ..leaf(b"foo")};
+    hi.reserve(foo.clone());
+    hi.commit(&foo.hash, &b"ref".to_vec());
+
+    match send(&mut hi, Msg::Uncommit(foo.hash.clone())) {
+      Reply::Disabled => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    hi.config.allow_uncommit = true;
+    match send(&mut hi, Msg::Uncommit(foo.hash.clone())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::FetchPersistentRef(foo.hash.clone())) {
+      Reply::Retry => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::Uncommit(foo.hash.clone())) {
+      Reply::AlreadyReserved => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::Uncommit(leaf(b"unknown").hash)) {
+      Reply::HashNotKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // Later entries are not held up by the uncommitted entry:
+    let bar = leaf(b"bar");
+    hi.reserve(bar.clone());
+    hi.commit(&bar.hash, &b"ref".to_vec());
+    assert_eq!(Some(2), hi.index_locate(&bar.hash).unwrap().map(|qe| qe.id));
+
+    // Committing again inserts the entry under its old id, with its payload:
+    hi.commit(&foo.hash, &b"new ref".to_vec());
+    assert_eq!(Some(1), hi.index_locate(&foo.hash).unwrap().map(|qe| qe.id));
+    match send(&mut hi, Msg::FetchPersistentRef(foo.hash.clone())) {
+      Reply::PersistentRef(r) => assert_eq!(b"new ref".to_vec(), r),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::FetchPayload(foo.hash.clone())) {
+      Reply::Payload(payload) => assert_eq!(foo.payload, payload),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn strict_tree_refuses_malformed_entries() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).strict_tree(true).build();
+
+    let with_payload = HashEntry{payload: Some(b"children".to_vec()), ..leaf(b"leaf")};
+    let without_payload = HashEntry{level: 1, ..leaf(b"branch")};
+    let empty_ref = HashEntry{persistent_ref: Some(vec!()), ..leaf(b"empty")};
+    for e in vec!(with_payload, without_payload, empty_ref).into_iter() {
+      match send(&mut hi, Msg::Reserve(e)) {
+        Reply::InvalidEntry(_) => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+    assert_eq!(0, hi.debug_dump_queue().len());
+
+    let foo = leaf(b"foo");
+    let branch = HashEntry{level: 1, payload: Some(b"children".to_vec()), ..leaf(b"branch")};
+    hi.reserve(foo.clone());
+    hi.reserve(branch.clone());
+    match send(&mut hi, Msg::Commit(foo.hash.clone(), vec!())) {
+      Reply::InvalidEntry(_) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    hi.commit(&branch.hash, &b"branch-ref".to_vec());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn level_mismatch_is_refused() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).strict_levels(true).build();
+
+    let foo = leaf(b"foo");
+    hi.reserve(foo.clone());
+    hi.commit(&foo.hash, &b"ref".to_vec());
+
+    match send(&mut hi, Msg::Reserve(HashEntry{level: 1, ..foo.clone()})) {
+      Reply::LevelMismatch{existing, requested} => assert_eq!((0, 1), (existing, requested)),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::Reserve(foo.clone())) {
+      Reply::HashKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // Without strict levels, the reserve is deduplicated to the known entry:
+    hi.config.strict_levels = false;
+    match send(&mut hi, Msg::Reserve(HashEntry{level: 1, ..foo.clone()})) {
+      Reply::HashKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn collisions_are_suspected() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).verify_collisions(true).build();
+
+    let foo = HashEntry{payload: Some(b"children".to_vec()), level: 1, ..leaf(b"foo")};
+    hi.reserve(foo.clone());
+    hi.commit(&foo.hash, &b"ref".to_vec());
+
+    match send(&mut hi, Msg::Reserve(foo.clone())) {
+      Reply::HashKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::Reserve(HashEntry{level: 0, ..foo.clone()})) {
+      Reply::CollisionSuspected(h) => assert_eq!(foo.hash, h),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::Reserve(HashEntry{payload: None, ..foo.clone()})) {
+      Reply::CollisionSuspected(h) => assert_eq!(foo.hash, h),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn relocate_committed_hash() {
+    let mut hi = HashIndex::new_for_testing();
+
+    let foo = leaf(b"foo");
+    let bar = leaf(b"bar");
+    hi.reserve(foo.clone());
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    hi.reserve(bar.clone());
+
+    let moved = BlobRef{name: b"packed".to_vec(), offset: 10, length: 3, kind: RefKind::Unknown};
+    match send(&mut hi, Msg::Relocate(foo.hash.clone(), moved.clone())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::FetchPersistentRef(foo.hash.clone())) {
+      Reply::PersistentRef(r) => assert_eq!(moved.to_bytes(), r),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // Queued entries are not relocated:
+    match send(&mut hi, Msg::Relocate(bar.hash.clone(), moved.clone())) {
+      Reply::HashNotKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn batch_relocate() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+
+    let entries: Vec<HashEntry> = (0..10).map(|i| leaf(format!("{}", i).as_bytes())).collect();
+    for e in entries.iter() {
+      hi.reserve(e.clone());
+      hi.commit(&e.hash, &b"ref".to_vec());
+    }
+
+    let mut moves: Vec<(Hash, BlobRef)> = entries.iter().enumerate().map(|(i, e)| {
+      (e.hash.clone(),
+       BlobRef{name: b"packed".to_vec(), offset: i as u64, length: 1, kind: RefKind::Unknown})
+    }).collect();
+    moves.push((Hash::new(b"unknown"),
+                BlobRef{name: b"packed".to_vec(), offset: 0, length: 1, kind: RefKind::Unknown}));
+    let queued = leaf(b"queued");
+    hi.reserve(queued.clone());
+    moves.push((queued.hash.clone(),
+                BlobRef{name: b"packed".to_vec(), offset: 0, length: 1, kind: RefKind::Unknown}));
+
+    // The unknown and the queued hash are each counted once:
+    match send(&mut hi, Msg::BatchRelocate(moves)) {
+      Reply::Relocated{updated, not_found} => {
+        assert_eq!(10, updated);
+        assert_eq!(2, not_found);
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::FetchPersistentRef(entries[3].hash.clone())) {
+      Reply::PersistentRef(r) =>
+        assert_eq!(Some(BlobRef{name: b"packed".to_vec(), offset: 3, length: 1,
+                                kind: RefKind::Unknown}),
+                   BlobRef::from_bytes(&r[..])),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn await_persistent_ref() {
+    let mut hi = HashIndex::new_for_testing();
+    let start = SteadyTime::now();
+    let elapsed = Rc::new(Cell::new(Duration::seconds(0)));
+    let clock_elapsed = elapsed.clone();
+    hi.set_clock(Box::new(move|| start + clock_elapsed.get()));
+
+    let foo = leaf(b"foo");
+    let bar = leaf(b"bar");
+    hi.reserve(foo.clone());
+    hi.reserve(bar.clone());
+
+    let (sender, receiver) = mpsc::channel();
+    let foo_sender = sender.clone();
+    hi.handle(Msg::AwaitPersistentRef(foo.hash.clone(), Duration::seconds(5)),
+              Box::new(move|r| foo_sender.send(r).unwrap()));
+    hi.handle(Msg::AwaitPersistentRef(bar.hash.clone(), Duration::seconds(1)),
+              Box::new(move|r| sender.send(r).unwrap()));
+    assert!(receiver.try_recv().is_err());
+
+    // The waiter on `foo` is woken by its commit:
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    match receiver.try_recv() {
+      Ok(Reply::PersistentRef(r)) => assert_eq!(b"ref".to_vec(), r),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert!(receiver.try_recv().is_err());
+
+    // The waiter on `bar` times out with the next message after its deadline:
+    elapsed.set(Duration::seconds(2));
+    match send(&mut hi, Msg::HashExists(bar.hash.clone())) {
+      Reply::HashKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match receiver.try_recv() {
+      Ok(Reply::Timeout) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    match send(&mut hi, Msg::AwaitPersistentRef(foo.hash.clone(), Duration::seconds(1))) {
+      Reply::PersistentRef(r) => assert_eq!(b"ref".to_vec(), r),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::AwaitPersistentRef(bar.hash.clone(), Duration::zero())) {
+      Reply::Timeout => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::AwaitPersistentRef(leaf(b"baz").hash, Duration::seconds(1))) {
+      Reply::HashNotKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // Setting the reference of a reserved hash wakes its waiters as well:
+    let qux = leaf(b"qux");
+    hi.reserve(qux.clone());
+    let (sender, receiver) = mpsc::channel();
+    hi.handle(Msg::AwaitPersistentRef(qux.hash.clone(), Duration::seconds(5)),
+              Box::new(move|r| sender.send(r).unwrap()));
+    hi.update_reserved(HashEntry{persistent_ref: Some(b"early-ref".to_vec()), ..qux.clone()});
+    match receiver.try_recv() {
+      Ok(Reply::PersistentRef(r)) => assert_eq!(b"early-ref".to_vec(), r),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    hi.commit(&qux.hash, &b"early-ref".to_vec());
+
+    // Abandoning a waiting hash makes it unknown:
+    let (sender, receiver) = mpsc::channel();
+    hi.handle(Msg::AwaitPersistentRef(bar.hash.clone(), Duration::seconds(5)),
+              Box::new(move|r| sender.send(r).unwrap()));
+    send(&mut hi, Msg::Abandon(bar.hash.clone()));
+    match receiver.try_recv() {
+      Ok(Reply::HashNotKnown) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(0, hi.ref_waiters.len());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn health_reports_open_transaction() {
+    let mut hi = HashIndex::new_for_testing();
+
+    let start = SteadyTime::now();
+    let elapsed = Rc::new(Cell::new(Duration::seconds(0)));
+    let clock_elapsed = elapsed.clone();
+    hi.set_clock(Box::new(move|| start + clock_elapsed.get()));
+
+    let foo = leaf(b"foo");
+    hi.reserve(foo.clone());
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+
+    hi.reserve(leaf(b"bar"));
+    let moved = BlobRef{name: b"packed".to_vec(), offset: 0, length: 3, kind: RefKind::Unknown};
+    send(&mut hi, Msg::Relocate(foo.hash.clone(), moved));
+
+    elapsed.set(Duration::seconds(30));
+    match send(&mut hi, Msg::Health) {
+      Reply::Health{uncommitted_writes, seconds_since_commit, queue_depth} => {
+        assert_eq!(1, uncommitted_writes);
+        assert_eq!(30, seconds_since_commit);
+        assert_eq!(1, queue_depth);
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.flush());
+    match send(&mut hi, Msg::Health) {
+      Reply::Health{uncommitted_writes, seconds_since_commit, ..} => {
+        assert_eq!(0, uncommitted_writes);
+        assert_eq!(0, seconds_since_commit);
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn stats_report_queue_waits() {
+    let mut hi = HashIndex::new_for_testing();
+
+    let start = SteadyTime::now();
+    let elapsed = Rc::new(Cell::new(Duration::seconds(0)));
+    let clock_elapsed = elapsed.clone();
+    hi.set_clock(Box::new(move|| start + clock_elapsed.get()));
+
+    let entries: Vec<HashEntry> = (0..20).map(|i| leaf(format!("{}", i).as_bytes())).collect();
+    for entry in entries.iter() {
+      hi.reserve(entry.clone());
+    }
+    // Updating an entry does not restart its wait:
+    elapsed.set(Duration::milliseconds(50));
+    send(&mut hi, Msg::UpdateReserved(entries[0].clone()));
+
+    elapsed.set(Duration::milliseconds(100));
+    for entry in entries[..19].iter() {
+      hi.commit(&entry.hash, &b"ref".to_vec());
+    }
+    elapsed.set(Duration::milliseconds(5000));
+    hi.commit(&entries[19].hash, &b"ref".to_vec());
+
+    match send(&mut hi, Msg::Stats) {
+      Reply::Stats{committed, wait_p50_ms, wait_p95_ms, wait_max_ms} => {
+        assert_eq!(20, committed);
+        assert_eq!(127, wait_p50_ms);
+        assert_eq!(127, wait_p95_ms);
+        assert_eq!(5000, wait_max_ms);
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn debounced_flush() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string())
+      .flush_quiet_period(Duration::seconds(5))
+      .flush_max_delay(Duration::seconds(20))
+      .build();
+
+    let start = SteadyTime::now();
+    let elapsed = Rc::new(Cell::new(Duration::seconds(0)));
+    let clock_elapsed = elapsed.clone();
+    hi.set_clock(Box::new(move|| start + clock_elapsed.get()));
+    let uncommitted = |hi: &mut HashIndex| match send(hi, Msg::Health) {
+      Reply::Health{uncommitted_writes, ..} => uncommitted_writes,
+      _ => panic!("Unexpected reply from hash index."),
+    };
+
+    // A burst of commits, each within the quiet period of the previous one, is not flushed:
+    for i in 0..4 {
+      elapsed.set(Duration::seconds(4 * i));
+      let entry = leaf(format!("burst {}", i).as_bytes());
+      hi.reserve(entry.clone());
+      hi.commit(&entry.hash, &b"ref".to_vec());
+    }
+    assert_eq!(4, uncommitted(&mut hi));
+
+    // Once the quiet period has passed, the next commit flushes:
+    elapsed.set(Duration::seconds(18));
+    let quiet = leaf(b"after quiet");
+    hi.reserve(quiet.clone());
+    hi.commit(&quiet.hash, &b"ref".to_vec());
+    assert_eq!(0, uncommitted(&mut hi));
+
+    // The debounce is visible without flushing:
+    let flush_due = |hi: &mut HashIndex| match send(hi, Msg::FlushDue) {
+      Reply::FlushDue(due) => due,
+      _ => panic!("Unexpected reply from hash index."),
+    };
+    let probe = leaf(b"probe");
+    hi.reserve(probe.clone());
+    hi.commit(&probe.hash, &b"ref".to_vec());
+    assert!(!flush_due(&mut hi));
+    elapsed.set(Duration::seconds(23));
+    assert!(flush_due(&mut hi));
+    assert!(flush_due(&mut hi));
+    assert_eq!(1, uncommitted(&mut hi));
+    hi.flush();
+    assert!(!flush_due(&mut hi));
+
+    // A steady stream of commits is flushed after the max delay:
+    for i in 1..7 {
+      elapsed.set(Duration::seconds(18 + 4 * i));
+      let entry = leaf(format!("stream {}", i).as_bytes());
+      hi.reserve(entry.clone());
+      hi.commit(&entry.hash, &b"ref".to_vec());
+    }
+    assert_eq!(1, uncommitted(&mut hi));
+
+    // An idle index flushes once the quiet period has passed, without waiting for a message:
+    assert!(hi.has_deferred_work());
+    hi.resume();
+    assert_eq!(1, hi.uncommitted_writes);
+    elapsed.set(Duration::seconds(18 + 4 * 6 + 5));
+    hi.resume();
+    assert_eq!(0, hi.uncommitted_writes);
+    assert!(!hi.has_deferred_work());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn periodic_flush_due() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    let flush_due = |hi: &mut HashIndex| match send(hi, Msg::FlushDue) {
+      Reply::FlushDue(due) => due,
+      _ => panic!("Unexpected reply from hash index."),
+    };
+
+    // Commits are not flushed before the interval has passed:
+    let foo = leaf(b"foo");
+    hi.reserve(foo.clone());
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    assert!(!flush_due(&mut hi));
+    match send(&mut hi, Msg::Health) {
+      Reply::Health{uncommitted_writes, ..} => assert_eq!(1, uncommitted_writes),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // Once it has, probing does not restart it, but the next commit flushes:
+    hi.flush_timer = PeriodicTimer::new(Duration::zero());
+    assert!(flush_due(&mut hi));
+    assert!(flush_due(&mut hi));
+    let bar = leaf(b"bar");
+    hi.reserve(bar.clone());
+    hi.commit(&bar.hash, &b"ref2".to_vec());
+    match send(&mut hi, Msg::Health) {
+      Reply::Health{uncommitted_writes, ..} => assert_eq!(0, uncommitted_writes),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn stale_reserves_expire() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string())
+      .reserve_ttl(Duration::seconds(60))
+      .build();
+
+    let start = SteadyTime::now();
+    let elapsed = Rc::new(Cell::new(Duration::seconds(0)));
+    let clock_elapsed = elapsed.clone();
+    hi.set_clock(Box::new(move|| start + clock_elapsed.get()));
+
+    let slow = leaf(b"slow");
+    let crashed = leaf(b"crashed");
+    let ready = leaf(b"ready");
+    hi.reserve(crashed.clone());
+    hi.reserve(slow.clone());
+    hi.reserve(ready.clone());
+    hi.commit(&ready.hash, &b"ref".to_vec());
+
+    elapsed.set(Duration::seconds(50));
+    hi.update_reserved(slow.clone());
+
+    elapsed.set(Duration::seconds(70));
+    match send(&mut hi, Msg::ExpireStaleReserves) {
+      Reply::ExpiredReserves(n) => assert_eq!(1, n),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert!(hi.locate(&crashed.hash).unwrap().is_none());
+    assert!(hi.locate(&slow.hash).unwrap().is_some());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn abandoned_id_does_not_block_later_ids() {
+    let mut hi = HashIndex::new_for_testing();
+
+    let first = leaf(b"first");
+    let second = leaf(b"second");
+    assert_eq!(1, hi.reserve(first.clone()));
+    assert_eq!(2, hi.reserve(second.clone()));
+
+    match send(&mut hi, Msg::Abandon(first.hash.clone())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(vec!((2, second.hash.bytes.to_vec(), false)), hi.debug_dump_queue());
+    hi.commit(&second.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+
+    assert!(hi.index_locate(&first.hash).unwrap().is_none());
+    assert_eq!(Some(2), hi.index_locate(&second.hash).unwrap().map(|qe| qe.id));
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn queue_keeps_ready_entries_behind_pending_ones() {
+    let mut hi = HashIndex::new_for_testing();
+
+    let first = leaf(b"first");
+    let second = leaf(b"second");
+    let third = leaf(b"third");
+    for e in vec!(&first, &second, &third).into_iter() {
+      hi.reserve(e.clone());
+    }
+    hi.commit(&second.hash, &b"ref".to_vec());
+    hi.commit(&third.hash, &b"ref".to_vec());
+    assert_eq!(vec!((1, first.hash.bytes.to_vec(), false),
+                    (2, second.hash.bytes.to_vec(), true),
+                    (3, third.hash.bytes.to_vec(), true)),
+               hi.debug_dump_queue());
+
+    hi.commit(&first.hash, &b"ref".to_vec());
+    assert_eq!(Vec::<(i64, Vec<u8>, bool)>::new(), hi.debug_dump_queue());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn all_leaves_skips_branches() {
+    let mut hi = HashIndex::new_for_testing();
+
+    let foo = leaf(b"foo");
+    let branch = HashEntry{level: 1, payload: Some(b"children".to_vec()), ..leaf(b"branch")};
+    let bar = leaf(b"bar");
+    for e in vec!(&foo, &branch, &bar).into_iter() {
+      hi.reserve(e.clone());
+      hi.commit(&e.hash, &b"ref".to_vec());
+    }
+
+    let leaves = match send(&mut hi, Msg::AllLeaves(Box::new(move|_| {}))) {
+      Reply::HashBatch(entries) => entries,
+      _ => panic!("Unexpected reply from hash index."),
+    };
+    assert_eq!(vec!(foo.hash.clone(), bar.hash.clone()),
+               leaves.into_iter().map(|e| e.hash).collect::<Vec<Hash>>());
+
+    match send(&mut hi, Msg::AllHashes(Box::new(move|_| {}))) {
+      Reply::HashBatch(entries) => assert_eq!(3, entries.len()),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn all_hashes_resumes_after_token() {
+    let mut hi = HashIndex::new_for_testing();
+
+    let entries: Vec<HashEntry> =
+      (0..STREAM_BATCH_SIZE + 100).map(|i| leaf(format!("{}", i).as_bytes())).collect();
+    for e in entries.iter() {
+      hi.reserve(e.clone());
+      hi.commit(&e.hash, &b"ref".to_vec());
+    }
+
+    // Stop after the first batch, keeping only its token:
+    let (sender, receiver) = mpsc::channel();
+    let sink = Box::new(move|_, token| { sender.send(token).unwrap(); });
+    send(&mut hi, Msg::AllHashesFrom(ResumeToken::start(), sink));
+    let token = receiver.recv().unwrap();
+    assert_eq!(Some(token.clone()), ResumeToken::from_bytes(&token.as_bytes()[..]));
+
+    let sink = Box::new(move|_, _| panic!("Unexpected full batch."));
+    match send(&mut hi, Msg::AllHashesFrom(token, sink)) {
+      Reply::ResumableBatch(batch, _) => {
+        let remaining: Vec<Hash> =
+          entries[STREAM_BATCH_SIZE..].iter().map(|e| e.hash.clone()).collect();
+        assert_eq!(remaining, batch.into_iter().map(|e| e.hash).collect::<Vec<Hash>>());
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn enumeration_is_interrupted() {
+    let interrupt = Interrupt::new();
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).interrupt(interrupt.clone()).build();
+
+    for i in 0..2 * STREAM_BATCH_SIZE + 10 {
+      let e = leaf(format!("{}", i).as_bytes());
+      hi.reserve(e.clone());
+      hi.commit(&e.hash, &b"ref".to_vec());
+    }
+
+    // Stop from within the sink, after the first batch:
+    let (sender, receiver) = mpsc::channel();
+    let sink_interrupt = interrupt.clone();
+    let sink = Box::new(move|batch: Vec<HashEntry>| {
+      sender.send(batch.len()).unwrap();
+      sink_interrupt.interrupt();
+    });
+    match send(&mut hi, Msg::AllHashes(sink)) {
+      Reply::Interrupted => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(Ok(STREAM_BATCH_SIZE), receiver.try_recv());
+    assert!(receiver.try_recv().is_err());
+    assert!(!interrupt.is_set());
+
+    // An interrupt while idle does not stop the next enumeration, nor the invariant checks:
+    interrupt.interrupt();
+    assert_eq!(Ok(()), hi.check_invariants());
+    match send(&mut hi, Msg::AllLeaves(Box::new(move|_| {}))) {
+      Reply::HashBatch(entries) => assert_eq!(10, entries.len()),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // The open transaction is still usable, and the next enumeration runs to the end:
+    let foo = leaf(b"foo");
+    hi.reserve(foo.clone());
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+    match send(&mut hi, Msg::AllHashes(Box::new(move|_| {}))) {
+      Reply::HashBatch(entries) => assert_eq!(11, entries.len()),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn hashes_in_object_are_streamed() {
+    let mut hi = HashIndex::new_for_testing();
+
+    let entries: Vec<HashEntry> =
+      (0..2 * STREAM_BATCH_SIZE + 10).map(|i| leaf(format!("{}", i).as_bytes())).collect();
+    for (i, e) in entries.iter().enumerate() {
+      // Every other entry is stored in another object:
+      let name = if i % 2 == 0 { b"x".to_vec() } else { b"y".to_vec() };
+      hi.reserve(e.clone());
+      let blob_ref = BlobRef{name: name, offset: i as u64, length: 1, kind: RefKind::Unknown};
+      hi.commit(&e.hash, &blob_ref.to_bytes());
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let sink = Box::new(move|batch: Vec<HashEntry>| { sender.send(batch).unwrap(); });
+    let last = match send(&mut hi, Msg::HashesInObject(b"x".to_vec(), sink)) {
+      Reply::HashBatch(batch) => batch,
+      _ => panic!("Unexpected reply from hash index."),
+    };
+    let mut streamed: Vec<Hash> =
+      receiver.iter().flat_map(|batch| batch.into_iter()).map(|e| e.hash).collect();
+    assert_eq!(STREAM_BATCH_SIZE, streamed.len());
+    streamed.extend(last.into_iter().map(|e| e.hash));
+    let expected: Vec<Hash> = entries.iter().enumerate()
+      .filter(|&(i, _)| i % 2 == 0).map(|(_, e)| e.hash.clone()).collect();
+    assert_eq!(expected, streamed);
+
+    match send(&mut hi, Msg::HashesInObject(b"z".to_vec(), Box::new(move|_| {}))) {
+      Reply::HashBatch(batch) => assert_eq!(0, batch.len()),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  fn check_storage_summary<B: HashBackend>(mut hi: HashIndex<B>) {
+    let blob = |name: &[u8], offset: u64, length: u64| {
+      BlobRef{name: name.to_vec(), offset: offset, length: length, kind: RefKind::Unknown}
+    };
+    let refs = vec!((leaf(b"a"), blob(b"x", 0, 10)),
+                    (leaf(b"b"), blob(b"x", 10, 5)),
+                    (leaf(b"c"), blob(b"y", 0, 7)),
+                    (HashEntry{level: 1, ..leaf(b"d")}, blob(b"z", 0, 100)));
+    for &(ref e, ref r) in refs.iter() {
+      hi.reserve(e.clone());
+      hi.commit(&e.hash, &r.to_bytes());
+    }
+    // A reserved entry is not included:
+    hi.reserve(leaf(b"e"));
+
+    match send(&mut hi, Msg::StorageSummary) {
+      Reply::StorageSummary{total_bytes, distinct_objects, leaf_count} => {
+        assert_eq!(22, total_bytes);
+        assert_eq!(2, distinct_objects);
+        assert_eq!(3, leaf_count);
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn storage_summary() {
+    check_storage_summary(HashIndex::new_for_testing());
+    check_storage_summary(HashIndexBuilder::new(":memory:".to_string()).build());
+  }
+
+  #[test]
+  fn hash_from_reader() {
+    let data: Vec<u8> = (0..200000).map(|i| (i % 251) as u8).collect();
+    assert_eq!(Hash::new(&data[..]), Hash::from_reader(&mut &data[..]).unwrap());
+    assert_eq!(Hash::new(b""), Hash::from_reader(&mut &b""[..]).unwrap());
+  }
+
+  #[test]
+  fn tagged_hashes_are_separated() {
+    assert!(Hash::tagged(b"foo", 0) != Hash::tagged(b"foo", 1));
+    assert!(Hash::tagged(b"foo", 0) != Hash::new(b"foo"));
+    assert_eq!(Hash::tagged(b"foo", 1), Hash::tagged(b"foo", 1));
+    assert_eq!(Ok(()), Hash::tagged(b"", 0).validate(DIGEST_BYTES));
+  }
+
+  #[test]
+  fn reopen_with_other_digest_width() {
+    let path = env::temp_dir().join("hat_reopen_with_other_digest_width.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+
+    {
+      let mut hi = HashIndexBuilder::new(path_str.clone()).build();
+      let foo = leaf(b"foo");
+      hi.reserve(foo.clone());
+      hi.commit(&foo.hash, &b"ref".to_vec());
+      assert_eq!(Ok(()), hi.flush());
+    }
+
+    match HashIndexBuilder::new(path_str.clone()).digest_width(DIGEST_BYTES / 2).try_build() {
+      Err(e) => assert_eq!(HashIndexError::DigestWidthMismatch{expected: DIGEST_BYTES / 2,
+                                                               found: DIGEST_BYTES}, e),
+      Ok(_) => panic!("Opened an index with another digest width."),
+    }
+    let hi = HashIndexBuilder::new(path_str.clone()).try_build();
+    assert!(hi.is_ok());
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn rescan_repairs_derived_state() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).warm_up(true).build();
+    let foo = leaf(b"foo");
+    hi.reserve(foo.clone());
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+
+    match send(&mut hi, Msg::Rescan) {
+      Reply::Rescanned(report) =>
+        assert_eq!(RescanReport{entries: 1, ids_skipped: 0, missing_from_bloom: 0}, report),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // Lose track of the committed entry and of a queued one:
+    let bar = leaf(b"bar");
+    hi.reserve(bar.clone());
+    hi.id_counter = CumulativeCounter::new(0);
+    hi.bloom = Some(BloomFilter::with_capacity(2));
+    match send(&mut hi, Msg::Rescan) {
+      Reply::Rescanned(report) =>
+        assert_eq!(RescanReport{entries: 1, ids_skipped: 2, missing_from_bloom: 1}, report),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::HashExists(foo.hash.clone())) {
+      Reply::HashKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(1, hi.debug_dump_queue().len());
+
+    // New reserves get fresh ids:
+    let baz = leaf(b"baz");
+    match send(&mut hi, Msg::ReserveReturningId(baz.clone())) {
+      Reply::Reserved(id) => assert_eq!(3, id),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    hi.commit(&bar.hash, &b"ref".to_vec());
+    hi.commit(&baz.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn warm_up_loads_committed_hashes() {
+    let path = env::temp_dir().join("hat_warm_up_loads_committed_hashes.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+
+    let entries = vec!(leaf(b"foo"), leaf(b"bar"), leaf(b"baz"));
+    {
+      let mut hi = HashIndexBuilder::new(path_str.clone()).build();
+      assert_eq!(None, hi.warm_up_report());
+      for e in entries.iter() {
+        hi.reserve(e.clone());
+        hi.commit(&e.hash, &b"ref".to_vec());
+      }
+      assert_eq!(Ok(()), hi.flush());
+    }
+
+    let mut hi = HashIndexBuilder::new(path_str.clone()).warm_up(true).build();
+    assert_eq!(Some(3), hi.warm_up_report().map(|report| report.entries));
+    for e in entries.iter() {
+      match send(&mut hi, Msg::HashExists(e.hash.clone())) {
+        Reply::HashKnown => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+    match send(&mut hi, Msg::HashExists(leaf(b"qux").hash)) {
+      Reply::HashNotKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // Entries committed after the warm-up are found as well:
+    let qux = leaf(b"qux");
+    hi.reserve(qux.clone());
+    hi.commit(&qux.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+    match send(&mut hi, Msg::HashExists(qux.hash.clone())) {
+      Reply::HashKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn rollback_to_savepoint() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    let (sender, receiver) = mpsc::channel();
+
+    let before = leaf(b"before");
+    let after = leaf(b"after");
+    hi.reserve(before.clone());
+    hi.commit(&before.hash, &b"ref".to_vec());
+
+    match send(&mut hi, Msg::Savepoint("file".to_string())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    hi.reserve(after.clone());
+    let callback = Box::new(move|| { sender.send(()).unwrap(); });
+    match send(&mut hi, Msg::CallAfterHashIsComitted(after.hash.clone(), callback)) {
+      Reply::CallbackRegistered(_) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    hi.commit(&after.hash, &b"ref".to_vec());
+    assert!(hi.index_locate(&after.hash).unwrap().is_some());
+
+    match send(&mut hi, Msg::RollbackToSavepoint("file".to_string())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    // The savepoint stays open until released:
+    match send(&mut hi, Msg::ReleaseSavepoint("file".to_string())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::ReleaseSavepoint("file".to_string())) {
+      Reply::Error(e) => assert_eq!(HashIndexError::UnknownSavepoint("file".to_string()), e),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.flush());
+    assert!(hi.index_locate(&before.hash).unwrap().is_some());
+    assert!(hi.index_locate(&after.hash).unwrap().is_none());
+    assert!(receiver.try_recv().is_err());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn rollback_to_savepoint_resets_queued_commits() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+
+    let early = leaf(b"early");
+    let late = leaf(b"late");
+    let reserved = leaf(b"reserved");
+    hi.reserve(early.clone());
+    hi.reserve(reserved.clone());
+    hi.update_reserved(HashEntry{persistent_ref: Some(b"old".to_vec()), ..reserved.clone()});
+    match send(&mut hi, Msg::Savepoint("file".to_string())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    // Both wait for `early`, which has a lower id:
+    hi.reserve(late.clone());
+    hi.commit(&late.hash, &b"late".to_vec());
+    hi.commit(&reserved.hash, &b"new".to_vec());
+    assert_eq!(Some(true), hi.queue.is_ready(&late.hash.bytes));
+
+    match send(&mut hi, Msg::RollbackToSavepoint("file".to_string())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(Some(false), hi.queue.is_ready(&late.hash.bytes));
+    assert_eq!(Some(false), hi.queue.is_ready(&reserved.hash.bytes));
+    assert_eq!(Some(b"old".to_vec()),
+               hi.queue.find_value_of_key(&reserved.hash.bytes).and_then(|qe| qe.persistent_ref));
+
+    // Committing `early` does not insert the others:
+    hi.commit(&early.hash, &b"early".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+    assert!(hi.index_locate(&early.hash).unwrap().is_some());
+    assert!(hi.index_locate(&late.hash).unwrap().is_none());
+
+    hi.commit(&late.hash, &b"again".to_vec());
+    hi.commit(&reserved.hash, &b"new".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+    assert_eq!(Some(b"again".to_vec()),
+               hi.index_locate(&late.hash).unwrap().and_then(|e| e.persistent_ref));
+    assert_eq!(0, hi.queue.len());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn find_orphans() {
+    let mut hi = HashIndex::new_for_testing();
+    let blob = |name: &[u8]| {
+      BlobRef{name: name.to_vec(), offset: 0, length: 3, kind: RefKind::Unknown}
+    };
+
+    let kept = leaf(b"kept");
+    let lost = leaf(b"lost");
+    let unstructured = leaf(b"unstructured");
+    let queued = leaf(b"queued");
+    hi.reserve(kept.clone());
+    hi.commit(&kept.hash, &blob(b"kept").to_bytes());
+    hi.reserve(lost.clone());
+    hi.commit(&lost.hash, &blob(b"lost").to_bytes());
+    hi.reserve(unstructured.clone());
+    hi.commit(&unstructured.hash, &b"ref".to_vec());
+    hi.reserve(queued.clone());
+
+    let exists = Box::new(|r: &BlobRef| r.name != b"lost".to_vec());
+    match send(&mut hi, Msg::FindOrphans(exists)) {
+      Reply::Orphans(hashes) => assert_eq!(vec!(lost.hash), hashes),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn tagged_replies() {
+    let mut hi = HashIndex::new_for_testing();
+    let foo = leaf(b"foo");
+
+    match send(&mut hi, Msg::Tagged(RequestId(7), Box::new(Msg::Reserve(foo.clone())))) {
+      Reply::Tagged(id, reply) => {
+        assert_eq!(RequestId(7), id);
+        match *reply {
+          Reply::ReserveOK => (),
+          _ => panic!("Unexpected reply from hash index."),
+        }
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // Messages may be tagged more than once, e.g. by nested layers of tracing:
+    let twice = Msg::Tagged(RequestId(1), Box::new(
+      Msg::Tagged(RequestId(2), Box::new(Msg::HashExists(foo.hash.clone())))));
+    match send(&mut hi, twice) {
+      Reply::Tagged(RequestId(1), outer) => match *outer {
+        Reply::Tagged(RequestId(2), inner) => match *inner {
+          Reply::HashKnown => (),
+          _ => panic!("Unexpected reply from hash index."),
+        },
+        _ => panic!("Unexpected reply from hash index."),
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    match send(&mut hi, Msg::HashExists(foo.hash.clone())) {
+      Reply::HashKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn callback_limit() {
+    let mut hi = HashIndexBuilder::new(String::new())
+      .max_callbacks(2, CallbackLimitPolicy::Reject)
+      .build_in_memory();
+    let (sender, receiver) = mpsc::channel();
+    let (foo, bar) = (leaf(b"foo"), leaf(b"bar"));
+    hi.reserve(foo.clone());
+    hi.reserve(bar.clone());
+
+    let register = |hi: &mut HashIndex<MemoryBackend>, hash: &Hash, name: &'static str| {
+      let sender = sender.clone();
+      send(hi, Msg::CallAfterHashIsComitted(hash.clone(),
+                                            Box::new(move|| { sender.send(name).unwrap(); })))
+    };
+    for name in ["first", "second"].iter() {
+      match register(&mut hi, &foo.hash, *name) {
+        Reply::CallbackRegistered(_) => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+    match register(&mut hi, &bar.hash, "rejected") {
+      Reply::CallbackLimitReached => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::CallAfterAllCommitted(vec!(bar.hash.clone()), Box::new(|| {}))) {
+      Reply::CallbackLimitReached => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::CallbackCount) {
+      Reply::CallbackCount(n) => assert_eq!(2, n),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // Evicting drops the oldest callback instead:
+    hi.config.callback_limit_policy = CallbackLimitPolicy::EvictOldest;
+    match register(&mut hi, &bar.hash, "evicting") {
+      Reply::CallbackRegistered(_) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    hi.commit(&bar.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+    let mut called = vec!();
+    while let Ok(name) = receiver.try_recv() {
+      called.push(name);
+    }
+    called.sort();
+    assert_eq!(vec!("evicting", "second"), called);
+
+    match send(&mut hi, Msg::CallbackCount) {
+      Reply::CallbackCount(n) => assert_eq!(0, n),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn flush_reports_empty_commits() {
+    let mut hi = HashIndexBuilder::new(String::new())
+      .flush_quiet_period(Duration::seconds(5))
+      .build_in_memory();
+    let start = SteadyTime::now();
+    hi.set_clock(Box::new(move|| start));
+
+    match send(&mut hi, Msg::Flush) {
+      Reply::FlushedNothing => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    let foo = leaf(b"foo");
+    hi.reserve(foo.clone());
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    match send(&mut hi, Msg::Flush) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::Flush) {
+      Reply::FlushedNothing => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn limited_inserts_per_commit() {
+    // Hold back the periodic flush, which would insert everything:
+    let mut hi = HashIndexBuilder::new(String::new())
+      .max_inserts_per_commit(2)
+      .flush_quiet_period(Duration::seconds(5))
+      .build_in_memory();
+    let start = SteadyTime::now();
+    hi.set_clock(Box::new(move|| start));
+
+    let entries: Vec<HashEntry> = (0..5u8).map(|i| leaf(&[i])).collect();
+    for e in entries.iter() {
+      hi.reserve(e.clone());
+    }
+    // Nothing can be inserted until the first entry is committed:
+    for e in entries[1..].iter() {
+      hi.commit(&e.hash, &b"ref".to_vec());
+    }
+    assert!(hi.index_locate(&entries[1].hash).unwrap().is_none());
+
+    hi.commit(&entries[0].hash, &b"ref".to_vec());
+    assert!(hi.index_locate(&entries[1].hash).unwrap().is_some());
+    assert!(hi.index_locate(&entries[2].hash).unwrap().is_none());
+    assert_eq!(Ok(()), hi.check_invariants());
+
+    // Flushing inserts the rest:
+    assert_eq!(Ok(()), hi.flush());
+    for e in entries.iter() {
+      assert!(hi.index_locate(&e.hash).unwrap().is_some());
+    }
+    assert_eq!(0, hi.debug_dump_queue().len());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn callbacks_run_in_batches() {
+    let mut hi = HashIndexBuilder::new(String::new())
+      .max_callbacks_per_batch(2)
+      .build_in_memory();
+    let (sender, receiver) = mpsc::channel();
+    let called = || {
+      let mut n = 0;
+      while receiver.try_recv().is_ok() {
+        n += 1;
+      }
+      n
+    };
+
+    let entries: Vec<HashEntry> = (0..5u8).map(|i| leaf(&[i])).collect();
+    for e in entries.iter() {
+      hi.reserve(e.clone());
+      let sender = sender.clone();
+      let callback = Box::new(move|| { sender.send(()).unwrap(); });
+      match send(&mut hi, Msg::CallAfterHashIsComitted(e.hash.clone(), callback)) {
+        Reply::CallbackRegistered(_) => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+    for e in entries.iter() {
+      hi.commit(&e.hash, &b"ref".to_vec());
+    }
+
+    // The flush calls the first batch:
+    assert_eq!(Ok(()), hi.flush());
+    assert_eq!(2, called());
+    assert!(hi.has_deferred_work());
+
+    // Each message first calls another batch:
+    match send(&mut hi, Msg::CallbackCount) {
+      Reply::CallbackCount(_) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(2, called());
+
+    // And so does resuming while idle:
+    hi.resume();
+    assert_eq!(1, called());
+    assert!(!hi.has_deferred_work());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn fetch_by_id() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    let committed = leaf(b"committed");
+    let queued = leaf(b"queued");
+    let prioritized = leaf(b"prioritized");
+    hi.reserve(committed.clone());
+    hi.commit(&committed.hash, &b"ref".to_vec());
+    hi.reserve(queued.clone());
+    assert!(hi.reserve_at(prioritized.clone(), true).is_ok());
+
+    for e in [committed.clone(), queued, prioritized].iter() {
+      let id = match send(&mut hi, Msg::FetchId(e.hash.clone())) {
+        Reply::Id(id) => id,
+        _ => panic!("Unexpected reply from hash index."),
+      };
+      match send(&mut hi, Msg::FetchById(id)) {
+        Reply::Entry(found) => assert_eq!(e.hash, found.hash),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+    match send(&mut hi, Msg::FetchById(1)) {
+      Reply::Entry(found) => assert_eq!(HashEntry{persistent_ref: Some(b"ref".to_vec()),
+                                                  ..committed}, found),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    match send(&mut hi, Msg::FetchId(Hash::new(b"unknown"))) {
+      Reply::HashNotKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::FetchById(1000)) {
+      Reply::HashNotKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn reserve_returning_id() {
+    let mut hi = HashIndex::new_for_testing();
+    let foo = leaf(b"foo");
+
+    let id = match send(&mut hi, Msg::ReserveReturningId(foo.clone())) {
+      Reply::Reserved(id) => id,
+      _ => panic!("Unexpected reply from hash index."),
+    };
+    match send(&mut hi, Msg::FetchId(foo.hash.clone())) {
+      Reply::Id(found) => assert_eq!(id, found),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::ReserveReturningId(foo.clone())) {
+      Reply::AlreadyReserved => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn reserve_or_fetch() {
+    let mut hi = HashIndex::new_for_testing();
+    let foo = leaf(b"foo");
+
+    let id = match send(&mut hi, Msg::ReserveOrFetch(foo.clone())) {
+      Reply::Reserved(id) => id,
+      _ => panic!("Unexpected reply from hash index."),
+    };
+    assert_eq!(Some(id), hi.locate(&foo.hash).unwrap().map(|qe| qe.id));
+
+    // The reserved entry has no persistent reference until it is updated or committed:
+    match send(&mut hi, Msg::ReserveOrFetch(foo.clone())) {
+      Reply::Retry => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+    match send(&mut hi, Msg::ReserveOrFetch(foo.clone())) {
+      Reply::PersistentRef(r) => assert_eq!(b"ref".to_vec(), r),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(0, hi.debug_dump_queue().len());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn trace_for_hash() {
+    let mut hi = HashIndexBuilder::new(String::new()).trace(10, 100).build_in_memory();
+    let start = SteadyTime::now();
+    let elapsed = Rc::new(Cell::new(Duration::seconds(0)));
+    let clock_elapsed = elapsed.clone();
+    hi.set_clock(Box::new(move|| start + clock_elapsed.get()));
+
+    let foo = leaf(b"foo");
+    hi.reserve(foo.clone());
+    elapsed.set(Duration::milliseconds(5));
+    send(&mut hi, Msg::UpdateReserved(foo.clone()));
+    send(&mut hi, Msg::CallAfterHashIsComitted(foo.hash.clone(), Box::new(move|| {})));
+    elapsed.set(Duration::milliseconds(10));
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+
+    let kinds = vec!((TraceKind::Reserved, 0), (TraceKind::Updated, 5),
+                     (TraceKind::Committed, 10), (TraceKind::Inserted, 10),
+                     (TraceKind::CallbackFired, 10));
+    match send(&mut hi, Msg::TraceFor(foo.hash.clone())) {
+      Reply::Trace(events) =>
+        assert_eq!(kinds.into_iter().map(|(k, t)| TraceEvent{kind: k, at_ms: t})
+                        .collect::<Vec<_>>(),
+                   events),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // Tracing is off by default:
+    let mut untraced = HashIndex::new_for_testing();
+    untraced.reserve(foo.clone());
+    match send(&mut untraced, Msg::TraceFor(foo.hash.clone())) {
+      Reply::Trace(events) => assert_eq!(0, events.len()),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn internal_errors_are_replied() {
+    let mut hi = HashIndexBuilder::new(String::new()).fail_fast(false).build_in_memory();
+    let foo = leaf(b"foo");
+    hi.reserve(foo.clone());
+    hi.commit(&foo.hash, &b"ref".to_vec());
+
+    match send(&mut hi, Msg::UpdateReserved(leaf(b"bar"))) {
+      Reply::InternalError(_) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // The index keeps working:
+    match send(&mut hi, Msg::HashExists(foo.hash.clone())) {
+      Reply::HashKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn exhausted_ids_are_replied() {
+    let mut hi = HashIndexBuilder::new(String::new()).fail_fast(true).build_in_memory();
+    hi.id_counter = CumulativeCounter::new(MAX_VALUE);
+    let foo = leaf(b"foo");
+    match send(&mut hi, Msg::Reserve(foo.clone())) {
+      Reply::Error(HashIndexError::IdsExhausted) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // Nothing was reserved:
+    match send(&mut hi, Msg::HashExists(foo.hash.clone())) {
+      Reply::HashNotKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn rewrite_ref_prefix() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    let blob = |name: &[u8]| {
+      BlobRef{name: name.to_vec(), offset: 10, length: 20, kind: RefKind::Unknown}
+    };
+
+    let moved = leaf(b"moved");
+    let other = leaf(b"other");
+    let queued = leaf(b"queued");
+    hi.reserve(moved.clone());
+    hi.commit(&moved.hash, &blob(b"old/moved").to_bytes());
+    hi.reserve(other.clone());
+    hi.commit(&other.hash, &blob(b"elsewhere/old/other").to_bytes());
+    hi.reserve(queued.clone());
+    hi.update_reserved(HashEntry{persistent_ref: Some(blob(b"old/queued").to_bytes()),
+                                 ..queued.clone()});
+
+    let rewrite = Msg::RewriteRefPrefix{from: b"old/".to_vec(), to: b"new/".to_vec()};
+    match send(&mut hi, rewrite) {
+      Reply::Relocated{updated, not_found} => {
+        assert_eq!(1, updated);
+        assert_eq!(0, not_found);
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    let persistent_ref = |hi: &mut HashIndex, hash: &Hash| {
+      hi.locate(hash).unwrap().and_then(|qe| qe.persistent_ref)
+        .and_then(|r| BlobRef::from_bytes(&r[..]))
+    };
+    assert_eq!(Some(blob(b"new/moved")), persistent_ref(&mut hi, &moved.hash));
+    assert_eq!(Some(blob(b"elsewhere/old/other")), persistent_ref(&mut hi, &other.hash));
+    assert_eq!(Some(blob(b"old/queued")), persistent_ref(&mut hi, &queued.hash));
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn leaves_of_checks_ref_kinds() {
+    let mut hi = HashIndex::new_for_testing();
+    let blob = |name: &[u8], kind: RefKind| {
+      BlobRef{name: name.to_vec(), offset: 0, length: 3, kind: kind}
+    };
+    let branch = |name: &[u8], children: &[&Hash]| {
+      let children: Vec<Hash> = children.iter().map(|&h| h.clone()).collect();
+      HashEntry{hash: Hash::new(name), level: 1,
+                payload: Some(encode_children(PayloadVersion::current(), &children[..])),
+                persistent_ref: None, content_len: 0}
+    };
+
+    let (a, b) = (leaf(b"a"), leaf(b"b"));
+    let good = branch(b"good", &[&a.hash]);
+    let bad_branch = branch(b"bad branch", &[&a.hash]);
+    let bad_leaf = branch(b"bad leaf", &[&b.hash]);
+    for &(e, kind) in [(&a, RefKind::Leaf), (&b, RefKind::Branch), (&good, RefKind::Branch),
+                       (&bad_branch, RefKind::Leaf), (&bad_leaf, RefKind::Branch)].iter() {
+      hi.reserve(e.clone());
+      hi.commit(&e.hash, &blob(&e.hash.bytes[..2], kind).to_bytes());
+    }
+
+    let sink = Box::new(move|_: BlobRef| {});
+    match send(&mut hi, Msg::LeavesOf(good.hash.clone(), sink)) {
+      Reply::LeafCount(1) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    for &(ref root, ref wrong) in [(&bad_branch, &bad_branch), (&bad_leaf, &b)].iter() {
+      let sink = Box::new(move|_: BlobRef| {});
+      match send(&mut hi, Msg::LeavesOf(root.hash.clone(), sink)) {
+        Reply::BrokenTree(e) => assert_eq!(TreeError::WrongRefKind(wrong.hash.clone()), e),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn leaves_of_tree() {
+    let mut hi = HashIndex::new_for_testing();
+    let blob = |name: &[u8]| {
+      BlobRef{name: name.to_vec(), offset: 0, length: 3, kind: RefKind::Unknown}
+    };
+    let branch = |level: i64, name: &[u8], children: &[&Hash]| {
+      let children: Vec<Hash> = children.iter().map(|&h| h.clone()).collect();
+      HashEntry{hash: Hash::new(name), level: level,
+                payload: Some(encode_children(PayloadVersion::current(), &children[..])),
+                persistent_ref: None, content_len: 0}
+    };
+
+    let (a, b, c) = (leaf(b"a"), leaf(b"b"), leaf(b"c"));
+    let inner = branch(1, b"inner", &[&a.hash, &b.hash]);
+    let root = branch(2, b"root", &[&inner.hash, &c.hash]);
+    for e in [&a, &b, &c, &inner, &root].iter() {
+      hi.reserve((*e).clone());
+      hi.commit(&e.hash, &blob(&e.hash.bytes[..2]).to_bytes());
+    }
+
+    let leaves_of = |hi: &mut HashIndex<MemoryBackend>, hash: &Hash| {
+      let (sender, receiver) = mpsc::channel();
+      let sink = Box::new(move|r: BlobRef| { sender.send(r).unwrap(); });
+      let reply = send(hi, Msg::LeavesOf(hash.clone(), sink));
+      (reply, receiver.iter().collect::<Vec<BlobRef>>())
+    };
+
+    match leaves_of(&mut hi, &root.hash) {
+      (Reply::LeafCount(n), refs) => {
+        assert_eq!(3, n);
+        let expected: Vec<BlobRef> =
+          [&a, &b, &c].iter().map(|e| blob(&e.hash.bytes[..2])).collect();
+        assert_eq!(expected, refs);
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match leaves_of(&mut hi, &c.hash) {
+      (Reply::LeafCount(1), _) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    let missing = Hash::new(b"missing");
+    let broken = branch(1, b"broken", &[&a.hash, &missing]);
+    hi.reserve(broken.clone());
+    hi.commit(&broken.hash, &b"ref".to_vec());
+    match leaves_of(&mut hi, &broken.hash) {
+      (Reply::BrokenTree(e), refs) => {
+        assert_eq!(TreeError::UnknownHash(missing), e);
+        assert_eq!(1, refs.len());
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // Sequenced payloads are followed in sequence order, and must not skip any:
+    let sequenced = |name: &[u8], children: &[(u64, &Hash)]| {
+      let children: Vec<(u64, Hash)> = children.iter().map(|&(s, h)| (s, h.clone())).collect();
+      HashEntry{hash: Hash::new(name), level: 1, payload: Some(encode_sequenced(&children[..])),
+                persistent_ref: None, content_len: 0}
+    };
+    let reordered = sequenced(b"reordered", &[(1, &b.hash), (0, &a.hash)]);
+    let gap = sequenced(b"gap", &[(0, &a.hash), (2, &b.hash)]);
+    for e in [&reordered, &gap].iter() {
+      hi.reserve((*e).clone());
+      hi.commit(&e.hash, &b"ref".to_vec());
+    }
+    match leaves_of(&mut hi, &reordered.hash) {
+      (Reply::LeafCount(2), refs) =>
+        assert_eq!(vec!(blob(&a.hash.bytes[..2]), blob(&b.hash.bytes[..2])), refs),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match leaves_of(&mut hi, &gap.hash) {
+      (Reply::BrokenTree(e), refs) => {
+        assert_eq!(TreeError::InvalidPayload(gap.hash.clone()), e);
+        assert_eq!(0, refs.len());
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn fetch_content_length() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    let foo = leaf(b"foo");
+    let queued = leaf(b"queued");
+    hi.reserve(foo.clone());
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    hi.reserve(queued.clone());
+
+    for (entry, len) in vec!((foo, 3), (queued, 6)).into_iter() {
+      match send(&mut hi, Msg::FetchLength(entry.hash)) {
+        Reply::Length(n) => assert_eq!(len, n),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+    match send(&mut hi, Msg::FetchLength(Hash::new(b"unknown"))) {
+      Reply::HashNotKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn estimate_count_is_an_upper_bound() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    let estimate = |hi: &mut HashIndex| match send(hi, Msg::EstimateCount) {
+      Reply::Estimate(n) => n,
+      _ => panic!("Unexpected reply from hash index."),
+    };
+    assert_eq!(0, estimate(&mut hi));
+
+    let abandoned = leaf(b"abandoned");
+    hi.reserve(abandoned.clone());
+    for name in vec!("foo", "bar").into_iter() {
+      let entry = leaf(name.as_bytes());
+      hi.reserve(entry.clone());
+      hi.commit(&entry.hash, &b"ref".to_vec());
+    }
+    assert_eq!(0, estimate(&mut hi));
+
+    // The abandoned id is counted, but queued entries are not:
+    hi.abandon(&abandoned.hash.bytes);
+    hi.reserve(leaf(b"queued"));
+    assert_eq!(3, estimate(&mut hi));
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn filter_unknown_hashes() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    let committed = leaf(b"committed");
+    let queued = leaf(b"queued");
+    hi.reserve(committed.clone());
+    hi.commit(&committed.hash, &b"ref".to_vec());
+    hi.reserve(queued.clone());
+
+    let hashes = vec!(Hash::new(b"new"), committed.hash.clone(), queued.hash.clone(),
+                      Hash::new(b"other"));
+    match send(&mut hi, Msg::FilterUnknown(hashes)) {
+      Reply::Unknown(unknown) => assert_eq!(vec!(Hash::new(b"new"), Hash::new(b"other")), unknown),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn call_after_all_committed() {
+    let mut hi = HashIndex::new_for_testing();
+    let (sender, receiver) = mpsc::channel();
+
+    let committed = leaf(b"committed");
+    let first = leaf(b"first");
+    let second = leaf(b"second");
+    hi.reserve(committed.clone());
+    hi.commit(&committed.hash, &b"ref".to_vec());
+    hi.reserve(first.clone());
+    hi.reserve(second.clone());
+
+    let all = vec!(first.hash.clone(), committed.hash.clone(), second.hash.clone());
+    let all_sender = sender.clone();
+    let callback = Box::new(move|| { all_sender.send("all").unwrap(); });
+    match send(&mut hi, Msg::CallAfterAllCommitted(all, callback)) {
+      Reply::CallbackRegistered(_) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    hi.commit(&first.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+    assert!(receiver.try_recv().is_err());
+
+    hi.commit(&second.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+    assert_eq!(Ok("all"), receiver.try_recv());
+    assert!(receiver.try_recv().is_err());
+
+    // All hashes are committed already, so the callback is called right away:
+    let now_sender = sender.clone();
+    let callback = Box::new(move|| { now_sender.send("now").unwrap(); });
+    match send(&mut hi, Msg::CallAfterAllCommitted(vec!(first.hash.clone()), callback)) {
+      Reply::CallbackRegistered(_) => assert_eq!(Ok("now"), receiver.try_recv()),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    let callback = Box::new(move|| { sender.send("never").unwrap(); });
+    match send(&mut hi, Msg::CallAfterAllCommitted(vec!(Hash::new(b"unknown")), callback)) {
+      Reply::HashNotKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn call_after_each_committed() {
+    let mut hi = HashIndex::new_for_testing();
+    let (sender, receiver) = mpsc::channel();
+
+    let committed = leaf(b"committed");
+    let first = leaf(b"first");
+    let second = leaf(b"second");
+    hi.reserve(committed.clone());
+    hi.commit(&committed.hash, &b"ref".to_vec());
+    hi.reserve(first.clone());
+    hi.reserve(second.clone());
+
+    let all = vec!(first.hash.clone(), committed.hash.clone(), second.hash.clone());
+    let each_sender = sender.clone();
+    let callback = Box::new(move|hash: Hash| { each_sender.send(hash).unwrap(); });
+    match send(&mut hi, Msg::CallAfterEachCommitted(all, callback)) {
+      Reply::CallbacksRegistered{registered, fired, ..} => {
+        assert_eq!(2, registered);
+        assert_eq!(1, fired);
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(Ok(committed.hash.clone()), receiver.try_recv());
+    assert!(receiver.try_recv().is_err());
+
+    hi.commit(&second.hash, &b"ref".to_vec());
+    hi.commit(&first.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+    let mut fired = vec!(receiver.try_recv().unwrap(), receiver.try_recv().unwrap());
+    assert!(receiver.try_recv().is_err());
+    fired.sort_by(|a, b| a.bytes.cmp(&b.bytes));
+    let mut expected = vec!(first.hash.clone(), second.hash.clone());
+    expected.sort_by(|a, b| a.bytes.cmp(&b.bytes));
+    assert_eq!(expected, fired);
+
+    let callback = Box::new(move|hash: Hash| { sender.send(hash).unwrap(); });
+    let hashes = vec!(first.hash.clone(), Hash::new(b"unknown"));
+    match send(&mut hi, Msg::CallAfterEachCommitted(hashes, callback)) {
+      Reply::HashNotKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert!(receiver.try_recv().is_err());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn cancel_callback() {
+    let mut hi = HashIndex::new_for_testing();
+    let (sender, receiver) = mpsc::channel();
+
+    let foo = leaf(b"foo");
+    hi.reserve(foo.clone());
+    let mut tokens = vec!();
+    for name in vec!("cancelled", "called").into_iter() {
+      let sender = sender.clone();
+      let callback = Box::new(move|| { sender.send(name).unwrap(); });
+      match send(&mut hi, Msg::CallAfterHashIsComitted(foo.hash.clone(), callback)) {
+        Reply::CallbackRegistered(token) => tokens.push(token),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+
+    match send(&mut hi, Msg::CancelCallback(tokens[0])) {
+      Reply::CallbackCancelled(cancelled) => assert!(cancelled),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+    assert_eq!(Ok("called"), receiver.try_recv());
+    assert!(receiver.try_recv().is_err());
+
+    // Cancelling a callback that was already called does nothing:
+    match send(&mut hi, Msg::CancelCallback(tokens[1])) {
+      Reply::CallbackCancelled(cancelled) => assert!(!cancelled),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn prioritized_entries_skip_the_backlog() {
+    let mut hi = HashIndex::new_for_testing();
+
+    let first = leaf(b"first");
+    let second = leaf(b"second");
+    let branch = HashEntry{level: 1, ..leaf(b"branch")};
+    hi.reserve(first.clone());
+    hi.reserve(second.clone());
+    match send(&mut hi, Msg::ReservePrioritized(branch.clone())) {
+      Reply::ReserveOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(branch.hash.bytes.to_vec(), hi.debug_dump_queue()[0].1);
+
+    // The prioritized entry is inserted without waiting for the earlier entries:
+    hi.commit(&branch.hash, &b"ref".to_vec());
+    assert_eq!(Some(3), hi.index_locate(&branch.hash).unwrap().map(|qe| qe.id));
+    assert!(hi.index_locate(&first.hash).unwrap().is_none());
+
+    hi.commit(&second.hash, &b"ref".to_vec());
+    hi.commit(&first.hash, &b"ref".to_vec());
+    assert_eq!(Some(1), hi.index_locate(&first.hash).unwrap().map(|qe| qe.id));
+    assert_eq!(Some(2), hi.index_locate(&second.hash).unwrap().map(|qe| qe.id));
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn branches_first_order() {
+    let mut hi =
+      HashIndexBuilder::new(String::new()).queue_order(QueueOrder::BranchesFirst).build_in_memory();
+
+    let (a, b) = (leaf(b"a"), leaf(b"b"));
+    let branch = HashEntry{level: 1, ..leaf(b"branch")};
+    let root = HashEntry{level: 2, ..leaf(b"root")};
+    for e in [&a, &b, &branch, &root].iter() {
+      hi.reserve((*e).clone());
+    }
+    assert_eq!(vec!(4, 3, 1, 2),
+               hi.debug_dump_queue().into_iter().map(|(id, _, _)| id).collect::<Vec<i64>>());
+
+    // Queued entries are found by id at any rank:
+    for &(id, ref e) in [(1, &a), (3, &branch), (4, &root)].iter() {
+      match send(&mut hi, Msg::FetchById(id)) {
+        Reply::Entry(entry) => assert_eq!(e.hash, entry.hash),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+
+    // Branches wait only for the higher levels:
+    hi.commit(&branch.hash, &b"ref".to_vec());
+    assert!(hi.index_locate(&branch.hash).unwrap().is_none());
+    hi.commit(&root.hash, &b"ref".to_vec());
+    assert_eq!(Some(3), hi.index_locate(&branch.hash).unwrap().map(|qe| qe.id));
+    assert_eq!(Some(4), hi.index_locate(&root.hash).unwrap().map(|qe| qe.id));
+    assert_eq!(vec!((1, a.hash.bytes.to_vec(), false), (2, b.hash.bytes.to_vec(), false)),
+               hi.debug_dump_queue());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn page_through_committed_entries() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    let entries: Vec<HashEntry> = (0..5).map(|i| leaf(format!("{}", i).as_bytes())).collect();
+    for e in entries.iter() {
+      hi.reserve(e.clone());
+      hi.commit(&e.hash, &b"ref".to_vec());
+    }
+    // Queued entries are not counted:
+    hi.reserve(leaf(b"queued"));
+
+    let page = |hi: &mut HashIndex, offset, limit| {
+      match send(hi, Msg::Page{offset: offset, limit: limit}) {
+        Reply::Page(es, total) => (es.into_iter().map(|e| e.hash).collect::<Vec<_>>(), total),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    };
+    assert_eq!((vec!(entries[0].hash.clone(), entries[1].hash.clone()), 5), page(&mut hi, 0, 2));
+    assert_eq!((vec!(entries[4].hash.clone()), 5), page(&mut hi, 4, 2));
+    assert_eq!((vec!(), 5), page(&mut hi, 6, 2));
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn file_size_counts_pages() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    let empty = match send(&mut hi, Msg::FileSize) {
+      Reply::FileSize(size) => size,
+      _ => panic!("Unexpected reply from hash index."),
+    };
+    assert!(empty > 0);
+
+    let entry = HashEntry{payload: Some(vec![1u8; 100000]), ..leaf(b"foo")};
+    hi.reserve(entry.clone());
+    hi.commit(&entry.hash, &b"ref".to_vec());
+    match send(&mut hi, Msg::FileSize) {
+      Reply::FileSize(size) => assert!(size >= empty + 100000),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn merge_from_other_index() {
+    let path = env::temp_dir().join("hat_merge_from_other_index.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+
+    let foo = leaf(b"foo");
+    let bar = leaf(b"bar");
+    let baz = HashEntry{payload: Some(b"payload".to_vec()), ..leaf(b"baz")};
+    {
+      let mut other = HashIndex::new(path_str.clone());
+      let entries: Vec<(&HashEntry, &[u8])> = vec!((&foo, b"ref"), (&bar, b"new"), (&baz, b"ref"));
+      for (e, r) in entries.into_iter() {
+        other.reserve(e.clone());
+        other.commit(&e.hash, &r.to_vec());
+      }
+      assert_eq!(Ok(()), other.flush());
+    }
+
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    for e in vec!(&foo, &bar).into_iter() {
+      hi.reserve(e.clone());
+      hi.commit(&e.hash, &b"ref".to_vec());
+    }
+
+    assert_eq!(Ok(MergeReport{added: 1, duplicates: 1, conflicts: 1}),
+               hi.merge_from(&path_str[..]));
+    match send(&mut hi, Msg::FetchPayload(baz.hash.clone())) {
+      Reply::Payload(p) => assert_eq!(baz.payload, p),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // New entries get ids after the merged ones:
+    let qux = leaf(b"qux");
+    assert!(hi.reserve(qux) > hi.index_locate(&baz.hash).unwrap().unwrap().id);
+
+    assert_eq!(Ok(()), hi.check_invariants());
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn merge_keeps_ids_of_queued_entries() {
+    let path = env::temp_dir().join("hat_merge_keeps_ids_of_queued_entries.sqlite3");
+    let path_str = path.to_str().unwrap().to_string();
+    let _ = fs::remove_file(&path);
+    drop(HashIndex::new(path_str.clone()));
+
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    let foo = leaf(b"foo");
+    let foo_id = hi.reserve(foo.clone());
+
+    // Nothing is merged, and the entry in flight keeps its id to itself:
+    assert_eq!(Ok(MergeReport{added: 0, duplicates: 0, conflicts: 0}),
+               hi.merge_from(&path_str[..]));
+    assert!(hi.reserve(leaf(b"bar")) > foo_id);
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn blob_ref_identity() {
+    let r = BlobRef{name: b"name".to_vec(), offset: 3, length: 4, kind: RefKind::Unknown};
+    assert_eq!(Some(r.clone()), BlobRef::from_bytes(&r.to_bytes()[..]));
+    assert_eq!(None, BlobRef::from_bytes(b"not a ref"));
+
+    // Known kinds are written after the version:
+    for &kind in [RefKind::Leaf, RefKind::Branch].iter() {
+      let r = BlobRef{kind: kind, ..r.clone()};
+      let bytes = r.to_bytes();
+      assert_eq!(vec!(BLOB_REF_V2, kind.code()), bytes[..2].to_vec());
+      assert_eq!(Some(r), BlobRef::from_bytes(&bytes[..]));
+    }
+    assert_eq!(None, BlobRef::from_bytes(&[BLOB_REF_V2, 9, 0, 0, 0, 0]));
+  }
+
+  #[test]
+  fn blob_ref_encoding_is_big_endian() {
+    let bytes = vec!(BLOB_REF_V1,
+                     0, 0, 0, 2, b'a', b'b',
+                     0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+                     0, 0, 0, 0, 0, 0, 0x01, 0x00);
+    let r = BlobRef{name: b"ab".to_vec(), offset: 0x0102030405060708, length: 0x100,
+                    kind: RefKind::Unknown};
+    assert_eq!(bytes, r.to_bytes());
+    assert_eq!(Some(r), BlobRef::from_bytes(&bytes[..]));
+
+    // Truncated or trailing bytes are not references:
+    assert_eq!(None, BlobRef::from_bytes(&bytes[..bytes.len() - 1]));
+    let mut long = bytes.clone();
+    long.push(0);
+    assert_eq!(None, BlobRef::from_bytes(&long[..]));
+    assert_eq!(None, BlobRef::from_bytes(&[BLOB_REF_V1, 0xff, 0xff, 0xff, 0xff]));
+  }
+
+  #[test]
+  fn blob_store_refs_are_read() {
+    let id = BlobID{name: b"name".to_vec(), begin: 3, end: 7};
+    let r = BlobRef{name: b"name".to_vec(), offset: 3, length: 4, kind: RefKind::Unknown};
+    assert_eq!(Some(r.clone()), BlobRef::from_bytes(&id.as_bytes()[..]));
+    assert_eq!(id, r.blob_id());
+
+    let leaf = BlobRef::from_blob_id(&id, RefKind::Leaf);
+    assert_eq!(RefKind::Leaf, leaf.kind);
+    assert_eq!(Some(leaf.clone()), BlobRef::from_bytes(&leaf.to_bytes()[..]));
+    assert_eq!(id, leaf.blob_id());
+  }
+
+  #[test]
+  fn invalid_hashes_are_rejected() {
+    let mut hi = HashIndex::new_for_testing();
+
+    let zero = Hash{bytes: HashBytes::new(&[0u8; DIGEST_BYTES])};
+    match send(&mut hi, Msg::HashExists(zero)) {
+      Reply::InvalidHash(HashError::AllZero) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    let short = HashEntry{hash: Hash{bytes: HashBytes::new(&[1, 2, 3])}, ..leaf(b"")};
+    match send(&mut hi, Msg::Reserve(short)) {
+      Reply::InvalidHash(HashError::WrongWidth{expected, found}) => {
+        assert_eq!(DIGEST_BYTES, expected);
+        assert_eq!(3, found);
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn empty_hashes_are_rejected() {
+    let mut hi = HashIndex::new_for_testing();
+    let foo = leaf(b"foo");
+    hi.reserve(foo.clone());
+
+    let empty = Hash{bytes: HashBytes::new(&[])};
+    let blob_ref = BlobRef{name: b"name".to_vec(), offset: 0, length: 1, kind: RefKind::Unknown};
+    let msgs = vec!(
+      Msg::Reserve(HashEntry{hash: empty.clone(), ..leaf(b"")}),
+      Msg::UpdateReserved(HashEntry{hash: empty.clone(), ..leaf(b"")}),
+      Msg::Commit(empty.clone(), b"ref".to_vec()),
+      Msg::CallAfterHashIsComitted(empty.clone(), Box::new(move|| {})),
+      Msg::CallAfterAllCommitted(vec!(foo.hash.clone(), empty.clone()), Box::new(move|| {})),
+      Msg::FilterUnknown(vec!(foo.hash.clone(), empty.clone())),
+      Msg::BatchRelocate(vec!((empty.clone(), blob_ref))),
+      Msg::Tagged(RequestId(1), Box::new(Msg::Abandon(empty.clone()))));
+    for msg in msgs.into_iter() {
+      let reply = match send(&mut hi, msg) {
+        Reply::Tagged(_, reply) => *reply,
+        reply => reply,
+      };
+      match reply {
+        Reply::InvalidHash(HashError::WrongWidth{expected, found}) => {
+          assert_eq!(DIGEST_BYTES, expected);
+          assert_eq!(0, found);
+        },
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+
+    // Nothing was changed by the rejected messages:
+    assert_eq!(vec!((1, foo.hash.bytes.to_vec(), false)), hi.debug_dump_queue());
+    assert_eq!(0, hi.callbacks.len());
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn prefix_ranges() {
+    assert_eq!(Some((vec!(), None)), prefix_range(""));
+    assert_eq!(Some((vec!(0xab), Some(vec!(0xac)))), prefix_range("aB"));
+    assert_eq!(Some((vec!(0x1a, 0x70), Some(vec!(0x1a, 0x80)))), prefix_range("1a7"));
+    assert_eq!(Some((vec!(0x1f, 0xf0), Some(vec!(0x20, 0x00)))), prefix_range("1ff"));
+    assert_eq!(Some((vec!(0xff, 0xf0), None)), prefix_range("fff"));
+    assert_eq!(None, prefix_range("0x12"));
+  }
+
+  #[test]
+  fn resolve_prefix_is_capped() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).max_prefix_matches(2).build();
+    let names: Vec<Vec<u8>> = (0..5).map(|i| format!("leaf{}", i).into_bytes()).collect();
+    for name in names.iter() {
+      let entry = leaf(&name[..]);
+      hi.reserve(entry.clone());
+      hi.commit(&entry.hash, name);
+    }
+    hi.flush();
+
+    match send(&mut hi, Msg::ResolvePrefix(String::new())) {
+      Reply::ResolvedAmbiguous{hashes, total, too_many} => {
+        assert_eq!(2, hashes.len());
+        assert!(hashes[0].bytes < hashes[1].bytes);
+        assert_eq!(5, total);
+        assert!(too_many);
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    let hash = leaf(&names[3][..]).hash;
+    match send(&mut hi, Msg::ResolvePrefix(hash.bytes.to_hex().to_uppercase())) {
+      Reply::Resolved(h) => assert_eq!(hash, h),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::ResolvePrefix("zz".to_string())) {
+      Reply::InvalidHash(HashError::NotHex) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn config_is_reported() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string())
+      .digest_width(DIGEST_BYTES)
+      .max_prefix_matches(3)
+      .strict_refs(true)
+      .build();
+    match send(&mut hi, Msg::Config) {
+      Reply::Config{config, encrypted} => {
+        assert_eq!(DIGEST_BYTES, config.digest_width);
+        assert_eq!(3, config.max_prefix_matches);
+        assert!(config.strict_refs);
+        assert!(!config.audit);
+        assert!(!encrypted);
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn config_does_not_reveal_key() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string())
+      .encryption_key(b"secret".to_vec())
+      .build();
+    match send(&mut hi, Msg::Config) {
+      Reply::Config{config, encrypted} => {
+        assert!(config.encryption_key.is_none());
+        assert!(encrypted);
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn leaves_of_cyclic_tree() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).max_tree_nodes(6).build();
+
+    // A branch that lists itself, next to a leaf:
+    let a = leaf(b"a");
+    let cycle = Hash::new(b"cycle");
+    let branch = HashEntry{
+      hash: cycle.clone(), level: 1,
+      payload: Some(encode_children(PayloadVersion::current(), &[a.hash.clone(), cycle.clone()])),
+      persistent_ref: None, content_len: 0};
+    for e in [&a, &branch].iter() {
+      hi.reserve((*e).clone());
+      let blob_ref = BlobRef{name: e.hash.bytes[..2].to_vec(), offset: 0, length: 3,
+                             kind: RefKind::Unknown};
+      hi.commit(&e.hash, &blob_ref.to_bytes());
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let sink = Box::new(move|r: BlobRef| { sender.send(r).unwrap(); });
+    match send(&mut hi, Msg::LeavesOf(cycle.clone(), sink)) {
+      Reply::BrokenTree(e) => assert_eq!(TreeError::TooLarge(cycle), e),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(3, receiver.iter().count());
+
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn tree_stats() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).max_tree_nodes(6).build();
+    let branch = |level: i64, name: &[u8], children: &[&Hash]| {
+      let children: Vec<Hash> = children.iter().map(|&h| h.clone()).collect();
+      HashEntry{hash: Hash::new(name), level: level,
+                payload: Some(encode_children(PayloadVersion::current(), &children[..])),
+                persistent_ref: None, content_len: 0}
+    };
+
+    let (a, b, c) = (leaf(b"a"), leaf(b"b"), leaf(b"c"));
+    let inner = branch(1, b"inner", &[&a.hash, &b.hash, &c.hash]);
+    let root = branch(2, b"root", &[&inner.hash, &c.hash]);
+    for e in [&a, &b, &c, &inner, &root].iter() {
+      hi.reserve((*e).clone());
+    }
+    hi.commit(&root.hash, &b"ref".to_vec());
+
+    match send(&mut hi, Msg::TreeStats(root.hash.clone())) {
+      Reply::TreeStats{depth, leaves, branches, avg_fanout} => {
+        assert_eq!(3, depth);
+        assert_eq!(4, leaves);
+        assert_eq!(2, branches);
+        assert_eq!(2.5, avg_fanout);
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::TreeStats(c.hash.clone())) {
+      Reply::TreeStats{depth: 1, leaves: 1, branches: 0, avg_fanout} => assert_eq!(0.0, avg_fanout),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    // With a new top, the tree has seven nodes:
+    let top = branch(3, b"top", &[&root.hash]);
+    hi.reserve(top.clone());
+    match send(&mut hi, Msg::TreeStats(top.hash.clone())) {
+      Reply::BrokenTree(e) => assert_eq!(TreeError::TooLarge(top.hash.clone()), e),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn meta_is_kept_apart() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).allow_uncommit(true).build();
+    let (foo, bar) = (leaf(b"foo"), leaf(b"bar"));
+    hi.reserve(foo.clone());
+    hi.reserve(bar.clone());
+    hi.commit(&foo.hash, &b"ref".to_vec());
+
+    // Only committed hashes have metadata:
+    match send(&mut hi, Msg::SetMeta(bar.hash.clone(), "mime".to_string(), b"x".to_vec())) {
+      Reply::HashNotKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    let set_mime = Msg::SetMeta(foo.hash.clone(), "mime".to_string(), b"text/plain".to_vec());
+    match send(&mut hi, set_mime) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::GetMeta(foo.hash.clone(), "mime".to_string())) {
+      Reply::MetaValue(value) => assert_eq!(Some(b"text/plain".to_vec()), value),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::GetMeta(foo.hash.clone(), "tags".to_string())) {
+      Reply::MetaValue(None) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    match send(&mut hi, Msg::Uncommit(foo.hash.clone())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    match send(&mut hi, Msg::GetMeta(foo.hash.clone(), "mime".to_string())) {
+      Reply::MetaValue(None) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn commits_are_replicated_in_order() {
+    let replicated = Arc::new(Mutex::new(vec!()));
+    let sink_replicated = replicated.clone();
+    let sink = Box::new(move|e: &HashEntry| {
+      sink_replicated.lock().unwrap().push((e.hash.clone(), e.persistent_ref.clone()));
+    });
+    let mut hi = HashIndexBuilder::new(String::new()).replicate_to(sink).build_in_memory();
+
+    let (foo, bar, baz) = (leaf(b"foo"), leaf(b"bar"), leaf(b"baz"));
+    for e in [&foo, &bar, &baz].iter() {
+      hi.reserve((*e).clone());
+    }
+    hi.commit(&baz.hash, &b"baz-ref".to_vec());
+    hi.commit(&bar.hash, &b"bar-ref".to_vec());
+    // Nothing is inserted before the first reservation is committed:
+    assert_eq!(0, replicated.lock().unwrap().len());
+
+    hi.commit(&foo.hash, &b"foo-ref".to_vec());
+    hi.flush();
+    assert_eq!(vec!((foo.hash.clone(), Some(b"foo-ref".to_vec())),
+                    (bar.hash.clone(), Some(b"bar-ref".to_vec())),
+                    (baz.hash.clone(), Some(b"baz-ref".to_vec()))),
+               *replicated.lock().unwrap());
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn delete_id_range() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    let entries: Vec<HashEntry> = (0..4).map(|i| leaf(format!("e{}", i).as_bytes())).collect();
+    for e in entries.iter() {
+      hi.reserve(e.clone());
+    }
+    for (i, e) in entries.iter().take(3).enumerate() {
+      hi.commit(&e.hash, &format!("ref{}", i).into_bytes());
+    }
+    let ids: Vec<i64> = entries.iter().map(|e| match send(&mut hi, Msg::FetchId(e.hash.clone())) {
+      Reply::Id(id) => id,
+      _ => panic!("Unexpected reply from hash index."),
+    }).collect();
+
+    // The last entry is still queued:
+    match send(&mut hi, Msg::DeleteIdRange{lo: ids[1], hi: ids[3] + 1}) {
+      Reply::QueueNotEmpty(1) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::DeleteIdRange{lo: ids[1], hi: ids[3]}) {
+      Reply::DeletedRange{deleted, freed_refs} => {
+        assert_eq!(2, deleted);
+        assert_eq!(vec!(b"ref1".to_vec(), b"ref2".to_vec()), freed_refs);
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    for (i, e) in entries.iter().enumerate() {
+      match send(&mut hi, Msg::HashExists(e.hash.clone())) {
+        Reply::HashKnown => assert!(i == 0 || i == 3),
+        Reply::HashNotKnown => assert!(i == 1 || i == 2),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn schema_info() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).audit(true).build();
+    match send(&mut hi, Msg::SchemaInfo) {
+      Reply::SchemaInfo{version, columns, features} => {
+        assert_eq!(SCHEMA_VERSION, version);
+        assert!(columns.iter().any(|c| &c[..] == "payload_version"));
+        assert!(features.iter().any(|f| &f[..] == "audit"));
+        assert!(!features.iter().any(|f| &f[..] == "encrypted"));
+      },
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn trim_memory() {
+    let mut hi = HashIndex::new_for_testing();
+    let entries: Vec<HashEntry> = (0..100).map(|i| leaf(format!("e{}", i).as_bytes())).collect();
+    for e in entries.iter() {
+      hi.reserve(e.clone());
+      let callback = Thunk::new(move|| {});
+      match send(&mut hi, Msg::CallAfterHashIsComitted(e.hash.clone(), callback)) {
+        Reply::CallbackRegistered(_) => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+    for e in entries.iter() {
+      hi.commit(&e.hash, &b"ref".to_vec());
+    }
+    hi.flush();
+
+    match send(&mut hi, Msg::TrimMemory) {
+      Reply::Trimmed(_) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    // The index works as before:
+    let foo = leaf(b"foo");
+    hi.reserve(foo.clone());
+    hi.commit(&foo.hash, &b"ref".to_vec());
+    match send(&mut hi, Msg::HashExists(foo.hash.clone())) {
+      Reply::HashKnown => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn commit_is_idempotent() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    let (foo, bar) = (leaf(b"foo"), leaf(b"bar"));
+    hi.reserve(foo.clone());
+    hi.reserve(bar.clone());
+
+    // `bar` is committed, but not inserted behind the pending `foo`:
+    for _ in 0..2 {
+      match send(&mut hi, Msg::Commit(bar.hash.clone(), b"bar-ref".to_vec())) {
+        Reply::CommitOK => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+    hi.commit(&foo.hash, &b"foo-ref".to_vec());
+    hi.flush();
+
+    // Both are inserted now:
+    for &(ref e, r) in [(&foo, &b"foo-ref"[..]), (&bar, &b"bar-ref"[..])].iter() {
+      match send(&mut hi, Msg::Commit(e.hash.clone(), r.to_vec())) {
+        Reply::CommitOK => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+    match send(&mut hi, Msg::Commit(foo.hash.clone(), b"other-ref".to_vec())) {
+      Reply::RefConflict(h) => assert_eq!(foo.hash, h),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    match send(&mut hi, Msg::FetchPersistentRef(foo.hash.clone())) {
+      Reply::PersistentRef(r) => assert_eq!(b"foo-ref".to_vec(), r),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(Ok(()), hi.check_invariants());
+  }
+
+  #[test]
+  fn commit_of_entry_reserved_with_ref() {
+    let mut hi = HashIndexBuilder::new(":memory:".to_string()).build();
+    let foo = HashEntry{persistent_ref: Some(b"foo-ref".to_vec()), ..leaf(b"foo")};
+    let bar = leaf(b"bar");
+    hi.reserve(foo.clone());
+    hi.reserve(bar.clone());
+
+    // The reference given at reserve time does not make `foo` committed:
+    match send(&mut hi, Msg::Commit(foo.hash.clone(), b"foo-ref".to_vec())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    hi.commit(&bar.hash, &b"bar-ref".to_vec());
+    assert_eq!(Ok(()), hi.flush());
+    assert_eq!(0, hi.debug_dump_queue().len());
+    assert!(hi.index_locate(&bar.hash).unwrap().is_some());
+
+    // Nor does one set with `UpdateReserved`, even if it differs from the committed one:
+    let baz = leaf(b"baz");
+    hi.reserve(baz.clone());
+    hi.update_reserved(HashEntry{persistent_ref: Some(b"early-ref".to_vec()), ..baz.clone()});
+    match send(&mut hi, Msg::Commit(baz.hash.clone(), b"baz-ref".to_vec())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+    assert_eq!(Ok(()), hi.flush());
+    assert_eq!(0, hi.debug_dump_queue().len());
+
+    assert_eq!(Ok(()), hi.check_invariants());
   }
 }
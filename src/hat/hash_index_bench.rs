@@ -0,0 +1,410 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarks of the hot paths of the hash index, run with `cargo bench hash_index_bench`.
+//!
+//! Every benchmark of the index runs against an index in `:memory:` and in a temporary file. Each
+//! iteration performs `HAT_BENCH_ENTRIES` operations (default `DEFAULT_ENTRIES`), and `bench.bytes`
+//! is set to that count, so the reported throughput in MB/s reads as millions of operations per
+//! second.
+//!
+//! `pending_callbacks_memory` also prints how much resident memory an index with many pending
+//! callbacks takes (on Linux), measured once before its timed iterations.
+
+use std::env;
+use std::fs;
+use std::io::{Read};
+use std::sync::mpsc;
+
+use test::{self, Bencher};
+
+use hash_backend::{HashBackend, SqliteBackend};
+use hash_index::{Hash, HashEntry, HashIndex, HashIndexBuilder, IndexConfig, Msg, Reply};
+use process::{MsgHandler};
+
+
+const DEFAULT_ENTRIES: u64 = 1000;
+
+/// The callbacks registered for each pending hash by `pending_callbacks_memory`.
+const CALLBACKS_PER_HASH: u64 = 4;
+
+fn entries() -> u64 {
+  env::var("HAT_BENCH_ENTRIES").ok().and_then(|n| n.parse().ok()).unwrap_or(DEFAULT_ENTRIES)
+}
+
+fn in_memory() -> HashIndex<SqliteBackend> {
+  HashIndexBuilder::new(":memory:".to_string()).build()
+}
+
+fn in_memory_expecting(n: u64) -> HashIndex<SqliteBackend> {
+  HashIndexBuilder::new(":memory:".to_string()).expected_inflight(n as usize).build()
+}
+
+/// Run `bench` against an index in a fresh temporary file, which is removed afterwards.
+fn in_file<F>(name: &str, bench: F) where F: FnOnce(HashIndex<SqliteBackend>) {
+  let path = env::temp_dir().join(format!("hat_bench_{}.sqlite3", name));
+  fs::remove_file(&path).ok();
+  bench(HashIndexBuilder::new(path.to_str().unwrap().to_string()).build());
+  fs::remove_file(&path).ok();
+}
+
+/// The resident memory of this process in bytes, if it can be read from `/proc`.
+fn resident_bytes() -> Option<u64> {
+  let mut status = String::new();
+  if fs::File::open("/proc/self/status").and_then(|mut f| f.read_to_string(&mut status)).is_err() {
+    return None;
+  }
+  status.lines().find(|line| line.starts_with("VmRSS:"))
+    .and_then(|line| line.split(' ').filter(|word| word.len() > 0).nth(1))
+    .and_then(|kb| kb.parse::<u64>().ok())
+    .map(|kb| kb * 1024)
+}
+
+fn send<B: HashBackend>(hi: &mut HashIndex<B>, msg: Msg) -> Reply {
+  let (sender, receiver) = mpsc::channel();
+  hi.handle(msg, Box::new(move|r| { sender.send(r).unwrap(); }));
+  receiver.recv().unwrap()
+}
+
+fn leaf(n: u64) -> HashEntry {
+  let data = format!("entry {}", n).into_bytes();
+  HashEntry{hash: Hash::new(&data[..]), level: 0, payload: None, persistent_ref: None,
+            content_len: data.len() as u64}
+}
+
+fn reserve_and_commit<B: HashBackend>(hi: &mut HashIndex<B>, n: u64) {
+  let entry = leaf(n);
+  match send(hi, Msg::Reserve(entry.clone())) {
+    Reply::ReserveOK => (),
+    _ => panic!("Unexpected reply from hash index."),
+  }
+  match send(hi, Msg::Commit(entry.hash, b"ref".to_vec())) {
+    Reply::CommitOK => (),
+    _ => panic!("Unexpected reply from hash index."),
+  }
+}
+
+fn flush<B: HashBackend>(hi: &mut HashIndex<B>) {
+  match send(hi, Msg::Flush) {
+    Reply::CommitOK | Reply::FlushedNothing => (),
+    _ => panic!("Unexpected reply from hash index."),
+  }
+}
+
+/// Commit entries `0..n` in one batch.
+fn populate<B: HashBackend>(hi: &mut HashIndex<B>, n: u64) {
+  for i in 0..n {
+    reserve_and_commit(hi, i);
+  }
+  flush(hi);
+}
+
+
+fn exists_hits<B: HashBackend>(bench: &mut Bencher, mut hi: HashIndex<B>) {
+  let n = entries();
+  populate(&mut hi, n);
+  bench.iter(|| {
+    for i in 0..n {
+      match send(&mut hi, Msg::HashExists(leaf(i).hash)) {
+        Reply::HashKnown => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+  });
+  bench.bytes = n;
+}
+
+fn exists_misses<B: HashBackend>(bench: &mut Bencher, mut hi: HashIndex<B>) {
+  let n = entries();
+  populate(&mut hi, n);
+  bench.iter(|| {
+    for i in n..2 * n {
+      match send(&mut hi, Msg::HashExists(leaf(i).hash)) {
+        Reply::HashNotKnown => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+  });
+  bench.bytes = n;
+}
+
+/// Looks up as many hashes as `exists_hits` and `exists_misses` together, half of them known, in
+/// a single `FilterUnknown`.
+fn filter_unknown_batch<B: HashBackend>(bench: &mut Bencher, mut hi: HashIndex<B>) {
+  let n = entries();
+  populate(&mut hi, n);
+  let hashes: Vec<Hash> = (0..2 * n).map(|i| leaf(i).hash).collect();
+  bench.iter(|| {
+    match send(&mut hi, Msg::FilterUnknown(hashes.clone())) {
+      Reply::Unknown(ref unknown) => assert_eq!(n as usize, unknown.len()),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+  });
+  bench.bytes = 2 * n;
+}
+
+/// Clones `n` hashes, which does not allocate since their bytes are stored inline. Compare with
+/// `clone_hash_vecs`.
+#[bench]
+fn clone_hashes(bench: &mut Bencher) {
+  let n = entries();
+  let hashes: Vec<Hash> = (0..n).map(|i| leaf(i).hash).collect();
+  bench.iter(|| {
+    for hash in hashes.iter() {
+      test::black_box(hash.clone());
+    }
+  });
+  bench.bytes = n;
+}
+
+/// Clones `n` hash digests held as `Vec`s (as `Hash` used to), which allocates once per clone.
+#[bench]
+fn clone_hash_vecs(bench: &mut Bencher) {
+  let n = entries();
+  let hashes: Vec<Vec<u8>> = (0..n).map(|i| leaf(i).hash.bytes.to_vec()).collect();
+  bench.iter(|| {
+    for hash in hashes.iter() {
+      test::black_box(hash.clone());
+    }
+  });
+  bench.bytes = n;
+}
+
+/// Looks up a metadata key of each committed entry (see `Msg::GetMeta`).
+fn get_meta<B: HashBackend>(bench: &mut Bencher, mut hi: HashIndex<B>) {
+  let n = entries();
+  populate(&mut hi, n);
+  for i in 0..n {
+    match send(&mut hi, Msg::SetMeta(leaf(i).hash, "mime".to_string(), b"text/plain".to_vec())) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+  }
+  flush(&mut hi);
+  bench.iter(|| {
+    for i in 0..n {
+      match send(&mut hi, Msg::GetMeta(leaf(i).hash, "mime".to_string())) {
+        Reply::MetaValue(Some(_)) => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+  });
+  bench.bytes = n;
+}
+
+/// Looks up a metadata key of each entry in the backend itself, in a metadata table without a
+/// rowid or, for `rowid`, with one as older versions created it (see `SqliteBackend::drop_rowid`).
+fn meta_lookups(bench: &mut Bencher, rowid: bool) {
+  let n = entries();
+  let mut backend = SqliteBackend::open(":memory:".to_string(), &IndexConfig::new()).unwrap();
+  backend.insert_batch((0..n).map(|i| {
+    (i as i64, HashEntry{persistent_ref: Some(b"ref".to_vec()), ..leaf(i)})
+  }).collect());
+  for i in 0..n {
+    backend.set_meta(i as i64, "mime", b"text/plain");
+  }
+  if rowid {
+    backend.restore_rowid("hash_meta");
+  }
+  backend.commit_txn().unwrap();
+  bench.iter(|| {
+    for i in 0..n {
+      match backend.meta(i as i64, "mime") {
+        Ok(Some(_)) => (),
+        _ => panic!("Unexpected metadata lookup result."),
+      }
+    }
+  });
+  bench.bytes = n;
+}
+
+/// Reserves entries with `CALLBACKS_PER_HASH` callbacks each, and leaves them pending, in a fresh
+/// index each iteration. Before that, prints how much resident memory such an index takes, which
+/// is where sharing the bytes of each hash between the queue and its callbacks pays off.
+/// Memory is counted in pages, so it takes a large `HAT_BENCH_ENTRIES` to be accurate.
+#[bench]
+fn pending_callbacks_memory(bench: &mut Bencher) {
+  let n = entries();
+  let reserve_pending = || {
+    let mut hi = in_memory();
+    for i in 0..n {
+      let entry = leaf(i);
+      match send(&mut hi, Msg::Reserve(entry.clone())) {
+        Reply::ReserveOK => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+      for _ in 0..CALLBACKS_PER_HASH {
+        match send(&mut hi, Msg::CallAfterHashIsComitted(entry.hash.clone(), Box::new(move|| {}))) {
+          Reply::CallbackRegistered(_) => (),
+          _ => panic!("Unexpected reply from hash index."),
+        }
+      }
+    }
+    hi
+  };
+
+  let before = resident_bytes();
+  let hi = reserve_pending();
+  match (before, resident_bytes()) {
+    (Some(before), Some(after)) => {
+      let grown = if after > before { after - before } else { 0 };
+      println!("\n{} pending hashes with {} callbacks each take {} bytes ({} per hash)",
+               n, CALLBACKS_PER_HASH, grown, grown / n);
+    },
+    _ => (),
+  }
+  drop(hi);
+
+  bench.iter(|| reserve_pending());
+  bench.bytes = n;
+}
+
+/// Reserves, commits and flushes one entry at a time.
+fn reserve_commit_cycles<B: HashBackend>(bench: &mut Bencher, mut hi: HashIndex<B>) {
+  let n = entries();
+  let mut next = 0;
+  bench.iter(|| {
+    for _ in 0..n {
+      reserve_and_commit(&mut hi, next);
+      flush(&mut hi);
+      next += 1;
+    }
+  });
+  bench.bytes = n;
+}
+
+/// Reserves and commits a batch of entries, and flushes them together.
+fn reserve_commit_batch<B: HashBackend>(bench: &mut Bencher, mut hi: HashIndex<B>) {
+  let n = entries();
+  let mut next = 0;
+  bench.iter(|| {
+    for _ in 0..n {
+      reserve_and_commit(&mut hi, next);
+      next += 1;
+    }
+    flush(&mut hi);
+  });
+  bench.bytes = n;
+}
+
+/// Reserves a burst of entries with a callback each, and commits and flushes them together, in a
+/// fresh index each iteration. Compare `callback_burst_without_hint` with
+/// `callback_burst_with_hint`, whose index expects the burst (see
+/// `IndexConfig::expected_inflight`).
+fn callback_burst(bench: &mut Bencher, new_index: &Fn() -> HashIndex<SqliteBackend>) {
+  let n = entries();
+  bench.iter(|| {
+    let mut hi = new_index();
+    for i in 0..n {
+      let entry = leaf(i);
+      match send(&mut hi, Msg::Reserve(entry.clone())) {
+        Reply::ReserveOK => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+      match send(&mut hi, Msg::CallAfterHashIsComitted(entry.hash, Box::new(move|| {}))) {
+        Reply::CallbackRegistered(_) => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+    for i in 0..n {
+      match send(&mut hi, Msg::Commit(leaf(i).hash, b"ref".to_vec())) {
+        Reply::CommitOK => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+    flush(&mut hi);
+  });
+  bench.bytes = n;
+}
+
+
+#[bench]
+fn exists_hits_in_memory(bench: &mut Bencher) {
+  exists_hits(bench, in_memory());
+}
+
+#[bench]
+fn exists_hits_in_file(bench: &mut Bencher) {
+  in_file("exists_hits", |hi| exists_hits(bench, hi));
+}
+
+#[bench]
+fn exists_misses_in_memory(bench: &mut Bencher) {
+  exists_misses(bench, in_memory());
+}
+
+#[bench]
+fn exists_misses_in_file(bench: &mut Bencher) {
+  in_file("exists_misses", |hi| exists_misses(bench, hi));
+}
+
+#[bench]
+fn filter_unknown_batch_in_memory(bench: &mut Bencher) {
+  filter_unknown_batch(bench, in_memory());
+}
+
+#[bench]
+fn filter_unknown_batch_in_file(bench: &mut Bencher) {
+  in_file("filter_unknown_batch", |hi| filter_unknown_batch(bench, hi));
+}
+
+#[bench]
+fn reserve_commit_cycles_in_memory(bench: &mut Bencher) {
+  reserve_commit_cycles(bench, in_memory());
+}
+
+#[bench]
+fn reserve_commit_cycles_in_file(bench: &mut Bencher) {
+  in_file("reserve_commit_cycles", |hi| reserve_commit_cycles(bench, hi));
+}
+
+#[bench]
+fn reserve_commit_batch_in_memory(bench: &mut Bencher) {
+  reserve_commit_batch(bench, in_memory());
+}
+
+#[bench]
+fn reserve_commit_batch_in_file(bench: &mut Bencher) {
+  in_file("reserve_commit_batch", |hi| reserve_commit_batch(bench, hi));
+}
+
+#[bench]
+fn get_meta_in_memory(bench: &mut Bencher) {
+  get_meta(bench, in_memory());
+}
+
+#[bench]
+fn get_meta_in_file(bench: &mut Bencher) {
+  in_file("get_meta", |hi| get_meta(bench, hi));
+}
+
+#[bench]
+fn meta_lookups_without_rowid(bench: &mut Bencher) {
+  meta_lookups(bench, false);
+}
+
+#[bench]
+fn meta_lookups_with_rowid(bench: &mut Bencher) {
+  meta_lookups(bench, true);
+}
+
+#[bench]
+fn callback_burst_without_hint(bench: &mut Bencher) {
+  callback_burst(bench, &|| in_memory());
+}
+
+#[bench]
+fn callback_burst_with_hint(bench: &mut Bencher) {
+  callback_burst(bench, &|| in_memory_expecting(entries()));
+}
@@ -0,0 +1,1125 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compact binary encoding of hash index messages, for sharing an index across processes.
+//!
+//! Every `Msg` and `Reply` is encoded as a single variant tag byte followed by its fields:
+//!
+//! - Integers are 8 bytes, big-endian. Floats are the bits of their IEEE 754 representation.
+//! - Byte strings (hashes, payloads, references) are a 4-byte big-endian length followed by the
+//!   bytes themselves.
+//! - An `Option` is a `0` byte for `None`, or a `1` byte followed by the value.
+//! - A `HashEntry` is its hash, level, payload, persistent reference and content length, in that
+//!   order.
+//! - A list is its 8-byte length followed by its elements.
+//! - A `BlobRef` is its object name, offset and length. `Relocate` and `BatchRelocate` have a
+//!   second, kinded form (tags 55 and 56) that also carries the kind code of each reference. The
+//!   original form is used when all kinds are `RefKind::Unknown`, so older peers can still read
+//!   those messages.
+//!
+//! Messages that carry closures (e.g. `CallAfterHashIsComitted`) cannot cross a process boundary
+//! and are refused with `WireError::NotEncodable`. So is `Reply::Config`, whose settings include
+//! handles that only work within the process (e.g. `IndexConfig::interrupt`).
+
+use std::mem;
+use std::time::duration::{Duration};
+
+use audit::{AuditEvent, AuditOp};
+use callback_container::{CallbackToken};
+use hash_bytes::{HashBytes};
+use hash_index::{BlobRef, Hash, HashEntry, HashError, HashIndexError, Msg, RefKind, Reply,
+                 RequestId, RescanReport, ResumeToken, TreeError};
+use trace::{TraceEvent, TraceKind};
+
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WireError {
+  NotEncodable,
+  Truncated,
+  UnknownTag(u8),
+  TrailingBytes,
+  InvalidText,
+}
+
+
+struct Writer {
+  bytes: Vec<u8>,
+}
+
+impl Writer {
+
+  fn new(tag: u8) -> Writer {
+    Writer{bytes: vec!(tag)}
+  }
+
+  fn u8(&mut self, x: u8) {
+    self.bytes.push(x);
+  }
+
+  fn i64(&mut self, x: i64) {
+    for i in 0..8 {
+      self.bytes.push((x >> (56 - 8 * i)) as u8);
+    }
+  }
+
+  fn blob(&mut self, b: &[u8]) {
+    let len = b.len() as u32;
+    for i in 0..4 {
+      self.bytes.push((len >> (24 - 8 * i)) as u8);
+    }
+    self.bytes.extend(b.iter().map(|&x| x));
+  }
+
+  fn blob_opt(&mut self, b: &Option<Vec<u8>>) {
+    match *b {
+      None => self.u8(0),
+      Some(ref b) => { self.u8(1); self.blob(b); },
+    }
+  }
+
+  fn hash(&mut self, h: &Hash) {
+    self.blob(&h.bytes);
+  }
+
+  fn hashes(&mut self, hs: &Vec<Hash>) {
+    self.i64(hs.len() as i64);
+    for h in hs.iter() {
+      self.hash(h);
+    }
+  }
+
+  fn texts(&mut self, ts: &Vec<String>) {
+    self.i64(ts.len() as i64);
+    for t in ts.iter() {
+      self.blob(t.as_bytes());
+    }
+  }
+
+  fn blob_ref(&mut self, r: &BlobRef, kinded: bool) {
+    self.blob(&r.name);
+    self.i64(r.offset as i64);
+    self.i64(r.length as i64);
+    if kinded {
+      self.u8(r.kind.code());
+    }
+  }
+
+  fn entry(&mut self, e: &HashEntry) {
+    self.hash(&e.hash);
+    self.i64(e.level);
+    self.blob_opt(&e.payload);
+    self.blob_opt(&e.persistent_ref);
+    self.i64(e.content_len as i64);
+  }
+
+  fn entries(&mut self, es: &Vec<HashEntry>) {
+    self.i64(es.len() as i64);
+    for e in es.iter() {
+      self.entry(e);
+    }
+  }
+}
+
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+impl <'a> Reader<'a> {
+
+  fn new(bytes: &'a [u8]) -> Reader<'a> {
+    Reader{bytes: bytes, pos: 0}
+  }
+
+  fn take(&mut self, n: usize) -> Result<&'a [u8], WireError> {
+    if self.bytes.len() - self.pos < n {
+      return Err(WireError::Truncated);
+    }
+    let taken = &self.bytes[self.pos .. self.pos + n];
+    self.pos += n;
+    Ok(taken)
+  }
+
+  fn u8(&mut self) -> Result<u8, WireError> {
+    Ok(try!(self.take(1))[0])
+  }
+
+  fn i64(&mut self) -> Result<i64, WireError> {
+    Ok(try!(self.take(8)).iter().fold(0i64, |acc, &b| (acc << 8) | b as i64))
+  }
+
+  fn blob(&mut self) -> Result<Vec<u8>, WireError> {
+    let len = try!(self.take(4)).iter().fold(0u32, |acc, &b| (acc << 8) | b as u32);
+    Ok(try!(self.take(len as usize)).to_vec())
+  }
+
+  fn text(&mut self) -> Result<String, WireError> {
+    String::from_utf8(try!(self.blob())).map_err(|_| WireError::InvalidText)
+  }
+
+  fn blob_opt(&mut self) -> Result<Option<Vec<u8>>, WireError> {
+    match try!(self.u8()) {
+      0 => Ok(None),
+      1 => Ok(Some(try!(self.blob()))),
+      t => Err(WireError::UnknownTag(t)),
+    }
+  }
+
+  fn hash(&mut self) -> Result<Hash, WireError> {
+    Ok(Hash{bytes: HashBytes::from_vec(try!(self.blob()))})
+  }
+
+  fn hashes(&mut self) -> Result<Vec<Hash>, WireError> {
+    let len = try!(self.i64());
+    let mut hs = vec!();
+    for _ in 0..len {
+      hs.push(try!(self.hash()));
+    }
+    Ok(hs)
+  }
+
+  fn texts(&mut self) -> Result<Vec<String>, WireError> {
+    let len = try!(self.i64());
+    let mut ts = vec!();
+    for _ in 0..len {
+      ts.push(try!(self.text()));
+    }
+    Ok(ts)
+  }
+
+  fn blob_ref(&mut self, kinded: bool) -> Result<BlobRef, WireError> {
+    let name = try!(self.blob());
+    let offset = try!(self.i64()) as u64;
+    let length = try!(self.i64()) as u64;
+    let code = if kinded { try!(self.u8()) } else { RefKind::Unknown.code() };
+    match RefKind::from_code(code) {
+      Some(kind) => Ok(BlobRef{name: name, offset: offset, length: length, kind: kind}),
+      None => Err(WireError::UnknownTag(code)),
+    }
+  }
+
+  fn entry(&mut self) -> Result<HashEntry, WireError> {
+    let hash = try!(self.hash());
+    let level = try!(self.i64());
+    let payload = try!(self.blob_opt());
+    let persistent_ref = try!(self.blob_opt());
+    let content_len = try!(self.i64()) as u64;
+    Ok(HashEntry{hash: hash, level: level, payload: payload, persistent_ref: persistent_ref,
+                 content_len: content_len})
+  }
+
+  fn entries(&mut self) -> Result<Vec<HashEntry>, WireError> {
+    let len = try!(self.i64());
+    let mut es = vec!();
+    for _ in 0..len {
+      es.push(try!(self.entry()));
+    }
+    Ok(es)
+  }
+
+  fn done<T>(&self, value: T) -> Result<T, WireError> {
+    if self.pos == self.bytes.len() { Ok(value) } else { Err(WireError::TrailingBytes) }
+  }
+}
+
+
+pub fn encode_msg(msg: &Msg) -> Result<Vec<u8>, WireError> {
+  let w = match *msg {
+    Msg::HashExists(ref h) => { let mut w = Writer::new(1); w.hash(h); w },
+    Msg::FetchPayload(ref h) => { let mut w = Writer::new(2); w.hash(h); w },
+    Msg::FetchPersistentRef(ref h) => { let mut w = Writer::new(3); w.hash(h); w },
+    Msg::Reserve(ref e) => { let mut w = Writer::new(4); w.entry(e); w },
+    Msg::UpdateReserved(ref e) => { let mut w = Writer::new(5); w.entry(e); w },
+    Msg::Commit(ref h, ref r) => { let mut w = Writer::new(6); w.hash(h); w.blob(r); w },
+    Msg::Flush => Writer::new(7),
+    Msg::Barrier => Writer::new(8),
+    Msg::RebuildIndexes => Writer::new(9),
+    Msg::ExpireStaleReserves => Writer::new(10),
+    Msg::Abandon(ref h) => { let mut w = Writer::new(11); w.hash(h); w },
+    Msg::StorageSummary => Writer::new(12),
+    Msg::Relocate(ref h, ref r) => {
+      let kinded = r.kind != RefKind::Unknown;
+      let mut w = Writer::new(if kinded { 55 } else { 13 });
+      w.hash(h);
+      w.blob_ref(r, kinded);
+      w
+    },
+    Msg::BatchRelocate(ref moves) => {
+      let kinded = moves.iter().any(|&(_, ref r)| r.kind != RefKind::Unknown);
+      let mut w = Writer::new(if kinded { 56 } else { 14 });
+      w.i64(moves.len() as i64);
+      for &(ref h, ref r) in moves.iter() {
+        w.hash(h);
+        w.blob_ref(r, kinded);
+      }
+      w
+    },
+    Msg::Health => Writer::new(15),
+    Msg::Stats => Writer::new(33),
+    Msg::Config => Writer::new(45),
+    Msg::TreeStats(ref h) => { let mut w = Writer::new(46); w.hash(h); w },
+    Msg::SetMeta(ref h, ref key, ref value) => {
+      let mut w = Writer::new(47);
+      w.hash(h);
+      w.blob(key.as_bytes());
+      w.blob(value);
+      w
+    },
+    Msg::SchemaInfo => Writer::new(50),
+    Msg::TrimMemory => Writer::new(51),
+    Msg::FlushDue => Writer::new(52),
+    Msg::Rescan => Writer::new(54),
+    Msg::HashesAfter(ref token) => { let mut w = Writer::new(53); w.blob(&token.as_bytes()); w },
+    Msg::DeleteIdRange{lo, hi} => { let mut w = Writer::new(49); w.i64(lo); w.i64(hi); w },
+    Msg::GetMeta(ref h, ref key) => {
+      let mut w = Writer::new(48);
+      w.hash(h);
+      w.blob(key.as_bytes());
+      w
+    },
+    Msg::ListNamespaces => Writer::new(16),
+    Msg::FileSize => Writer::new(17),
+    Msg::Page{offset, limit} => {
+      let mut w = Writer::new(18);
+      w.i64(offset as i64);
+      w.i64(limit as i64);
+      w
+    },
+    Msg::ReservePrioritized(ref e) => { let mut w = Writer::new(19); w.entry(e); w },
+    Msg::ReserveReturningId(ref e) => { let mut w = Writer::new(34); w.entry(e); w },
+    Msg::ReserveOrFetch(ref e) => { let mut w = Writer::new(37); w.entry(e); w },
+    Msg::BeginShutdown => Writer::new(38),
+    Msg::Shutdown => Writer::new(39),
+    Msg::Uncommit(ref hash) => { let mut w = Writer::new(40); w.hash(hash); w },
+    Msg::Seal => Writer::new(42),
+    Msg::AuditSince(seq) => { let mut w = Writer::new(43); w.i64(seq); w },
+    Msg::ResolvePrefix(ref prefix) => { let mut w = Writer::new(44); w.blob(prefix.as_bytes()); w },
+    Msg::AwaitPersistentRef(ref hash, timeout) => {
+      let mut w = Writer::new(41);
+      w.hash(hash);
+      w.i64(timeout.num_milliseconds());
+      w
+    },
+    Msg::CancelCallback(token) => { let mut w = Writer::new(20); w.i64(token.0 as i64); w },
+    Msg::FilterUnknown(ref hashes) => { let mut w = Writer::new(21); w.hashes(hashes); w },
+    Msg::Checkpoint => Writer::new(22),
+    Msg::IncrementalVacuum(pages) => { let mut w = Writer::new(35); w.i64(pages as i64); w },
+    Msg::TraceFor(ref h) => { let mut w = Writer::new(36); w.hash(h); w },
+    Msg::EstimateCount => Writer::new(23),
+    Msg::FetchLength(ref h) => { let mut w = Writer::new(24); w.hash(h); w },
+    Msg::Savepoint(ref name) => { let mut w = Writer::new(25); w.blob(name.as_bytes()); w },
+    Msg::ReleaseSavepoint(ref name) => { let mut w = Writer::new(26); w.blob(name.as_bytes()); w },
+    Msg::RollbackToSavepoint(ref name) => {
+      let mut w = Writer::new(27);
+      w.blob(name.as_bytes());
+      w
+    },
+    Msg::Tagged(id, ref msg) => {
+      let mut w = Writer::new(28);
+      w.i64(id.0 as i64);
+      w.blob(&try!(encode_msg(msg))[..]);
+      w
+    },
+    Msg::CallbackCount => Writer::new(29),
+    Msg::FetchId(ref h) => { let mut w = Writer::new(30); w.hash(h); w },
+    Msg::FetchById(id) => { let mut w = Writer::new(31); w.i64(id); w },
+    Msg::RewriteRefPrefix{ref from, ref to} => {
+      let mut w = Writer::new(32);
+      w.blob(from);
+      w.blob(to);
+      w
+    },
+    _ => return Err(WireError::NotEncodable),
+  };
+  Ok(w.bytes)
+}
+
+pub fn decode_msg(bytes: &[u8]) -> Result<Msg, WireError> {
+  let mut r = Reader::new(bytes);
+  let msg = match try!(r.u8()) {
+    1 => Msg::HashExists(try!(r.hash())),
+    2 => Msg::FetchPayload(try!(r.hash())),
+    3 => Msg::FetchPersistentRef(try!(r.hash())),
+    4 => Msg::Reserve(try!(r.entry())),
+    5 => Msg::UpdateReserved(try!(r.entry())),
+    6 => { let h = try!(r.hash()); Msg::Commit(h, try!(r.blob())) },
+    7 => Msg::Flush,
+    8 => Msg::Barrier,
+    9 => Msg::RebuildIndexes,
+    10 => Msg::ExpireStaleReserves,
+    11 => Msg::Abandon(try!(r.hash())),
+    12 => Msg::StorageSummary,
+    tag @ 13 | tag @ 55 => {
+      let h = try!(r.hash());
+      Msg::Relocate(h, try!(r.blob_ref(tag == 55)))
+    },
+    tag @ 14 | tag @ 56 => {
+      let len = try!(r.i64());
+      let mut moves = vec!();
+      for _ in 0..len {
+        let h = try!(r.hash());
+        moves.push((h, try!(r.blob_ref(tag == 56))));
+      }
+      Msg::BatchRelocate(moves)
+    },
+    15 => Msg::Health,
+    16 => Msg::ListNamespaces,
+    17 => Msg::FileSize,
+    18 => {
+      let offset = try!(r.i64()) as u64;
+      Msg::Page{offset: offset, limit: try!(r.i64()) as u64}
+    },
+    19 => Msg::ReservePrioritized(try!(r.entry())),
+    20 => Msg::CancelCallback(CallbackToken(try!(r.i64()) as u64)),
+    21 => Msg::FilterUnknown(try!(r.hashes())),
+    22 => Msg::Checkpoint,
+    23 => Msg::EstimateCount,
+    24 => Msg::FetchLength(try!(r.hash())),
+    25 => Msg::Savepoint(try!(r.text())),
+    26 => Msg::ReleaseSavepoint(try!(r.text())),
+    27 => Msg::RollbackToSavepoint(try!(r.text())),
+    28 => {
+      let id = RequestId(try!(r.i64()) as u64);
+      Msg::Tagged(id, Box::new(try!(decode_msg(&try!(r.blob())[..]))))
+    },
+    29 => Msg::CallbackCount,
+    30 => Msg::FetchId(try!(r.hash())),
+    31 => Msg::FetchById(try!(r.i64())),
+    32 => {
+      let from = try!(r.blob());
+      Msg::RewriteRefPrefix{from: from, to: try!(r.blob())}
+    },
+    33 => Msg::Stats,
+    45 => Msg::Config,
+    46 => Msg::TreeStats(try!(r.hash())),
+    47 => {
+      let h = try!(r.hash());
+      let key = try!(r.text());
+      Msg::SetMeta(h, key, try!(r.blob()))
+    },
+    48 => {
+      let h = try!(r.hash());
+      Msg::GetMeta(h, try!(r.text()))
+    },
+    49 => Msg::DeleteIdRange{lo: try!(r.i64()), hi: try!(r.i64())},
+    50 => Msg::SchemaInfo,
+    51 => Msg::TrimMemory,
+    52 => Msg::FlushDue,
+    54 => Msg::Rescan,
+    53 => match ResumeToken::from_bytes(&try!(r.blob())[..]) {
+      Some(token) => Msg::HashesAfter(token),
+      None => return Err(WireError::Truncated),
+    },
+    34 => Msg::ReserveReturningId(try!(r.entry())),
+    35 => Msg::IncrementalVacuum(try!(r.i64()) as u64),
+    36 => Msg::TraceFor(try!(r.hash())),
+    37 => Msg::ReserveOrFetch(try!(r.entry())),
+    38 => Msg::BeginShutdown,
+    39 => Msg::Shutdown,
+    40 => Msg::Uncommit(try!(r.hash())),
+    41 => {
+      let hash = try!(r.hash());
+      Msg::AwaitPersistentRef(hash, Duration::milliseconds(try!(r.i64())))
+    },
+    42 => Msg::Seal,
+    43 => Msg::AuditSince(try!(r.i64())),
+    44 => Msg::ResolvePrefix(try!(r.text())),
+    t => return Err(WireError::UnknownTag(t)),
+  };
+  r.done(msg)
+}
+
+pub fn encode_reply(reply: &Reply) -> Result<Vec<u8>, WireError> {
+  let w = match *reply {
+    Reply::HashKnown => Writer::new(1),
+    Reply::HashNotKnown => Writer::new(2),
+    Reply::Entry(ref e) => { let mut w = Writer::new(3); w.entry(e); w },
+    Reply::Payload(ref p) => { let mut w = Writer::new(4); w.blob_opt(p); w },
+    Reply::PersistentRef(ref r) => { let mut w = Writer::new(5); w.blob(r); w },
+    Reply::ReserveOK => Writer::new(6),
+    Reply::Reserved(id) => { let mut w = Writer::new(37); w.i64(id); w },
+    Reply::AlreadyReserved => Writer::new(38),
+    Reply::Vacuumed(pages) => { let mut w = Writer::new(39); w.i64(pages as i64); w },
+    Reply::PayloadTooLarge(size) => { let mut w = Writer::new(41); w.i64(size as i64); w },
+    Reply::FlushedNothing => Writer::new(42),
+    Reply::LevelMismatch{existing, requested} => {
+      let mut w = Writer::new(43);
+      w.i64(existing);
+      w.i64(requested);
+      w
+    },
+    Reply::ShuttingDown => Writer::new(44),
+    Reply::Disabled => Writer::new(45),
+    Reply::Timeout => Writer::new(47),
+    Reply::Sealed => Writer::new(48),
+    Reply::QueueNotEmpty(queued) => { let mut w = Writer::new(49); w.i64(queued as i64); w },
+    Reply::Interrupted => Writer::new(51),
+    Reply::Resolved(ref hash) => { let mut w = Writer::new(52); w.hash(hash); w },
+    Reply::ResolvedAmbiguous{ref hashes, total, too_many} => {
+      let mut w = Writer::new(53);
+      w.hashes(hashes);
+      w.i64(total as i64);
+      w.u8(too_many as u8);
+      w
+    },
+    Reply::AuditEvents(ref events) => {
+      let mut w = Writer::new(50);
+      w.i64(events.len() as i64);
+      for event in events.iter() {
+        w.i64(event.seq);
+        w.i64(event.ts_ms);
+        w.u8(event.op.code());
+        w.hash(&event.hash);
+        w.blob(&event.detail[..]);
+      }
+      w
+    },
+    Reply::CallbacksRegistered{token, registered, fired} => {
+      let mut w = Writer::new(46);
+      w.i64(token.0 as i64);
+      w.i64(registered as i64);
+      w.i64(fired as i64);
+      w
+    },
+    Reply::Trace(ref events) => {
+      let mut w = Writer::new(40);
+      w.i64(events.len() as i64);
+      for event in events.iter() {
+        w.u8(match event.kind {
+          TraceKind::Reserved => 1,
+          TraceKind::Updated => 2,
+          TraceKind::Committed => 3,
+          TraceKind::Inserted => 4,
+          TraceKind::CallbackFired => 5,
+          TraceKind::Abandoned => 6,
+        });
+        w.i64(event.at_ms);
+      }
+      w
+    },
+    Reply::CommitOK => Writer::new(7),
+    Reply::CallbackRegistered(token) => { let mut w = Writer::new(8); w.i64(token.0 as i64); w },
+    Reply::Retry => Writer::new(9),
+    Reply::RefConflict(ref h) => { let mut w = Writer::new(10); w.hash(h); w },
+    Reply::CollisionSuspected(ref h) => { let mut w = Writer::new(17); w.hash(h); w },
+    Reply::ExpiredReserves(n) => { let mut w = Writer::new(11); w.i64(n as i64); w },
+    Reply::Relocated{updated, not_found} => {
+      let mut w = Writer::new(18);
+      w.i64(updated as i64);
+      w.i64(not_found as i64);
+      w
+    },
+    Reply::Error(ref e) => {
+      let mut w = Writer::new(12);
+      match *e {
+        HashIndexError::Busy => w.u8(1),
+        HashIndexError::WrongKey => w.u8(2),
+        HashIndexError::DiskFull => w.u8(3),
+        HashIndexError::DigestWidthMismatch{expected, found} => {
+          w.u8(4);
+          w.i64(expected as i64);
+          w.i64(found as i64);
+        },
+        HashIndexError::UnknownSavepoint(ref name) => {
+          w.u8(5);
+          w.blob(name.as_bytes());
+        },
+        HashIndexError::DuplicateHash(ref hash) => {
+          w.u8(6);
+          w.hash(hash);
+        },
+        HashIndexError::QueryFailed(ref message) => {
+          w.u8(7);
+          w.blob(message.as_bytes());
+        },
+        HashIndexError::NotAHashIndex => w.u8(8),
+        HashIndexError::CorruptRow(ref message) => {
+          w.u8(9);
+          w.blob(message.as_bytes());
+        },
+        HashIndexError::IdsExhausted => w.u8(10),
+      }
+      w
+    },
+    Reply::HashBatch(ref es) => { let mut w = Writer::new(13); w.entries(es); w },
+    Reply::ResumableBatch(ref es, ref token) => {
+      let mut w = Writer::new(16);
+      w.entries(es);
+      w.blob(&token.as_bytes());
+      w
+    },
+    Reply::StorageSummary{total_bytes, distinct_objects, leaf_count} => {
+      let mut w = Writer::new(14);
+      w.i64(total_bytes as i64);
+      w.i64(distinct_objects as i64);
+      w.i64(leaf_count as i64);
+      w
+    },
+    Reply::Health{uncommitted_writes, seconds_since_commit, queue_depth} => {
+      let mut w = Writer::new(19);
+      w.i64(uncommitted_writes as i64);
+      w.i64(seconds_since_commit);
+      w.i64(queue_depth as i64);
+      w
+    },
+    Reply::Namespaces(ref names) => { let mut w = Writer::new(20); w.texts(names); w },
+    Reply::FileSize(size) => { let mut w = Writer::new(21); w.i64(size as i64); w },
+    Reply::Unknown(ref hashes) => { let mut w = Writer::new(24); w.hashes(hashes); w },
+    Reply::Estimate(n) => { let mut w = Writer::new(26); w.i64(n as i64); w },
+    Reply::Length(n) => { let mut w = Writer::new(27); w.i64(n as i64); w },
+    Reply::Orphans(ref hashes) => { let mut w = Writer::new(28); w.hashes(hashes); w },
+    Reply::Tagged(id, ref reply) => {
+      let mut w = Writer::new(29);
+      w.i64(id.0 as i64);
+      w.blob(&try!(encode_reply(reply))[..]);
+      w
+    },
+    Reply::CallbackLimitReached => Writer::new(30),
+    Reply::CallbackCount(n) => { let mut w = Writer::new(31); w.i64(n as i64); w },
+    Reply::Id(id) => { let mut w = Writer::new(32); w.i64(id); w },
+    Reply::InternalError(ref what) => { let mut w = Writer::new(33); w.blob(what.as_bytes()); w },
+    Reply::LeafCount(n) => { let mut w = Writer::new(34); w.i64(n as i64); w },
+    Reply::BrokenTree(ref e) => {
+      let mut w = Writer::new(35);
+      let (tag, h) = match *e {
+        TreeError::UnknownHash(ref h) => (1, h),
+        TreeError::NotCommitted(ref h) => (2, h),
+        TreeError::InvalidPayload(ref h) => (3, h),
+        TreeError::InvalidRef(ref h) => (4, h),
+        TreeError::TooLarge(ref h) => (5, h),
+        TreeError::WrongRefKind(ref h) => (6, h),
+      };
+      w.u8(tag);
+      w.hash(h);
+      w
+    },
+    Reply::Stats{committed, wait_p50_ms, wait_p95_ms, wait_max_ms} => {
+      let mut w = Writer::new(36);
+      w.i64(committed as i64);
+      w.i64(wait_p50_ms);
+      w.i64(wait_p95_ms);
+      w.i64(wait_max_ms);
+      w
+    },
+    Reply::Checkpointed{log_pages, moved_pages} => {
+      let mut w = Writer::new(25);
+      w.i64(log_pages as i64);
+      w.i64(moved_pages as i64);
+      w
+    },
+    Reply::CallbackCancelled(cancelled) => {
+      let mut w = Writer::new(23);
+      w.u8(if cancelled { 1 } else { 0 });
+      w
+    },
+    Reply::Page(ref es, total) => {
+      let mut w = Writer::new(22);
+      w.entries(es);
+      w.i64(total as i64);
+      w
+    },
+    Reply::InvalidHash(ref e) => {
+      let mut w = Writer::new(15);
+      match *e {
+        HashError::WrongWidth{expected, found} => {
+          w.u8(1);
+          w.i64(expected as i64);
+          w.i64(found as i64);
+        },
+        HashError::AllZero => w.u8(2),
+        HashError::NotHex => w.u8(3),
+      }
+      w
+    },
+    Reply::TreeStats{depth, leaves, branches, avg_fanout} => {
+      let mut w = Writer::new(54);
+      w.i64(depth as i64);
+      w.i64(leaves as i64);
+      w.i64(branches as i64);
+      w.i64(unsafe { mem::transmute::<f64, i64>(avg_fanout) });
+      w
+    },
+    Reply::MetaValue(ref value) => { let mut w = Writer::new(55); w.blob_opt(value); w },
+    Reply::Trimmed(bytes) => { let mut w = Writer::new(58); w.i64(bytes as i64); w },
+    Reply::FlushDue(due) => { let mut w = Writer::new(59); w.u8(due as u8); w },
+    Reply::Rescanned(ref report) => {
+      let mut w = Writer::new(61);
+      w.i64(report.entries as i64);
+      w.i64(report.ids_skipped);
+      w.i64(report.missing_from_bloom as i64);
+      w
+    },
+    Reply::InvalidEntry(ref what) => { let mut w = Writer::new(60); w.blob(what.as_bytes()); w },
+    Reply::SchemaInfo{version, ref columns, ref features} => {
+      let mut w = Writer::new(57);
+      w.i64(version as i64);
+      w.texts(columns);
+      w.texts(features);
+      w
+    },
+    Reply::DeletedRange{deleted, ref freed_refs} => {
+      let mut w = Writer::new(56);
+      w.i64(deleted as i64);
+      w.i64(freed_refs.len() as i64);
+      for r in freed_refs.iter() {
+        w.blob(r);
+      }
+      w
+    },
+    Reply::Config{..} => return Err(WireError::NotEncodable),
+  };
+  Ok(w.bytes)
+}
+
+pub fn decode_reply(bytes: &[u8]) -> Result<Reply, WireError> {
+  let mut r = Reader::new(bytes);
+  let reply = match try!(r.u8()) {
+    1 => Reply::HashKnown,
+    2 => Reply::HashNotKnown,
+    3 => Reply::Entry(try!(r.entry())),
+    4 => Reply::Payload(try!(r.blob_opt())),
+    5 => Reply::PersistentRef(try!(r.blob())),
+    6 => Reply::ReserveOK,
+    7 => Reply::CommitOK,
+    8 => Reply::CallbackRegistered(CallbackToken(try!(r.i64()) as u64)),
+    9 => Reply::Retry,
+    10 => Reply::RefConflict(try!(r.hash())),
+    11 => Reply::ExpiredReserves(try!(r.i64()) as usize),
+    12 => Reply::Error(match try!(r.u8()) {
+      1 => HashIndexError::Busy,
+      2 => HashIndexError::WrongKey,
+      3 => HashIndexError::DiskFull,
+      4 => {
+        let expected = try!(r.i64()) as usize;
+        HashIndexError::DigestWidthMismatch{expected: expected, found: try!(r.i64()) as usize}
+      },
+      5 => HashIndexError::UnknownSavepoint(try!(r.text())),
+      6 => HashIndexError::DuplicateHash(try!(r.hash())),
+      7 => HashIndexError::QueryFailed(try!(r.text())),
+      8 => HashIndexError::NotAHashIndex,
+      9 => HashIndexError::CorruptRow(try!(r.text())),
+      10 => HashIndexError::IdsExhausted,
+      t => return Err(WireError::UnknownTag(t)),
+    }),
+    13 => Reply::HashBatch(try!(r.entries())),
+    14 => {
+      let total_bytes = try!(r.i64()) as u64;
+      let distinct_objects = try!(r.i64()) as u64;
+      Reply::StorageSummary{total_bytes: total_bytes,
+                            distinct_objects: distinct_objects,
+                            leaf_count: try!(r.i64()) as u64}
+    },
+    15 => Reply::InvalidHash(match try!(r.u8()) {
+      1 => {
+        let expected = try!(r.i64()) as usize;
+        HashError::WrongWidth{expected: expected, found: try!(r.i64()) as usize}
+      },
+      2 => HashError::AllZero,
+      3 => HashError::NotHex,
+      t => return Err(WireError::UnknownTag(t)),
+    }),
+    16 => {
+      let es = try!(r.entries());
+      match ResumeToken::from_bytes(&try!(r.blob())[..]) {
+        Some(token) => Reply::ResumableBatch(es, token),
+        None => return Err(WireError::Truncated),
+      }
+    },
+    17 => Reply::CollisionSuspected(try!(r.hash())),
+    18 => {
+      let updated = try!(r.i64()) as usize;
+      Reply::Relocated{updated: updated, not_found: try!(r.i64()) as usize}
+    },
+    19 => {
+      let uncommitted_writes = try!(r.i64()) as u64;
+      let seconds_since_commit = try!(r.i64());
+      Reply::Health{uncommitted_writes: uncommitted_writes,
+                    seconds_since_commit: seconds_since_commit,
+                    queue_depth: try!(r.i64()) as usize}
+    },
+    20 => Reply::Namespaces(try!(r.texts())),
+    21 => Reply::FileSize(try!(r.i64()) as u64),
+    22 => {
+      let es = try!(r.entries());
+      Reply::Page(es, try!(r.i64()) as u64)
+    },
+    23 => Reply::CallbackCancelled(try!(r.u8()) != 0),
+    24 => Reply::Unknown(try!(r.hashes())),
+    25 => {
+      let log_pages = try!(r.i64()) as u64;
+      Reply::Checkpointed{log_pages: log_pages, moved_pages: try!(r.i64()) as u64}
+    },
+    26 => Reply::Estimate(try!(r.i64()) as u64),
+    27 => Reply::Length(try!(r.i64()) as u64),
+    28 => Reply::Orphans(try!(r.hashes())),
+    29 => {
+      let id = RequestId(try!(r.i64()) as u64);
+      Reply::Tagged(id, Box::new(try!(decode_reply(&try!(r.blob())[..]))))
+    },
+    30 => Reply::CallbackLimitReached,
+    31 => Reply::CallbackCount(try!(r.i64()) as usize),
+    32 => Reply::Id(try!(r.i64())),
+    33 => Reply::InternalError(try!(r.text())),
+    34 => Reply::LeafCount(try!(r.i64()) as u64),
+    35 => Reply::BrokenTree(match try!(r.u8()) {
+      1 => TreeError::UnknownHash(try!(r.hash())),
+      2 => TreeError::NotCommitted(try!(r.hash())),
+      3 => TreeError::InvalidPayload(try!(r.hash())),
+      4 => TreeError::InvalidRef(try!(r.hash())),
+      5 => TreeError::TooLarge(try!(r.hash())),
+      6 => TreeError::WrongRefKind(try!(r.hash())),
+      t => return Err(WireError::UnknownTag(t)),
+    }),
+    36 => {
+      let committed = try!(r.i64()) as u64;
+      let wait_p50_ms = try!(r.i64());
+      let wait_p95_ms = try!(r.i64());
+      Reply::Stats{committed: committed,
+                   wait_p50_ms: wait_p50_ms,
+                   wait_p95_ms: wait_p95_ms,
+                   wait_max_ms: try!(r.i64())}
+    },
+    37 => Reply::Reserved(try!(r.i64())),
+    38 => Reply::AlreadyReserved,
+    39 => Reply::Vacuumed(try!(r.i64()) as u64),
+    40 => {
+      let len = try!(r.i64());
+      let mut events = vec!();
+      for _ in 0..len {
+        let kind = match try!(r.u8()) {
+          1 => TraceKind::Reserved,
+          2 => TraceKind::Updated,
+          3 => TraceKind::Committed,
+          4 => TraceKind::Inserted,
+          5 => TraceKind::CallbackFired,
+          6 => TraceKind::Abandoned,
+          t => return Err(WireError::UnknownTag(t)),
+        };
+        events.push(TraceEvent{kind: kind, at_ms: try!(r.i64())});
+      }
+      Reply::Trace(events)
+    },
+    41 => Reply::PayloadTooLarge(try!(r.i64()) as u64),
+    42 => Reply::FlushedNothing,
+    43 => {
+      let existing = try!(r.i64());
+      Reply::LevelMismatch{existing: existing, requested: try!(r.i64())}
+    },
+    44 => Reply::ShuttingDown,
+    45 => Reply::Disabled,
+    46 => {
+      let token = CallbackToken(try!(r.i64()) as u64);
+      let registered = try!(r.i64()) as usize;
+      let fired = try!(r.i64()) as usize;
+      Reply::CallbacksRegistered{token: token, registered: registered, fired: fired}
+    },
+    47 => Reply::Timeout,
+    48 => Reply::Sealed,
+    49 => Reply::QueueNotEmpty(try!(r.i64()) as usize),
+    50 => {
+      let len = try!(r.i64());
+      let mut events = vec!();
+      for _ in 0..len {
+        let seq = try!(r.i64());
+        let ts_ms = try!(r.i64());
+        let code = try!(r.u8());
+        let op = match AuditOp::from_code(code) {
+          Some(op) => op,
+          None => return Err(WireError::UnknownTag(code)),
+        };
+        let hash = try!(r.hash());
+        events.push(AuditEvent{seq: seq, ts_ms: ts_ms, op: op, hash: hash, detail: try!(r.blob())});
+      }
+      Reply::AuditEvents(events)
+    },
+    51 => Reply::Interrupted,
+    52 => Reply::Resolved(try!(r.hash())),
+    53 => {
+      let hashes = try!(r.hashes());
+      let total = try!(r.i64()) as u64;
+      Reply::ResolvedAmbiguous{hashes: hashes, total: total, too_many: try!(r.u8()) != 0}
+    },
+    54 => {
+      let depth = try!(r.i64()) as u32;
+      let leaves = try!(r.i64()) as u64;
+      let branches = try!(r.i64()) as u64;
+      let avg_fanout = unsafe { mem::transmute::<i64, f64>(try!(r.i64())) };
+      Reply::TreeStats{depth: depth, leaves: leaves, branches: branches, avg_fanout: avg_fanout}
+    },
+    55 => Reply::MetaValue(try!(r.blob_opt())),
+    56 => {
+      let deleted = try!(r.i64()) as usize;
+      let mut freed_refs = vec!();
+      for _ in 0..try!(r.i64()) {
+        freed_refs.push(try!(r.blob()));
+      }
+      Reply::DeletedRange{deleted: deleted, freed_refs: freed_refs}
+    },
+    58 => Reply::Trimmed(try!(r.i64()) as u64),
+    59 => Reply::FlushDue(try!(r.u8()) != 0),
+    60 => Reply::InvalidEntry(try!(r.text())),
+    61 => Reply::Rescanned(RescanReport{entries: try!(r.i64()) as u64,
+                                        ids_skipped: try!(r.i64()),
+                                        missing_from_bloom: try!(r.i64()) as u64}),
+    57 => {
+      let version = try!(r.i64()) as u32;
+      let columns = try!(r.texts());
+      Reply::SchemaInfo{version: version, columns: columns, features: try!(r.texts())}
+    },
+    t => return Err(WireError::UnknownTag(t)),
+  };
+  r.done(reply)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::time::duration::{Duration};
+
+  use audit::{AuditEvent, AuditOp};
+  use callback_container::{CallbackToken};
+  use hash_index::{BlobRef, Hash, HashEntry, HashError, HashIndexError, IndexConfig, Msg, Reply,
+                   RefKind, RequestId, RescanReport, ResumeToken, TreeError};
+  use trace::{TraceEvent, TraceKind};
+
+  fn entry() -> HashEntry {
+    HashEntry{hash: Hash::new(b"foo"), level: -3,
+              payload: Some(vec!()), persistent_ref: Some(b"ref".to_vec()), content_len: 3}
+  }
+
+  fn msg_identity(msg: Msg) {
+    let bytes = encode_msg(&msg).unwrap();
+    let decoded = decode_msg(&bytes[..]).unwrap();
+    assert_eq!(Ok(bytes), encode_msg(&decoded));
+  }
+
+  fn reply_identity(reply: Reply) {
+    let bytes = encode_reply(&reply).unwrap();
+    let decoded = decode_reply(&bytes[..]).unwrap();
+    assert_eq!(Ok(bytes), encode_reply(&decoded));
+  }
+
+  #[test]
+  fn msg_round_trip() {
+    let hash = Hash::new(b"foo");
+    msg_identity(Msg::HashExists(hash.clone()));
+    msg_identity(Msg::FetchPayload(hash.clone()));
+    msg_identity(Msg::FetchPersistentRef(hash.clone()));
+    msg_identity(Msg::Reserve(entry()));
+    msg_identity(Msg::UpdateReserved(HashEntry{payload: None, persistent_ref: None, ..entry()}));
+    msg_identity(Msg::Commit(hash.clone(), b"ref".to_vec()));
+    msg_identity(Msg::Flush);
+    msg_identity(Msg::Barrier);
+    msg_identity(Msg::RebuildIndexes);
+    msg_identity(Msg::ExpireStaleReserves);
+    msg_identity(Msg::Abandon(hash.clone()));
+    msg_identity(Msg::StorageSummary);
+    msg_identity(Msg::Health);
+    msg_identity(Msg::Stats);
+    msg_identity(Msg::Config);
+    msg_identity(Msg::TreeStats(hash.clone()));
+    msg_identity(Msg::SetMeta(hash.clone(), "mime".to_string(), b"text/plain".to_vec()));
+    msg_identity(Msg::GetMeta(hash.clone(), "mime".to_string()));
+    msg_identity(Msg::DeleteIdRange{lo: 3, hi: 7});
+    msg_identity(Msg::SchemaInfo);
+    msg_identity(Msg::TrimMemory);
+    msg_identity(Msg::FlushDue);
+    msg_identity(Msg::HashesAfter(ResumeToken::start()));
+    msg_identity(Msg::Rescan);
+    msg_identity(Msg::ListNamespaces);
+    msg_identity(Msg::FileSize);
+    msg_identity(Msg::Page{offset: 20, limit: 10});
+    msg_identity(Msg::ReservePrioritized(entry()));
+    msg_identity(Msg::ReserveReturningId(entry()));
+    msg_identity(Msg::ReserveOrFetch(entry()));
+    msg_identity(Msg::BeginShutdown);
+    msg_identity(Msg::Shutdown);
+    msg_identity(Msg::Uncommit(hash.clone()));
+    msg_identity(Msg::Seal);
+    msg_identity(Msg::AuditSince(12));
+    msg_identity(Msg::ResolvePrefix("c0ffee".to_string()));
+    msg_identity(Msg::AwaitPersistentRef(hash.clone(), Duration::milliseconds(1500)));
+    msg_identity(Msg::IncrementalVacuum(100));
+    msg_identity(Msg::TraceFor(Hash::new(b"foo")));
+    msg_identity(Msg::CancelCallback(CallbackToken(7)));
+    msg_identity(Msg::FilterUnknown(vec!(hash.clone(), Hash::new(b"bar"))));
+    msg_identity(Msg::Checkpoint);
+    msg_identity(Msg::EstimateCount);
+    msg_identity(Msg::FetchLength(hash.clone()));
+    msg_identity(Msg::Savepoint("chunks".to_string()));
+    msg_identity(Msg::ReleaseSavepoint("chunks".to_string()));
+    msg_identity(Msg::RollbackToSavepoint("chunks".to_string()));
+    msg_identity(Msg::Tagged(RequestId(7), Box::new(Msg::Commit(hash.clone(), b"ref".to_vec()))));
+    msg_identity(Msg::CallbackCount);
+    msg_identity(Msg::FetchId(hash.clone()));
+    msg_identity(Msg::FetchById(-3));
+    msg_identity(Msg::RewriteRefPrefix{from: b"old/".to_vec(), to: b"new/".to_vec()});
+    msg_identity(Msg::Relocate(hash.clone(),
+                               BlobRef{name: b"name".to_vec(), offset: 1, length: 2,
+                                       kind: RefKind::Unknown}));
+    msg_identity(Msg::BatchRelocate(vec!(
+      (hash.clone(), BlobRef{name: b"a".to_vec(), offset: 1, length: 2, kind: RefKind::Leaf}),
+      (hash.clone(), BlobRef{name: b"b".to_vec(), offset: 3, length: 4, kind: RefKind::Branch}))));
+    msg_identity(Msg::Relocate(hash.clone(),
+                               BlobRef{name: b"name".to_vec(), offset: 1, length: 2,
+                                       kind: RefKind::Leaf}));
+    msg_identity(Msg::BatchRelocate(vec!(
+      (hash.clone(), BlobRef{name: b"a".to_vec(), offset: 1, length: 2, kind: RefKind::Unknown}))));
+  }
+
+  #[test]
+  fn reply_round_trip() {
+    reply_identity(Reply::HashKnown);
+    reply_identity(Reply::HashNotKnown);
+    reply_identity(Reply::Entry(entry()));
+    reply_identity(Reply::Payload(None));
+    reply_identity(Reply::Payload(Some(b"payload".to_vec())));
+    reply_identity(Reply::PersistentRef(b"ref".to_vec()));
+    reply_identity(Reply::ReserveOK);
+    reply_identity(Reply::Reserved(7));
+    reply_identity(Reply::AlreadyReserved);
+    reply_identity(Reply::FlushedNothing);
+    reply_identity(Reply::LevelMismatch{existing: 0, requested: 1});
+    reply_identity(Reply::ShuttingDown);
+    reply_identity(Reply::Disabled);
+    reply_identity(Reply::CallbacksRegistered{token: CallbackToken(7), registered: 2, fired: 1});
+    reply_identity(Reply::Timeout);
+    reply_identity(Reply::Sealed);
+    reply_identity(Reply::QueueNotEmpty(3));
+    reply_identity(Reply::Interrupted);
+    reply_identity(Reply::Resolved(hash.clone()));
+    reply_identity(Reply::ResolvedAmbiguous{hashes: vec!(hash.clone(), Hash::new(b"bar")), total: 7,
+                                            too_many: true});
+    reply_identity(Reply::AuditEvents(vec!(
+      AuditEvent{seq: 1, ts_ms: 1000, op: AuditOp::Reserve, hash: Hash::new(b"foo"),
+                 detail: vec!()},
+      AuditEvent{seq: 2, ts_ms: 1001, op: AuditOp::Commit, hash: Hash::new(b"foo"),
+                 detail: b"ref".to_vec()})));
+    reply_identity(Reply::Vacuumed(100));
+    reply_identity(Reply::Trace(vec!(TraceEvent{kind: TraceKind::Reserved, at_ms: 1},
+                                     TraceEvent{kind: TraceKind::CallbackFired, at_ms: 20})));
+    reply_identity(Reply::CommitOK);
+    reply_identity(Reply::CallbackRegistered(CallbackToken(7)));
+    reply_identity(Reply::CallbackCancelled(true));
+    reply_identity(Reply::Unknown(vec!(Hash::new(b"foo"))));
+    reply_identity(Reply::Checkpointed{log_pages: 12, moved_pages: 10});
+    reply_identity(Reply::Estimate(1000));
+    reply_identity(Reply::Length(4096));
+    reply_identity(Reply::Orphans(vec!(Hash::new(b"foo"), Hash::new(b"bar"))));
+    reply_identity(Reply::Tagged(RequestId(7), Box::new(Reply::Length(4096))));
+    reply_identity(Reply::CallbackLimitReached);
+    reply_identity(Reply::CallbackCount(3));
+    reply_identity(Reply::Id(42));
+    reply_identity(Reply::LeafCount(12));
+    reply_identity(Reply::BrokenTree(TreeError::UnknownHash(Hash::new(b"foo"))));
+    reply_identity(Reply::BrokenTree(TreeError::InvalidRef(Hash::new(b"foo"))));
+    reply_identity(Reply::BrokenTree(TreeError::TooLarge(Hash::new(b"foo"))));
+    reply_identity(Reply::BrokenTree(TreeError::WrongRefKind(Hash::new(b"foo"))));
+    reply_identity(Reply::TreeStats{depth: 3, leaves: 4, branches: 2, avg_fanout: 2.5});
+    reply_identity(Reply::MetaValue(Some(b"text/plain".to_vec())));
+    reply_identity(Reply::MetaValue(None));
+    reply_identity(Reply::Trimmed(4096));
+    reply_identity(Reply::FlushDue(true));
+    reply_identity(Reply::Rescanned(RescanReport{entries: 10, ids_skipped: 2,
+                                                 missing_from_bloom: 1}));
+    reply_identity(Reply::InvalidEntry("leaf 00 has a payload".to_string()));
+    reply_identity(Reply::SchemaInfo{version: 1, columns: vec!("id".to_string()),
+                                     features: vec!("audit".to_string())});
+    reply_identity(Reply::DeletedRange{deleted: 3, freed_refs: vec!(b"a".to_vec(), b"b".to_vec())});
+    reply_identity(Reply::InternalError("commit of hash 00 that is already committed".to_string()));
+    reply_identity(Reply::Retry);
+    reply_identity(Reply::RefConflict(Hash::new(b"foo")));
+    reply_identity(Reply::CollisionSuspected(Hash::new(b"foo")));
+    reply_identity(Reply::ExpiredReserves(42));
+    reply_identity(Reply::Relocated{updated: 3, not_found: 4});
+    reply_identity(Reply::Error(HashIndexError::Busy));
+    reply_identity(Reply::Error(HashIndexError::WrongKey));
+    reply_identity(Reply::Error(HashIndexError::DiskFull));
+    reply_identity(Reply::Error(HashIndexError::DigestWidthMismatch{expected: 32, found: 64}));
+    reply_identity(Reply::Error(HashIndexError::UnknownSavepoint("chunks".to_string())));
+    reply_identity(Reply::Error(HashIndexError::DuplicateHash(Hash::new(b"foo"))));
+    reply_identity(Reply::Error(HashIndexError::QueryFailed("SQLITE_ERROR".to_string())));
+    reply_identity(Reply::Error(HashIndexError::NotAHashIndex));
+    reply_identity(Reply::Error(HashIndexError::CorruptRow("entry 1".to_string())));
+    reply_identity(Reply::Error(HashIndexError::IdsExhausted));
+    reply_identity(Reply::HashBatch(vec!(entry(), entry())));
+    reply_identity(Reply::ResumableBatch(vec!(entry()), ResumeToken::start()));
+    reply_identity(Reply::Namespaces(vec!("".to_string(), "backups".to_string())));
+    reply_identity(Reply::FileSize(4096));
+    reply_identity(Reply::Page(vec!(entry()), 21));
+    reply_identity(Reply::StorageSummary{total_bytes: 1, distinct_objects: 2, leaf_count: 3});
+    reply_identity(Reply::Health{uncommitted_writes: 1, seconds_since_commit: -2, queue_depth: 3});
+    reply_identity(Reply::Stats{committed: 1, wait_p50_ms: 2, wait_p95_ms: 3, wait_max_ms: 4});
+    reply_identity(Reply::InvalidHash(HashError::WrongWidth{expected: 64, found: 3}));
+    reply_identity(Reply::InvalidHash(HashError::AllZero));
+    reply_identity(Reply::InvalidHash(HashError::NotHex));
+    reply_identity(Reply::PayloadTooLarge(1 << 30));
+  }
+
+  #[test]
+  fn decoded_fields() {
+    match decode_msg(&encode_msg(&Msg::Reserve(entry())).unwrap()[..]) {
+      Ok(Msg::Reserve(e)) => {
+        assert_eq!(entry().hash, e.hash);
+        assert_eq!(-3, e.level);
+        assert_eq!(Some(vec!()), e.payload);
+        assert_eq!(Some(b"ref".to_vec()), e.persistent_ref);
+      },
+      _ => panic!("Unexpected decoding."),
+    }
+  }
+
+  #[test]
+  fn ref_kinds_use_the_kinded_form() {
+    let hash = Hash::new(b"foo");
+    let blob_ref = |kind| BlobRef{name: b"name".to_vec(), offset: 1, length: 2, kind: kind};
+    let tag = |msg: Msg| encode_msg(&msg).unwrap()[0];
+    assert_eq!(13, tag(Msg::Relocate(hash.clone(), blob_ref(RefKind::Unknown))));
+    assert_eq!(55, tag(Msg::Relocate(hash.clone(), blob_ref(RefKind::Leaf))));
+    assert_eq!(14, tag(Msg::BatchRelocate(vec!((hash.clone(), blob_ref(RefKind::Unknown))))));
+    assert_eq!(56, tag(Msg::BatchRelocate(vec!((hash.clone(), blob_ref(RefKind::Unknown)),
+                                               (hash.clone(), blob_ref(RefKind::Branch))))));
+
+    // The original form has no kind byte:
+    let unknown = encode_msg(&Msg::Relocate(hash.clone(), blob_ref(RefKind::Unknown))).unwrap();
+    let leaf = encode_msg(&Msg::Relocate(hash.clone(), blob_ref(RefKind::Leaf))).unwrap();
+    assert_eq!(unknown.len() + 1, leaf.len());
+  }
+
+  #[test]
+  fn closures_are_not_encodable() {
+    let msg = Msg::CallAfterHashIsComitted(Hash::new(b"foo"), Box::new(move|| {}));
+    assert_eq!(Err(WireError::NotEncodable), encode_msg(&msg));
+    let msg = Msg::CallAfterAllCommitted(vec!(Hash::new(b"foo")), Box::new(move|| {}));
+    assert_eq!(Err(WireError::NotEncodable), encode_msg(&msg));
+  }
+
+  #[test]
+  fn config_is_not_encodable() {
+    let config = IndexConfig::new();
+    assert_eq!(Err(WireError::NotEncodable),
+               encode_reply(&Reply::Config{config: config, encrypted: false}));
+  }
+
+  #[test]
+  fn malformed_input() {
+    assert!(decode_msg(&[]).is_err());
+    assert_eq!(Some(WireError::UnknownTag(200)), decode_msg(&[200]).err());
+    assert_eq!(Some(WireError::Truncated), decode_msg(&[1, 0, 0, 0, 5, 1]).err());
+    assert_eq!(Some(WireError::TrailingBytes), decode_reply(&[1, 1]).err());
+  }
+}
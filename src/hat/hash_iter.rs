@@ -0,0 +1,127 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Client-side iteration over the committed entries of a running hash index.
+
+use std::vec;
+
+use hash_index::{HashEntry, HashIndexProcess, Msg, Reply, ResumeToken};
+use process::{ReplyToken};
+
+
+/// Iterates over the committed entries of a hash index in id order, fetching them a batch at a
+/// time with `Msg::HashesAfter`. The next batch is requested as soon as the previous one arrives,
+/// so that it is read while the caller works through the current one.
+///
+/// Entries that are committed while iterating may or may not be included.
+pub struct HashIter {
+  index: HashIndexProcess,
+  batch: vec::IntoIter<HashEntry>,
+  next_batch: Option<ReplyToken<Reply>>,
+  token: ResumeToken,
+}
+
+impl HashIter {
+
+  /// Iterate from the beginning.
+  pub fn new(index: HashIndexProcess) -> HashIter {
+    HashIter::resume_from(index, ResumeToken::start())
+  }
+
+  /// Iterate over the entries after the position of `token` (see `HashIter::token`).
+  pub fn resume_from(index: HashIndexProcess, token: ResumeToken) -> HashIter {
+    let next_batch = index.try_request(Msg::HashesAfter(token.clone()));
+    HashIter{index: index,
+             batch: vec!().into_iter(),
+             next_batch: Some(next_batch),
+             token: token}
+  }
+
+  /// The position after the last batch that was fetched, e.g. for storing a checkpoint. Entries of
+  /// that batch that were not yet returned are not covered by an iteration resumed from it.
+  pub fn token(&self) -> ResumeToken {
+    self.token.clone()
+  }
+}
+
+impl Iterator for HashIter {
+  type Item = HashEntry;
+
+  fn next(&mut self) -> Option<HashEntry> {
+    loop {
+      match self.batch.next() {
+        Some(entry) => return Some(entry),
+        None => (),
+      }
+      let next_batch = match self.next_batch.take() {
+        Some(next_batch) => next_batch,
+        None => return None,
+      };
+      match next_batch.wait() {
+        Reply::ResumableBatch(ref batch, _) if batch.len() == 0 => return None,
+        Reply::ResumableBatch(batch, token) => {
+          self.next_batch = Some(self.index.try_request(Msg::HashesAfter(token.clone())));
+          self.batch = batch.into_iter();
+          self.token = token;
+        },
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use hash_index::{Hash, HashEntry, HashIndex, HashIndexProcess, Msg, Reply};
+  use process::{Process};
+
+  #[test]
+  fn iterates_committed_entries() {
+    let index: HashIndexProcess =
+      Process::new(Box::new(move|| { HashIndex::new_for_testing() }));
+
+    let entries: Vec<HashEntry> = (0..3u8).map(|i| {
+      HashEntry{hash: Hash::new(&[i]), level: 0, payload: None, persistent_ref: None,
+                content_len: 1}
+    }).collect();
+    for e in entries.iter() {
+      match index.send_reply(Msg::Reserve(e.clone())) {
+        Reply::ReserveOK => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+      match index.send_reply(Msg::Commit(e.hash.clone(), b"ref".to_vec())) {
+        Reply::CommitOK => (),
+        _ => panic!("Unexpected reply from hash index."),
+      }
+    }
+    match index.send_reply(Msg::Flush) {
+      Reply::CommitOK => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+
+    let found: Vec<Vec<u8>> =
+      HashIter::new(index.clone()).map(|e| e.hash.bytes.to_vec()).collect();
+    assert_eq!(entries.iter().map(|e| e.hash.bytes.to_vec()).collect::<Vec<_>>(), found);
+
+    // Stopping early leaves the index running:
+    assert_eq!(1, HashIter::new(index.clone()).take(1).count());
+    match index.send_reply(Msg::CallbackCount) {
+      Reply::CallbackCount(0) => (),
+      _ => panic!("Unexpected reply from hash index."),
+    }
+  }
+}
@@ -0,0 +1,348 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Versioned encoding of branch payloads (the child digests of a hash tree node).
+//!
+//! Every encoded payload starts with a header byte naming its version, so that later layouts can
+//! coexist with data written by older versions:
+//!
+//! - `PayloadVersion::V1` (header `1`): a byte with the digest width, followed by the child
+//!   digests concatenated in order.
+//! - `PayloadVersion::V2` (header `2`): a byte with the digest width, followed by each child as an
+//!   8-byte big-endian sequence number and its digest. Children are decoded in sequence order, so
+//!   that a reordered payload is still read in the original order, and the sequence numbers must
+//!   count up from `0` without gaps or duplicates.
+//!
+//! Either version can mark its children as digest-ordered by setting the high bit of the header
+//! (see `ChildOrder`). Such payloads list their children sorted by digest (see
+//! `sort_hashes_canonical`) rather than by position, e.g. for trees of unordered sets, whose
+//! hashes must not depend on the order their members were added in. The ordering is checked when
+//! decoding.
+//!
+//! The header `0` is never written. It marks rows from before payloads were versioned, whose
+//! payload is the bare concatenation of child digests (see `upgrade_legacy`).
+
+use hash_bytes::{HashBytes};
+use hash_index::{Hash};
+
+
+/// Version of rows whose payload has no header.
+pub const LEGACY_PAYLOAD_VERSION: u8 = 0;
+
+/// Header bit of payloads whose children are ordered by digest.
+const DIGEST_ORDERED: u8 = 0x80;
+
+/// How the children of a branch are ordered in its payload.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChildOrder {
+  /// By their position in the tree, e.g. the order of the blocks of a file.
+  Position,
+  /// By digest, regardless of the order they were added in.
+  Digest,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PayloadVersion {
+  V1,
+  V2,
+}
+
+impl PayloadVersion {
+  pub fn current() -> PayloadVersion {
+    PayloadVersion::V1
+  }
+
+  pub fn header(&self) -> u8 {
+    match *self {
+      PayloadVersion::V1 => 1,
+      PayloadVersion::V2 => 2,
+    }
+  }
+
+  pub fn from_header(header: u8) -> Option<PayloadVersion> {
+    match header {
+      1 => Some(PayloadVersion::V1),
+      2 => Some(PayloadVersion::V2),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DecodeError {
+  Empty,
+  UnknownVersion(u8),
+  Truncated,
+  /// The digests do not divide evenly into the declared width.
+  Misaligned,
+  /// No child has this sequence number, although later ones do.
+  SequenceGap(u64),
+  /// Several children have this sequence number.
+  DuplicateSequence(u64),
+  /// The payload is marked as digest-ordered, but its children are not sorted by digest.
+  Unordered,
+}
+
+
+/// Sort hashes into the canonical order of digest-ordered payloads (bytewise by digest).
+pub fn sort_hashes_canonical(hashes: &mut [Hash]) {
+  hashes.sort_by(|a, b| a.bytes.cmp(&b.bytes));
+}
+
+
+/// Encode the child digests of a branch. All children must have the same digest width.
+pub fn encode_children(version: PayloadVersion, children: &[Hash]) -> Vec<u8> {
+  match version {
+    PayloadVersion::V2 => {
+      let sequenced: Vec<(u64, Hash)> = children.iter().cloned().enumerate()
+                                                .map(|(seq, child)| (seq as u64, child))
+                                                .collect();
+      encode_sequenced(&sequenced[..])
+    },
+    PayloadVersion::V1 => {
+      let width = children.first().map(|h| h.bytes.len()).unwrap_or(0);
+      assert!(width <= 255);
+
+      let mut bytes = Vec::with_capacity(2 + width * children.len());
+      bytes.push(version.header());
+      bytes.push(width as u8);
+      for child in children.iter() {
+        assert_eq!(width, child.bytes.len());
+        bytes.extend(child.bytes.iter().cloned());
+      }
+      bytes
+    },
+  }
+}
+
+/// Like `encode_children`, but with the given ordering. Digest-ordered children are sorted first,
+/// so they may be given in any order.
+pub fn encode_children_ordered(version: PayloadVersion, order: ChildOrder, children: &[Hash])
+                               -> Vec<u8> {
+  match order {
+    ChildOrder::Position => encode_children(version, children),
+    ChildOrder::Digest => {
+      let mut sorted = children.to_vec();
+      sort_hashes_canonical(&mut sorted[..]);
+      let mut bytes = encode_children(version, &sorted[..]);
+      bytes[0] |= DIGEST_ORDERED;
+      bytes
+    },
+  }
+}
+
+/// The ordering of the children of an encoded payload, or `None` if it has no known header.
+pub fn child_order(bytes: &[u8]) -> Option<ChildOrder> {
+  match bytes.first() {
+    Some(&h) if PayloadVersion::from_header(h & !DIGEST_ORDERED).is_some() => {
+      Some(if h & DIGEST_ORDERED != 0 { ChildOrder::Digest } else { ChildOrder::Position })
+    },
+    _ => None,
+  }
+}
+
+/// Encode the child digests of a branch with explicit sequence numbers (as `PayloadVersion::V2`),
+/// in the order given.
+pub fn encode_sequenced(children: &[(u64, Hash)]) -> Vec<u8> {
+  let width = children.first().map(|&(_, ref h)| h.bytes.len()).unwrap_or(0);
+  assert!(width <= 255);
+
+  let mut bytes = Vec::with_capacity(2 + (8 + width) * children.len());
+  bytes.push(PayloadVersion::V2.header());
+  bytes.push(width as u8);
+  for &(seq, ref child) in children.iter() {
+    assert_eq!(width, child.bytes.len());
+    for shift in (0..8).rev() {
+      bytes.push((seq >> (8 * shift)) as u8);
+    }
+    bytes.extend(child.bytes.iter().cloned());
+  }
+  bytes
+}
+
+/// Decode the child digests of a branch, dispatching on the header byte.
+/// Children of digest-ordered payloads are returned in digest order, or `Unordered` if they are
+/// not sorted.
+pub fn decode_children(bytes: &[u8]) -> Result<Vec<Hash>, DecodeError> {
+  let children = try!(decode_unchecked(bytes));
+  if child_order(bytes) == Some(ChildOrder::Digest) &&
+     children.windows(2).any(|pair| pair[0].bytes > pair[1].bytes) {
+    return Err(DecodeError::Unordered);
+  }
+  Ok(children)
+}
+
+fn decode_unchecked(bytes: &[u8]) -> Result<Vec<Hash>, DecodeError> {
+  let header = match bytes.first() {
+    Some(&h) => h & !DIGEST_ORDERED,
+    None => return Err(DecodeError::Empty),
+  };
+  match PayloadVersion::from_header(header) {
+    Some(PayloadVersion::V1) => {
+      if bytes.len() < 2 {
+        return Err(DecodeError::Truncated);
+      }
+      let width = bytes[1] as usize;
+      let digests = &bytes[2..];
+      if width == 0 {
+        return if digests.len() == 0 { Ok(vec!()) } else { Err(DecodeError::Misaligned) };
+      }
+      if digests.len() % width != 0 {
+        return Err(DecodeError::Misaligned);
+      }
+      Ok(digests.chunks(width).map(|d| Hash{bytes: HashBytes::new(d)}).collect())
+    },
+    Some(PayloadVersion::V2) => {
+      if bytes.len() < 2 {
+        return Err(DecodeError::Truncated);
+      }
+      let width = bytes[1] as usize;
+      let children = &bytes[2..];
+      if children.len() % (8 + width) != 0 {
+        return Err(DecodeError::Misaligned);
+      }
+      let mut sequenced: Vec<(u64, Hash)> = children.chunks(8 + width).map(|c| {
+        let seq = c[..8].iter().fold(0u64, |seq, &b| (seq << 8) | b as u64);
+        (seq, Hash{bytes: HashBytes::new(&c[8..])})
+      }).collect();
+      sequenced.sort_by(|a, b| a.0.cmp(&b.0));
+
+      for (expected, &(seq, _)) in sequenced.iter().enumerate() {
+        let expected = expected as u64;
+        if seq < expected {
+          return Err(DecodeError::DuplicateSequence(seq));
+        } else if seq > expected {
+          return Err(DecodeError::SequenceGap(expected));
+        }
+      }
+      Ok(sequenced.into_iter().map(|(_, child)| child).collect())
+    },
+    None => Err(DecodeError::UnknownVersion(bytes[0])),
+  }
+}
+
+/// Re-encode a headerless payload of `digest_width` wide digests with the current version.
+pub fn upgrade_legacy(bytes: &[u8], digest_width: usize) -> Result<Vec<u8>, DecodeError> {
+  if digest_width == 0 || bytes.len() % digest_width != 0 {
+    return Err(DecodeError::Misaligned);
+  }
+  let children: Vec<Hash> =
+    bytes.chunks(digest_width).map(|d| Hash{bytes: HashBytes::new(d)}).collect();
+  Ok(encode_children(PayloadVersion::current(), &children[..]))
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use hash_index::{Hash};
+
+  fn children() -> Vec<Hash> {
+    vec!(Hash::new(b"foo"), Hash::new(b"bar"), Hash::new(b"baz"))
+  }
+
+  #[test]
+  fn round_trip() {
+    let encoded = encode_children(PayloadVersion::V1, &children()[..]);
+    assert_eq!(PayloadVersion::V1.header(), encoded[0]);
+    assert_eq!(Ok(children()), decode_children(&encoded[..]));
+
+    let empty = encode_children(PayloadVersion::V1, &[]);
+    assert_eq!(Ok(vec!()), decode_children(&empty[..]));
+  }
+
+  #[test]
+  fn sequenced_round_trip() {
+    let encoded = encode_children(PayloadVersion::V2, &children()[..]);
+    assert_eq!(PayloadVersion::V2.header(), encoded[0]);
+    assert_eq!(Ok(children()), decode_children(&encoded[..]));
+
+    // Children are decoded in sequence order, whatever their order in the payload:
+    let c = children();
+    let reordered = encode_sequenced(&[(2, c[2].clone()), (0, c[0].clone()), (1, c[1].clone())]);
+    assert_eq!(Ok(children()), decode_children(&reordered[..]));
+  }
+
+  #[test]
+  fn invalid_sequences() {
+    let c = children();
+    let gap = encode_sequenced(&[(0, c[0].clone()), (2, c[1].clone())]);
+    assert_eq!(Err(DecodeError::SequenceGap(1)), decode_children(&gap[..]));
+
+    let duplicate = encode_sequenced(&[(0, c[0].clone()), (1, c[1].clone()), (1, c[2].clone())]);
+    assert_eq!(Err(DecodeError::DuplicateSequence(1)), decode_children(&duplicate[..]));
+
+    let mut truncated = encode_children(PayloadVersion::V2, &c[..]);
+    truncated.pop();
+    assert_eq!(Err(DecodeError::Misaligned), decode_children(&truncated[..]));
+  }
+
+  #[test]
+  fn digest_ordered_round_trip() {
+    let mut sorted = children();
+    sort_hashes_canonical(&mut sorted[..]);
+    assert!(sorted.windows(2).all(|pair| pair[0].bytes < pair[1].bytes));
+
+    for &version in [PayloadVersion::V1, PayloadVersion::V2].iter() {
+      let encoded = encode_children_ordered(version, ChildOrder::Digest, &children()[..]);
+      assert_eq!(Some(ChildOrder::Digest), child_order(&encoded[..]));
+      assert_eq!(Ok(sorted.clone()), decode_children(&encoded[..]));
+
+      // Any order of the same children gives the same payload:
+      let mut reversed = children();
+      reversed.reverse();
+      assert_eq!(encoded, encode_children_ordered(version, ChildOrder::Digest, &reversed[..]));
+
+      let positioned = encode_children_ordered(version, ChildOrder::Position, &children()[..]);
+      assert_eq!(encode_children(version, &children()[..]), positioned);
+      assert_eq!(Some(ChildOrder::Position), child_order(&positioned[..]));
+      assert_eq!(Ok(children()), decode_children(&positioned[..]));
+    }
+  }
+
+  #[test]
+  fn unsorted_digest_ordered_payloads_are_rejected() {
+    let mut unsorted = encode_children(PayloadVersion::V1, &children()[..]);
+    unsorted[0] |= 0x80;
+    assert_eq!(Err(DecodeError::Unordered), decode_children(&unsorted[..]));
+    assert_eq!(None, child_order(&[0x80 | 7]));
+    assert_eq!(Err(DecodeError::UnknownVersion(0x80 | 7)), decode_children(&[0x80 | 7]));
+  }
+
+  #[test]
+  fn invalid_payloads() {
+    assert_eq!(Err(DecodeError::Empty), decode_children(&[]));
+    assert_eq!(Err(DecodeError::UnknownVersion(LEGACY_PAYLOAD_VERSION)),
+               decode_children(&[LEGACY_PAYLOAD_VERSION, 1, 2]));
+    assert_eq!(Err(DecodeError::Truncated), decode_children(&[1]));
+
+    let mut encoded = encode_children(PayloadVersion::V1, &children()[..]);
+    encoded.pop();
+    assert_eq!(Err(DecodeError::Misaligned), decode_children(&encoded[..]));
+  }
+
+  #[test]
+  fn legacy_payloads_are_upgraded() {
+    let mut legacy = vec!();
+    for child in children().into_iter() {
+      legacy.extend(child.bytes.iter().cloned());
+    }
+    let width = Hash::new(b"").bytes.len();
+
+    let upgraded = upgrade_legacy(&legacy[..], width).unwrap();
+    assert_eq!(encode_children(PayloadVersion::current(), &children()[..]), upgraded);
+    assert_eq!(Err(DecodeError::Misaligned), upgrade_legacy(&legacy[1..], width));
+  }
+}
@@ -21,7 +21,9 @@
 // use serialize::json;
 
 use rustc_serialize::json;
+use hash_bytes::{HashBytes};
 use hash_index::{Hash};
+use hash_payload::{PayloadVersion, encode_children};
 use std::{str};
 
 
@@ -81,6 +83,7 @@ pub struct SimpleHashTreeWriter<B> {
   backend: B,
   order: usize,
   levels: Vec<Vec<HashRef>>,  // Representation of rightmost path to root
+  payload_version: PayloadVersion,
 }
 
 impl <B: HashTreeBackend + Clone> SimpleHashTreeWriter<B> {
@@ -89,7 +92,15 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeWriter<B> {
   pub fn new(order: usize, backend: B) -> SimpleHashTreeWriter<B> {
     SimpleHashTreeWriter{backend: backend,
                          order: order,
-                         levels: Vec::new()}
+                         levels: Vec::new(),
+                         payload_version: PayloadVersion::current()}
+  }
+
+  /// Encode branch payloads as `version`, e.g. `PayloadVersion::V2` to record the order of the
+  /// children explicitly. The hashes of the tree do not depend on the version.
+  pub fn with_payload_version(mut self, version: PayloadVersion) -> SimpleHashTreeWriter<B> {
+    self.payload_version = version;
+    self
   }
 
   fn top_level(&self) -> Option<usize> {
@@ -114,7 +125,7 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeWriter<B> {
 
   fn append_at(&mut self, level: usize, hash: Hash, data: Vec<u8>, metadata: Option<Vec<u8>>) {
     let persistent_ref = self.backend.insert_chunk(hash.clone(), level as i64, metadata, data);
-    let hash_ref = HashRef::new(hash.bytes, persistent_ref);
+    let hash_ref = HashRef::new(hash.bytes.to_vec(), persistent_ref);
     self.append_hashref_at(level, hash_ref);
   }
 
@@ -144,16 +155,19 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeWriter<B> {
     assert_eq!(Some(level_v.clone()), hash_refs_from_bytes(&data[..]));
 
     // The hashes for this level is stored as metadata for future use:
-    let metadata_bytes = {
-      let mut metadata = Vec::new();
-      for hashref in level_v.into_iter() {
-        metadata.extend(hashref.hash.into_iter());
-      }
-      metadata
-    };
+    let children: Vec<Hash> = level_v.into_iter()
+                                     .map(|hashref| Hash{bytes: HashBytes::from_vec(hashref.hash)})
+                                     .collect();
+    let metadata = encode_children(self.payload_version, &children[..]);
+
+    // The node is identified by its bare child hashes, so that trees keep their hashes:
+    let mut hashes_bytes = Vec::new();
+    for child in children.into_iter() {
+      hashes_bytes.extend(child.bytes.iter().cloned());
+    }
 
-    let hash = Hash::new(metadata_bytes.as_slice());
-    self.append_at(level + 1, hash, data, Some(metadata_bytes));
+    let hash = Hash::new(hashes_bytes.as_slice());
+    self.append_at(level + 1, hash, data, Some(metadata));
   }
 
   /// Retrieve the hash and backend persistent reference that identified this tree.
@@ -184,7 +198,7 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeWriter<B> {
     assert_eq!(self.levels.last().map(|x| x.len()), Some(1));
     let hashref = self.levels.last().and_then(|x| x.last()).expect("asserted");
 
-    (Hash{bytes:hashref.hash.clone()}, hashref.persistent_ref.clone())
+    (Hash{bytes: HashBytes::new(&hashref.hash[..])}, hashref.persistent_ref.clone())
   }
 }
 
@@ -252,7 +266,7 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeReader<B> {
     while self.stack.len() > 0 {
       let child = self.stack.pop().expect("len() > 0");
 
-      let hash = Hash{bytes: child.hash};
+      let hash = Hash{bytes: HashBytes::from_vec(child.hash)};
       let data = self.backend.fetch_chunk(hash).expect("Invalid hash ref");
 
       match hash_refs_from_bytes(data.as_slice()) {
@@ -298,6 +312,7 @@ mod tests {
   use std::sync::{Arc, Mutex};
 
   use hash_index::{Hash};
+  use hash_payload::{PayloadVersion, decode_children};
   use std::collections::{BTreeMap, BTreeSet};
 
   #[derive(Clone)]
@@ -323,18 +338,18 @@ mod tests {
 
     fn fetch_chunk(&mut self, hash:Hash) -> Option<Vec<u8>> {
       let guarded_chunks = self.chunks.lock().unwrap();
-      guarded_chunks.get(&hash.bytes).map(|&(_, _, ref chunk)| chunk.clone())
+      guarded_chunks.get(&hash.bytes[..]).map(|&(_, _, ref chunk)| chunk.clone())
     }
 
     fn fetch_payload(&mut self, hash:Hash) -> Option<Vec<u8>> {
       let guarded_chunks = self.chunks.lock().unwrap();
-      guarded_chunks.get(&hash.bytes).and_then(|&(_, ref payload, _)| payload.clone())
+      guarded_chunks.get(&hash.bytes[..]).and_then(|&(_, ref payload, _)| payload.clone())
     }
 
     fn fetch_persistent_ref(&mut self, hash:Hash) -> Option<Vec<u8>> {
       let guarded_chunks = self.chunks.lock().unwrap();
-      if guarded_chunks.contains_key(&hash.bytes) {
-        Some(hash.bytes)
+      if guarded_chunks.contains_key(&hash.bytes[..]) {
+        Some(hash.bytes.to_vec())
       } else {
         None
       }
@@ -346,9 +361,9 @@ mod tests {
       guarded_seen.insert(chunk.clone());
 
       let mut guarded_chunks = self.chunks.lock().unwrap();
-      guarded_chunks.insert(hash.bytes.clone(), (level, payload, chunk));
+      guarded_chunks.insert(hash.bytes.to_vec(), (level, payload, chunk));
 
-      hash.bytes
+      hash.bytes.to_vec()
     }
   }
 
@@ -383,6 +398,39 @@ mod tests {
     true
   }
 
+  #[test]
+  fn branch_payloads_list_children() {
+    let backend = MemoryBackend::new();
+    let mut ht = SimpleHashTreeWriter::new(2, backend.clone());
+
+    ht.append(b"foo".to_vec());
+    ht.append(b"bar".to_vec());
+
+    let (hash, _) = ht.hash();
+    let payload = backend.clone().fetch_payload(hash.clone()).expect("branch has a payload");
+    assert_eq!(Ok(vec!(Hash::new(b"foo"), Hash::new(b"bar"))), decode_children(&payload[..]));
+
+    // The branch is identified by its bare child hashes:
+    let mut hashes = Hash::new(b"foo").bytes.to_vec();
+    hashes.extend(Hash::new(b"bar").bytes.iter().cloned());
+    assert_eq!(Hash::new(&hashes[..]), hash);
+  }
+
+  #[test]
+  fn sequenced_branch_payloads() {
+    let backend = MemoryBackend::new();
+    let mut ht = SimpleHashTreeWriter::new(2, backend.clone())
+      .with_payload_version(PayloadVersion::V2);
+
+    ht.append(b"foo".to_vec());
+    ht.append(b"bar".to_vec());
+
+    let (hash, _) = ht.hash();
+    let payload = backend.clone().fetch_payload(hash.clone()).expect("branch has a payload");
+    assert_eq!(PayloadVersion::V2.header(), payload[0]);
+    assert_eq!(Ok(vec!(Hash::new(b"foo"), Hash::new(b"bar"))), decode_children(&payload[..]));
+  }
+
   #[test]
   fn identity_empty() {
     let block = Vec::new();
@@ -25,6 +25,7 @@ use process::{Process};
 use blob_index::{BlobIndex};
 use blob_store::{BlobStore, BlobStoreProcess, BlobStoreBackend};
 
+use hash_bytes::{HashBytes};
 use hash_index::{Hash, HashIndex, HashIndexProcess};
 use key_index::{KeyIndex, KeyEntry};
 use key_store::{KeyStore, KeyStoreProcess};
@@ -158,7 +159,7 @@ impl <B: 'static + BlobStoreBackend + Clone + Send> Hat<B> {
           // TODO(jos): Replace all uses of JSON with either protocol bufffers or cap'n proto.
           let bytes = m.get("dir_hash").or(m.get("data_hash"))
             .and_then(|x| x.as_array()).unwrap().iter().map(|y| y.as_i64().unwrap() as u8);
-          let hash = Hash{bytes: bytes.collect()};
+          let hash = Hash{bytes: HashBytes::from_vec(bytes.collect())};
 
           let pref = m.get("dir_ref").or(m.get("data_ref"))
             .and_then(|x| x.as_array()).unwrap().iter().map(|x| x.as_i64().unwrap() as u8).collect();
@@ -478,7 +479,7 @@ impl Family
         self.commit_to_tree(&mut inner_tree, Some(id));
         // Store a reference for the sub-tree in our tree:
         let (dir_hash, dir_ref) = inner_tree.hash();
-        m.insert("dir_hash".to_string(), dir_hash.bytes.to_json());
+        m.insert("dir_hash".to_string(), dir_hash.bytes.to_vec().to_json());
         m.insert("dir_ref".to_string(), dir_ref.to_json());
       }
 
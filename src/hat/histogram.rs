@@ -0,0 +1,111 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A histogram with power-of-two buckets, for cheap approximate percentiles.
+
+use std::cmp;
+
+
+/// One bucket for `0`, and one for each bit length of a `u64`.
+const BUCKETS: usize = 65;
+
+
+pub struct Histogram {
+  // Bucket `i > 0` counts the values in `2^(i-1) .. 2^i - 1`.
+  buckets: Vec<u64>,
+  count: u64,
+  max: u64,
+}
+
+
+impl Histogram {
+
+  pub fn new() -> Histogram {
+    Histogram{buckets: vec![0; BUCKETS], count: 0, max: 0}
+  }
+
+  pub fn record(&mut self, value: u64) {
+    let bucket = 64 - value.leading_zeros() as usize;
+    self.buckets[bucket] += 1;
+    self.count += 1;
+    if value > self.max {
+      self.max = value;
+    }
+  }
+
+  pub fn count(&self) -> u64 {
+    self.count
+  }
+
+  pub fn max(&self) -> u64 {
+    self.max
+  }
+
+  /// An upper bound of the `percent`th percentile, which is at most twice the exact value.
+  /// Returns `0` if nothing was recorded.
+  pub fn percentile(&self, percent: u64) -> u64 {
+    assert!(percent <= 100);
+    // The rank of the percentile, counting from 1:
+    let rank = cmp::max(1, (self.count * percent + 99) / 100);
+    let mut seen = 0;
+    for (bucket, &n) in self.buckets.iter().enumerate() {
+      seen += n;
+      if seen >= rank {
+        let upper = if bucket == 0 { 0 } else { (((1u64 << (bucket - 1)) - 1) << 1) + 1 };
+        return cmp::min(upper, self.max);
+      }
+    }
+    0
+  }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn empty() {
+    let h = Histogram::new();
+    assert_eq!(0, h.count());
+    assert_eq!(0, h.max());
+    assert_eq!(0, h.percentile(50));
+  }
+
+  #[test]
+  fn percentiles() {
+    let mut h = Histogram::new();
+    for v in 1..101 {
+      h.record(v);
+    }
+    assert_eq!(100, h.count());
+    assert_eq!(100, h.max());
+
+    // 50 is in the bucket 32..63, and 95 in 64..127 (which is capped at the max):
+    assert_eq!(63, h.percentile(50));
+    assert_eq!(100, h.percentile(95));
+    assert_eq!(1, h.percentile(1));
+
+    h.record(0);
+    assert_eq!(0, h.percentile(0));
+  }
+
+  #[test]
+  fn large_values() {
+    let mut h = Histogram::new();
+    h.record(u64::max_value());
+    assert_eq!(u64::max_value(), h.percentile(50));
+  }
+}
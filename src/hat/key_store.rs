@@ -19,6 +19,7 @@ use std::thunk::Thunk;
 use blob_store;
 use hash_tree::{SimpleHashTreeWriter, HashTreeBackend,
                 SimpleHashTreeReader, ReaderResult};
+use hash_bytes::{HashBytes};
 use hash_index;
 
 use process::{Process, MsgHandler};
@@ -111,7 +112,9 @@ impl HashStoreBackend {
     assert!(hash.bytes.len() > 0);
     match self.hash_index.send_reply(hash_index::Msg::FetchPersistentRef(hash)) {
       hash_index::Reply::PersistentRef(chunk_ref_bytes) => {
-        let chunk_ref = blob_store::BlobID::from_bytes(chunk_ref_bytes);
+        // Relocated entries have a `BlobRef` rather than the blob store's own encoding:
+        let chunk_ref = hash_index::BlobRef::from_bytes(&chunk_ref_bytes[..])
+          .expect("Persistent reference is not a blob reference").blob_id();
         self.fetch_chunk_from_persistent_ref(chunk_ref)
       },
       _ => None  // TODO: Do we need to distinguish `missing` from `unknown ref`?
@@ -158,10 +161,13 @@ impl HashTreeBackend for HashStoreBackend
     assert!(hash.bytes.len() > 0);
 
     let mut hash_entry = hash_index::HashEntry{hash:hash.clone(), level:level, payload:payload,
-                                               persistent_ref: None};
+                                               persistent_ref: None,
+                                               content_len: chunk.len() as u64};
+    // Tag the reference with what it points at, so that walks of the tree can check it:
+    let kind = if level == 0 { hash_index::RefKind::Leaf } else { hash_index::RefKind::Branch };
 
     match self.hash_index.send_reply(hash_index::Msg::Reserve(hash_entry.clone())) {
-      hash_index::Reply::HashKnown => {
+      hash_index::Reply::HashKnown | hash_index::Reply::AlreadyReserved => {
         // Someone came before us: piggyback on their result.
         return self.fetch_persistent_ref(hash).expect(
           "Could not find persistent_ref for known chunk.");
@@ -171,13 +177,15 @@ impl HashTreeBackend for HashStoreBackend
         let local_hash_index = self.hash_index.clone();
 
         let callback = Box::new(move|blobid: blob_store::BlobID| {
-          local_hash_index.send_reply(hash_index::Msg::Commit(hash, blobid.as_bytes()));
+          let persistent_ref = hash_index::BlobRef::from_blob_id(&blobid, kind).to_bytes();
+          local_hash_index.send_reply(hash_index::Msg::Commit(hash, persistent_ref));
         });
         match self.blob_store.send_reply(blob_store::Msg::Store(chunk, callback)) {
           blob_store::Reply::StoreOK(blob_ref) => {
-            hash_entry.persistent_ref = Some(blob_ref.as_bytes());
+            let persistent_ref = hash_index::BlobRef::from_blob_id(&blob_ref, kind).to_bytes();
+            hash_entry.persistent_ref = Some(persistent_ref.clone());
             self.hash_index.send_reply(hash_index::Msg::UpdateReserved(hash_entry));
-            return blob_ref.as_bytes();
+            return persistent_ref;
           },
           _ => panic!("Unexpected reply from BlobStore."),
         };
@@ -214,7 +222,7 @@ impl
             let mut my_entries = Vec::with_capacity(entries.len());
             for (id, name, created, modified, accessed, hash, persistent_ref) in entries.into_iter()
             {
-              let local_hash = hash_index::Hash{bytes: hash.clone()};
+              let local_hash = hash_index::Hash{bytes: HashBytes::from_vec(hash.clone())};
               let local_ref = persistent_ref.clone();
 
               my_entries.push(
@@ -278,7 +286,7 @@ impl
 
             // Install a callback for updating the entry's data hash once the data has been stored:
             let local_index = self.index.clone();
-            let hash_bytes = hash.bytes.clone();
+            let hash_bytes = hash.bytes.to_vec();
             let callback = Box::new(move|| {
               local_index.send_reply(
                 key_index::Msg::UpdateDataHash(new_entry, Some(hash_bytes), Some(persistent_ref)));
@@ -42,6 +42,9 @@ extern crate sqlite3;
 extern crate rustc_serialize;
 extern crate threadpool;
 
+#[cfg(feature = "blake2")]
+extern crate blake2_rfc;
+
 // Testing
 #[cfg(test)]
 extern crate quickcheck;
@@ -50,8 +53,10 @@ extern crate quickcheck;
 use std::env;
 use std::path::PathBuf;
 
+mod bloom;
 mod callback_container;
 mod cumulative_counter;
+mod histogram;
 mod ordered_collection;
 mod periodic_timer;
 mod unique_priority_queue;
@@ -60,8 +65,18 @@ mod hat;
 mod listdir;
 mod process;
 
+mod audit;
+mod digest;
+mod hash_bytes;
 mod hash_index;
+mod hash_iter;
+#[cfg(test)]
+mod hash_index_bench;
+mod hash_index_wire;
+mod hash_backend;
+mod hash_payload;
 mod hash_tree;
+mod trace;
 
 mod blob_index;
 mod blob_store;
@@ -26,6 +26,10 @@ pub trait OrderedCollection<K: Clone + Ord, V> {
   fn pop_min_when<F>(&mut self, ready: F) -> Option<(K, V)>
     where F: Fn(&K, &V) -> bool;
   fn update_value<F>(&mut self, k: K, f: F) where F: FnOnce(Option<&V>) -> V;
+
+  /// Like `update_value`, but the key is removed (or not inserted) when `f` returns `None`.
+  fn update_or_remove<F>(&mut self, k: K, f: F) where F: FnOnce(Option<&V>) -> Option<V>;
+
   fn find_min<'a>(&'a self) -> Option<(&'a K, &'a V)>;
 }
 
@@ -42,6 +46,23 @@ impl <K: Clone + Ord, V> OrderedCollection<K, V> for BTreeMap<K, V> {
     }
   }
 
+  fn update_or_remove<F>(&mut self, k: K, f: F) where F: FnOnce(Option<&V>) -> Option<V> {
+    match self.entry(k) {
+      btree_map::Entry::Occupied(mut entry) => {
+        match f(Some(entry.get())) {
+          Some(new_v) => { entry.insert(new_v); },
+          None => { entry.remove(); },
+        }
+      },
+      btree_map::Entry::Vacant(space) => {
+        match f(None) {
+          Some(v) => { space.insert(v); },
+          None => (),
+        }
+      }
+    }
+  }
+
   fn pop_min_when<F>(&mut self, ready: F) -> Option<(K, V)>
     where F: Fn(&K, &V) -> bool
   {
@@ -54,3 +75,41 @@ impl <K: Clone + Ord, V> OrderedCollection<K, V> for BTreeMap<K, V> {
     self.iter().next()
   }
 }
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::collections::{BTreeMap};
+
+  fn decrement(count: Option<&u32>) -> Option<u32> {
+    match count {
+      Some(&1) => None,
+      Some(&n) => Some(n - 1),
+      None => Some(0),
+    }
+  }
+
+  #[test]
+  fn update_or_remove() {
+    let mut counts = BTreeMap::new();
+
+    // Insert:
+    counts.update_or_remove("foo", decrement);
+    assert_eq!(Some(&0), counts.get(&"foo"));
+
+    // Update:
+    counts.insert("bar", 2);
+    counts.update_or_remove("bar", decrement);
+    assert_eq!(Some(&1), counts.get(&"bar"));
+
+    // Remove:
+    counts.update_or_remove("bar", decrement);
+    assert_eq!(None, counts.get(&"bar"));
+
+    // Not inserted:
+    counts.update_or_remove("baz", |_| None);
+    assert_eq!(vec!(&"foo"), counts.keys().collect::<Vec<_>>());
+  }
+}
@@ -28,12 +28,19 @@ impl PeriodicTimer {
     PeriodicTimer{start: SteadyTime::now(), interval:interval}
   }
 
+  /// Whether `did_fire` would return true now. Unlike `did_fire`, this does not restart the
+  /// interval.
+  pub fn is_due(&self) -> bool {
+    SteadyTime::now() - self.start >= self.interval
+  }
+
+  /// Whether the interval has passed, in which case it restarts.
   pub fn did_fire(&mut self) -> bool {
-    if SteadyTime::now() - self.start >= self.interval {
+    if self.is_due() {
       self.start = SteadyTime::now();
       return true;
     } else {
-      return true;
+      return false;
     }
   }
 
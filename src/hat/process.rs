@@ -70,8 +70,35 @@ impl <Msg: Send, Reply: Send> Clone
   }
 }
 
+/// A pending reply to a request sent with `try_request()`.
+pub struct ReplyToken<Reply> {
+  receiver: mpsc::Receiver<Reply>,
+}
+
+impl <Reply: Send> ReplyToken<Reply> {
+
+  /// Non-blocking check for the reply.
+  ///
+  /// Returns `None` until the receiving `process` has replied. The reply is returned at most once.
+  pub fn poll(&self) -> Option<Reply> {
+    self.receiver.try_recv().ok()
+  }
+
+  /// Block until the reply is available.
+  pub fn wait(self) -> Reply {
+    self.receiver.recv().unwrap()
+  }
+}
+
 pub trait MsgHandler<Msg, Reply> {
   fn handle(&mut self, msg: Msg, callback: Box<Fn(Reply)>);
+
+  /// Whether the handler has put off work to answer messages sooner (see `resume`).
+  fn has_deferred_work(&self) -> bool { false }
+
+  /// Do a bounded part of the deferred work. The process calls this whenever no message is waiting,
+  /// and keeps calling it as long as `has_deferred_work` holds, also after the last sender is gone.
+  fn resume(&mut self) {}
 }
 
 impl <Msg:'static + Send, Reply:'static + Send>
@@ -99,12 +126,30 @@ impl <Msg:'static + Send, Reply:'static + Send>
       // fork handler
       let mut my_handler = handler_proc();
       loop {
-        match receiver.recv() {
+        let next = if my_handler.has_deferred_work() {
+          match receiver.try_recv() {
+            Ok(next) => Ok(next),
+            Err(mpsc::TryRecvError::Empty) => {
+              my_handler.resume();
+              continue;
+            },
+            Err(mpsc::TryRecvError::Disconnected) => {
+              while my_handler.has_deferred_work() {
+                my_handler.resume();
+              }
+              break;
+            },
+          }
+        } else {
+          receiver.recv()
+        };
+        match next {
           Ok((msg, None)) => {
             my_handler.handle(msg, Box::new(|_r: Reply| {}));
           },
           Ok((msg, Some(rep))) => {
-            my_handler.handle(msg, Box::new(move|r| { rep.send(r).unwrap(); }));
+            // The caller may have dropped its `ReplyToken`, and no longer wants the reply:
+            my_handler.handle(msg, Box::new(move|r| { rep.send(r).ok(); }));
           },
           Err(_recv_error) => break,
         };
@@ -112,6 +157,17 @@ impl <Msg:'static + Send, Reply:'static + Send>
     });
   }
 
+  /// Asynchronous send.
+  ///
+  /// Returns a `ReplyToken` that can be polled for the reply, allowing the caller to have several
+  /// requests outstanding at once. Note that this still blocks while the input-channel is full.
+  /// The token may be dropped without waiting; the reply is then discarded.
+  pub fn try_request(&self, msg: Msg) -> ReplyToken<Reply> {
+    let (sender, receiver) = mpsc::channel();
+    self.sender.send((msg, Some(sender))).ok();
+    ReplyToken{receiver: receiver}
+  }
+
   /// Synchronous send.
   ///
   /// Will always wait for a reply from the receiving `process`.
@@ -121,3 +177,78 @@ impl <Msg:'static + Send, Reply:'static + Send>
     return receiver.recv().unwrap();
   }
 }
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  struct Echo;
+
+  impl MsgHandler<u32, u32> for Echo {
+    fn handle(&mut self, msg: u32, reply: Box<Fn(u32)>) {
+      return reply(msg);
+    }
+  }
+
+  // Counts down in steps of one while idle:
+  struct Countdown(u32);
+
+  impl MsgHandler<u32, u32> for Countdown {
+    fn handle(&mut self, msg: u32, reply: Box<Fn(u32)>) {
+      let left = self.0;
+      self.0 += msg;
+      return reply(left);
+    }
+
+    fn has_deferred_work(&self) -> bool {
+      self.0 > 0
+    }
+
+    fn resume(&mut self) {
+      self.0 -= 1;
+    }
+  }
+
+  #[test]
+  fn deferred_work_runs_while_idle() {
+    let p: Process<u32, u32> = Process::new(Box::new(move|| { Countdown(0) }));
+
+    assert_eq!(0, p.send_reply(1000));
+    let mut left = p.send_reply(0);
+    while left > 0 {
+      left = p.send_reply(0);
+    }
+  }
+
+  #[test]
+  fn interleaved_requests() {
+    let p: Process<u32, u32> = Process::new(Box::new(move|| { Echo }));
+
+    let mut tokens: Vec<(u32, ReplyToken<u32>)> =
+      (0..5).map(|i| (i, p.try_request(i))).collect();
+
+    // Collect the replies in reverse order of the requests:
+    let mut replies = vec!();
+    while tokens.len() > 0 {
+      let (i, token) = tokens.pop().unwrap();
+      match token.poll() {
+        Some(r) => replies.push((i, r)),
+        None => tokens.insert(0, (i, token)),
+      }
+    }
+
+    assert_eq!(5, replies.len());
+    for &(i, r) in replies.iter() {
+      assert_eq!(i, r);
+    }
+  }
+
+  #[test]
+  fn dropped_requests() {
+    let p: Process<u32, u32> = Process::new(Box::new(move|| { Echo }));
+
+    drop(p.try_request(1));
+    assert_eq!(2, p.send_reply(2));
+  }
+}
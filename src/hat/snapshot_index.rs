@@ -21,6 +21,7 @@ use sqlite3::BindArg::{Blob};
 use sqlite3::types::ResultCode::{SQLITE_ROW, SQLITE_DONE, SQLITE_OK};
 use sqlite3::{open};
 
+use hash_bytes::{HashBytes};
 use hash_index;
 
 
@@ -82,7 +83,7 @@ impl SnapshotIndex {
       "INSERT INTO snapshot_index (family, hash, tree_ref) VALUES (?, ?, ?)", &None).unwrap();
 
     assert_eq!(SQLITE_OK, insert_stm.bind_param(1, &Blob(family.as_bytes().iter().map(|&x| x).collect())));
-    assert_eq!(SQLITE_OK, insert_stm.bind_param(2, &Blob(hash.bytes.clone())));
+    assert_eq!(SQLITE_OK, insert_stm.bind_param(2, &Blob(hash.bytes.to_vec())));
     assert_eq!(SQLITE_OK, insert_stm.bind_param(3, &Blob(tree_ref)));
 
     assert_eq!(SQLITE_DONE, insert_stm.step());
@@ -95,7 +96,7 @@ impl SnapshotIndex {
     assert_eq!(SQLITE_OK, lookup_stm.bind_param(1, &Blob(family.as_bytes().to_vec())));
 
     if lookup_stm.step() == SQLITE_ROW {
-      return Some((hash_index::Hash{bytes: lookup_stm.get_blob(0).unwrap().to_vec()},
+      return Some((hash_index::Hash{bytes: HashBytes::new(lookup_stm.get_blob(0).unwrap())},
                    lookup_stm.get_blob(1).unwrap().to_vec()));
     }
     return None;
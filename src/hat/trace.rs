@@ -0,0 +1,120 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A bounded trace of the state transitions of hashes, for debugging deduplication.
+
+use std::collections::{HashMap, VecDeque};
+
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TraceKind {
+  Reserved,
+  Updated,
+  Committed,
+  /// The entry was written to the backend (but not necessarily flushed).
+  Inserted,
+  /// The callbacks waiting for the entry were called.
+  CallbackFired,
+  Abandoned,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceEvent {
+  pub kind: TraceKind,
+  /// Milliseconds since the index was opened.
+  pub at_ms: i64,
+}
+
+/// How much is traced: the most recent `events_per_hash` events of at most `max_hashes` hashes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TraceLimits {
+  pub events_per_hash: usize,
+  pub max_hashes: usize,
+}
+
+
+pub struct Trace {
+  limits: TraceLimits,
+  events: HashMap<Vec<u8>, VecDeque<TraceEvent>>,
+  // The traced hashes in the order they were first traced, to forget the oldest first.
+  hashes: VecDeque<Vec<u8>>,
+}
+
+
+impl Trace {
+
+  pub fn new(limits: TraceLimits) -> Trace {
+    assert!(limits.events_per_hash > 0 && limits.max_hashes > 0);
+    Trace{limits: limits, events: HashMap::new(), hashes: VecDeque::new()}
+  }
+
+  pub fn record(&mut self, hash_bytes: &[u8], kind: TraceKind, at_ms: i64) {
+    if !self.events.contains_key(hash_bytes) {
+      if self.hashes.len() == self.limits.max_hashes {
+        let oldest = self.hashes.pop_front().expect("max_hashes > 0");
+        self.events.remove(&oldest);
+      }
+      self.hashes.push_back(hash_bytes.to_vec());
+      self.events.insert(hash_bytes.to_vec(), VecDeque::new());
+    }
+
+    let events = self.events.get_mut(hash_bytes).expect("hash is traced");
+    if events.len() == self.limits.events_per_hash {
+      events.pop_front();
+    }
+    events.push_back(TraceEvent{kind: kind, at_ms: at_ms});
+  }
+
+  /// The retained events of a hash, oldest first.
+  pub fn events_for(&self, hash_bytes: &[u8]) -> Vec<TraceEvent> {
+    self.events.get(hash_bytes).map(|events| events.iter().cloned().collect()).unwrap_or(vec!())
+  }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn limits(events_per_hash: usize, max_hashes: usize) -> TraceLimits {
+    TraceLimits{events_per_hash: events_per_hash, max_hashes: max_hashes}
+  }
+
+  #[test]
+  fn keeps_recent_events() {
+    let mut trace = Trace::new(limits(2, 10));
+    let foo = b"foo".to_vec();
+    trace.record(&foo, TraceKind::Reserved, 1);
+    trace.record(&foo, TraceKind::Committed, 2);
+    trace.record(&foo, TraceKind::Inserted, 3);
+
+    assert_eq!(vec!(TraceEvent{kind: TraceKind::Committed, at_ms: 2},
+                    TraceEvent{kind: TraceKind::Inserted, at_ms: 3}),
+               trace.events_for(&foo));
+    assert_eq!(Vec::<TraceEvent>::new(), trace.events_for(&b"bar".to_vec()));
+  }
+
+  #[test]
+  fn forgets_oldest_hashes() {
+    let mut trace = Trace::new(limits(2, 2));
+    let hashes = vec!(b"a".to_vec(), b"b".to_vec(), b"c".to_vec());
+    for (i, hash) in hashes.iter().enumerate() {
+      trace.record(hash, TraceKind::Reserved, i as i64);
+    }
+    assert_eq!(0, trace.events_for(&hashes[0]).len());
+    assert_eq!(1, trace.events_for(&hashes[1]).len());
+    assert_eq!(1, trace.events_for(&hashes[2]).len());
+  }
+}
@@ -14,6 +14,7 @@
 
 #![plugin(quickcheck_macros)]
 
+use std::borrow::{Borrow};
 use std::collections::{BTreeMap};
 use std::fmt::{Debug};
 
@@ -49,10 +50,18 @@ impl <P: Debug + Clone + Ord, K: Debug + Ord + Clone, V: Clone>
     return Ok(());
   }
 
-  pub fn find_key<'a>(&'a self, k: &K) -> Option<&'a P> {
+  pub fn find_key<'a, Q: ?Sized + Ord>(&'a self, k: &Q) -> Option<&'a P> where K: Borrow<Q> {
     self.key_to_priority.get(k)
   }
 
+  /// The stored key that equals `k`, e.g. to share it when keys are reference counted.
+  pub fn find_stored_key<Q: ?Sized + Ord>(&self, k: &Q) -> Option<K> where K: Borrow<Q> {
+    self.key_to_priority.get(k).and_then(|prio| self.priority.get(prio))
+      .map(|&(ref status, _)| match *status {
+        Status::Pending(ref k) | Status::Ready(ref k) => k.clone(),
+      })
+  }
+
   pub fn put_value(&mut self, k: K, v: V) {
     let prio = self.key_to_priority.get(&k).expect("put_value: Key must exist.");
     self.priority.update_value(prio.clone(), |opt| match opt {
@@ -61,12 +70,21 @@ impl <P: Debug + Clone + Ord, K: Debug + Ord + Clone, V: Clone>
     });
   }
 
-  pub fn find_value_of_key<'a>(&'a self, k: &K) -> Option<V> {
+  pub fn find_value_of_key<Q: ?Sized + Ord>(&self, k: &Q) -> Option<V> where K: Borrow<Q> {
     let prio_opt = self.key_to_priority.get(k);
     prio_opt.and_then(|prio| self.priority.get(prio).and_then(|&(_, ref v_opt)| v_opt.clone()))
   }
 
-  pub fn update_value<F>(&mut self, k: &K, f: F) where F: Fn(&V) -> V {
+  /// Find the key and value (if set) at priority `p`.
+  pub fn find_priority(&self, p: &P) -> Option<(K, Option<V>)> {
+    self.priority.get(p).map(|&(ref status, ref v_opt)| match *status {
+      Status::Pending(ref k) | Status::Ready(ref k) => (k.clone(), v_opt.clone()),
+    })
+  }
+
+  pub fn update_value<Q: ?Sized + Ord, F>(&mut self, k: &Q, f: F)
+    where K: Borrow<Q>, F: Fn(&V) -> V
+  {
     let prio = self.key_to_priority.get(k).expect("update_value: Key must exist.");
     self.priority.update_value(prio.clone(), |opt| match opt {
       Some(&(ref status, Some(ref v))) => (status.clone(), Some(f(v))),
@@ -74,6 +92,15 @@ impl <P: Debug + Clone + Ord, K: Debug + Ord + Clone, V: Clone>
     });
   }
 
+  /// Whether the priority of `k` was set ready, or `None` if `k` is not queued.
+  pub fn is_ready<Q: ?Sized + Ord>(&self, k: &Q) -> Option<bool> where K: Borrow<Q> {
+    self.key_to_priority.get(k).and_then(|prio| self.priority.get(prio))
+      .map(|&(ref status, _)| match *status {
+        Status::Pending(_) => false,
+        Status::Ready(_) => true,
+      })
+  }
+
   pub fn set_ready(&mut self, p: P) {
     self.priority.update_value(p, |opt| match opt {
       Some(&(Status::Pending(ref k), ref v_opt)) => (Status::Ready(k.clone()), v_opt.clone()),
@@ -81,6 +108,14 @@ impl <P: Debug + Clone + Ord, K: Debug + Ord + Clone, V: Clone>
     });
   }
 
+  /// Undo `set_ready`, e.g. when the commit of a key is rolled back.
+  pub fn set_pending(&mut self, p: P) {
+    self.priority.update_value(p, |opt| match opt {
+      Some(&(Status::Ready(ref k), ref v_opt)) => (Status::Pending(k.clone()), v_opt.clone()),
+      _ => unreachable!(),
+    });
+  }
+
   pub fn pop_min_if_complete(&mut self) -> Option<(P, K, V)> {
     let min_opt = self.priority.pop_min_when(|_k, min| match min {
       &(Status::Ready(_), Some(_)) => true,  // We are ready and have a value
@@ -96,10 +131,61 @@ impl <P: Debug + Clone + Ord, K: Debug + Ord + Clone, V: Clone>
     }})
   }
 
-  fn len(&self) -> usize {
+  /// Find the keys that are not yet ready and whose value satisfies `f`.
+  pub fn find_pending<F>(&self, f: F) -> Vec<K> where F: Fn(&V) -> bool {
+    self.priority.values().filter_map(|&(ref status, ref v_opt)| match (status, v_opt) {
+      (&Status::Pending(ref k), &Some(ref v)) if f(v) => Some(k.clone()),
+      _ => None,
+    }).collect()
+  }
+
+  /// Remove a key that is not yet ready, releasing its priority.
+  /// Returns `None` if the key does not exist or is already ready.
+  pub fn remove_pending<Q: ?Sized + Ord>(&mut self, k: &Q) -> Option<(P, Option<V>)>
+    where K: Borrow<Q>
+  {
+    let p = match self.key_to_priority.get(k) {
+      Some(p) => p.clone(),
+      None => return None,
+    };
+    match self.priority.get(&p) {
+      Some(&(Status::Pending(_), _)) => (),
+      _ => return None,
+    }
+    self.key_to_priority.remove(k);
+    self.priority.remove(&p).map(|(_, v_opt)| (p, v_opt))
+  }
+
+  /// The number of entries (ready or not) whose priority satisfies `f`.
+  pub fn count_priorities<F>(&self, f: F) -> usize where F: Fn(&P) -> bool {
+    self.priority.keys().filter(|p| f(p)).count()
+  }
+
+  /// All entries in priority order, with whether they are ready.
+  #[cfg(test)]
+  pub fn dump(&self) -> Vec<(P, K, bool)> {
+    self.priority.iter().map(|(p, &(ref status, _))| match *status {
+      Status::Pending(ref k) => (p.clone(), k.clone(), false),
+      Status::Ready(ref k) => (p.clone(), k.clone(), true),
+    }).collect()
+  }
+
+  pub fn len(&self) -> usize {
     self.priority.len()
   }
 
+  /// Release memory kept after a burst. The maps release their nodes as entries are removed, so
+  /// this only drops the allocations that empty maps may keep.
+  /// Returns whether anything was released.
+  pub fn shrink(&mut self) -> bool {
+    if self.priority.len() > 0 {
+      return false;
+    }
+    self.priority = BTreeMap::new();
+    self.key_to_priority = BTreeMap::new();
+    true
+  }
+
 }
 
 
@@ -108,6 +194,7 @@ mod tests {
   use super::*;
 
   use std::collections::{BTreeMap};
+  use std::rc::{Rc};
 
   #[quickcheck]
   fn insert1(priority: i8, key: isize, value: i8) -> bool {
@@ -122,6 +209,82 @@ mod tests {
     return true;
   }
 
+  #[test]
+  fn remove_pending() {
+    let mut upq = UniquePriorityQueue::new();
+    assert!(upq.reserve_priority(1, 10).is_ok());
+    assert!(upq.reserve_priority(2, 20).is_ok());
+    upq.put_value(10, 100);
+    upq.put_value(20, 200);
+
+    assert_eq!(upq.find_pending(|&v| v > 150), vec!(20));
+
+    upq.set_ready(2);
+    assert_eq!(upq.find_pending(|&v| v > 150), vec!());
+    assert_eq!(upq.remove_pending(&20), None);
+    assert_eq!(upq.pop_min_if_complete(), None);
+
+    assert_eq!(upq.remove_pending(&10), Some((1, Some(100))));
+    assert_eq!(upq.remove_pending(&10), None);
+    assert_eq!(upq.pop_min_if_complete(), Some((2, 20, 200)));
+  }
+
+  #[test]
+  fn count_priorities() {
+    let mut upq: UniquePriorityQueue<i32, i32, i32> = UniquePriorityQueue::new();
+    assert!(upq.reserve_priority(1, 10).is_ok());
+    assert!(upq.reserve_priority(2, 20).is_ok());
+    assert!(upq.reserve_priority(3, 30).is_ok());
+    upq.set_ready(3);
+
+    assert_eq!(upq.count_priorities(|&p| p >= 2), 2);
+    assert_eq!(upq.count_priorities(|&p| p > 3), 0);
+  }
+
+  #[test]
+  fn is_ready() {
+    let mut upq: UniquePriorityQueue<i32, i32, i32> = UniquePriorityQueue::new();
+    assert!(upq.reserve_priority(1, 10).is_ok());
+    assert_eq!(upq.is_ready(&10), Some(false));
+    upq.set_ready(1);
+    assert_eq!(upq.is_ready(&10), Some(true));
+    assert_eq!(upq.is_ready(&20), None);
+
+    upq.set_pending(1);
+    assert_eq!(upq.is_ready(&10), Some(false));
+  }
+
+  #[test]
+  fn shrink_only_when_empty() {
+    let mut upq = UniquePriorityQueue::new();
+    assert!(upq.reserve_priority(1, 10).is_ok());
+    upq.put_value(10, 100);
+    assert!(!upq.shrink());
+
+    upq.set_ready(1);
+    assert_eq!(upq.pop_min_if_complete(), Some((1, 10, 100)));
+    assert!(upq.shrink());
+    assert!(upq.reserve_priority(1, 10).is_ok());
+    assert_eq!(upq.len(), 1);
+  }
+
+  #[test]
+  fn stored_keys_are_shared() {
+    let mut upq = UniquePriorityQueue::new();
+    let key = Rc::new(b"foo".to_vec());
+    assert!(upq.reserve_priority(1, key.clone()).is_ok());
+    upq.put_value(key.clone(), 100);
+
+    // Keys can be looked up by what they borrow as:
+    let foo = b"foo".to_vec();
+    assert_eq!(upq.find_key(&foo), Some(&1));
+    assert_eq!(upq.find_value_of_key(&foo), Some(100));
+
+    let stored = upq.find_stored_key(&foo).unwrap();
+    assert_eq!(&*key as *const Vec<u8>, &*stored as *const Vec<u8>);
+    assert_eq!(upq.find_stored_key(&b"bar".to_vec()), None);
+  }
+
   #[quickcheck]
   fn insert_many(keys: Vec<(i8, isize, i8)>) -> bool {
     let mut upq = UniquePriorityQueue::new();